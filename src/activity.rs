@@ -0,0 +1,33 @@
+use std::sync::Mutex;
+
+use futures::future::join_all;
+use gitlab::AsyncGitlab;
+use log::warn;
+
+use crate::{
+    gitlab_wrap::fetch_latest_runner_job,
+    state::{Activity, StateDb},
+};
+
+/// Records when the runner of each registered instance last started executing a job, according to GitLab
+pub async fn refresh_executed(client: &AsyncGitlab, db: &Mutex<StateDb>) -> anyhow::Result<()> {
+    let registrations = db.lock().unwrap().read_registrations()?;
+    let results = join_all(
+        registrations
+            .values()
+            .map(|registration| fetch_latest_runner_job(client, registration.id)),
+    )
+    .await;
+    let db = db.lock().unwrap();
+    for (name, result) in registrations.keys().zip(results) {
+        match result {
+            Ok(job) => {
+                if let Some(started_at) = job.and_then(|job| job.started_at) {
+                    db.record_activity(name, Activity::Executed, started_at.timestamp())?;
+                }
+            }
+            Err(e) => warn!("Failed fetching latest job of runner {}: {:?}", name, e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use log::{debug, info};
+
+use crate::config::GitLabCacheConfig;
+
+/// Name of the environment variable pointing jobs to a cache, e.g. CCACHE_DIR for a cache named ccache
+pub fn variable_name(name: &str, cache: &GitLabCacheConfig) -> String {
+    cache
+        .variable
+        .clone()
+        .unwrap_or_else(|| format!("{}_DIR", name.to_uppercase().replace('-', "_")))
+}
+
+/// Collects all files below the directory with their size and modification time
+fn collect_files(dir: &Path, files: &mut Vec<(PathBuf, u64, SystemTime)>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir).context(format!("Failed reading directory {:?}", dir))? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else {
+            files.push((entry.path(), metadata.len(), metadata.modified()?));
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the least recently modified files of a cache until its total size is below max_size (in bytes)
+pub fn prune(dir: &Path, max_size: u64) -> anyhow::Result<()> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    let mut total_size: u64 = files.iter().map(|(_, size, _)| size).sum();
+    if total_size <= max_size {
+        return Ok(());
+    }
+    info!(
+        "Cache {:?} exceeds its maximum size ({} > {} bytes), pruning",
+        dir, total_size, max_size
+    );
+    // oldest first
+    files.sort_by_key(|(_, _, modified)| *modified);
+    for (path, size, _) in files {
+        if total_size <= max_size {
+            break;
+        }
+        debug!("Deleting cache file {:?}", path);
+        // concurrent jobs may have deleted the file already
+        if fs::remove_file(&path).is_ok() {
+            total_size -= size;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn prune_cache() {
+        let cache = GitLabCacheConfig {
+            dir: "/caches/ccache".to_owned(),
+            variable: None,
+            max_size: None,
+        };
+        assert_eq!(variable_name("ccache", &cache), "CCACHE_DIR");
+        assert_eq!(variable_name("pip-cache", &cache), "PIP_CACHE_DIR");
+        let dir = std::env::temp_dir().join(format!("cache-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let now = SystemTime::now();
+        for (index, name) in ["old", "sub/middle", "new"].iter().enumerate() {
+            let file = fs::File::create(dir.join(name)).unwrap();
+            file.set_len(100).unwrap();
+            file.set_modified(now - Duration::from_secs(100 * (3 - index as u64)))
+                .unwrap();
+        }
+        prune(&dir, 250).unwrap();
+        assert!(!dir.join("old").exists());
+        assert!(dir.join("sub/middle").exists());
+        assert!(dir.join("new").exists());
+        prune(&dir, 100).unwrap();
+        assert!(!dir.join("sub/middle").exists());
+        assert!(dir.join("new").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,13 +1,17 @@
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use colored::Colorize;
 use log::info;
+use regex::Regex;
 
 use crate::{
     cli,
     config::read_config,
+    executor::{check_shell_support, get_job_dir_depth, get_shell_command, is_per_job_layout},
+    gitlab_wrap::DEFAULT_JOB_TIMEOUT,
+    job_filter::JobFilter,
     template::{
-        expand_executor_config_template, expand_launch_config_template,
-        expand_runner_config_template,
+        expand_cancel_config_template, expand_executor_config_template,
+        expand_launch_config_template, expand_runner_config_template,
     },
 };
 
@@ -16,19 +20,149 @@ pub fn check(paths: &cli::Paths) -> anyhow::Result<()> {
         "Failed reading config file {:?}",
         paths.config_file
     ))?;
+    if config.project.as_slice().is_empty() {
+        Err(anyhow!("At least one project needs to be configured"))?;
+    }
+    if let Some(debounce) = config.poll.debounce {
+        if debounce >= config.poll.interval {
+            Err(anyhow!(
+                "poll.debounce ({}) must be smaller than poll.interval ({})",
+                debounce,
+                config.poll.interval
+            ))?;
+        }
+    }
+    for (name, window) in &config.maintenance {
+        if window.start >= window.end {
+            Err(anyhow!(
+                "Maintenance window {} must end after its start",
+                name
+            ))?;
+        }
+    }
+    if config
+        .notify
+        .as_ref()
+        .is_some_and(|notify| notify.failure_threshold == 0)
+    {
+        Err(anyhow!("notify.failure_threshold must be at least 1"))?;
+    }
+    if config.configure.auto_interval == Some(0) {
+        Err(anyhow!("configure.auto_interval must be at least 1"))?;
+    }
+    for (name, instance) in &config.runners {
+        JobFilter::new(instance).context(format!("Invalid job filter for instance {}", name))?;
+        if instance.max_launches_per_hour == Some(0) || instance.max_launches_per_day == Some(0) {
+            Err(anyhow!(
+                "Launch quotas of instance {} must be at least 1, pause it instead",
+                name
+            ))?;
+        }
+    }
+    if let Some(launch) = &config.launch {
+        if launch.executable.is_empty() {
+            Err(anyhow!(
+                "launch.executable must be set if launch.preset isn't"
+            ))?;
+        }
+        if launch.max_per_poll == Some(0) {
+            Err(anyhow!("launch.max_per_poll must be at least 1"))?;
+        }
+        if launch.parallelism == Some(0) {
+            Err(anyhow!("launch.parallelism must be at least 1"))?;
+        }
+        if let Some(pattern) = &launch.allocation_id_pattern {
+            Regex::new(pattern).context("Invalid launch.allocation_id_pattern")?;
+        }
+        if launch.reconcile_timeout.is_some()
+            && (launch.allocation_id_pattern.is_none() || launch.cancel.is_none())
+        {
+            Err(anyhow!(
+                "launch.reconcile_timeout requires launch.allocation_id_pattern and launch.cancel"
+            ))?;
+        }
+        if launch.cancel_orphaned
+            && (launch.allocation_id_pattern.is_none() || launch.cancel.is_none())
+        {
+            Err(anyhow!(
+                "launch.cancel_orphaned requires launch.allocation_id_pattern and launch.cancel"
+            ))?;
+        }
+    }
+    for (name, registry) in config.executor.iter().flat_map(|v| &v.registries) {
+        let sources = [
+            registry.password_variable.is_some(),
+            registry.password_file.is_some(),
+            !registry.password_command.is_empty(),
+        ];
+        if sources.iter().filter(|&&v| v).count() != 1 {
+            Err(anyhow!(
+                "executor.registries.{} must have exactly one password source",
+                name
+            ))?;
+        }
+    }
+    if let Some(limits) = config.executor.as_ref().and_then(|v| v.limits.as_ref()) {
+        if limits.cpus.is_some_and(|cpus| cpus <= 0.0) {
+            Err(anyhow!("executor.limits.cpus must be positive"))?;
+        }
+        if limits.memory == Some(0) || limits.pids == Some(0) {
+            Err(anyhow!(
+                "executor.limits.memory and executor.limits.pids must be at least 1"
+            ))?;
+        }
+    }
     let num_jobs = config.launch.as_ref().map_or(1, |v| v.group_size);
     for (instance_name, instance) in &config.runners {
         expand_runner_config_template(&config.runner, instance_name, instance).context(format!(
             "Failed expanding [runner] for instance {}",
             instance_name
         ))?;
-        expand_executor_config_template(&config, instance_name, instance).context(format!(
-            "Failed expanding [executor] for instance {}",
+        let executor = expand_executor_config_template(&config, instance_name, instance).context(
+            format!("Failed expanding [executor] for instance {}", instance_name),
+        )?;
+        let layout = executor.builds_dir_layout.as_deref();
+        if executor.stale_builds_max_age.is_some()
+            && is_per_job_layout(layout)
+            && get_job_dir_depth(layout).is_none()
+        {
+            Err(anyhow!(
+                "executor.stale_builds_max_age requires executor.builds_dir_layout to end with $CI_JOB_ID for instance {}",
+                instance_name
+            ))?;
+        }
+        if executor.run_as.is_some() && executor.reuse_instance {
+            Err(anyhow!(
+                "executor.run_as can't be combined with executor.reuse_instance for instance {}",
+                instance_name
+            ))?;
+        }
+        get_shell_command(&executor)
+            .and_then(|_| check_shell_support(executor.shell.as_deref(), executor.backend))
+            .context(format!(
+                "Invalid shell configuration for instance {}",
+                instance_name
+            ))?;
+        expand_launch_config_template(
+            paths,
+            &config,
+            instance_name,
+            instance,
+            num_jobs,
+            num_jobs as u64 * DEFAULT_JOB_TIMEOUT,
+        )
+        .context(format!(
+            "Failed expanding [launch] for instance {}",
             instance_name
         ))?;
-        expand_launch_config_template(paths, &config, instance_name, instance, num_jobs).context(
-            format!("Failed expanding [launch] for instance {}", instance_name),
-        )?;
+        if config.launch.as_ref().is_some_and(|v| v.cancel.is_some()) {
+            expand_cancel_config_template(&config, instance_name, instance, "0").context(
+                format!(
+                    "Failed expanding [launch.cancel] for instance {}",
+                    instance_name
+                ),
+            )?;
+        }
     }
     info!("Config check successful, no errors found");
     Ok(())
@@ -79,8 +213,15 @@ pub fn show(paths: &cli::Paths) -> anyhow::Result<()> {
         println!(
             "{}",
             toml::to_string_pretty(
-                &expand_launch_config_template(paths, &config, instance_name, instance, num_jobs)
-                    .context(format!(
+                &expand_launch_config_template(
+                    paths,
+                    &config,
+                    instance_name,
+                    instance,
+                    num_jobs,
+                    num_jobs as u64 * DEFAULT_JOB_TIMEOUT,
+                )
+                .context(format!(
                     "Failed expanding [launch] for instance {}",
                     instance_name
                 ),)?
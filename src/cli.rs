@@ -2,6 +2,7 @@ use std::path::PathBuf;
 
 use clap::{Args, Parser, Subcommand};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
+use serde_derive::{Deserialize, Serialize};
 
 use crate::config;
 
@@ -10,7 +11,7 @@ pub struct Paths {
     /// Configuration file for the meta-runner
     #[arg(long, default_value = config::get_default_config_file_path().into_os_string())]
     pub config_file: PathBuf,
-    /// Directory used to store meta-runner data (registered runners, their tokens and generated gitlab-runner config files)
+    /// Directory used to store meta-runner data (state database containing registered runners and their tokens, generated gitlab-runner config files)
     /// The files in this directory will be prefixed by the meta-runner's name
     #[arg(long, default_value = config::get_default_data_dir().into_os_string(), verbatim_doc_comment)]
     pub data_dir: PathBuf,
@@ -35,16 +36,53 @@ pub enum ExecutorCommand {
     },
     // Run the cleanup step of the custom executor
     Cleanup,
+    // Pull the images listed in executor.prewarm_images outside of a job
+    Prewarm,
 }
 
 #[derive(Debug, Args)]
 pub struct ExecutorOptions {
     /// The name of the runner configuration to use
     pub runner_name: String,
+    /// Expanded executor configuration embedded by `configure`, used instead of reading the config file
+    #[arg(long, global = true)]
+    pub embedded_config: Option<String>,
     #[command(subcommand)]
     pub command: ExecutorCommand,
 }
 
+#[derive(Debug, Subcommand, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlCommand {
+    /// Stop launching runners for a runner instance
+    Pause {
+        /// The name of the runner instance
+        instance: String,
+    },
+    /// Resume launching runners for a paused runner instance
+    Resume {
+        /// The name of the runner instance
+        instance: String,
+    },
+    /// Keep polling and matching pending jobs, but stop launching runners for all runner instances,
+    /// e.g. before a cluster maintenance
+    Drain,
+    /// Launch runners again after draining
+    Undrain,
+    /// Poll for pending jobs immediately
+    Poll,
+    /// Print the current state of the meta-runner
+    DumpState,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MaintenanceCommand {
+    /// Pause all runners on GitLab and stop launching runners until maintenance is ended
+    Start,
+    /// Unpause all runners on GitLab and resume launching runners
+    End,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Command {
     /// Creates an example configuration file
@@ -63,6 +101,17 @@ pub enum Command {
     RunSingle,
     /// Run the meta-runner continuously to dispatch runners at regular intervals
     Run,
+    /// Run a persistent gitlab-runner process for all runner instances, restarting it if it exits
+    /// and reloading it when the generated config file changes
+    RunMulti,
+    /// Print the status of the running meta-runner, like the time of the last poll and launch failures
+    Status,
+    /// Send a command to the running meta-runner via its control socket
+    #[command(subcommand)]
+    Control(ControlCommand),
+    /// Manually start or end maintenance mode, in addition to the configured maintenance windows
+    #[command(subcommand)]
+    Maintenance(MaintenanceCommand),
 }
 
 #[derive(Parser, Debug)]
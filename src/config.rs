@@ -1,4 +1,5 @@
 use anyhow::Context;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDateTime, NaiveTime, Utc, Weekday};
 use documented::DocumentedFields;
 use inkjet::{
     formatter::Terminal,
@@ -20,7 +21,7 @@ use toml_edit::{DocumentMut, RawString};
 use serde::{de::Error, Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
 
-use crate::{cli, gitlab_config};
+use crate::{cli, gitlab_config, launcher::apply_preset};
 
 pub const CONFIG_FILE_NAME: &str = "gitlab-meta-runner.toml";
 pub const DATA_DIR_NAME: &str = "gitlab-meta-runner";
@@ -37,6 +38,22 @@ pub fn get_tokens_file_path(data_dir: &PathBuf, meta_runner_name: &String) -> Pa
     data_dir.join(format!("{}.tokens", meta_runner_name))
 }
 
+pub fn get_state_db_path(data_dir: &Path, meta_runner_name: &str) -> PathBuf {
+    data_dir.join(format!("{}.state.sqlite", meta_runner_name))
+}
+
+pub fn get_decision_log_path(data_dir: &Path, meta_runner_name: &str) -> PathBuf {
+    data_dir.join(format!("{}.decisions.jsonl", meta_runner_name))
+}
+
+pub fn get_session_log_path(data_dir: &Path, meta_runner_name: &str) -> PathBuf {
+    data_dir.join(format!("{}.sessions.jsonl", meta_runner_name))
+}
+
+pub fn get_control_socket_path(data_dir: &Path, meta_runner_name: &str) -> PathBuf {
+    data_dir.join(format!("{}.sock", meta_runner_name))
+}
+
 pub fn get_generated_config_file_path(paths: &cli::Paths, meta_runner_name: &String) -> PathBuf {
     paths
         .generated_config_file
@@ -62,6 +79,38 @@ fn one() -> usize {
     1
 }
 
+fn default_api_concurrency() -> usize {
+    8
+}
+
+fn default_gitlab_runner_executable() -> String {
+    "gitlab-runner".into()
+}
+
+fn default_restart_delay() -> u32 {
+    10
+}
+
+fn default_docker_executable() -> String {
+    "docker".into()
+}
+
+fn default_enroot_executable() -> String {
+    "enroot".into()
+}
+
+fn default_retry_delay() -> u32 {
+    10
+}
+
+fn default_api_timeout() -> u32 {
+    60
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
 /// Used for bools that can be variable-expanded
 #[derive(Debug)]
 pub enum BoolOrString {
@@ -95,6 +144,55 @@ impl<'de> serde::Deserialize<'de> for BoolOrString {
     }
 }
 
+/// Used for values that can be given either as a single string or as a list of strings
+#[derive(Debug)]
+pub enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+impl StringOrList {
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            StringOrList::String(s) => std::slice::from_ref(s),
+            StringOrList::List(v) => v,
+        }
+    }
+}
+
+impl serde::Serialize for StringOrList {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            StringOrList::String(s) => serializer.serialize_str(s),
+            StringOrList::List(v) => v.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for StringOrList {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = toml::Value::deserialize(deserializer)?;
+        match value {
+            toml::Value::String(s) => Ok(StringOrList::String(s)),
+            toml::Value::Array(a) => a
+                .into_iter()
+                .map(|v| match v {
+                    toml::Value::String(s) => Ok(s),
+                    _ => Err(D::Error::custom("Expected list of strings")),
+                })
+                .collect::<Result<_, _>>()
+                .map(StringOrList::List),
+            _ => Err(D::Error::custom("Expected string or list of strings")),
+        }
+    }
+}
+
 #[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
 pub struct GitLabRunnerInstance {
     /// Tags whose associated jobs will be run by this runner
@@ -102,6 +200,30 @@ pub struct GitLabRunnerInstance {
     /// Priority in which the instances' launch processes should be executed, higher priority means earlier launch.
     /// All jobs without a priority will be launched last.
     pub launch_priority: Option<u32>,
+    /// Regular expression that the names of jobs run by this runner must match completely
+    pub job_name_pattern: Option<String>,
+    /// Regular expression that the branch or tag names of jobs run by this runner must match completely
+    pub ref_pattern: Option<String>,
+    #[serde(default = "Vec::new")]
+    /// Pipeline sources (e.g. push, schedule, merge_request_event) of jobs run by this runner,
+    /// jobs from all pipeline sources will be run if this is empty
+    pub pipeline_source: Vec<String>,
+    /// Boolean expression the tags of jobs run by this runner must satisfy in addition to being a subset of tags,
+    /// combining tags with & (and), | (or), ! (not) and parentheses, e.g. "cuda & !long-running"
+    pub tag_expression: Option<String>,
+    #[serde(default)]
+    /// Whether this runner also runs jobs without any tags, this is also set for its registration on GitLab
+    pub run_untagged: bool,
+    /// Maximum number of successful launches of this runner per hour, e.g. to contain runaway pipeline retries
+    /// Failed and postponed launches don't count towards it
+    /// The quota refills continuously, jobs exceeding it stay pending until enough of it is available again
+    pub max_launches_per_hour: Option<u32>,
+    /// Maximum number of successful launches of this runner per day, refilling like max_launches_per_hour
+    pub max_launches_per_day: Option<u32>,
+    #[serde(default)]
+    /// Recurring windows during which runners may be launched for this instance, in addition to the global schedule
+    /// Matched jobs outside these windows stay pending until the next window starts
+    pub schedule: HashMap<String, GitLabScheduleWindow>,
     /// Variables to be expanded in the template instantiation.
     /// Each value needs to be a string!
     // Naming to avoid confusing with environment variables
@@ -110,8 +232,14 @@ pub struct GitLabRunnerInstance {
 
 #[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
 pub struct GitLabLaunchConfig {
+    /// Batch system preset providing defaults for executable, args, stdin, allocation_id_pattern, cancel
+    /// and queue_check, one of "slurm", "pbs", "lsf" or "flux", will NOT be variable-expanded
+    /// Fields that are set explicitly take precedence over the preset
+    pub preset: Option<GitLabLauncherPreset>,
+    #[serde(default)]
     /// Executable name or path, will be variable-expanded
     pub executable: String,
+    #[serde(default = "Vec::new")]
     /// Arguments to pass to the executable, they will be variable-expanded
     pub args: Vec<String>,
     /// Working directory for the executable, this will be variable-expanded
@@ -120,9 +248,99 @@ pub struct GitLabLaunchConfig {
     pub stdin: Option<String>,
     /// The time to wait (in seconds) for each launch command to finish, will NOT be variable-expanded
     pub timeout: Option<u32>,
+    #[serde(default)]
+    /// How often a failed launch command is retried within the same poll before its jobs are postponed
+    /// to the next poll, will NOT be variable-expanded
+    /// Note that a launch command that timed out may have succeeded nonetheless
+    pub retries: u32,
+    #[serde(default = "default_retry_delay")]
+    /// The time to wait (in seconds) before retrying a failed launch command, will NOT be variable-expanded
+    pub retry_delay: u32,
     #[serde(default = "one")]
     /// The number of jobs to launch in a single launch command, will NOT be variable-expanded
     pub group_size: usize,
+    /// Maximum number of launch commands to issue in a single poll, will NOT be variable-expanded
+    /// Jobs exceeding this limit (starting with the lowest launch_priority) stay pending until the following polls
+    pub max_per_poll: Option<usize>,
+    /// Time (in seconds) after the start of the launches of a poll after which no further launch commands are started,
+    /// will NOT be variable-expanded
+    /// The remaining jobs stay pending until the next poll, if not set, all launch commands are started
+    pub budget: Option<u32>,
+    /// Maximum number of launch commands running concurrently, e.g. to avoid overloading a login node,
+    /// will NOT be variable-expanded
+    /// If not set, all launch commands of a poll run concurrently
+    pub parallelism: Option<usize>,
+    /// Regular expression extracting the allocation ID (first capture group) from the launch command's stdout,
+    /// will NOT be variable-expanded
+    pub allocation_id_pattern: Option<String>,
+    /// Time (in seconds) after launch after which allocations whose runner didn't pick up their jobs will be cancelled,
+    /// should be larger than the expected queueing time plus the runner's --wait-timeout, will NOT be variable-expanded
+    /// Requires allocation_id_pattern and cancel
+    pub reconcile_timeout: Option<u32>,
+    #[serde(default)]
+    /// Whether to cancel allocations as soon as none of their jobs is pending anymore
+    /// and none of them was picked up by their runner, e.g. because the jobs were cancelled on GitLab,
+    /// will NOT be variable-expanded
+    /// Requires allocation_id_pattern and cancel
+    pub cancel_orphaned: bool,
+    /// Command to cancel an allocation
+    pub cancel: Option<GitLabCancelConfig>,
+    /// Time (in seconds) after a successful launch after which a job that is still pending will be dispatched again,
+    /// should be larger than the expected queueing time, will NOT be variable-expanded
+    /// If not set, a runner is only launched once for every job
+    pub redispatch_timeout: Option<u32>,
+    /// Time (in seconds) after a successful launch after which jobs that are still pending are reported as starved,
+    /// e.g. because the launched runner never receives them due to a tag mismatch on the GitLab side,
+    /// will NOT be variable-expanded
+    pub starvation_timeout: Option<u32>,
+    /// Time (in seconds) for which a runner instance is not used after its jobs failed in the executor's prepare step
+    /// prepare_failure_threshold times in a row, e.g. because the image directory isn't writable on its cluster,
+    /// will NOT be variable-expanded
+    /// Jobs only matching unhealthy instances stay pending until the cooldown ends, if not set, instances are always used
+    pub prepare_failure_cooldown: Option<u32>,
+    #[serde(default = "default_failure_threshold")]
+    /// Number of consecutive failed prepare steps after which a runner instance is considered unhealthy,
+    /// will NOT be variable-expanded
+    /// Note that this includes jobs requesting images that don't exist
+    pub prepare_failure_threshold: u32,
+    /// Command to run before every launch command, e.g. to record accounting entries or warm caches
+    /// If it fails, the launch is considered failed and the launch command is not run
+    pub pre_hook: Option<GitLabLaunchHookConfig>,
+    /// Command to run after every launch command, regardless of whether the launch succeeded
+    pub post_hook: Option<GitLabLaunchHookConfig>,
+    /// Command determining how many launches of a runner instance are already queued or running
+    pub queue_check: Option<GitLabQueueCheckConfig>,
+}
+
+#[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabQueueCheckConfig {
+    /// Executable name or path, will be variable-expanded
+    /// Its stdout needs to contain the number of launches for this instance that are queued or running.
+    /// Each of them is assumed to pick up one of the oldest matched jobs, so only the remaining jobs are launched.
+    /// If the command fails, all matched jobs are launched.
+    pub executable: String,
+    /// Arguments to pass to the executable, they will be variable-expanded
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabLaunchHookConfig {
+    /// Executable name or path, will be variable-expanded
+    /// The environment contains $META_RUNNER_INSTANCE, the name of the runner instance,
+    /// $META_RUNNER_JOB_IDS, the space-separated IDs of the launched jobs,
+    /// and, for the post_hook only, $META_RUNNER_LAUNCH_STATUS, either "success" or "failed"
+    pub executable: String,
+    /// Arguments to pass to the executable, they will be variable-expanded
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabCancelConfig {
+    /// Executable name or path, will be variable-expanded
+    pub executable: String,
+    /// Arguments to pass to the executable, they will be variable-expanded
+    /// $ALLOCATION_ID expands to the ID of the allocation to be cancelled
+    pub args: Vec<String>,
 }
 
 #[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq)]
@@ -136,63 +354,670 @@ pub enum GitLabExecutorPullPolicy {
     #[serde(rename = "never")]
     /// Never pull an image
     Never,
+    #[serde(rename = "if-digest-changed")]
+    /// Pull an image if it is not present, or if its tag points to a different digest in the registry
+    /// than when it was pulled. The digests are recorded in image_cache_dir (or image_dir)
+    IfDigestChanged,
+}
+
+#[derive(Debug, Default, Copy, Clone, Deserialize, Serialize, PartialEq)]
+pub enum GitLabExecutorBackend {
+    #[default]
+    #[serde(rename = "apptainer")]
+    /// Run the job steps inside an apptainer container created from the job image
+    Apptainer,
+    #[serde(rename = "nix")]
+    /// Run the job steps inside `nix develop` for the Nix flake referenced by the job
+    Nix,
+    #[serde(rename = "guix")]
+    /// Run the job steps inside `guix shell` for the Guix manifest referenced by the job
+    Guix,
+    #[serde(rename = "docker")]
+    /// Run the job steps inside a docker container created from the job image
+    Docker,
+    #[serde(rename = "enroot")]
+    /// Run the job steps inside an enroot container created from the job image
+    Enroot,
+}
+
+#[derive(Debug, Default, Copy, Clone, Deserialize, Serialize, PartialEq)]
+pub enum GitLabMatchStrategy {
+    #[default]
+    #[serde(rename = "fewest-tags")]
+    /// Pick the instance with the fewest tags, i.e. the most specific one
+    FewestTags,
+    #[serde(rename = "round-robin")]
+    /// Cycle through the matching instances
+    RoundRobin,
+    #[serde(rename = "random")]
+    /// Pick a random matching instance
+    Random,
+    #[serde(rename = "least-recently-launched")]
+    /// Pick the instance whose last launch is the longest ago
+    LeastRecentlyLaunched,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq)]
+pub enum GitLabLauncherPreset {
+    #[serde(rename = "slurm")]
+    /// Submit with sbatch, query with squeue and cancel with scancel
+    Slurm,
+    #[serde(rename = "pbs")]
+    /// Submit with qsub, query with qselect and cancel with qdel
+    Pbs,
+    #[serde(rename = "lsf")]
+    /// Submit with bsub, query with bjobs and cancel with bkill
+    Lsf,
+    #[serde(rename = "flux")]
+    /// Submit with flux batch, query with flux jobs and cancel with flux cancel
+    Flux,
+}
+
+#[derive(Debug, Default, Copy, Clone, Deserialize, Serialize, PartialEq)]
+pub enum GitLabNotifyFormat {
+    #[default]
+    #[serde(rename = "json")]
+    /// Generic JSON object containing the event, meta-runner and instance name, message and timestamp
+    Json,
+    #[serde(rename = "slack")]
+    /// Message for Slack incoming webhooks
+    Slack,
+    #[serde(rename = "matrix")]
+    /// Content of a Matrix m.room.message event
+    Matrix,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum HookEvent {
+    #[serde(rename = "job-matched")]
+    /// A pending job was matched to a runner instance
+    JobMatched,
+    #[serde(rename = "launch-succeeded")]
+    /// A launch command finished successfully
+    LaunchSucceeded,
+    #[serde(rename = "launch-failed")]
+    /// A launch command failed
+    LaunchFailed,
+    #[serde(rename = "runner-registered")]
+    /// A runner was registered with GitLab
+    RunnerRegistered,
+    #[serde(rename = "runner-deleted")]
+    /// A runner was deleted from GitLab
+    RunnerDeleted,
+    #[serde(rename = "api-outage")]
+    /// Polling the GitLab API failed
+    ApiOutage,
+    #[serde(rename = "job-starved")]
+    /// A job is still pending launch.starvation_timeout seconds after a runner was launched for it
+    JobStarved,
+}
+
+#[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabMaintenanceWindow {
+    /// Start of the maintenance window as RFC 3339 timestamp, e.g. 2024-10-01T08:00:00+02:00
+    pub start: DateTime<FixedOffset>,
+    /// End of the maintenance window as RFC 3339 timestamp, must be after start
+    pub end: DateTime<FixedOffset>,
+}
+
+impl GitLabMaintenanceWindow {
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+#[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabScheduleWindow {
+    #[serde(default = "Vec::new")]
+    /// Weekdays (e.g. Mon, Tue) on which the window starts, the window applies to every day if empty
+    pub days: Vec<Weekday>,
+    /// Start of the window in local time, e.g. 08:00:00
+    pub start: NaiveTime,
+    /// End of the window in local time, e.g. 18:00:00
+    /// If it is before start, the window extends past midnight into the following day
+    pub end: NaiveTime,
+}
+
+impl GitLabScheduleWindow {
+    pub fn contains(&self, time: NaiveDateTime) -> bool {
+        let starts_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        let (day, time) = (time.weekday(), time.time());
+        if self.start <= self.end {
+            starts_on(day) && self.start <= time && time < self.end
+        } else {
+            (starts_on(day) && self.start <= time) || (starts_on(day.pred()) && time < self.end)
+        }
+    }
+}
+
+/// Runners may be launched if no schedule windows are configured or any of them contains the given time
+pub fn in_schedule(windows: &HashMap<String, GitLabScheduleWindow>, time: NaiveDateTime) -> bool {
+    windows.is_empty() || windows.values().any(|window| window.contains(time))
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabHookConfig {
+    /// Events triggering this hook, possible values are
+    /// job-matched, launch-succeeded, launch-failed, runner-registered, runner-deleted, api-outage, job-starved
+    pub events: Vec<HookEvent>,
+    /// Executable name or path, it will receive a JSON description of the event via stdin
+    pub executable: String,
+    #[serde(default = "Vec::new")]
+    /// Arguments to pass to the executable
+    pub args: Vec<String>,
+    /// The time to wait (in seconds) for the hook to finish, defaults to 60 seconds
+    pub timeout: Option<u32>,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabSpackConfig {
+    /// Path to the spack executable (may be relative to workdir or $PATH), will be variable-expanded
+    pub executable: String,
+    /// Directory to store concretized environments created from spack.yaml files in, will be variable-expanded
+    /// The directory containing the spack.yaml file is copied, jobs using identical directories share the same environment
+    /// With run_as, environments are installed as that user, who needs write access to spack's install tree
+    pub cache_dir: String,
+    /// Named spack environment to activate for jobs that don't request an environment, will be variable-expanded
+    /// Jobs can request an environment via the CI variables META_RUNNER_SPACK_ENV (named environment)
+    /// or META_RUNNER_SPACK_YAML (path to a spack.yaml file inside the project directory)
+    pub environment: Option<String>,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabImageBuildConfig {
+    #[serde(default = "Vec::new")]
+    /// Additional arguments to pass to `apptainer build` for definition files, e.g. --fakeroot,
+    /// every individual entry will be variable-expanded
+    pub apptainer_args: Vec<String>,
+    /// Executable building an image file from a Dockerfile, will be variable-expanded
+    /// It is called with the Dockerfile, the build context directory and the image file to create as arguments
+    /// Jobs using a Dockerfile will fail if this is not set
+    pub dockerfile_builder: Option<String>,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabRegistryConfig {
+    /// User name to authenticate with, will be variable-expanded
+    pub username: Option<String>,
+    /// Environment variable containing the password or token, will NOT be variable-expanded
+    pub password_variable: Option<String>,
+    /// File containing the password or token, will be variable-expanded
+    pub password_file: Option<String>,
+    #[serde(default = "Vec::new")]
+    /// Command printing the password or token, every individual entry will be variable-expanded
+    pub password_command: Vec<String>,
+    /// URL of the apptainer library endpoint, will NOT be variable-expanded
+    /// If set, library:// images are pulled from this endpoint after logging in with the token
+    pub library_url: Option<String>,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabCacheConfig {
+    /// Host directory of the cache, will be variable-expanded
+    /// It is created if missing and mounted into the job container at the same path
+    pub dir: String,
+    /// Environment variable pointing the job to the cache directory, will NOT be variable-expanded
+    /// Defaults to the uppercase cache name followed by _DIR, e.g. CCACHE_DIR for a cache named ccache
+    pub variable: Option<String>,
+    /// Maximum size (in MiB) of the cache, will NOT be variable-expanded
+    /// The cleanup step deletes the least recently modified files once the cache exceeds it
+    pub max_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabExecutorMetricsConfig {
+    /// Prometheus textfile the executor adds its counters to, will be variable-expanded
+    /// Point the textfile collector of the node exporter to its directory. The counters are labeled
+    /// with the runner name and cover image pulls (count, seconds, bytes) and job steps (count by result, seconds)
+    pub textfile: String,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabLimitsConfig {
+    /// Number of CPUs the job may use, fractions are allowed, will NOT be variable-expanded
+    pub cpus: Option<f64>,
+    /// Maximum memory (in MiB) of the job, including swap, will NOT be variable-expanded
+    pub memory: Option<u64>,
+    /// Maximum number of processes and threads of the job, will NOT be variable-expanded
+    pub pids: Option<u32>,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabMinFreeSpaceConfig {
+    /// Minimum free space (in MiB) on the filesystem containing image_dir, will NOT be variable-expanded
+    pub image_dir: Option<u64>,
+    /// Minimum free space (in MiB) on the filesystem containing image_tmp_dir, or the system temporary
+    /// directory if it is not set, will NOT be variable-expanded
+    pub image_tmp_dir: Option<u64>,
+    /// Minimum free space (in MiB) on the filesystem containing builds_dir, will NOT be variable-expanded
+    pub builds_dir: Option<u64>,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabJobOverridesConfig {
+    #[serde(default)]
+    /// Allow jobs to select the mounted GPU devices via the CI variable META_RUNNER_GPU
+    /// (comma-separated "amd", "nvidia" or "intel", or "none"), will NOT be variable-expanded
+    pub gpu: bool,
+    #[serde(default = "Vec::new")]
+    /// Flags jobs may pass to the container runtime command executing their steps via the CI variable
+    /// META_RUNNER_EXTRA_FLAGS (space-separated), will NOT be variable-expanded
+    /// Flags of the form --flag=value are allowed if --flag is listed
+    pub flags: Vec<String>,
+    #[serde(default = "Vec::new")]
+    /// Host directories below which jobs may add bind mounts via the CI variable META_RUNNER_MOUNTS
+    /// (space-separated, same format as mount), every individual entry will be variable-expanded
+    pub mount_prefixes: Vec<String>,
+}
+
+#[derive(Debug, Clone, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabFailedBuildsConfig {
+    /// Directory to retain the builds directories of failed jobs in, will be variable-expanded
+    /// Each retained directory is named <runner name>-<job id> and contains a description of the job
+    pub dir: String,
+    #[serde(default)]
+    /// Store the builds directories as .tar.gz archives instead of moving them, will NOT be variable-expanded
+    pub archive: bool,
+    /// Maximum number of retained builds directories, will NOT be variable-expanded
+    pub max_count: Option<usize>,
+    /// Maximum age (in hours) of retained builds directories, will NOT be variable-expanded
+    pub max_age: Option<u32>,
+    /// Maximum total size (in MiB) of all retained builds directories, will NOT be variable-expanded
+    pub max_size: Option<u64>,
 }
 
 #[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
 pub struct GitLabCustomExecutorConfigTemplate {
     /// Override builds_dir provided by gitlab-runner config, will be variable-expanded
     pub builds_dir: Option<String>,
+    #[serde(default = "Vec::new")]
+    /// Environment variables pointing to node-local scratch directories, e.g. ["SLURM_TMPDIR", "TMPDIR"],
+    /// will NOT be variable-expanded
+    /// The builds directories are placed in the first of these directories that exists on the node the job
+    /// runs on, falling back to builds_dir if none of them is set. Failed builds can only be retained from
+    /// scratch with failed_builds.archive, since directories can't be moved across filesystems
+    pub builds_dir_scratch: Vec<String>,
+    /// Path of the builds directory of a job relative to builds_dir, e.g. "$CI_PROJECT_PATH_SLUG/$CI_JOB_ID",
+    /// will NOT be variable-expanded
+    /// The CI variables of the job are expanded by the config step, defaults to "$CI_JOB_ID". Builds directories
+    /// without $CI_JOB_ID in their path may be shared by several jobs, so they are kept by the cleanup step.
+    /// With stale_builds_max_age, the last component must be $CI_JOB_ID and the variables before it must not
+    /// contain slashes (like $CI_PROJECT_PATH_SLUG), so the stale builds directories can be found
+    pub builds_dir_layout: Option<String>,
+    #[serde(default)]
+    /// Report the builds directories to gitlab-runner as shared between runners, will NOT be variable-expanded
+    /// gitlab-runner then places the project directory below the runner's short token and the concurrency ID,
+    /// so runners sharing builds_dir (or a builds_dir_layout without $CI_JOB_ID) don't clone into the same path
+    pub builds_dir_is_shared: bool,
     /// Path to store the image files in, will be variable-expanded
     pub image_dir: String,
     /// Path to use for caching image layers, will be variable-expanded
     pub image_cache_dir: Option<String>,
     /// Path to use for temporary files during pull, will be variable-expanded
+    /// Every pull uses its own subdirectory named after the job ID, which is removed after the pull
     pub image_tmp_dir: Option<String>,
-    /// Pull policy to use for images, will NOT be variable-expanded
+    /// Pull policy to use for images, one of "always", "if-not-present", "if-digest-changed" or "never",
+    /// will NOT be variable-expanded
     pub pull_policy: GitLabExecutorPullPolicy,
+    #[serde(default)]
+    /// How often a failed or timed out image pull is retried before the prepare step fails, will NOT be variable-expanded
+    pub pull_retries: u32,
+    #[serde(default = "default_retry_delay")]
+    /// The time to wait (in seconds) before the first retry of a failed image pull, doubling for every further retry,
+    /// will NOT be variable-expanded
+    pub pull_retry_delay: u32,
+    /// The time to wait (in seconds) for an image pull to finish before it is killed, will NOT be variable-expanded
+    pub pull_timeout: Option<u32>,
+    /// Architecture of the pulled images in the naming of apptainer, e.g. "amd64" or "arm64", will NOT be
+    /// variable-expanded
+    /// Defaults to the architecture of the host. It is part of the image filename, so runners on nodes of
+    /// different architectures can share the image_dir
+    pub arch: Option<String>,
+    /// Image to use for jobs that don't specify an image, will be variable-expanded
+    /// Jobs without an image fail if this is not set, unless allow_host_fallback is enabled
+    pub default_image: Option<String>,
+    #[serde(default)]
+    /// Run jobs that specify no image directly on the host if no default_image is set, will NOT be variable-expanded
+    /// The job script runs in the builds directory with the permissions of the executor, outside of any container,
+    /// so this should only be enabled on runners that don't execute untrusted jobs
+    pub allow_host_fallback: bool,
+    #[serde(default = "Vec::new")]
+    /// Images to pull ahead of time via `gitlab-meta-runner executor <runner name> prewarm`,
+    /// e.g. from the launch command before starting the runner, every individual entry will be variable-expanded
+    pub prewarm_images: Vec<String>,
+    #[serde(default = "Vec::new")]
+    /// Mirrors of Docker Hub, e.g. pull-through caches like "mirror.gcr.io" or "harbor.example.com/dockerhub",
+    /// every individual entry will be variable-expanded
+    /// If pulling an image from Docker Hub fails, including all retries, the image is pulled from these mirrors
+    /// in order. Only used by the apptainer backend, configure the registry mirrors of the docker daemon instead
+    pub image_mirrors: Vec<String>,
+    #[serde(default)]
+    /// Backend providing the job environment (apptainer, docker, enroot, nix or guix), will NOT be variable-expanded
+    /// The nix and guix backends use the flake or manifest referenced by the CI variable
+    /// META_RUNNER_ENVIRONMENT (defaulting to the flake or manifest.scm in the project directory)
+    pub backend: GitLabExecutorBackend,
     /// Path to the apptainer executable (may be relative to workdir or $PATH), will be variable-expanded
     pub apptainer_executable: String,
+    #[serde(default = "default_docker_executable")]
+    /// Path to the docker executable used by the docker backend (may be relative to workdir or $PATH),
+    /// will be variable-expanded
+    /// The docker backend pulls images according to pull_policy into the docker engine instead of image_dir,
+    /// and doesn't support building images
+    pub docker_executable: String,
+    #[serde(default = "default_enroot_executable")]
+    /// Path to the enroot executable used by the enroot backend (may be relative to workdir or $PATH),
+    /// will be variable-expanded
+    /// The enroot backend imports images as squashfs files into image_dir, and doesn't support building images
+    pub enroot_executable: String,
     #[serde(default = "false_bool_or_string")]
     /// Mount AMD GPU devices, will be variable-expanded
     pub gpu_amd: BoolOrString,
     #[serde(default = "false_bool_or_string")]
     /// Mount NVIDIA GPU devices, will be variable-expanded
     pub gpu_nvidia: BoolOrString,
+    #[serde(default = "false_bool_or_string")]
+    /// Mount Intel GPU devices, will be variable-expanded
+    /// The apptainer backend additionally mounts the Level Zero libraries of the host
+    pub gpu_intel: BoolOrString,
+    #[serde(default = "false_bool_or_string")]
+    /// Home directory of the container, will be variable-expanded. Only used by the apptainer backend
+    /// false provides no home directory (--no-home), true mounts the home directory of the host, and a path
+    /// is created if missing and mounted as home directory, e.g. $CUSTOM_ENV_CI_BUILDS_DIR/.home for a per-job home
+    /// that is deleted together with the builds directory
+    pub mount_home: BoolOrString,
     #[serde(default = "Vec::new")]
-    /// Additional bind mounts to use in the container, every individual entry will be variable-expanded
+    /// Additional bind mounts to use in the container in the format src[:dst[:ro|rw]],
+    /// every individual entry will be variable-expanded
+    /// Jobs fail if a source doesn't exist, unless create_mount_sources is set
     pub mount: Vec<String>,
+    #[serde(default)]
+    /// Create missing mount sources as directories instead of failing the job, will NOT be variable-expanded
+    pub create_mount_sources: bool,
+    #[serde(default = "Vec::new")]
+    /// Additional arguments to pass to `apptainer exec` for every job step, e.g. --containall or --env,
+    /// every individual entry will be variable-expanded
+    pub exec_args: Vec<String>,
+    #[serde(default = "Vec::new")]
+    /// Names of host environment variables to forward into the job container, e.g. SLURM_* or http_proxy,
+    /// every individual entry will be variable-expanded
+    /// The names may contain * and ? wildcards
+    pub pass_env: Vec<String>,
+    #[serde(default = "Vec::new")]
+    /// Command the user steps are executed with, e.g. ["srun", "--ntasks=1", "--cpu-bind=cores"] to bind them
+    /// to the resources of the batch job, every individual entry will be variable-expanded
+    /// Not used by the docker backend, whose containers don't run inside the batch job
+    pub step_wrapper: Vec<String>,
+    /// Resource limits of the user steps, enforced via cgroup v2 to protect shared hosts like login nodes
+    /// The steps are placed in a transient systemd scope (via systemd-run --scope, in the user's service manager
+    /// unless running as root) inside the step wrapper. The docker backend passes the limits to the container
+    pub limits: Option<GitLabLimitsConfig>,
+    /// Unprivileged user executing all job steps, e.g. a dedicated service account, will NOT be variable-expanded
+    /// The builds and cache directories are owned by this user while the job runs. The executor switches users
+    /// via setpriv when running as root, otherwise via sudo, which needs to allow running any command as this user
+    /// with --preserve-env and chown and install as root without a password
+    /// The environment modules and spack environments are also loaded as this user
+    /// Not supported by the docker backend, whose containers are started by the docker daemon, with reuse_instance
+    /// or for jobs with services
+    pub run_as: Option<String>,
+    /// Shell executing the job scripts, e.g. "ash" for busybox-based images, will NOT be variable-expanded
+    /// Defaults to the shell matching runner.shell, the job scripts are still generated for runner.shell
+    pub shell: Option<String>,
+    /// Arguments passed to the shell before the job script, e.g. ["--noprofile"], will NOT be variable-expanded
+    /// Defaults to the arguments used for runner.shell, e.g. ["-l"] for bash
+    pub shell_args: Option<Vec<String>>,
+    /// Network of the job container, will NOT be variable-expanded
+    /// "host" (the default) shares the network of the host, "none" disables networking except for the loopback device,
+    /// any other value names an apptainer (CNI) or docker network. Not supported by the enroot backend
+    /// Services are only reachable with host networking
+    pub network: Option<String>,
+    #[serde(default = "Vec::new")]
+    /// Additional arguments for the apptainer network, e.g. "portmap=8080:80/tcp", passed via --network-args,
+    /// every individual entry will be variable-expanded
+    pub network_args: Vec<String>,
+    /// Size (in MiB) of a writable ext3 overlay image created for every job, will NOT be variable-expanded
+    /// The overlay is used instead of --writable-tmpfs, so changes to the container are no longer limited
+    /// by the tmpfs size, and persist between the steps of a job
+    pub overlay_size_mb: Option<u32>,
+    #[serde(default)]
+    /// Execute all steps of a job in an apptainer instance started by the prepare step, will NOT be variable-expanded
+    /// Changes to the container persist between the steps and the container is only started once.
+    /// exec_args are passed to `apptainer instance start` instead
+    pub reuse_instance: bool,
+    /// Path of a persistent overlay image that is reused by all jobs instead of creating one per job,
+    /// will be variable-expanded
+    /// It is created with overlay_size_mb if it doesn't exist. Since an overlay can only be used by one container
+    /// at a time, it should be unique for concurrent jobs, e.g. $HOME/overlays/$NAME-$CUSTOM_ENV_CI_CONCURRENT_ID.img
+    pub overlay: Option<String>,
+    #[serde(default)]
+    /// Refuse to execute images that are neither signed by a trusted key (checked via `apptainer verify`)
+    /// nor listed in trusted_digests, will NOT be variable-expanded
+    /// Only supported by the apptainer backend, jobs building their own image are refused as well
+    pub verify_signatures: bool,
+    #[serde(default = "Vec::new")]
+    /// Fingerprints of the keys trusted to sign images, will NOT be variable-expanded
+    /// If empty, any valid signature whose key is known to apptainer is accepted
+    pub trusted_fingerprints: Vec<String>,
+    #[serde(default = "Vec::new")]
+    /// SHA-256 digests of image files that are trusted without a signature, will NOT be variable-expanded
+    pub trusted_digests: Vec<String>,
     /// Custom string whose variable-expanded value will be reported in the driver name in the config stage
     pub description: Option<String>,
+    #[serde(default = "Vec::new")]
+    /// Environment modules to load before executing the job script, every individual entry will be variable-expanded
+    /// Jobs can override the list via the CI variable META_RUNNER_MODULES (space-separated)
+    /// The environment changes will be propagated into the container
+    pub modules: Vec<String>,
+    /// Activate a spack environment before executing the job script
+    /// The environment changes will be propagated into the container
+    pub spack: Option<GitLabSpackConfig>,
+    /// Retain the builds directories of failed jobs for later inspection instead of deleting them
+    pub failed_builds: Option<GitLabFailedBuildsConfig>,
+    /// Time (in hours) since the last step of a job, after which its builds directory is considered stale
+    /// and deleted by the prepare step of other jobs, will NOT be variable-expanded
+    /// This removes the builds directories of jobs that never ran their cleanup step, e.g. because the batch job
+    /// was killed. It needs to exceed the longest job timeout
+    /// Temporary pull directories in image_tmp_dir left behind by killed jobs are removed the same way
+    pub stale_builds_max_age: Option<u32>,
+    /// Free space required on the filesystems used by a job, checked by the prepare step before pulling the image
+    /// Jobs fail with a system failure if any of the filesystems has less free space
+    pub min_free_space: Option<GitLabMinFreeSpaceConfig>,
+    #[serde(default)]
+    /// Named caches shared between jobs, e.g. for ccache or sccache, which are mounted into every job container
+    /// and exported to the job via an environment variable
+    pub caches: HashMap<String, GitLabCacheConfig>,
+    #[serde(default)]
+    /// Credentials of authenticated registries by host name, used when pulling docker:// or oras:// images
+    /// from them with the apptainer backend. Exactly one password source has to be configured
+    pub registries: HashMap<String, GitLabRegistryConfig>,
+    /// Record metrics of image pulls and job steps on the node the job runs on
+    pub metrics: Option<GitLabExecutorMetricsConfig>,
+    /// Build the job image from an apptainer definition file or Dockerfile in the repository,
+    /// if the job sets the CI variable META_RUNNER_IMAGE_DEFINITION to its path inside the project directory
+    /// Built images are cached in image_dir based on the hash of the file, project and commit. Since the sources are only
+    /// fetched after the prepare step, the image is built before the first job script step
+    pub image_build: Option<GitLabImageBuildConfig>,
+    /// Executor options jobs may override via CI variables, jobs setting any of these variables fail if this is not set
+    pub job_overrides: Option<GitLabJobOverridesConfig>,
+}
+
+/// Home directory of the job container
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub enum GitLabHomeMount {
+    None,
+    Host,
+    Path(PathBuf),
 }
 
 /// GitLabCustomExcutorConfigTemplate after variable expansion
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct GitLabCustomExecutorConfig {
     pub image_dir: PathBuf,
     pub image_cache_dir: Option<PathBuf>,
     pub image_tmp_dir: Option<PathBuf>,
     pub pull_policy: GitLabExecutorPullPolicy,
+    pub pull_retries: u32,
+    pub pull_retry_delay: u32,
+    pub pull_timeout: Option<u32>,
+    pub arch: Option<String>,
+    pub default_image: Option<String>,
+    pub allow_host_fallback: bool,
+    pub prewarm_images: Vec<String>,
+    pub image_mirrors: Vec<String>,
+    pub backend: GitLabExecutorBackend,
     pub apptainer_executable: PathBuf,
+    pub docker_executable: PathBuf,
+    pub enroot_executable: PathBuf,
     pub gpu_amd: bool,
     pub gpu_nvidia: bool,
+    pub gpu_intel: bool,
+    pub mount_home: GitLabHomeMount,
     pub mount: Vec<String>,
+    pub create_mount_sources: bool,
+    pub exec_args: Vec<String>,
+    pub pass_env: Vec<String>,
+    pub step_wrapper: Vec<String>,
+    pub limits: Option<GitLabLimitsConfig>,
+    pub run_as: Option<String>,
+    pub shell_executable: Option<String>,
+    pub shell_args: Option<Vec<String>>,
+    pub network: Option<String>,
+    pub network_args: Vec<String>,
+    pub overlay_size_mb: Option<u32>,
+    pub reuse_instance: bool,
+    pub overlay: Option<PathBuf>,
+    pub verify_signatures: bool,
+    pub trusted_fingerprints: Vec<String>,
+    pub trusted_digests: Vec<String>,
     pub builds_dir: PathBuf,
+    pub builds_dir_scratch: Vec<String>,
+    pub builds_dir_layout: Option<String>,
+    pub builds_dir_is_shared: bool,
     pub cache_dir: PathBuf,
+    pub shell: Option<String>,
     pub description: Option<String>,
+    pub modules: Vec<String>,
+    pub spack: Option<GitLabSpackConfig>,
+    pub failed_builds: Option<GitLabFailedBuildsConfig>,
+    pub stale_builds_max_age: Option<u32>,
+    pub min_free_space: Option<GitLabMinFreeSpaceConfig>,
+    pub caches: HashMap<String, GitLabCacheConfig>,
+    pub registries: HashMap<String, GitLabRegistryConfig>,
+    pub metrics: Option<GitLabExecutorMetricsConfig>,
+    pub image_build: Option<GitLabImageBuildConfig>,
+    pub job_overrides: Option<GitLabJobOverridesConfig>,
+}
+
+/// Executor configuration embedded into the arguments of the custom executor, see configure.embed_executor_config
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmbeddedExecutorConfig {
+    pub state_db_path: PathBuf,
+    pub config: GitLabCustomExecutorConfig,
 }
 
 #[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
 pub struct GitLabPollConfig {
     /// Interval (in seconds) for polling for new jobs
     pub interval: u32,
+    #[serde(default = "default_api_timeout")]
+    /// Time (in seconds) to wait for the GitLab API requests of a poll, e.g. for fetching pending jobs,
+    /// before the poll fails
+    pub api_timeout: u32,
+    /// Time (in seconds) to wait for further jobs after new pending jobs were found, before dispatching them.
+    /// This allows bursts of jobs to be grouped according to launch.group_size, must be smaller than interval
+    pub debounce: Option<u32>,
+    #[serde(default)]
+    /// How to choose between several runner instances matching a job, one of
+    /// "fewest-tags" (default), "round-robin", "random" or "least-recently-launched"
+    pub match_strategy: GitLabMatchStrategy,
+    #[serde(default)]
+    /// Whether to append every dispatch decision (matched instance, reason and launch outcome for every job)
+    /// to <name>.decisions.jsonl in the data directory
+    pub decision_log: bool,
+}
+
+#[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabConfigureConfig {
+    #[serde(default = "default_api_concurrency")]
+    /// Maximum number of concurrent GitLab API requests while updating runner registrations
+    pub concurrency: usize,
+    /// Legacy runner registration token for the project
+    /// If set, new runners will be registered using the deprecated registration token flow,
+    /// which is necessary for GitLab versions older than 15.10
+    pub registration_token: Option<String>,
+    /// Interval (in seconds) in which `gitlab-meta-runner run` updates the runner registrations
+    /// and regenerates the gitlab-runner configuration file like `gitlab-meta-runner configure`,
+    /// using the configuration it was started or last reloaded with
+    pub auto_interval: Option<u32>,
+    #[serde(default)]
+    /// Embed the expanded [executor] configuration into the arguments of the custom executor in the generated
+    /// gitlab-runner configuration, so the executor doesn't read this file, e.g. on compute nodes that can't see it
+    /// Variables in [executor] are then expanded by `configure` instead of the executor, so they can't refer to
+    /// the environment of the job, like $SLURM_JOB_ID
+    pub embed_executor_config: bool,
+}
+
+impl Default for GitLabConfigureConfig {
+    fn default() -> Self {
+        GitLabConfigureConfig {
+            concurrency: default_api_concurrency(),
+            registration_token: None,
+            auto_interval: None,
+            embed_executor_config: false,
+        }
+    }
+}
+
+#[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabSuperviseConfig {
+    #[serde(default = "default_gitlab_runner_executable")]
+    /// Path to the gitlab-runner executable (may be relative to workdir or $PATH), will NOT be variable-expanded
+    pub executable: String,
+    #[serde(default = "Vec::new")]
+    /// Additional arguments to pass to `gitlab-runner run`, will NOT be variable-expanded
+    pub args: Vec<String>,
+    #[serde(default = "default_restart_delay")]
+    /// Time (in seconds) to wait before restarting gitlab-runner after it exited unexpectedly
+    pub restart_delay: u32,
+}
+
+#[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabNotifyConfig {
+    /// URL of the webhook notifications are sent to via POST
+    pub url: String,
+    #[serde(default)]
+    /// Format of the request body, one of "json" (default), "slack" or "matrix"
+    pub format: GitLabNotifyFormat,
+    #[serde(default = "default_failure_threshold")]
+    /// Number of consecutive failed polls, or failed launches of a runner instance, after which a notification is sent
+    pub failure_threshold: u32,
+    /// The time to wait (in seconds) for the webhook to respond
+    pub timeout: Option<u32>,
+}
+
+#[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
+pub struct GitLabMetricsConfig {
+    /// Address to serve Prometheus metrics on (under /metrics), e.g. 127.0.0.1:9400
+    /// Changes are only applied after a restart
+    pub listen: String,
+}
+
+impl Default for GitLabSuperviseConfig {
+    fn default() -> Self {
+        GitLabSuperviseConfig {
+            executable: default_gitlab_runner_executable(),
+            args: Vec::new(),
+            restart_delay: default_restart_delay(),
+        }
+    }
 }
 
 #[derive(Debug, DocumentedFields, FieldNamesAsArray, Deserialize, Serialize)]
 pub struct GitLabRunnersConfig {
     /// Unique name for the meta-runner
     pub name: String,
-    /// GitLab Project name for the meta-runner
-    pub project: String,
+    /// GitLab Project name for the meta-runner, or a list of project names whose pending jobs will all be polled
+    /// Runners are registered with the first project and need to be enabled manually in all other projects
+    pub project: StringOrList,
     /// GitLab hostname for the meta-runner
     pub hostname: String,
     /// GitLab project token with read_api, create_runner, manage_runner permissions
@@ -202,13 +1027,41 @@ pub struct GitLabRunnersConfig {
     pub runners: HashMap<String, GitLabRunnerInstance>,
     /// Configuration for polling for new jobs
     pub poll: GitLabPollConfig,
+    #[serde(default)]
+    /// Configuration for updating runner registrations
+    pub configure: GitLabConfigureConfig,
+    #[serde(default)]
+    /// External programs to execute on certain events, e.g. for accounting purposes
+    pub hooks: HashMap<String, GitLabHookConfig>,
+    #[serde(default)]
+    /// Planned maintenance windows, during which all runners will be paused on GitLab
+    /// and no runners will be launched, so pending jobs stay queued
+    pub maintenance: HashMap<String, GitLabMaintenanceWindow>,
+    #[serde(default)]
+    /// Recurring windows during which runners may be launched, e.g. to avoid the cluster's nightly maintenance slot
+    /// Runners are launched at any time if this is empty
+    pub schedule: HashMap<String, GitLabScheduleWindow>,
+    #[serde(default)]
+    /// Configuration for supervising a persistent gitlab-runner process with `gitlab-meta-runner run-multi`,
+    /// as an alternative to launching ephemeral runners for every job
+    /// The process will be restarted if it exits and reloaded when the generated config file changes
+    pub supervise: GitLabSuperviseConfig,
+    /// Configuration for the Prometheus metrics endpoint of `gitlab-meta-runner run`
+    pub metrics: Option<GitLabMetricsConfig>,
+    /// Webhook notifying operators when polls or launches fail persistently
+    /// or a runner instance is in backoff after a failed launch
+    pub notify: Option<GitLabNotifyConfig>,
     /// Configuration for launching ephemeral runners
     /// Some of the configuration variables allow variable expansion from the runner instance variables
     /// Available variables are (in order of precedence)
     /// - $NAME for the runner instance name, to be passed to `gitlab-runner run-single --runner-name $NAME``
     /// - $THIS for the path to this executable
     /// - $CONFIG for the path to the generated gitlab-runner config file, to be passed to `gitlab-runner --config $CONFIG`
-    /// - $NUM_JOBS for the number of jobs that were grouped together for this launch, to be passed to `gitlab-runner run-single --max-builds 1`
+    /// - $NUM_JOBS for the number of jobs that were grouped together for this launch (at most launch.group_size),
+    ///   to be passed to `gitlab-runner run-single --max-builds $NUM_JOBS`
+    /// - $JOB_TIMEOUT for the sum of the timeouts (in seconds) of the grouped jobs, which run one after another,
+    ///   and $JOB_TIMEOUT_MINUTES for the same value rounded up to minutes, e.g. for `sbatch --time $JOB_TIMEOUT_MINUTES`
+    ///   Jobs that don't report a timeout are assumed to take at most one hour
     /// - Any variables defined in runners.<runner_name>.config_variables
     /// - Any environment variables provided by gitlab-runner to this custom executor
     pub launch: Option<GitLabLaunchConfig>,
@@ -238,13 +1091,14 @@ fn strs_to_strings(strs: &[&str]) -> Vec<String> {
 pub fn get_example_config() -> GitLabRunnersConfig {
     GitLabRunnersConfig {
         name: "meta-runner".into(),
-        project: "gitlab-org/gitlab".into(),
+        project: StringOrList::String("gitlab-org/gitlab".into()),
         hostname: "gitlab.com".into(),
         management_token: get_token_placeholder(),
         runner: gitlab_config::Runner {
             builds_dir: "$HOME/builds/$NAME/".into(),
             cache_dir: "$HOME/cache/".into(),
             output_limit: None,
+            shell: None,
             executor: gitlab_config::Executor::Custom {
                 custom: gitlab_config::CustomExecutor {
                     config_exec: "$THIS".into(),
@@ -260,21 +1114,116 @@ pub fn get_example_config() -> GitLabRunnersConfig {
             environment: Some(vec!["ENV_VARIABLE=value".into()]),
         },
         launch: Some(GitLabLaunchConfig {
+            preset: Some(GitLabLauncherPreset::Slurm),
             executable: "sbatch".into(),
-            args: [].into_iter().map(str::to_string).collect(),
+            args: strs_to_strings(&["--job-name", "$NAME", "--time", "$JOB_TIMEOUT_MINUTES"]),
             timeout: Some(300),
+            retries: 2,
+            retry_delay: 10,
             stdin: Some(
                 "#!/bin/bash\ngitlab-runner run-single --config $CONFIG --runner $NAME --max-builds $NUM_JOBS --wait-timeout 1\n".into(),
             ),
             workdir: Some("$HOME/launch".into()),
             group_size: 1,
+            max_per_poll: Some(50),
+            budget: Some(120),
+            parallelism: Some(4),
+            allocation_id_pattern: Some("Submitted batch job (\\d+)".into()),
+            reconcile_timeout: Some(3600),
+            cancel_orphaned: true,
+            cancel: Some(GitLabCancelConfig {
+                executable: "scancel".into(),
+                args: strs_to_strings(&["$ALLOCATION_ID"]),
+            }),
+            redispatch_timeout: Some(3600),
+            starvation_timeout: Some(7200),
+            prepare_failure_cooldown: Some(1800),
+            prepare_failure_threshold: 3,
+            pre_hook: Some(GitLabLaunchHookConfig {
+                executable: "$HOME/launch/pre-launch.sh".into(),
+                args: strs_to_strings(&["$NAME", "$NUM_JOBS"]),
+            }),
+            post_hook: None,
+            queue_check: Some(GitLabQueueCheckConfig {
+                executable: "sh".into(),
+                args: strs_to_strings(&["-c", "squeue --me --name $NAME -h | wc -l"]),
+            }),
+        }),
+        poll: GitLabPollConfig {
+            interval: 30,
+            api_timeout: 60,
+            debounce: Some(5),
+            match_strategy: GitLabMatchStrategy::FewestTags,
+            decision_log: true,
+        },
+        configure: GitLabConfigureConfig {
+            concurrency: 8,
+            registration_token: None,
+            auto_interval: Some(3600),
+            embed_executor_config: false,
+        },
+        hooks: [(
+            "accounting".to_owned(),
+            GitLabHookConfig {
+                events: vec![HookEvent::LaunchSucceeded, HookEvent::LaunchFailed],
+                executable: "/usr/local/bin/record-launch".into(),
+                args: Vec::new(),
+                timeout: Some(10),
+            },
+        )]
+        .into_iter()
+        .collect(),
+        maintenance: [(
+            "os-upgrade".to_owned(),
+            GitLabMaintenanceWindow {
+                start: DateTime::parse_from_rfc3339("2024-10-01T08:00:00+02:00").unwrap(),
+                end: DateTime::parse_from_rfc3339("2024-10-01T18:00:00+02:00").unwrap(),
+            },
+        )]
+        .into_iter()
+        .collect(),
+        schedule: [(
+            "outside-nightly-maintenance".to_owned(),
+            GitLabScheduleWindow {
+                days: Vec::new(),
+                start: NaiveTime::from_hms_opt(4, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(2, 0, 0).unwrap(),
+            },
+        )]
+        .into_iter()
+        .collect(),
+        supervise: GitLabSuperviseConfig::default(),
+        metrics: Some(GitLabMetricsConfig {
+            listen: "127.0.0.1:9400".into(),
+        }),
+        notify: Some(GitLabNotifyConfig {
+            url: "https://hooks.slack.com/services/...".into(),
+            format: GitLabNotifyFormat::Slack,
+            failure_threshold: 3,
+            timeout: Some(10),
         }),
-        poll: GitLabPollConfig { interval: 30 },
         runners: [(
             "test-runner".to_owned(),
             GitLabRunnerInstance {
                 tags: vec!["tag-1".to_owned(), "tag-2".to_owned()],
                 launch_priority: Some(10),
+                job_name_pattern: None,
+                ref_pattern: None,
+                pipeline_source: Vec::new(),
+                tag_expression: Some("tag-1 & !tag-2".into()),
+                run_untagged: false,
+                max_launches_per_hour: Some(10),
+                max_launches_per_day: None,
+                schedule: [(
+                    "weekend".to_owned(),
+                    GitLabScheduleWindow {
+                        days: vec![Weekday::Sat, Weekday::Sun],
+                        start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+                        end: NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+                    },
+                )]
+                .into_iter()
+                .collect(),
                 config_variables: [("VARIABLE", "value")]
                     .map(|(k, v)| (k.to_owned(), v.to_owned()))
                     .into_iter()
@@ -285,22 +1234,114 @@ pub fn get_example_config() -> GitLabRunnersConfig {
         .collect(),
         executor: Some(GitLabCustomExecutorConfigTemplate {
             builds_dir: Some("$HOME/builds".into()),
+            builds_dir_scratch: Vec::new(),
+            builds_dir_layout: Some("$CI_PROJECT_PATH_SLUG/$CI_JOB_ID".into()),
+            builds_dir_is_shared: false,
             image_dir: "$HOME/images".into(),
             image_cache_dir: Some("$HOME/image_cache".into()),
             image_tmp_dir: Some("$HOME/image_tmp".into()),
             pull_policy: GitLabExecutorPullPolicy::IfNotPresent,
+            pull_retries: 2,
+            pull_retry_delay: 10,
+            pull_timeout: Some(1800),
+            arch: None,
+            default_image: Some("docker://ubuntu:24.04".into()),
+            allow_host_fallback: false,
+            prewarm_images: vec!["docker://ubuntu:24.04".into()],
+            image_mirrors: vec!["mirror.gcr.io".into()],
+            backend: GitLabExecutorBackend::Apptainer,
             apptainer_executable: "apptainer".into(),
+            docker_executable: "docker".into(),
+            enroot_executable: "enroot".into(),
             gpu_amd: BoolOrString::Bool(false),
             gpu_nvidia: BoolOrString::Bool(false),
+            gpu_intel: BoolOrString::Bool(false),
+            mount_home: BoolOrString::Bool(false),
             mount: Vec::new(),
+            create_mount_sources: false,
+            exec_args: vec!["--containall".into()],
+            pass_env: vec!["SLURM_*".into(), "http_proxy".into(), "https_proxy".into()],
+            step_wrapper: Vec::new(),
+            limits: Some(GitLabLimitsConfig {
+                cpus: Some(4.0),
+                memory: Some(16384),
+                pids: Some(4096),
+            }),
+            run_as: None,
+            shell: None,
+            shell_args: None,
+            network: None,
+            network_args: Vec::new(),
+            overlay_size_mb: Some(4096),
+            reuse_instance: false,
+            overlay: None,
+            verify_signatures: false,
+            trusted_fingerprints: Vec::new(),
+            trusted_digests: Vec::new(),
             description: Some("Slurm job $SLURM_JOB_ID".into()),
+            modules: Vec::new(),
+            spack: Some(GitLabSpackConfig {
+                executable: "spack".into(),
+                cache_dir: "$HOME/spack_environments".into(),
+                environment: None,
+            }),
+            failed_builds: Some(GitLabFailedBuildsConfig {
+                dir: "$HOME/failed_builds".into(),
+                archive: true,
+                max_count: Some(10),
+                max_age: Some(168),
+                max_size: None,
+            }),
+            stale_builds_max_age: Some(48),
+            min_free_space: Some(GitLabMinFreeSpaceConfig {
+                image_dir: Some(10240),
+                image_tmp_dir: Some(10240),
+                builds_dir: Some(4096),
+            }),
+            caches: [(
+                "ccache".to_owned(),
+                GitLabCacheConfig {
+                    dir: "$HOME/caches/ccache".into(),
+                    variable: None,
+                    max_size: Some(20480),
+                },
+            )]
+            .into_iter()
+            .collect(),
+            registries: [(
+                "registry.example.com".to_owned(),
+                GitLabRegistryConfig {
+                    username: Some("ci-robot".into()),
+                    password_variable: None,
+                    password_file: Some("$HOME/.registry-token".into()),
+                    password_command: Vec::new(),
+                    library_url: None,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            metrics: Some(GitLabExecutorMetricsConfig {
+                textfile: "/var/lib/node_exporter/textfile/gitlab-meta-runner.prom".into(),
+            }),
+            image_build: Some(GitLabImageBuildConfig {
+                apptainer_args: vec!["--fakeroot".into()],
+                dockerfile_builder: None,
+            }),
+            job_overrides: Some(GitLabJobOverridesConfig {
+                gpu: true,
+                flags: vec!["--containall".into()],
+                mount_prefixes: vec!["/scratch".into()],
+            }),
         }),
     }
 }
 
 pub fn read_config(filename: &Path) -> anyhow::Result<GitLabRunnersConfig> {
     let content = read_to_string(filename)?;
-    let parsed: GitLabRunnersConfig = toml::from_str(&content)?;
+    let mut parsed: GitLabRunnersConfig = toml::from_str(&content)?;
+    if let Some(launch) = &mut parsed.launch {
+        apply_preset(launch);
+    }
     if parsed.management_token == get_token_placeholder() {
         warn!("management_token uses placeholder value, API operations will fail")
     }
@@ -344,25 +1385,138 @@ pub fn get_example_config_str() -> String {
     annotate_toml_table::<GitLabRunnersConfig>(document.as_table_mut());
     {
         let runners = document.get_mut("runners").unwrap();
-        for (name, _) in &config.runners {
-            annotate_toml_table::<GitLabRunnerInstance>(
-                runners.get_mut(name).unwrap().as_table_mut().unwrap(),
+        for (name, instance) in &config.runners {
+            let runner = runners.get_mut(name).unwrap().as_table_mut().unwrap();
+            annotate_toml_table::<GitLabRunnerInstance>(runner);
+            let schedule = runner.get_mut("schedule").unwrap();
+            for window in instance.schedule.keys() {
+                annotate_toml_table::<GitLabScheduleWindow>(
+                    schedule.get_mut(window).unwrap().as_table_mut().unwrap(),
+                );
+            }
+        }
+    }
+    {
+        let hooks = document.get_mut("hooks").unwrap();
+        for name in config.hooks.keys() {
+            annotate_toml_table::<GitLabHookConfig>(
+                hooks.get_mut(name).unwrap().as_table_mut().unwrap(),
+            );
+        }
+    }
+    {
+        let schedule = document.get_mut("schedule").unwrap();
+        for name in config.schedule.keys() {
+            annotate_toml_table::<GitLabScheduleWindow>(
+                schedule.get_mut(name).unwrap().as_table_mut().unwrap(),
+            );
+        }
+    }
+    {
+        let maintenance = document.get_mut("maintenance").unwrap();
+        for name in config.maintenance.keys() {
+            annotate_toml_table::<GitLabMaintenanceWindow>(
+                maintenance.get_mut(name).unwrap().as_table_mut().unwrap(),
             );
         }
     }
     annotate_toml_table::<GitLabPollConfig>(
         document.get_mut("poll").unwrap().as_table_mut().unwrap(),
     );
-    annotate_toml_table::<GitLabLaunchConfig>(
-        document.get_mut("launch").unwrap().as_table_mut().unwrap(),
+    annotate_toml_table::<GitLabConfigureConfig>(
+        document
+            .get_mut("configure")
+            .unwrap()
+            .as_table_mut()
+            .unwrap(),
     );
-    annotate_toml_table::<GitLabCustomExecutorConfigTemplate>(
+    annotate_toml_table::<GitLabSuperviseConfig>(
         document
-            .get_mut("executor")
+            .get_mut("supervise")
             .unwrap()
             .as_table_mut()
             .unwrap(),
     );
+    annotate_toml_table::<GitLabMetricsConfig>(
+        document.get_mut("metrics").unwrap().as_table_mut().unwrap(),
+    );
+    annotate_toml_table::<GitLabNotifyConfig>(
+        document.get_mut("notify").unwrap().as_table_mut().unwrap(),
+    );
+    {
+        let launch = document.get_mut("launch").unwrap().as_table_mut().unwrap();
+        annotate_toml_table::<GitLabLaunchConfig>(launch);
+        annotate_toml_table::<GitLabCancelConfig>(
+            launch.get_mut("cancel").unwrap().as_table_mut().unwrap(),
+        );
+        annotate_toml_table::<GitLabLaunchHookConfig>(
+            launch.get_mut("pre_hook").unwrap().as_table_mut().unwrap(),
+        );
+        annotate_toml_table::<GitLabQueueCheckConfig>(
+            launch
+                .get_mut("queue_check")
+                .unwrap()
+                .as_table_mut()
+                .unwrap(),
+        );
+    }
+    {
+        let executor = document
+            .get_mut("executor")
+            .unwrap()
+            .as_table_mut()
+            .unwrap();
+        annotate_toml_table::<GitLabCustomExecutorConfigTemplate>(executor);
+        annotate_toml_table::<GitLabSpackConfig>(
+            executor.get_mut("spack").unwrap().as_table_mut().unwrap(),
+        );
+        annotate_toml_table::<GitLabLimitsConfig>(
+            executor.get_mut("limits").unwrap().as_table_mut().unwrap(),
+        );
+        annotate_toml_table::<GitLabFailedBuildsConfig>(
+            executor
+                .get_mut("failed_builds")
+                .unwrap()
+                .as_table_mut()
+                .unwrap(),
+        );
+        annotate_toml_table::<GitLabMinFreeSpaceConfig>(
+            executor
+                .get_mut("min_free_space")
+                .unwrap()
+                .as_table_mut()
+                .unwrap(),
+        );
+        let caches = executor.get_mut("caches").unwrap();
+        for name in config.executor.as_ref().unwrap().caches.keys() {
+            annotate_toml_table::<GitLabCacheConfig>(
+                caches.get_mut(name).unwrap().as_table_mut().unwrap(),
+            );
+        }
+        let registries = executor.get_mut("registries").unwrap();
+        for name in config.executor.as_ref().unwrap().registries.keys() {
+            annotate_toml_table::<GitLabRegistryConfig>(
+                registries.get_mut(name).unwrap().as_table_mut().unwrap(),
+            );
+        }
+        annotate_toml_table::<GitLabExecutorMetricsConfig>(
+            executor.get_mut("metrics").unwrap().as_table_mut().unwrap(),
+        );
+        annotate_toml_table::<GitLabImageBuildConfig>(
+            executor
+                .get_mut("image_build")
+                .unwrap()
+                .as_table_mut()
+                .unwrap(),
+        );
+        annotate_toml_table::<GitLabJobOverridesConfig>(
+            executor
+                .get_mut("job_overrides")
+                .unwrap()
+                .as_table_mut()
+                .unwrap(),
+        );
+    }
     let runner = document.get_mut("runner").unwrap().as_table_mut().unwrap();
     annotate_toml_table::<gitlab_config::Runner>(runner);
     annotate_toml_table::<gitlab_config::CustomExecutor>(
@@ -406,21 +1560,6 @@ pub fn read_tokens(
     Ok(toml::from_str(&content)?)
 }
 
-pub fn write_tokens(
-    filename: &Path,
-    tokens: &HashMap<String, gitlab_config::RunnerRegistration>,
-) -> anyhow::Result<()> {
-    let mut file = std::fs::File::create(filename)?;
-    file.write_all(
-        format!(
-            "# autogenerated by gitlab-meta-runner\n{}",
-            toml::to_string(tokens)?
-        )
-        .as_bytes(),
-    )?;
-    Ok(())
-}
-
 pub fn write_gitlab_runner_configurations(
     filename: &PathBuf,
     runners: &Vec<gitlab_config::RegisteredRunner>,
@@ -473,4 +1612,42 @@ mod tests {
         let config_str = get_example_config_str();
         toml::from_str::<GitLabRunnersConfig>(&config_str).unwrap();
     }
+
+    #[test]
+    fn schedule_window() {
+        let window = GitLabScheduleWindow {
+            days: vec![Weekday::Fri],
+            start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+        };
+        // 2024-10-04 is a Friday
+        let time = |day, hour| {
+            chrono::NaiveDate::from_ymd_opt(2024, 10, day)
+                .unwrap()
+                .and_hms_opt(hour, 0, 0)
+                .unwrap()
+        };
+        assert!(window.contains(time(4, 23)));
+        assert!(window.contains(time(5, 5)));
+        assert!(!window.contains(time(5, 6)));
+        assert!(!window.contains(time(4, 5)));
+        assert!(!window.contains(time(5, 23)));
+        let windows = [("night".to_owned(), window)].into_iter().collect();
+        assert!(in_schedule(&HashMap::new(), time(4, 12)));
+        assert!(!in_schedule(&windows, time(4, 12)));
+    }
+
+    #[test]
+    fn string_or_list() {
+        #[derive(Deserialize)]
+        struct Projects {
+            single: StringOrList,
+            multiple: StringOrList,
+        }
+        let projects: Projects =
+            toml::from_str("single = \"a\"\nmultiple = [\"b\", \"c\"]\n").unwrap();
+        assert_eq!(projects.single.as_slice(), ["a"]);
+        assert_eq!(projects.multiple.as_slice(), ["b", "c"]);
+        assert!(toml::from_str::<Projects>("single = 1\nmultiple = []\n").is_err());
+    }
 }
@@ -1,44 +1,84 @@
 use std::{
     collections::{HashMap, HashSet},
-    path::PathBuf,
+    future::Future,
+    path::{Path, PathBuf},
 };
 
-use anyhow::Context;
-use futures::future::join_all;
+use anyhow::{anyhow, Context};
+use futures::{stream, StreamExt};
 use gitlab::{api::ApiError, RestError};
 use http::StatusCode;
-use log::{error, warn};
+use log::{error, info, warn};
+use serde_json::json;
+use tokio_util::task::TaskTracker;
 
 use crate::{
     cli::Paths,
+    config::HookEvent,
     config::{
-        get_generated_config_file_path, get_tokens_file_path, read_config, read_tokens,
-        write_gitlab_runner_configurations, write_tokens, GitLabRunnersConfig,
+        get_generated_config_file_path, get_state_db_path, get_tokens_file_path, read_config,
+        read_tokens, write_gitlab_runner_configurations, EmbeddedExecutorConfig,
+        GitLabRunnersConfig,
     },
-    gitlab_config::{RegisteredRunner, RunnerRegistration},
+    gitlab_config::{Executor, RegisteredRunner, Runner, RunnerRegistration},
     gitlab_wrap::{
-        add_project_runner, delete_runner, fetch_project, init_client, update_runner,
-        RunnerParameters,
+        add_project_runner, delete_runner, fetch_project, init_client, register_legacy_runner,
+        update_runner, RunnerParameters,
     },
-    template::expand_runner_config_template,
+    hooks::run_hooks,
+    state::{StateDb, FLAG_RUNNERS_PAUSED},
+    template::{expand_executor_config_template, expand_runner_config_template},
 };
 
 fn runner_name_to_description(config: &GitLabRunnersConfig, name: &str) -> String {
     format!("{}-{}", config.name, name)
 }
 
+/// Appends the expanded executor configuration to the arguments of all custom executor steps
+fn embed_executor_config(
+    runner: &mut Runner,
+    embedded: &EmbeddedExecutorConfig,
+) -> anyhow::Result<()> {
+    let Executor::Custom { custom } = &mut runner.executor else {
+        return Ok(());
+    };
+    let arg = format!(
+        "--embedded-config={}",
+        serde_json::to_string(embedded).context("Failed serializing executor config")?
+    );
+    for args in [
+        &mut custom.config_args,
+        &mut custom.prepare_args,
+        &mut custom.run_args,
+        &mut custom.cleanup_args,
+    ] {
+        args.push(arg.clone());
+    }
+    Ok(())
+}
+
 fn instantiate_gitlab_runner_configurations(
     config: &GitLabRunnersConfig,
     registrations: &HashMap<String, RunnerRegistration>,
+    state_db_path: &Path,
 ) -> anyhow::Result<Vec<RegisteredRunner>> {
     let runners = &config.runners;
     runners
         .iter()
         .map(|(name, instance)| {
+            let mut runner = expand_runner_config_template(&config.runner, name, instance)
+                .context(name.clone())?;
+            if config.configure.embed_executor_config {
+                let embedded = EmbeddedExecutorConfig {
+                    state_db_path: state_db_path.to_owned(),
+                    config: expand_executor_config_template(config, name, instance)
+                        .context(format!("Failed expanding [executor] for instance {}", name))?,
+                };
+                embed_executor_config(&mut runner, &embedded)?;
+            }
             Ok(RegisteredRunner {
                 name: name.clone(),
-                config: expand_runner_config_template(&config.runner, name, instance)
-                    .context(name.clone())?,
+                config: runner,
                 url: format!("https://{}", config.hostname),
                 registration: registrations.get(name).unwrap().clone(),
             })
@@ -46,31 +86,52 @@ fn instantiate_gitlab_runner_configurations(
         .collect()
 }
 
-pub fn configure(paths: &Paths) -> anyhow::Result<()> {
+#[tokio::main]
+pub async fn configure(paths: &Paths) -> anyhow::Result<()> {
     let config = read_config(&paths.config_file).context(format!(
         "Failed reading config file {:?}",
         paths.config_file
     ))?;
+    let hook_tasks = TaskTracker::new();
+    let runner_config_file_path = update_configuration(paths, &config, &hook_tasks).await?;
+    hook_tasks.close();
+    hook_tasks.wait().await;
+    eprintln!(
+        "Wrote gitlab-runner configuration file {:?}",
+        runner_config_file_path
+    );
+    Ok(())
+}
+
+/// Updates the runner registrations on GitLab and writes the gitlab-runner configuration file,
+/// returning its path. This is also used by `run` if configure.auto_interval is set.
+/// Hooks for the registration changes are spawned on hook_tasks.
+pub async fn update_configuration(
+    paths: &Paths,
+    config: &GitLabRunnersConfig,
+    hook_tasks: &TaskTracker,
+) -> anyhow::Result<PathBuf> {
     std::fs::create_dir_all(&paths.data_dir).context("Creating data dir failed")?;
     let token_file_path = get_tokens_file_path(&paths.data_dir, &config.name);
-    let runner_config_file_path = get_generated_config_file_path(&paths, &config.name);
-    let tokens = update_registrations(&config, &token_file_path).context(format!(
-        "Failed updating runner registrations at {:?}",
-        token_file_path
-    ))?;
-    let instantiated_configs = instantiate_gitlab_runner_configurations(&config, &tokens)
-        .context("Failed instantiating runner config entries")?;
+    let state_db_path = get_state_db_path(&paths.data_dir, &config.name);
+    let runner_config_file_path = get_generated_config_file_path(paths, &config.name);
+    let mut db = StateDb::open(&state_db_path)?;
+    let tokens = update_registrations(config, &mut db, &token_file_path, hook_tasks)
+        .await
+        .context(format!(
+            "Failed updating runner registrations in {:?}",
+            state_db_path
+        ))?;
+    let instantiated_configs =
+        instantiate_gitlab_runner_configurations(config, &tokens, &state_db_path)
+            .context("Failed instantiating runner config entries")?;
     write_gitlab_runner_configurations(&runner_config_file_path, &instantiated_configs).context(
         format!(
             "Failed writing runner configuration file {:?}",
             runner_config_file_path
         ),
     )?;
-    eprintln!(
-        "Wrote gitlab-runner configuration file {:?}",
-        runner_config_file_path
-    );
-    Ok(())
+    Ok(runner_config_file_path)
 }
 
 fn is_error_not_found<T>(v: &Result<T, ApiError<RestError>>) -> bool {
@@ -85,19 +146,91 @@ fn is_error_not_found<T>(v: &Result<T, ApiError<RestError>>) -> bool {
     }
 }
 
-#[tokio::main]
-async fn update_registrations(
-    config: &GitLabRunnersConfig,
+/// Runs the given API requests with at most `concurrency` of them in flight at once,
+/// reporting progress as they complete. The results are returned in input order.
+pub async fn run_bounded<F: Future>(
+    description: &str,
+    futures: impl IntoIterator<Item = F>,
+    concurrency: usize,
+) -> Vec<F::Output> {
+    let futures: Vec<_> = futures.into_iter().collect();
+    let total = futures.len();
+    if total == 0 {
+        return Vec::new();
+    }
+    // report roughly every 10% to avoid flooding the output for large instance counts
+    let report_every = total.div_ceil(10);
+    let mut finished = 0;
+    stream::iter(futures)
+        .buffered(concurrency.max(1))
+        .inspect(|_| {
+            finished += 1;
+            if finished % report_every == 0 || finished == total {
+                info!(
+                    "{} runners: {}/{} requests done",
+                    description, finished, total
+                );
+            }
+        })
+        .collect()
+        .await
+}
+
+/// Reads the registrations from the state database,
+/// migrating them from the tokens file used by older versions if necessary
+fn read_registrations(
+    db: &mut StateDb,
     token_file: &PathBuf,
 ) -> anyhow::Result<HashMap<String, RunnerRegistration>> {
-    let tokens = read_tokens(&token_file).context(format!(
+    let tokens = db
+        .read_registrations()
+        .context("Failed reading registration tokens")?;
+    if !tokens.is_empty() {
+        return Ok(tokens);
+    }
+    let legacy_tokens = read_tokens(token_file).context(format!(
         "Failed reading registration tokens {:?}",
         token_file
     ))?;
+    if !legacy_tokens.is_empty() {
+        info!(
+            "Migrating {} runner registrations from {:?} to state database",
+            legacy_tokens.len(),
+            token_file
+        );
+        db.write_registrations(&legacy_tokens)
+            .context("Failed migrating registration tokens")?;
+        let migrated_file = token_file.with_extension("tokens.migrated");
+        std::fs::rename(token_file, &migrated_file).context(format!(
+            "Failed renaming {:?} to {:?}",
+            token_file, migrated_file
+        ))?;
+    }
+    Ok(legacy_tokens)
+}
+
+async fn update_registrations(
+    config: &GitLabRunnersConfig,
+    db: &mut StateDb,
+    token_file: &PathBuf,
+    hook_tasks: &TaskTracker,
+) -> anyhow::Result<HashMap<String, RunnerRegistration>> {
+    let tokens = read_registrations(db, token_file)?;
+    // registrations must not unpause runners during maintenance
+    let paused = db.get_flag(FLAG_RUNNERS_PAUSED)?;
+    if paused {
+        warn!("Maintenance mode is active, runners will stay paused");
+    }
     let client = init_client(&config.hostname, &config.management_token)
         .await
         .context("Failed initializing GitLab client")?;
-    let project = fetch_project(&client, &config.project)
+    // runners are registered with the first project, other projects need to enable them manually
+    let project = config
+        .project
+        .as_slice()
+        .first()
+        .ok_or(anyhow!("No project configured"))?;
+    let project = fetch_project(&client, project)
         .await
         .context("Failed fetching project information")?;
     let mut current_keys: HashSet<String> = tokens.keys().cloned().collect();
@@ -111,14 +244,17 @@ async fn update_registrations(
         let params = RunnerParameters {
             description: runner_name_to_description(config, key),
             tags: runner.tags.clone(),
+            paused,
+            run_untagged: runner.run_untagged,
         };
         update_runner(&client, runner_id, params)
     });
-    let update_results = join_all(update_futures).await;
+    let concurrency = config.configure.concurrency;
+    let update_results = run_bounded("Updating", update_futures, concurrency).await;
     let mut new_tokens = HashMap::new();
     let mut errors = Vec::new();
     // first handle all updated runners, any 404 means we need to move it to new_keys
-    for (key, result) in to_update.into_iter().zip(update_results.into_iter()) {
+    for (key, result) in to_update.into_iter().zip(update_results) {
         if is_error_not_found(&result) {
             warn!("Runner {} is missing, will recreate it", key);
             current_keys.remove(&key);
@@ -141,20 +277,36 @@ async fn update_registrations(
         let params = RunnerParameters {
             description: runner_name_to_description(config, new_key),
             tags: runner.tags.clone(),
+            paused,
+            run_untagged: runner.run_untagged,
         };
-        add_project_runner(&client, &project, params)
+        let client = &client;
+        let project = &project;
+        async move {
+            match &config.configure.registration_token {
+                Some(registration_token) => {
+                    register_legacy_runner(client, registration_token, params).await
+                }
+                None => add_project_runner(client, project, params).await,
+            }
+        }
     });
     let delete_futures = to_delete.iter().map(|old_key| {
         let runner_id = tokens.get(*old_key).unwrap().id;
         delete_runner(&client, runner_id)
     });
     // first wait for all futures to finish
-    let add_results = join_all(add_futures).await;
-    let delete_results = join_all(delete_futures).await;
+    let add_results = run_bounded("Adding", add_futures, concurrency).await;
+    let delete_results = run_bounded("Deleting", delete_futures, concurrency).await;
+    let mut hook_events = Vec::new();
     // then add all successfully registered runners to the file
-    for (key, result) in to_add.into_iter().zip(add_results.into_iter()) {
+    for (key, result) in to_add.into_iter().zip(add_results) {
         match result {
             Ok(registration) => {
+                hook_events.push((
+                    HookEvent::RunnerRegistered,
+                    json!({"instance": key, "runner_id": registration.id}),
+                ));
                 new_tokens.insert(key.clone(), registration.clone());
             }
             Err(e) => {
@@ -164,15 +316,24 @@ async fn update_registrations(
         };
     }
     // then check if there were any non 404 errors during deletion
-    for (key, result) in to_delete.into_iter().zip(delete_results.into_iter()) {
+    for (key, result) in to_delete.into_iter().zip(delete_results) {
         if is_error_not_found(&result) {
             warn!("Runner {} is missing, removing from token list", key);
         } else if let Err(e) = result {
             error!("Deletion of runner {} failed, keeping it in the list", key);
             errors.push(e);
+        } else {
+            hook_events.push((
+                HookEvent::RunnerDeleted,
+                json!({"instance": key, "runner_id": tokens[key].id}),
+            ));
         }
     }
-    write_tokens(&token_file, &new_tokens).context("Writing runner registration tokens")?;
+    for (event, payload) in hook_events {
+        run_hooks(config, hook_tasks, event, payload);
+    }
+    db.write_registrations(&new_tokens)
+        .context("Writing runner registration tokens")?;
     eprintln!(
         "API requests done, {} runners added, {} runners updated, {} runners deleted",
         add_count, update_count, del_count
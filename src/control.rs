@@ -0,0 +1,364 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use log::{debug, error, info};
+use serde_derive::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader},
+    net::{UnixListener, UnixStream as AsyncUnixStream},
+    sync::Notify,
+    time,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    cli::{self, ControlCommand},
+    config::{get_control_socket_path, read_config},
+    metrics::Metrics,
+    state::{unix_timestamp, InstanceActivity},
+};
+
+/// Current state of the running daemon as reported via the control socket
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct DaemonStatus {
+    /// All configured runner instances
+    pub instances: BTreeSet<String>,
+    /// Runner instances for which no runners will be launched
+    pub paused_instances: BTreeSet<String>,
+    /// Whether no runners will be launched for any runner instance
+    pub draining: bool,
+    /// Unix timestamp of the last finished poll
+    pub last_poll: Option<i64>,
+    /// Error message of the last poll, if it failed
+    pub last_poll_error: Option<String>,
+    /// Number of jobs that were launched or ignored so far
+    pub handled_jobs: usize,
+    /// Last activities of all configured runner instances
+    pub activity: BTreeMap<String, InstanceActivity>,
+    /// Unix timestamp of the daemon start
+    pub started_at: i64,
+    /// Number of jobs runners were launched for since the daemon start
+    pub dispatched_jobs: usize,
+    /// Number of failed launch commands per runner instance since the daemon start
+    pub launch_failures: BTreeMap<String, usize>,
+    /// Number of allocations per runner instance that weren't reconciled yet,
+    /// only tracked if launch.allocation_id_pattern is set
+    pub outstanding_launches: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ControlResponse {
+    Ok,
+    Error(String),
+    State(DaemonStatus),
+}
+
+/// State shared between the poll loop and the control socket
+pub struct DaemonControl {
+    pub status: Mutex<DaemonStatus>,
+    /// Notified when an immediate poll was requested
+    pub poll_trigger: Notify,
+    /// Counters exposed via the metrics endpoint
+    pub metrics: Metrics,
+}
+
+impl DaemonControl {
+    pub fn new(instances: impl IntoIterator<Item = String>) -> DaemonControl {
+        DaemonControl {
+            status: Mutex::new(DaemonStatus {
+                instances: instances.into_iter().collect(),
+                started_at: unix_timestamp(),
+                ..Default::default()
+            }),
+            poll_trigger: Notify::new(),
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Replaces the configured runner instances after a config reload,
+    /// forgetting paused instances that no longer exist
+    pub fn set_instances(&self, instances: impl IntoIterator<Item = String>) {
+        let mut status = self.status.lock().unwrap();
+        status.instances = instances.into_iter().collect();
+        let DaemonStatus {
+            instances,
+            paused_instances,
+            ..
+        } = &mut *status;
+        paused_instances.retain(|instance| instances.contains(instance));
+    }
+
+    pub fn is_paused(&self, instance: &str) -> bool {
+        self.status
+            .lock()
+            .unwrap()
+            .paused_instances
+            .contains(instance)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.status.lock().unwrap().draining
+    }
+
+    /// Updates the status after a poll finished
+    pub fn finish_poll(&self, error: Option<String>, handled_jobs: usize) {
+        let mut status = self.status.lock().unwrap();
+        status.last_poll = Some(unix_timestamp());
+        status.last_poll_error = error;
+        status.handled_jobs = handled_jobs;
+    }
+
+    /// Records the launch commands issued for a runner instance in the status and metrics
+    pub fn record_launches(
+        &self,
+        instance: &str,
+        dispatched_jobs: usize,
+        launches: usize,
+        failures: usize,
+    ) {
+        let mut status = self.status.lock().unwrap();
+        status.dispatched_jobs += dispatched_jobs;
+        *status
+            .launch_failures
+            .entry(instance.to_owned())
+            .or_default() += failures;
+        self.metrics
+            .record_launches(instance, dispatched_jobs, launches, failures);
+    }
+
+    pub fn set_outstanding_launches(&self, outstanding_launches: BTreeMap<String, usize>) {
+        self.status.lock().unwrap().outstanding_launches = outstanding_launches;
+    }
+
+    /// Updates the last activities of all configured runner instances
+    pub fn set_activity(&self, mut activity: HashMap<String, InstanceActivity>) {
+        let mut status = self.status.lock().unwrap();
+        status.activity = status
+            .instances
+            .iter()
+            .map(|name| (name.clone(), activity.remove(name).unwrap_or_default()))
+            .collect();
+    }
+
+    fn handle(&self, command: ControlCommand) -> ControlResponse {
+        let mut status = self.status.lock().unwrap();
+        match command {
+            ControlCommand::Pause { instance } => {
+                if !status.instances.contains(&instance) {
+                    return ControlResponse::Error(format!("Unknown runner instance {}", instance));
+                }
+                info!("Pausing runner instance {}", instance);
+                status.paused_instances.insert(instance);
+                ControlResponse::Ok
+            }
+            ControlCommand::Resume { instance } => {
+                if !status.paused_instances.remove(&instance) {
+                    return ControlResponse::Error(format!(
+                        "Runner instance {} is not paused",
+                        instance
+                    ));
+                }
+                info!("Resuming runner instance {}", instance);
+                ControlResponse::Ok
+            }
+            ControlCommand::Drain => {
+                if status.draining {
+                    return ControlResponse::Error("Already draining".into());
+                }
+                info!("Draining, no runners will be launched until drain mode is ended");
+                status.draining = true;
+                ControlResponse::Ok
+            }
+            ControlCommand::Undrain => {
+                if !status.draining {
+                    return ControlResponse::Error("Not draining".into());
+                }
+                info!("Ending drain mode, launching runners again");
+                status.draining = false;
+                ControlResponse::Ok
+            }
+            ControlCommand::Poll => {
+                info!("Immediate poll requested");
+                self.poll_trigger.notify_one();
+                ControlResponse::Ok
+            }
+            ControlCommand::DumpState => ControlResponse::State(status.clone()),
+        }
+    }
+}
+
+/// Time a client has to send its control request, so an idle client can't hold up the socket
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn handle_connection(stream: AsyncUnixStream, control: &DaemonControl) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    time::timeout(
+        REQUEST_TIMEOUT,
+        AsyncBufReader::new(reader).read_line(&mut line),
+    )
+    .await
+    .context("Timed out waiting for control request")?
+    .context("Failed reading control request")?;
+    debug!("Received control request {}", line.trim());
+    let response = match serde_json::from_str::<ControlCommand>(&line) {
+        Ok(command) => control.handle(command),
+        Err(e) => ControlResponse::Error(format!("Invalid control request: {}", e)),
+    };
+    writer
+        .write_all(serde_json::to_string(&response)?.as_bytes())
+        .await
+        .context("Failed writing control response")?;
+    Ok(())
+}
+
+/// Listens on the control socket until the cancellation token is triggered
+pub async fn serve(
+    socket_path: PathBuf,
+    control: Arc<DaemonControl>,
+    cancel_token: CancellationToken,
+) -> anyhow::Result<()> {
+    // remove a stale socket left behind by a previous instance, unless that is still running
+    if std::fs::exists(&socket_path)? {
+        if UnixStream::connect(&socket_path).is_ok() {
+            Err(anyhow!(
+                "Control socket {:?} is in use, is another meta-runner running?",
+                socket_path
+            ))?;
+        }
+        std::fs::remove_file(&socket_path)
+            .context(format!("Failed removing stale socket {:?}", socket_path))?;
+    }
+    let listener = UnixListener::bind(&socket_path)
+        .context(format!("Failed binding control socket {:?}", socket_path))?;
+    info!("Listening on control socket {:?}", socket_path);
+    loop {
+        tokio::select! {
+            connection = listener.accept() => match connection {
+                Ok((stream, _)) => {
+                    let control = control.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &control).await {
+                            error!("Failed handling control connection: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Failed accepting control connection: {:?}", e),
+            },
+            _ = cancel_token.cancelled() => break,
+        }
+    }
+    std::fs::remove_file(&socket_path)
+        .context(format!("Failed removing control socket {:?}", socket_path))?;
+    Ok(())
+}
+
+/// Sends a command to the running daemon and returns its response
+pub fn send(paths: &cli::Paths, command: &ControlCommand) -> anyhow::Result<ControlResponse> {
+    let config = read_config(&paths.config_file).context(format!(
+        "Failed reading config file {:?}",
+        paths.config_file
+    ))?;
+    let socket_path = get_control_socket_path(&paths.data_dir, &config.name);
+    let mut stream = UnixStream::connect(&socket_path).context(format!(
+        "Failed connecting to control socket {:?}, is the meta-runner running?",
+        socket_path
+    ))?;
+    writeln!(stream, "{}", serde_json::to_string(command)?)?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    serde_json::from_str(&response).context("Failed parsing control response")
+}
+
+fn format_timestamp(timestamp: Option<i64>) -> String {
+    match timestamp.and_then(|t| DateTime::<Utc>::from_timestamp(t, 0)) {
+        Some(time) => {
+            let ago = unix_timestamp() - time.timestamp();
+            format!("{} ({}s ago)", time.to_rfc3339(), ago)
+        }
+        None => "never".into(),
+    }
+}
+
+/// Prints a human-readable summary of the daemon status
+fn print_status(status: &DaemonStatus) {
+    println!(
+        "Running since:  {}",
+        format_timestamp(Some(status.started_at))
+    );
+    println!("Last poll:      {}", format_timestamp(status.last_poll));
+    if status.draining {
+        println!("{}", "Draining, no runners will be launched".yellow());
+    }
+    if let Some(error) = &status.last_poll_error {
+        println!("{}", format!("Last poll failed: {}", error).red());
+    }
+    println!("Dispatched jobs this session: {}", status.dispatched_jobs);
+    println!("Handled jobs in total:        {}", status.handled_jobs);
+    for instance in &status.instances {
+        let paused = if status.paused_instances.contains(instance) {
+            " (paused)".yellow()
+        } else {
+            "".normal()
+        };
+        println!("Runner instance {}{}", instance.bold(), paused);
+        let failures = status.launch_failures.get(instance).copied().unwrap_or(0);
+        let failures_str = failures.to_string();
+        println!(
+            "  Launch failures:      {}",
+            if failures > 0 {
+                failures_str.red()
+            } else {
+                failures_str.normal()
+            }
+        );
+        if let Some(outstanding) = status.outstanding_launches.get(instance) {
+            println!("  Outstanding launches: {}", outstanding);
+        }
+        let activity = status.activity.get(instance).cloned().unwrap_or_default();
+        println!(
+            "  Last matched:         {}",
+            format_timestamp(activity.last_matched)
+        );
+        println!(
+            "  Last launched:        {}",
+            format_timestamp(activity.last_launched)
+        );
+        println!(
+            "  Last executed:        {}",
+            format_timestamp(activity.last_executed)
+        );
+    }
+}
+
+/// Prints the status of the running daemon
+pub fn status(paths: &cli::Paths) -> anyhow::Result<()> {
+    match send(paths, &ControlCommand::DumpState)? {
+        ControlResponse::State(status) => {
+            print_status(&status);
+            Ok(())
+        }
+        ControlResponse::Error(e) => Err(anyhow!(e)),
+        ControlResponse::Ok => Err(anyhow!("Unexpected control response")),
+    }
+}
+
+pub fn control(paths: &cli::Paths, command: &ControlCommand) -> anyhow::Result<()> {
+    match send(paths, command)? {
+        ControlResponse::Ok => Ok(()),
+        ControlResponse::Error(e) => Err(anyhow!(e)),
+        ControlResponse::State(status) => {
+            println!("{}", serde_json::to_string_pretty(&status)?);
+            Ok(())
+        }
+    }
+}
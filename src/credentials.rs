@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use log::debug;
+use serde_json::json;
+
+use crate::config::GitLabRegistryConfig;
+
+/// Extracts the registry host from a docker:// or oras:// image URI
+fn registry_host(image: &str) -> Option<&str> {
+    let path = image
+        .strip_prefix("docker://")
+        .or(image.strip_prefix("oras://"))?;
+    let (host, _) = path.split_once('/')?;
+    // like docker, only treat the first component as a host if it looks like one
+    (host.contains(['.', ':']) || host == "localhost").then_some(host)
+}
+
+/// Finds the registry configuration for an image pull URI. library:// images use the registry
+/// with a configured library_url, since their URIs don't contain a host.
+pub fn find_registry<'a>(
+    registries: &'a HashMap<String, GitLabRegistryConfig>,
+    image: &str,
+) -> Option<(&'a String, &'a GitLabRegistryConfig)> {
+    if image.starts_with("library://") {
+        registries
+            .iter()
+            .find(|(_, registry)| registry.library_url.is_some())
+    } else {
+        let host = registry_host(image)?;
+        registries.get_key_value(host)
+    }
+}
+
+/// Reads the password or token of a registry from its configured source
+pub fn read_password(
+    name: &str,
+    registry: &GitLabRegistryConfig,
+    variable: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<String> {
+    if let Some(password_variable) = &registry.password_variable {
+        variable(password_variable).ok_or(anyhow!(
+            "Password variable {} of registry {} is not set",
+            password_variable,
+            name
+        ))
+    } else if let Some(password_file) = &registry.password_file {
+        let password = fs::read_to_string(password_file)
+            .context(format!("Failed reading password file of registry {}", name))?;
+        Ok(password.trim_end().to_owned())
+    } else if let Some((program, args)) = registry.password_command.split_first() {
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit());
+        debug!("Reading password of registry {} via {:?}", name, command);
+        let output = command
+            .output()
+            .context(format!("Failed spawning {:?}", command))?;
+        if !output.status.success() {
+            Err(anyhow!(
+                "Password command of registry {} failed: {:?}",
+                name,
+                output.status
+            ))?;
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .trim_end()
+            .to_owned())
+    } else {
+        Err(anyhow!("Registry {} has no password source", name))
+    }
+}
+
+/// Encodes bytes as standard base64 with padding
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let value = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |value, (i, &b)| value | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(value >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Builds a docker config file authenticating with the registry, passed to the pull via --authfile,
+/// so the password neither shows up in the environment nor in the logged pull command
+pub fn auth_file_content(host: &str, username: &str, password: &str) -> String {
+    json!({
+        "auths": {
+            host: {
+                "auth": base64(format!("{}:{}", username, password).as_bytes())
+            }
+        }
+    })
+    .to_string()
+}
+
+/// Logs in to the library endpoint of a registry, adding it as an apptainer remote if necessary.
+/// The token is passed via a file, so it doesn't show up in the process list.
+pub fn login_library(
+    apptainer_executable: &Path,
+    name: &str,
+    library_url: &str,
+    token_file: &Path,
+) -> anyhow::Result<()> {
+    let remote = format!("{}-{}", env!("CARGO_PKG_NAME"), name);
+    // adding fails if the remote already exists from an earlier job
+    let _ = Command::new(apptainer_executable)
+        .args(["remote", "add", "--no-login", &remote, library_url])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    let output = Command::new(apptainer_executable)
+        .args(["remote", "login", "--tokenfile"])
+        .arg(token_file)
+        .arg(&remote)
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed spawning apptainer remote login")?;
+    if !output.status.success() {
+        Err(anyhow!(
+            "Logging in to library {} failed:\n{}",
+            library_url,
+            String::from_utf8_lossy(&output.stderr)
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_credentials() {
+        let registry = |library_url: Option<&str>| GitLabRegistryConfig {
+            username: Some("robot".to_owned()),
+            password_variable: Some("REGISTRY_TOKEN".to_owned()),
+            password_file: None,
+            password_command: Vec::new(),
+            library_url: library_url.map(str::to_owned),
+        };
+        let registries: HashMap<_, _> = [
+            ("registry.example.com".to_owned(), registry(None)),
+            (
+                "library.example.com".to_owned(),
+                registry(Some("https://library.example.com")),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let host = |image| find_registry(&registries, image).map(|(host, _)| host.as_str());
+        assert_eq!(
+            host("oras://registry.example.com/images/base:1"),
+            Some("registry.example.com")
+        );
+        assert_eq!(
+            host("docker://registry.example.com/base"),
+            Some("registry.example.com")
+        );
+        assert_eq!(host("docker://ubuntu:24.04"), None);
+        assert_eq!(host("docker://other.example.com/base"), None);
+        assert_eq!(
+            host("library://entity/collection/image:1"),
+            Some("library.example.com")
+        );
+        let (name, config) = find_registry(&registries, "oras://registry.example.com/a").unwrap();
+        let variable = |name: &str| (name == "REGISTRY_TOKEN").then(|| "secret".to_owned());
+        assert_eq!(read_password(name, config, variable).unwrap(), "secret");
+        assert!(read_password(name, config, |_| None).is_err());
+        let command = GitLabRegistryConfig {
+            password_variable: None,
+            password_command: vec!["echo".to_owned(), "from-command".to_owned()],
+            ..registry(None)
+        };
+        assert_eq!(
+            read_password(name, &command, |_| None).unwrap(),
+            "from-command"
+        );
+        assert_eq!(base64(b"robot:secret"), "cm9ib3Q6c2VjcmV0");
+        assert_eq!(base64(b"a:bc"), "YTpiYw==");
+        assert_eq!(
+            auth_file_content("registry.example.com", "robot", "secret"),
+            r#"{"auths":{"registry.example.com":{"auth":"cm9ib3Q6c2VjcmV0"}}}"#
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use anyhow::Context;
+use serde_derive::Serialize;
+
+use crate::{gitlab_wrap::Job, state::unix_timestamp};
+
+/// Outcome of dispatching a pending job in a single poll
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Decision {
+    /// No runner instance matches the job, it will not be considered again
+    Unmatched,
+    /// The meta-runner is draining via the control socket, so no runners are launched
+    Draining,
+    /// The matching runner instance is paused via the control socket
+    InstancePaused,
+    /// The matching runner instance is in backoff after failed launches
+    InstanceBackoff,
+    /// The matching runner instance is unhealthy after failed prepare steps
+    InstanceUnhealthy,
+    /// The matching runner instance is outside its dispatch schedule
+    OutsideSchedule,
+    /// The launch queue check reported enough queued launches for the job
+    AlreadyQueued,
+    /// The job exceeded launch.max_per_poll and was deferred to the next poll
+    LaunchLimit,
+    /// The launch budget of the poll was used up before a runner could be launched for the job
+    LaunchBudget,
+    /// The matching runner instance exhausted its hourly or daily launch quota
+    LaunchQuota,
+    /// A runner was launched for the job
+    Launched,
+    /// Launching a runner for the job failed
+    LaunchFailed,
+}
+
+/// A single line of the decision log
+#[derive(Debug, Serialize)]
+pub struct DecisionEntry {
+    pub timestamp: i64,
+    pub job_id: u64,
+    pub job_name: String,
+    /// The runner instance matching the job, if any
+    pub instance: Option<String>,
+    pub decision: Decision,
+    /// Details like the error message of a failed launch
+    pub message: Option<String>,
+}
+
+impl DecisionEntry {
+    pub fn new(job: &Job, instance: Option<&str>, decision: Decision) -> DecisionEntry {
+        DecisionEntry {
+            timestamp: unix_timestamp(),
+            job_id: job.id,
+            job_name: job.name.clone(),
+            instance: instance.map(str::to_owned),
+            decision,
+            message: None,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> DecisionEntry {
+        self.message = Some(message);
+        self
+    }
+}
+
+/// Appends the given entries to the JSONL decision log
+pub fn append(path: &Path, entries: &[DecisionEntry]) -> anyhow::Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .context(format!("Failed writing decision log {:?}", path))
+}
@@ -0,0 +1,148 @@
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use log::debug;
+
+/// Command keeping the job container alive, so the job steps can be executed in it
+const KEEPALIVE_SCRIPT: &str = "while sleep 3600; do :; done";
+/// Directory inside the container the step scripts are copied to
+const SCRIPT_DIR: &str = "/tmp";
+
+/// Strips the docker:// prefix accepted by apptainer, so both backends can share image names
+pub fn image_name(image: &str) -> &str {
+    image.strip_prefix("docker://").unwrap_or(image)
+}
+
+/// Name of the container a job runs in
+pub fn container_name(job_id: &str) -> String {
+    format!("{}-job-{}", env!("CARGO_PKG_NAME"), job_id)
+}
+
+/// Path of the script for the given step inside the container
+pub fn script_path(step_name: &str) -> String {
+    format!("{}/{}-{}", SCRIPT_DIR, env!("CARGO_PKG_NAME"), step_name)
+}
+
+fn run(command: &mut Command) -> anyhow::Result<String> {
+    debug!("Executing docker command {:?}", command);
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .context(format!("Failed spawning {:?}", command))?;
+    if !output.status.success() {
+        Err(anyhow!(
+            "{:?} failed with exit code {}\nstderr:\n{}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ))?;
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+pub fn image_exists(executable: &Path, image: &str) -> bool {
+    run(Command::new(executable)
+        .arg("image")
+        .arg("inspect")
+        .arg(image_name(image)))
+    .is_ok()
+}
+
+/// Returns the repository digest of a pulled image
+pub fn image_digest(executable: &Path, image: &str) -> Option<String> {
+    let digest = run(Command::new(executable)
+        .arg("image")
+        .arg("inspect")
+        .arg("--format")
+        .arg("{{index .RepoDigests 0}}")
+        .arg(image_name(image)))
+    .ok()?;
+    digest.split_once('@').map(|(_, digest)| digest.to_owned())
+}
+
+/// Builds the command pulling the image, forwarding the progress output to the job log
+pub fn pull_command(executable: &Path, image: &str) -> async_process::Command {
+    let mut command = async_process::Command::new(executable);
+    command
+        .arg("pull")
+        .arg(image_name(image))
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null());
+    command
+}
+
+/// Creates and starts an idle container for the job, in which the job steps are executed
+pub fn create_container(
+    executable: &Path,
+    container: &str,
+    image: &str,
+    args: &[String],
+) -> anyhow::Result<()> {
+    run(Command::new(executable)
+        .arg("create")
+        .arg("--name")
+        .arg(container)
+        .args(args)
+        .arg("--entrypoint")
+        .arg("sh")
+        .arg(image_name(image))
+        .arg("-c")
+        .arg(KEEPALIVE_SCRIPT))
+    .context("Failed creating job container")?;
+    run(Command::new(executable).arg("start").arg(container))
+        .context("Failed starting job container")?;
+    Ok(())
+}
+
+/// Copies a step script into the container
+pub fn copy_script(
+    executable: &Path,
+    container: &str,
+    script: &Path,
+    step_name: &str,
+) -> anyhow::Result<()> {
+    run(Command::new(executable).arg("cp").arg(script).arg(format!(
+        "{}:{}",
+        container,
+        script_path(step_name)
+    )))
+    .map(|_| ())
+    .context("Failed copying step script into job container")
+}
+
+/// Forcibly removes the container of a job, if it exists
+pub fn remove_container(executable: &Path, container: &str) -> anyhow::Result<()> {
+    let exists = run(Command::new(executable)
+        .arg("container")
+        .arg("inspect")
+        .arg(container))
+    .is_ok();
+    if exists {
+        run(Command::new(executable)
+            .arg("rm")
+            .arg("--force")
+            .arg(container))
+        .context("Failed removing job container")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(image_name("docker://ubuntu:24.04"), "ubuntu:24.04");
+        assert_eq!(image_name("ghcr.io/org/image"), "ghcr.io/org/image");
+        assert_eq!(container_name("42"), "gitlab-meta-runner-job-42");
+        assert_eq!(
+            script_path("build_script"),
+            "/tmp/gitlab-meta-runner-build_script"
+        );
+    }
+}
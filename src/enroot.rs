@@ -0,0 +1,116 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use log::debug;
+
+use crate::docker;
+
+/// Converts an image name to the URI accepted by `enroot import`,
+/// which separates the registry from the image path by `#` instead of `/`
+pub fn import_uri(image: &str) -> String {
+    let image = docker::image_name(image);
+    match image.split_once('/') {
+        // like docker, only treat the first component as a registry if it looks like a hostname
+        Some((registry, path)) if registry.contains(['.', ':']) || registry == "localhost" => {
+            format!("docker://{}#{}", registry, path)
+        }
+        _ => format!("docker://{}", image),
+    }
+}
+
+/// Name of the squashfs file an image is imported to
+pub fn image_filename(image: &str) -> PathBuf {
+    let name: String = docker::image_name(image)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '+'
+            }
+        })
+        .collect();
+    format!("{}.sqsh", name).into()
+}
+
+/// Name of the container a job runs in
+pub fn container_name(job_id: &str) -> String {
+    docker::container_name(job_id)
+}
+
+fn run(command: &mut Command) -> anyhow::Result<()> {
+    debug!("Executing enroot command {:?}", command);
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .context(format!("Failed spawning {:?}", command))?;
+    if !output.status.success() {
+        Err(anyhow!(
+            "{:?} failed with exit code {}\nstderr:\n{}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ))?;
+    }
+    Ok(())
+}
+
+/// Builds the command importing the image into a squashfs file, forwarding the progress output to the job log
+pub fn import_command(executable: &Path, image: &str, output: &Path) -> async_process::Command {
+    let mut command = async_process::Command::new(executable);
+    command
+        .arg("import")
+        .arg("--output")
+        .arg(output)
+        .arg(import_uri(image))
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null());
+    command
+}
+
+/// Creates the container root filesystem for the job from an imported image
+pub fn create_container(
+    executable: &Path,
+    container: &str,
+    image_file: &Path,
+) -> anyhow::Result<()> {
+    run(Command::new(executable)
+        .arg("create")
+        .arg("--force")
+        .arg("--name")
+        .arg(container)
+        .arg(image_file))
+    .context("Failed creating job container")
+}
+
+/// Removes the container root filesystem of a job
+pub fn remove_container(executable: &Path, container: &str) -> anyhow::Result<()> {
+    run(Command::new(executable)
+        .arg("remove")
+        .arg("--force")
+        .arg(container))
+    .context("Failed removing job container")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_names() {
+        assert_eq!(import_uri("ubuntu:24.04"), "docker://ubuntu:24.04");
+        assert_eq!(
+            import_uri("docker://nvcr.io/nvidia/pytorch:24.05-py3"),
+            "docker://nvcr.io#nvidia/pytorch:24.05-py3"
+        );
+        assert_eq!(import_uri("org/image"), "docker://org/image");
+        assert_eq!(
+            image_filename("nvcr.io/nvidia/pytorch:24.05-py3"),
+            PathBuf::from("nvcr.io+nvidia+pytorch+24.05-py3.sqsh")
+        );
+    }
+}
@@ -1,26 +1,78 @@
 use anyhow::{anyhow, Context};
-use log::{debug, info};
-use std::{ffi::OsStr, fs, path::PathBuf, process::Stdio};
+use chrono::{DateTime, Utc};
+use futures::AsyncReadExt;
+use log::{debug, error, info, warn};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr, OsString},
+    fs,
+    io::Write,
+    mem::MaybeUninit,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{MetadataExt, OpenOptionsExt},
+        process::CommandExt,
+    },
+    path::{Component, Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+use tokio::{
+    signal::unix::{signal as unix_signal, SignalKind},
+    time,
+};
 
 use serde_json::{json, to_string_pretty};
 
 use crate::{
-    cli,
-    config::{read_config, GitLabCustomExecutorConfig, GitLabExecutorPullPolicy},
+    caches, cli,
+    config::{
+        get_state_db_path, read_config, EmbeddedExecutorConfig, GitLabCustomExecutorConfig,
+        GitLabExecutorBackend, GitLabExecutorPullPolicy, GitLabHomeMount, GitLabMinFreeSpaceConfig,
+        GitLabSpackConfig,
+    },
+    credentials, docker, enroot, executor_metrics, image_build, job_overrides, limits,
+    mounts::MountSpec,
+    progress::{self, ProgressFilter},
+    registry::{self, ImageReference},
+    retention, run_as,
+    services::{self, Service},
+    spack::{self, shell_quote, SpackEnvironment},
+    state::{unix_timestamp, StateDb},
     template::expand_executor_config_template,
+    verify,
 };
 
 #[derive(Debug)]
 struct JobEnv {
     job_id: String,
     builds_dir: PathBuf,
-    image: String,
+    project_dir: PathBuf,
+    /// Image requested by the job, falling back to the configured default_image
+    image: Option<String>,
+    /// Nix flake or Guix manifest reference for the nix and guix backends
+    environment: Option<String>,
+    /// Definition file or Dockerfile to build the image from, relative to the project directory
+    image_definition: Option<String>,
+    /// Timeout of the job in seconds
+    timeout: Option<u64>,
+    /// Time the job started at, used to enforce the timeout across all job steps
+    started_at: Option<DateTime<Utc>>,
 }
 
 struct JobContext {
     runner_name: String,
     env: JobEnv,
+    /// Service containers requested by the job
+    services: Vec<Service>,
+    /// Entrypoint of the job image overridden via `image:entrypoint` in the job definition
+    entrypoint: Vec<String>,
+    /// Additional flags for the container runtime requested by the job
+    extra_flags: Vec<String>,
     config: GitLabCustomExecutorConfig,
+    state_db_path: PathBuf,
 }
 
 fn get_env_var(name: &str) -> anyhow::Result<String> {
@@ -32,8 +84,99 @@ fn get_env() -> anyhow::Result<JobEnv> {
     Ok(JobEnv {
         job_id: get_env_var("CUSTOM_ENV_CI_JOB_ID")?,
         builds_dir: get_env_var("CUSTOM_ENV_CI_BUILDS_DIR")?.into(),
-        image: get_env_var("CUSTOM_ENV_CI_JOB_IMAGE")?,
+        project_dir: get_env_var("CUSTOM_ENV_CI_PROJECT_DIR")?.into(),
+        // jobs without an image use the configured default_image or, if allowed, run on the host
+        image: std::env::var("CUSTOM_ENV_CI_JOB_IMAGE")
+            .ok()
+            .filter(|image| !image.is_empty()),
+        environment: std::env::var("CUSTOM_ENV_META_RUNNER_ENVIRONMENT").ok(),
+        image_definition: std::env::var("CUSTOM_ENV_META_RUNNER_IMAGE_DEFINITION").ok(),
+        timeout: std::env::var("CUSTOM_ENV_CI_JOB_TIMEOUT")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok()),
+        started_at: std::env::var("CUSTOM_ENV_CI_JOB_STARTED_AT")
+            .ok()
+            .and_then(|start| DateTime::parse_from_rfc3339(&start).ok())
+            .map(|start| start.to_utc()),
+    })
+}
+
+/// Environment of the prewarm step, which doesn't belong to a job. The process ID distinguishes
+/// the temporary image files of concurrent pre-warming processes.
+fn get_prewarm_env() -> JobEnv {
+    JobEnv {
+        job_id: format!("prewarm-{}", std::process::id()),
+        builds_dir: PathBuf::new(),
+        project_dir: PathBuf::new(),
+        image: None,
+        environment: None,
+        image_definition: None,
+        timeout: None,
+        started_at: None,
+    }
+}
+
+/// Directory containing the builds directories of all jobs, which is the first existing node-local scratch
+/// directory from executor.builds_dir_scratch, or builds_dir otherwise
+fn get_builds_root(
+    builds_dir: &Path,
+    scratch_variables: &[String],
+    variable: impl Fn(&str) -> Option<String>,
+) -> PathBuf {
+    scratch_variables
+        .iter()
+        .filter_map(|name| variable(name))
+        .map(PathBuf::from)
+        .find(|dir| dir.is_absolute() && dir.is_dir())
+        .map_or(builds_dir.to_owned(), |dir| {
+            dir.join(format!("{}-builds", env!("CARGO_PKG_NAME")))
+        })
+}
+
+/// Layout of the builds directories if builds_dir_layout is not set, one directory per job
+const DEFAULT_BUILDS_DIR_LAYOUT: &str = "$CI_JOB_ID";
+
+/// Builds directory of a job below the builds root, expanding the CI variables in builds_dir_layout
+fn get_job_builds_dir(
+    builds_root: &Path,
+    layout: Option<&str>,
+    variable: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<PathBuf> {
+    let layout = layout.unwrap_or(DEFAULT_BUILDS_DIR_LAYOUT);
+    // CI variables are passed to the executor with a CUSTOM_ENV_ prefix
+    let relative = shellexpand::env_with_context(layout, |name| {
+        variable(&format!("CUSTOM_ENV_{}", name))
+            .map(Some)
+            .ok_or("Undefined variable")
     })
+    .map_err(|e| anyhow!(e.to_string()))
+    .context(format!("Failed expanding builds_dir_layout {:?}", layout))?;
+    let relative = PathBuf::from(relative.as_ref());
+    if relative.as_os_str().is_empty()
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        Err(anyhow!(
+            "builds_dir_layout {:?} expands to {:?}, which is not a path inside builds_dir",
+            layout,
+            relative
+        ))?;
+    }
+    Ok(builds_root.join(relative))
+}
+
+/// Returns whether every job gets its own builds directory, which can be deleted after the job
+pub fn is_per_job_layout(layout: Option<&str>) -> bool {
+    layout.is_none_or(|layout| layout.contains("CI_JOB_ID"))
+}
+
+/// Number of directory levels between the builds root and the builds directories of the jobs,
+/// if their last path component is the job ID, which the stale builds cleanup relies on
+pub fn get_job_dir_depth(layout: Option<&str>) -> Option<usize> {
+    let layout = Path::new(layout.unwrap_or(DEFAULT_BUILDS_DIR_LAYOUT));
+    let name = layout.file_name()?.to_str()?;
+    matches!(name, "$CI_JOB_ID" | "${CI_JOB_ID}").then(|| layout.components().count())
 }
 
 fn config_step(context: &JobContext) -> anyhow::Result<()> {
@@ -41,18 +184,34 @@ fn config_step(context: &JobContext) -> anyhow::Result<()> {
         "Executing config step for job {} with runner {}",
         context.env.job_id, context.runner_name
     );
-    let env = &context.env;
-    // append job ID to builds_dir to make unique paths
+    let builds_root = get_builds_root(
+        &context.config.builds_dir,
+        &context.config.builds_dir_scratch,
+        |name| std::env::var(name).ok(),
+    );
+    let builds_dir = get_job_builds_dir(
+        &builds_root,
+        context.config.builds_dir_layout.as_deref(),
+        |name| std::env::var(name).ok(),
+    )?;
     let config_obj = json!({
       "driver": {
         "name": match &context.config.description {
             None => format!("{} custom executor", env!("CARGO_PKG_NAME")),
             Some(description) => format!("{} custom executor ({})", env!("CARGO_PKG_NAME"), description)
         },
-        "version": "v1.0.0"
+        "version": concat!("v", env!("CARGO_PKG_VERSION"))
       },
-      "builds_dir_is_shared": false,
-      "builds_dir": context.config.builds_dir.join(&env.job_id)
+      "hostname": get_hostname(),
+      "builds_dir_is_shared": context.config.builds_dir_is_shared,
+      "builds_dir": builds_dir,
+      "cache_dir": context.config.cache_dir,
+      // available to the executor in all following steps
+      "job_env": {
+        "META_RUNNER_VERSION": env!("CARGO_PKG_VERSION"),
+        "META_RUNNER_INSTANCE": context.runner_name,
+        "META_RUNNER_BACKEND": context.config.backend,
+      }
     });
     println!("{}", to_string_pretty(&config_obj).unwrap());
     debug!(
@@ -62,8 +221,71 @@ fn config_step(context: &JobContext) -> anyhow::Result<()> {
     Ok(())
 }
 
-// This is a reimplementation of apptainer's url.GetName function
-fn build_image_filename(image_name: &str) -> PathBuf {
+/// Image file or archive on the local (shared) filesystem, which is built into a SIF file instead of pulled
+struct LocalImage {
+    path: PathBuf,
+    /// Source the SIF file is built from via `apptainer build`
+    source: String,
+}
+
+/// Recognizes local images referenced via `file://<path>` (SIF files or docker archives)
+/// or `docker-archive:<path>`
+fn get_local_image(image_name: &str) -> Option<LocalImage> {
+    if let Some(path) = image_name.strip_prefix("file://") {
+        let source = if path.ends_with(".sif") {
+            path.to_owned()
+        } else {
+            format!("docker-archive:{}", path)
+        };
+        Some(LocalImage {
+            path: path.into(),
+            source,
+        })
+    } else {
+        image_name
+            .strip_prefix("docker-archive:")
+            .map(|path| LocalImage {
+                path: path.into(),
+                source: image_name.to_owned(),
+            })
+    }
+}
+
+/// Architecture of the images to pull, in the naming of apptainer (which follows GOARCH)
+fn get_image_arch(config: &GitLabCustomExecutorConfig) -> String {
+    config.arch.clone().unwrap_or_else(|| {
+        match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" => "ppc64le",
+            arch => arch,
+        }
+        .to_owned()
+    })
+}
+
+/// Path of the image file in image_dir
+fn get_image_path(config: &GitLabCustomExecutorConfig, image_name: &str) -> PathBuf {
+    config
+        .image_dir
+        .join(build_image_filename(image_name, &get_image_arch(config)))
+}
+
+// This is a reimplementation of apptainer's url.GetName function,
+// extended by the architecture to keep images of different architectures apart
+fn build_image_filename(image_name: &str, arch: &str) -> PathBuf {
+    if let Some(local) = get_local_image(image_name) {
+        // archives with the same name may exist in different directories
+        let name = local.path.file_name().map_or("image".into(), |name| {
+            let name = name.to_string_lossy();
+            let name = name.strip_suffix(".gz").unwrap_or(&name);
+            let name = name.strip_suffix(".tar").unwrap_or(name);
+            name.strip_suffix(".sif").unwrap_or(name).to_owned()
+        });
+        let hash = format!("{:x}", Sha256::digest(local.path.as_os_str().as_bytes()));
+        return format!("{}_{}.sif", name, &hash[..12]).into();
+    }
     let url_parts = image_name.split_once(":");
     let (protocol, path) = match url_parts {
         None => ("", image_name),
@@ -79,12 +301,102 @@ fn build_image_filename(image_name: &str) -> PathBuf {
     let tag = parts
         .last()
         .map_or("latest", |s| s.split(",").next().unwrap());
-    format!("{}_{}.sif", name, tag).into()
+    format!("{}_{}_{}.sif", name, tag, arch).into()
+}
+
+/// Subdirectory of image_dir containing the image files, named after their SHA-256 registry digest
+const IMAGE_STORE_DIR: &str = "sha256";
+
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Filename of an image in the store, relative to image_dir. The registry digest of a multi-architecture
+/// image is the same for all architectures, so the architecture is part of the filename.
+fn get_stored_image_filename(digest: &str, arch: &str) -> PathBuf {
+    let hex = digest.strip_prefix("sha256:").unwrap_or(digest);
+    PathBuf::from(IMAGE_STORE_DIR).join(format!("{}_{}.sif", hex, arch))
+}
+
+/// Moves a freshly pulled image file to its tag-derived filename. If the registry digest of the image
+/// is known, the file is moved into the content-addressed store instead and the filename points at it,
+/// so images pulled under different tags share a single file. Returns the path of the stored image.
+fn store_image(
+    image_dir: &Path,
+    tmp_filename: &Path,
+    filename: &Path,
+    stored_filename: Option<&Path>,
+    job_id: &str,
+) -> anyhow::Result<PathBuf> {
+    let tmp_filepath = image_dir.join(tmp_filename);
+    let Some(stored_filename) = stored_filename else {
+        let filepath = image_dir.join(filename);
+        debug!("Moving {:?} to {:?}", tmp_filepath, filepath);
+        fs::rename(&tmp_filepath, &filepath)
+            .context(format!("Renaming {:?} to {:?}", tmp_filepath, filepath))?;
+        return Ok(filepath);
+    };
+    let store_dir = image_dir.join(IMAGE_STORE_DIR);
+    fs::create_dir_all(&store_dir).context("Failed creating image store directory")?;
+    let stored_filepath = image_dir.join(stored_filename);
+    if fs::exists(&stored_filepath).context("Failed checking for existence of stored image")? {
+        debug!(
+            "Image {:?} already stored, discarding duplicate",
+            stored_filepath
+        );
+        fs::remove_file(&tmp_filepath).context(format!(
+            "Failed removing duplicate image {:?}",
+            tmp_filepath
+        ))?;
+    } else {
+        debug!("Moving {:?} to {:?}", tmp_filepath, stored_filepath);
+        fs::rename(&tmp_filepath, &stored_filepath).context(format!(
+            "Renaming {:?} to {:?}",
+            tmp_filepath, stored_filepath
+        ))?;
+    }
+    link_image(image_dir, filename, stored_filename, job_id)?;
+    Ok(stored_filepath)
+}
+
+/// Atomically points the tag-derived filename of an image at the image in the store
+fn link_image(
+    image_dir: &Path,
+    filename: &Path,
+    stored_filename: &Path,
+    job_id: &str,
+) -> anyhow::Result<()> {
+    // the symlink is created under a temporary name and renamed over the old one,
+    // so concurrent jobs always see either the old or the new image
+    let mut tmp_link_filename = filename.to_owned();
+    tmp_link_filename.set_extension(format!("{}.link", job_id));
+    let tmp_link_filepath = image_dir.join(&tmp_link_filename);
+    let filepath = image_dir.join(filename);
+    std::os::unix::fs::symlink(stored_filename, &tmp_link_filepath)
+        .context(format!("Failed creating symlink {:?}", tmp_link_filepath))?;
+    fs::rename(&tmp_link_filepath, &filepath).context(format!(
+        "Renaming {:?} to {:?}",
+        tmp_link_filepath, filepath
+    ))
+}
+
+fn record_image_metadata(
+    context: &JobContext,
+    filename: &Path,
+    digest: &str,
+) -> anyhow::Result<()> {
+    StateDb::open(&context.state_db_path)?.record_image(&filename.to_string_lossy(), digest)
 }
 
 // This is derived from apptainer's pull.getImageNameFromURI function,
 // with docker being the default if the image name is not an URI
 fn build_image_pull_url(image_name: &str) -> String {
+    if let Some(local) = get_local_image(image_name) {
+        return local.source;
+    }
     let parts = image_name.split_once(":");
     // if the image name contains a valid URL (based on its protocol name), we use it directly
     match parts {
@@ -106,11 +418,145 @@ async fn prepare_step(context: &JobContext) -> anyhow::Result<()> {
     );
     let env = &context.env;
     let config = &context.config;
-    let image = &env.image;
-    let pull_url = build_image_pull_url(image);
-    let filename = build_image_filename(image);
-    let filepath = config.image_dir.join(&filename);
+    check_shell_support(config.shell.as_deref(), config.backend)?;
+
+    section_start("meta_runner_directories", "Preparing directories", true);
+    let result = prepare_directories(context);
+    section_end("meta_runner_directories");
+    result?;
+
+    if env.image_definition.is_some()
+        && matches!(
+            config.backend,
+            GitLabExecutorBackend::Docker | GitLabExecutorBackend::Enroot
+        )
+    {
+        Err(anyhow!(
+            "The job requests an image build, which is not supported by the {:?} backend",
+            config.backend
+        ))?;
+    }
+    if config.backend == GitLabExecutorBackend::Enroot
+        && config.network.as_deref().is_some_and(|n| n != "host")
+    {
+        Err(anyhow!(
+            "Network isolation is not supported by the enroot backend"
+        ))?;
+    }
+    if config.backend != GitLabExecutorBackend::Apptainer
+        && env.image.as_deref().and_then(get_local_image).is_some()
+    {
+        Err(anyhow!(
+            "Local image files are not supported by the {:?} backend",
+            config.backend
+        ))?;
+    }
+    if config.verify_signatures && config.backend != GitLabExecutorBackend::Apptainer {
+        Err(anyhow!(
+            "Signature verification is not supported by the {:?} backend",
+            config.backend
+        ))?;
+    }
+    if env.image_definition.is_some() && config.verify_signatures {
+        Err(anyhow!(
+            "The job requests an image build, but only images with verified signatures may be used"
+        ))?;
+    }
+    if env.image_definition.is_some() && config.image_build.is_none() {
+        Err(anyhow!(
+            "The job requests an image build, but executor.image_build is not configured"
+        ))?;
+    }
+    if is_container_backend(config.backend)
+        && get_container_image(context).is_none()
+        && !config.allow_host_fallback
+    {
+        Err(anyhow!(
+            "The job doesn't specify an image and executor.default_image is not configured, \
+             running it on the host requires executor.allow_host_fallback"
+        ))?;
+    }
+    section_start("meta_runner_image", "Preparing image", true);
+    let result = prepare_image(context).await;
+    section_end("meta_runner_image");
+    result?;
+    if config.backend == GitLabExecutorBackend::Apptainer && get_container_image(context).is_some()
+    {
+        create_overlay(context)?;
+    }
+    if !context.services.is_empty() {
+        section_start("meta_runner_services", "Starting services", true);
+        let result = start_services(context).await;
+        section_end("meta_runner_services");
+        result?;
+    }
+    if let (true, Some(ContainerImage::Pulled(image))) =
+        (uses_job_instance(context), get_container_image(context))
+    {
+        start_job_instance(context, image)?;
+    }
+    print_job_diagnostics(context);
+    Ok(())
+}
+
+/// Pulls the images to pre-warm, continuing with the remaining images if a pull fails
+async fn prewarm_step(context: &JobContext) -> anyhow::Result<()> {
+    let config = &context.config;
+    if !is_container_backend(config.backend) {
+        warn!(
+            "The {:?} backend doesn't use images, nothing to pre-warm",
+            config.backend
+        );
+        return Ok(());
+    }
+    fs::create_dir_all(&config.image_dir).context("Failed creating image_dir")?;
+    let mut failed = 0;
+    for image in &config.prewarm_images {
+        info!("Pre-warming image {}", image);
+        let result = match config.backend {
+            GitLabExecutorBackend::Docker => pull_docker_image(context, image).await,
+            GitLabExecutorBackend::Enroot => import_enroot_image(context, image).await.map(|_| ()),
+            _ => pull_image(context, image).await,
+        };
+        if let Err(e) = result {
+            error!("Failed pre-warming image {}: {:?}", image, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        Err(anyhow!("Pre-warming {} images failed", failed))?;
+    }
+    Ok(())
+}
+
+/// Pulls or imports the job image and creates the job container for the docker and enroot backends
+async fn prepare_image(context: &JobContext) -> anyhow::Result<()> {
+    let env = &context.env;
+    let config = &context.config;
+    match get_container_image(context) {
+        Some(ContainerImage::Pulled(image)) if config.backend == GitLabExecutorBackend::Docker => {
+            pull_docker_image(context, image).await?;
+            create_docker_container(context, image)?;
+        }
+        Some(ContainerImage::Pulled(image)) if config.backend == GitLabExecutorBackend::Enroot => {
+            let image_file = import_enroot_image(context, image).await?;
+            let container = enroot::container_name(&env.job_id);
+            enroot::create_container(&config.enroot_executable, &container, &image_file)?;
+        }
+        Some(ContainerImage::Pulled(image)) => pull_image(context, image).await?,
+        Some(ContainerImage::Built(definition)) => info!(
+            "Image will be built from {} before the job script is executed",
+            definition
+        ),
+        None => (),
+    }
+    Ok(())
+}
 
+/// Creates the directories used by the job, removes stale builds directories and checks the free space
+fn prepare_directories(context: &JobContext) -> anyhow::Result<()> {
+    let env = &context.env;
+    let config = &context.config;
     // create directories if missing
     debug!(
         "Creating image directory if necessary {:?}",
@@ -119,8 +565,17 @@ async fn prepare_step(context: &JobContext) -> anyhow::Result<()> {
     std::fs::create_dir_all(&config.image_dir).context("Failed creating image_dir")?;
     debug!("Creating builds_dir {:?}", env.builds_dir);
     std::fs::create_dir_all(&env.builds_dir).context("Failed creating builds_dir")?;
+    // the builds directory is only handed over to the run_as user below
+    retention::record_activity(&env.builds_dir, None)?;
+    if !is_per_job_layout(config.builds_dir_layout.as_deref()) {
+        retention::clear_failed(&env.builds_dir)?;
+    }
     debug!("Creating cache_dir if necessary {:?}", config.cache_dir);
     std::fs::create_dir_all(&config.cache_dir).context("Failed creating cache_dir")?;
+    if let GitLabHomeMount::Path(path) = &config.mount_home {
+        debug!("Creating home directory if necessary {:?}", path);
+        std::fs::create_dir_all(path).context("Failed creating mount_home")?;
+    }
     if let Some(path) = &config.image_cache_dir {
         debug!("Creating image cache directory if necessary {:?}", path);
         std::fs::create_dir_all(&path).context(format!(
@@ -135,33 +590,535 @@ async fn prepare_step(context: &JobContext) -> anyhow::Result<()> {
             config.image_tmp_dir
         ))?;
     }
+    if let Some(hours) = config.stale_builds_max_age {
+        let max_age = Duration::from_secs(hours as u64 * 3600);
+        // the builds directory may be located on node-local scratch instead of builds_dir
+        let builds_root = get_builds_root(&config.builds_dir, &config.builds_dir_scratch, |name| {
+            std::env::var(name).ok()
+        });
+        let job_dir_depth = get_job_dir_depth(config.builds_dir_layout.as_deref());
+        retention::remove_stale_builds(&builds_root, job_dir_depth, &env.job_id, max_age);
+        if let Some(path) = &config.image_tmp_dir {
+            retention::remove_stale_builds(path, Some(1), &env.job_id, max_age);
+        }
+    }
+    if let Some(min_free_space) = &config.min_free_space {
+        check_free_space(context, min_free_space)?;
+    }
+    // fail before pulling if the mounts are invalid
+    get_mounts(config)?;
+    if let Some(user) = &config.run_as {
+        if config.backend == GitLabExecutorBackend::Docker {
+            Err(anyhow!("run_as is not supported by the docker backend"))?;
+        }
+        if config.reuse_instance {
+            Err(anyhow!("run_as can't be combined with reuse_instance"))?;
+        }
+        let ids = run_as::lookup_user(user)?;
+        debug!("Handing builds_dir and cache_dir over to user {}", user);
+        for dir in [&env.builds_dir, &config.cache_dir] {
+            run_as::chown_recursive(dir, ids)
+                .context(format!("Failed handing {:?} over to user {}", dir, user))?;
+        }
+    }
+    Ok(())
+}
 
-    let image_exists =
-        std::fs::exists(&filepath).context("Failed checking for existence of image file")?;
-    let pull_needed = match config.pull_policy {
-        GitLabExecutorPullPolicy::Always => true,
+/// Returns the free space (in bytes) on the filesystem containing the path
+fn get_free_space(path: &Path) -> anyhow::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string, and stat is only read if statvfs succeeded
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        Err(std::io::Error::last_os_error())
+            .context(format!("Failed querying free space of {:?}", path))?;
+    }
+    let stat = unsafe { stat.assume_init() };
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Fails early if a filesystem used by the job is too full, instead of failing in the middle of a pull or build
+fn check_free_space(
+    context: &JobContext,
+    min_free_space: &GitLabMinFreeSpaceConfig,
+) -> anyhow::Result<()> {
+    let config = &context.config;
+    let tmp_dir = config
+        .image_tmp_dir
+        .clone()
+        .unwrap_or_else(std::env::temp_dir);
+    let checks = [
+        ("image_dir", &config.image_dir, min_free_space.image_dir),
+        ("image_tmp_dir", &tmp_dir, min_free_space.image_tmp_dir),
+        (
+            "builds_dir",
+            &context.env.builds_dir,
+            min_free_space.builds_dir,
+        ),
+    ];
+    for (name, path, min_mb) in checks {
+        let Some(min_mb) = min_mb else {
+            continue;
+        };
+        let free_mb = get_free_space(path)? / (1024 * 1024);
+        debug!("Free space of {} {:?}: {} MiB", name, path, free_mb);
+        if free_mb < min_mb {
+            Err(anyhow!(
+                "Not enough free space for {} {:?}: {} MiB available, at least {} MiB required",
+                name,
+                path,
+                free_mb,
+                min_mb
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the writable overlay image of the job container, if configured
+fn get_overlay_path(context: &JobContext) -> Option<PathBuf> {
+    let config = &context.config;
+    match (&config.overlay, config.overlay_size_mb) {
+        (Some(path), _) => Some(path.clone()),
+        // stored in the builds root, so it is not retained with the builds directory of failed jobs
+        // and found by the stale builds cleanup regardless of builds_dir_layout
+        (None, Some(_)) => Some(
+            get_builds_root(&config.builds_dir, &config.builds_dir_scratch, |name| {
+                std::env::var(name).ok()
+            })
+            .join(format!("{}.overlay.img", context.env.job_id)),
+        ),
+        (None, None) => None,
+    }
+}
+
+fn create_overlay(context: &JobContext) -> anyhow::Result<()> {
+    let Some(path) = get_overlay_path(context) else {
+        return Ok(());
+    };
+    if fs::exists(&path).context("Failed checking for existence of overlay")? {
+        info!("Using existing overlay {:?}", path);
+        return Ok(());
+    }
+    let size = context.config.overlay_size_mb.ok_or(anyhow!(
+        "Creating overlay {:?} requires executor.overlay_size_mb",
+        path
+    ))?;
+    let mut command = std::process::Command::new(&context.config.apptainer_executable);
+    command
+        .arg("overlay")
+        .arg("create")
+        .arg("--size")
+        .arg(size.to_string())
+        .arg(&path)
+        .stdin(Stdio::null());
+    debug!("Creating overlay with command {:?}", command);
+    let status = command
+        .status()
+        .context("Failed spawning overlay creation process")?;
+    if !status.success() {
+        Err(anyhow!("Failed creating overlay {:?}: {:?}", path, status))?;
+    }
+    Ok(())
+}
+
+/// Pulls the images of the services requested by the job and starts them as apptainer instances
+async fn start_services(context: &JobContext) -> anyhow::Result<()> {
+    if context.services.is_empty() {
+        return Ok(());
+    }
+    let config = &context.config;
+    if config.backend != GitLabExecutorBackend::Apptainer {
+        Err(anyhow!(
+            "The job requests services, which are only supported by the apptainer backend"
+        ))?;
+    }
+    // the service instances would run with the executor's credentials
+    if config.run_as.is_some() {
+        Err(anyhow!(
+            "The job requests services, which are not supported with executor.run_as"
+        ))?;
+    }
+    for (index, service) in context.services.iter().enumerate() {
+        pull_image(context, &service.name).await?;
+        info!("Starting service {}", service.name);
+        services::start(
+            &config.apptainer_executable,
+            &get_image_path(config, &service.name),
+            &services::instance_name(&context.env.job_id, index),
+            service,
+        )?;
+    }
+    services::write_hosts_file(&context.env.builds_dir, &context.services)
+}
+
+/// Image a job runs in
+enum ContainerImage<'a> {
+    /// Image pulled from a container registry
+    Pulled(&'a str),
+    /// Image built from a definition file or Dockerfile in the repository
+    Built(&'a str),
+}
+
+/// Returns the image to run the job in, or None if the job runs directly on the host
+fn get_container_image(context: &JobContext) -> Option<ContainerImage<'_>> {
+    let env = &context.env;
+    match context.config.backend {
+        GitLabExecutorBackend::Apptainer => match &env.image_definition {
+            Some(definition) => Some(ContainerImage::Built(definition)),
+            None => env.image.as_deref().map(ContainerImage::Pulled),
+        },
+        GitLabExecutorBackend::Docker | GitLabExecutorBackend::Enroot => {
+            env.image.as_deref().map(ContainerImage::Pulled)
+        }
+        GitLabExecutorBackend::Nix | GitLabExecutorBackend::Guix => None,
+    }
+}
+
+/// Determines whether an image needs to be pulled according to the configured pull policy
+fn is_pull_needed(
+    pull_policy: GitLabExecutorPullPolicy,
+    image_exists: bool,
+) -> anyhow::Result<bool> {
+    match pull_policy {
+        GitLabExecutorPullPolicy::Always => Ok(true),
         GitLabExecutorPullPolicy::Never => {
             if !image_exists {
-                Err(anyhow!(
-                    "Pull policy is 'never', but image file doesn't exist!"
-                ))?;
+                Err(anyhow!("Pull policy is 'never', but image doesn't exist!"))?;
             };
-            false
+            Ok(false)
+        }
+        GitLabExecutorPullPolicy::IfNotPresent | GitLabExecutorPullPolicy::IfDigestChanged => {
+            Ok(!image_exists)
+        }
+    }
+}
+
+/// File recording the registry digest an image file was pulled from
+fn get_digest_filepath(config: &GitLabCustomExecutorConfig, filename: &Path) -> PathBuf {
+    config
+        .image_cache_dir
+        .as_ref()
+        .unwrap_or(&config.image_dir)
+        .join(filename)
+        .with_extension("digest")
+}
+
+/// For the if-digest-changed pull policy, determines whether the tag of an image moved in its registry
+/// since the local image was pulled. Also returns the current digest, to be recorded after the pull.
+/// If the registry can't be queried, the local image is used.
+async fn check_digest(
+    config: &GitLabCustomExecutorConfig,
+    image: &str,
+    local_digest: Option<String>,
+) -> (bool, Option<String>) {
+    if config.pull_policy != GitLabExecutorPullPolicy::IfDigestChanged {
+        return (false, None);
+    }
+    let Some(reference) = ImageReference::parse(image) else {
+        return (false, None);
+    };
+    match registry::fetch_digest(&reference).await {
+        Ok(digest) => {
+            let changed = local_digest.as_ref() != Some(&digest);
+            if changed {
+                info!("Image {} was updated to {}", image, digest);
+            }
+            (changed, Some(digest))
+        }
+        Err(e) => {
+            warn!(
+                "Failed querying the registry digest of image {}, using the local image: {:?}",
+                image, e
+            );
+            (false, None)
+        }
+    }
+}
+
+/// Queries the registry digest of an image, which identifies it in the image store. Images that aren't
+/// pulled from a registry, or whose registry can't be queried, are stored without deduplication.
+async fn fetch_registry_digest(image: &str) -> Option<String> {
+    let reference = ImageReference::parse(image)?;
+    registry::fetch_digest(&reference)
+        .await
+        .inspect_err(|e| {
+            debug!(
+                "Failed querying the registry digest of image {}: {:?}",
+                image, e
+            )
+        })
+        .ok()
+}
+
+/// Records the registry digest of a pulled image file, see check_digest
+fn record_digest(config: &GitLabCustomExecutorConfig, filename: &Path, digest: Option<String>) {
+    let Some(digest) = digest else {
+        return;
+    };
+    let digest_filepath = get_digest_filepath(config, filename);
+    if let Err(e) = fs::write(&digest_filepath, digest) {
+        warn!("Failed writing image digest {:?}: {:?}", digest_filepath, e);
+    }
+}
+
+/// Acquires the advisory lock for pulling an image into the given path, so concurrent jobs
+/// using the same image wait for a single pull instead of pulling it separately.
+/// Also returns whether another job held the lock, in which case its freshly pulled image can be reused.
+fn lock_image(filepath: &Path) -> anyhow::Result<(fs::File, bool)> {
+    let lock_filepath = filepath.with_extension("lock");
+    let lock_file = fs::File::create(&lock_filepath)
+        .context(format!("Failed creating lock file {:?}", lock_filepath))?;
+    match lock_file.try_lock() {
+        Ok(()) => Ok((lock_file, false)),
+        Err(fs::TryLockError::WouldBlock) => {
+            info!("Waiting for a concurrent job to pull the image");
+            lock_file.lock().context("Failed locking image")?;
+            Ok((lock_file, true))
+        }
+        Err(fs::TryLockError::Error(e)) => Err(e).context("Failed locking image"),
+    }
+}
+
+/// Forwards the output of a pull process to the job log, throttling its progress bars.
+/// Returns the number of layers the pull reported.
+async fn forward_pull_output(
+    mut output: impl futures::AsyncRead + Unpin,
+    mut target: impl std::io::Write,
+) -> usize {
+    let mut filter = ProgressFilter::new(progress::PROGRESS_INTERVAL);
+    let mut buffer = [0; 4096];
+    loop {
+        let length = match output.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(length) => length,
+        };
+        for line in filter.process(&buffer[..length], Instant::now()) {
+            let _ = writeln!(target, "{}", line);
+        }
+    }
+    if let Some(line) = filter.finish() {
+        let _ = writeln!(target, "{}", line);
+    }
+    filter.layers
+}
+
+/// Runs a single pull attempt, returning the number of layers the pull reported
+async fn run_pull_attempt(
+    command: &mut async_process::Command,
+    pull_timeout: Option<u32>,
+) -> anyhow::Result<usize> {
+    debug!("Pulling image with command {:?}", command);
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut pull_process = command.spawn().context("Failed creating pull process")?;
+    let stdout = pull_process.stdout.take().unwrap();
+    let stderr = pull_process.stderr.take().unwrap();
+    let timeout = Duration::from_secs(pull_timeout.unwrap_or(u32::MAX) as u64);
+    // the pull process is killed when it is dropped after timing out
+    let (layers, status) = time::timeout(timeout, async {
+        let (stdout_layers, stderr_layers) = futures::join!(
+            forward_pull_output(stdout, std::io::stdout()),
+            forward_pull_output(stderr, std::io::stderr())
+        );
+        (stdout_layers + stderr_layers, pull_process.status().await)
+    })
+    .await
+    .map_err(|_| anyhow!("Pull timed out after {}s", timeout.as_secs()))?;
+    let status = status.context("Failed awaiting pull process finish")?;
+    if status.success() {
+        Ok(layers)
+    } else {
+        Err(anyhow!("Subprocess failed: {:?}", status))
+    }
+}
+
+/// Runs a pull command, retrying failed or timed out attempts up to pull_retries times.
+/// The delay between attempts starts at pull_retry_delay and doubles after every attempt.
+/// The partial file written by a failed attempt is removed before retrying.
+async fn run_pull_command(
+    context: &JobContext,
+    command: &mut async_process::Command,
+    partial_file: Option<&Path>,
+) -> anyhow::Result<()> {
+    let config = &context.config;
+    command.kill_on_drop(true);
+    let mut delay = config.pull_retry_delay;
+    let mut attempt = 0;
+    loop {
+        let start = Instant::now();
+        let result = run_pull_attempt(command, config.pull_timeout).await;
+        let e = match result {
+            Ok(layers) => {
+                let bytes = partial_file
+                    .and_then(|path| fs::metadata(path).ok())
+                    .map(|metadata| metadata.len());
+                let size = bytes.map_or(String::new(), |bytes| {
+                    format!(", {} MiB", bytes / (1024 * 1024))
+                });
+                info!(
+                    "Pulled image in {}s ({} layers{})",
+                    start.elapsed().as_secs(),
+                    layers,
+                    size
+                );
+                let labels = executor_metrics::labels(&[("runner", &context.runner_name)]);
+                let mut increments = vec![
+                    ("pulls_total", labels.clone(), 1.0),
+                    (
+                        "pull_seconds_total",
+                        labels.clone(),
+                        start.elapsed().as_secs_f64(),
+                    ),
+                ];
+                // the docker engine stores pulled images itself, so their size is unknown
+                if let Some(bytes) = bytes {
+                    increments.push(("pull_bytes_total", labels, bytes as f64));
+                }
+                record_metrics(context, &increments);
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        if let Some(path) = partial_file {
+            let _ = fs::remove_file(path);
+        }
+        attempt += 1;
+        if attempt > config.pull_retries {
+            return Err(e);
+        }
+        warn!(
+            "Pulling image failed, retrying in {}s ({}/{}): {:?}",
+            delay, attempt, config.pull_retries, e
+        );
+        time::sleep(Duration::from_secs(delay as u64)).await;
+        delay = delay.saturating_mul(2);
+    }
+}
+
+async fn pull_image(context: &JobContext, image: &str) -> anyhow::Result<()> {
+    let env = &context.env;
+    let config = &context.config;
+    let pull_url = build_image_pull_url(image);
+    let arch = get_image_arch(config);
+    let filename = build_image_filename(image, &arch);
+    let filepath = config.image_dir.join(&filename);
+    let local_image = get_local_image(image);
+
+    // the lock is held until the image was stored
+    let (_lock_file, pulled_concurrently) = lock_image(&filepath)?;
+    let image_exists =
+        std::fs::exists(&filepath).context("Failed checking for existence of image file")?;
+    let pull_needed = is_pull_needed(config.pull_policy, image_exists)?;
+    let (digest_changed, digest) = match &local_image {
+        // local images are rebuilt if the file was replaced since the last build
+        Some(local) => (
+            config.pull_policy != GitLabExecutorPullPolicy::Never
+                && image_exists
+                && is_local_image_updated(&local.path, &filepath)?,
+            None,
+        ),
+        None => {
+            let local_digest = fs::read_to_string(get_digest_filepath(config, &filename)).ok();
+            check_digest(config, image, local_digest).await
         }
-        GitLabExecutorPullPolicy::IfNotPresent => !image_exists,
     };
     info!("Using image {}", image);
-    if !pull_needed {
+    if !(pull_needed || digest_changed) || (pulled_concurrently && image_exists) {
         info!("No pull necessary");
-        return Ok(());
+        // images pulled before verification was enabled may not be trusted
+        return verify_image(config, &filepath);
+    }
+    // the registry digest identifies the image in the store regardless of its tag
+    let digest = match digest {
+        Some(digest) => Some(digest),
+        None if local_image.is_none() => fetch_registry_digest(image).await,
+        None => None,
+    };
+    let stored_filename = digest
+        .as_deref()
+        .map(|digest| get_stored_image_filename(digest, &arch));
+    if let Some(stored_filename) = &stored_filename {
+        let stored_filepath = config.image_dir.join(stored_filename);
+        if fs::exists(&stored_filepath).context("Failed checking for existence of stored image")? {
+            info!(
+                "Image {} is already stored as {:?}, no pull necessary",
+                image, stored_filepath
+            );
+            link_image(&config.image_dir, &filename, stored_filename, &env.job_id)?;
+            record_digest(config, &filename, digest);
+            return verify_image(config, &filepath);
+        }
     }
 
     // Pull if necessary
     // the temporary file is meant to prevent race conditions in image replacement
     let mut tmp_filename = filename.clone();
     tmp_filename.set_extension(format!("{}.tmp", env.job_id));
-    let tmp_filepath = config.image_dir.join(&tmp_filename);
     debug!("Preparing image pull for {} to {:?}", pull_url, filename);
+    let tmp_filepath = config.image_dir.join(&tmp_filename);
+    if let Some(dir) = get_job_tmp_dir(config, &env.job_id) {
+        fs::create_dir_all(&dir).context(format!("Failed creating {:?}", dir))?;
+    }
+    // execute pull
+    let mut result =
+        run_apptainer_pull(context, &pull_url, &tmp_filename, local_image.is_some()).await;
+    // local images can't be mirrored
+    let mirror_urls = config
+        .image_mirrors
+        .iter()
+        .filter(|_| local_image.is_none())
+        .filter_map(|mirror| registry::mirror_image(&pull_url, mirror));
+    for mirror_url in mirror_urls {
+        let Err(e) = &result else {
+            break;
+        };
+        warn!(
+            "Pulling {} failed, falling back to mirror {}: {:#}",
+            pull_url, mirror_url, e
+        );
+        result = run_apptainer_pull(context, &mirror_url, &tmp_filename, false).await;
+    }
+    remove_job_tmp_dir(config, &env.job_id);
+    result?;
+    // unverified images never enter the image store
+    if let Err(e) = verify_image(config, &tmp_filepath) {
+        if let Err(e) = fs::remove_file(&tmp_filepath) {
+            warn!(
+                "Failed removing unverified image {:?}: {:?}",
+                tmp_filepath, e
+            );
+        }
+        return Err(e);
+    }
+    // finally move temporary image to its final position
+    let stored_filepath = store_image(
+        &config.image_dir,
+        &tmp_filename,
+        &filename,
+        stored_filename.as_deref(),
+        &env.job_id,
+    )?;
+    info!("Stored image as {:?}", stored_filepath);
+    if let Some(digest) = &digest {
+        // the state database is only informational for the executor, so we don't fail the job
+        if let Err(e) = record_image_metadata(context, &filename, digest) {
+            warn!("Failed recording image metadata: {:?}", e);
+        }
+    }
+    record_digest(config, &filename, digest);
+    Ok(())
+}
+
+/// Pulls (or builds, for local images) an image via apptainer into the temporary file in image_dir
+async fn run_apptainer_pull(
+    context: &JobContext,
+    pull_url: &str,
+    tmp_filename: &Path,
+    is_local: bool,
+) -> anyhow::Result<()> {
+    let config = &context.config;
     // execute the pull process as a child with the same environment and output pipes
     let is_apptainer = config.apptainer_executable.ends_with("apptainer");
     let mut pull_command = async_process::Command::new(&config.apptainer_executable);
@@ -170,9 +1127,16 @@ async fn prepare_step(context: &JobContext) -> anyhow::Result<()> {
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .stdin(Stdio::null())
-        .arg("pull")
-        .arg(tmp_filename)
-        .arg(pull_url.as_str());
+        // apptainer pull only supports remote images
+        .arg(if is_local { "build" } else { "pull" });
+    let secret_dir = if is_local {
+        None
+    } else {
+        pull_command.arg("--arch").arg(get_image_arch(config));
+        apply_registry_credentials(context, &mut pull_command, pull_url)
+            .context("Failed applying registry credentials")?
+    };
+    pull_command.arg(tmp_filename).arg(pull_url);
     // set cache and image dir environment variables
     config.image_cache_dir.as_ref().map(|dir| {
         if is_apptainer {
@@ -182,91 +1146,1538 @@ async fn prepare_step(context: &JobContext) -> anyhow::Result<()> {
             pull_command.env("SINGULARITY_CACHEDIR", dir);
         }
     });
-    config.image_tmp_dir.as_ref().map(|dir| {
+    if let Some(dir) = get_job_tmp_dir(config, &context.env.job_id) {
         if is_apptainer {
             pull_command.env_remove("SINGULARITY_TMPDIR");
             pull_command.env("APPTAINER_TMPDIR", dir);
         } else {
             pull_command.env("SINGULARITY_TMPDIR", dir);
         }
-    });
-    debug!("Pulling image with command {:?}", pull_command);
-    // execute pull
-    let mut pull_process = pull_command
-        .spawn()
-        .context("Failed creating pull process")?;
-    let status = pull_process
-        .status()
-        .await
-        .context("Failed awaiting pull process finish")?;
-    if status.success() {
-        debug!("Renaming {:?} to {:?}", tmp_filepath, filepath);
-        // finally move temporary image to final position
-        fs::rename(&tmp_filepath, &filepath)
-            .context(format!("Renaming {:?} to {:?}", tmp_filepath, filepath))?;
-        Ok(())
-    } else {
-        Err(anyhow!("Subprocess failed: {:?}", status))
     }
+    let tmp_filepath = config.image_dir.join(tmp_filename);
+    let result = run_pull_command(context, &mut pull_command, Some(&tmp_filepath)).await;
+    if let Some(secret_dir) = secret_dir {
+        let _ = fs::remove_dir_all(secret_dir);
+    }
+    result
+}
+
+/// Writes a file only readable by the current user, e.g. containing registry credentials
+fn write_secret_file(path: &Path, content: &str) -> anyhow::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .context(format!("Failed writing {:?}", path))
+}
+
+/// Prefix of the directories holding the credentials of a job
+fn get_secret_dir_prefix(job_id: &str) -> String {
+    format!("{}-{}-secrets.", env!("CARGO_PKG_NAME"), job_id)
+}
+
+/// Creates a directory with an unpredictable name that only the current user can access,
+/// so other users of a shared node can neither read nor redirect the credentials written into it
+fn create_secret_dir(job_id: &str) -> anyhow::Result<PathBuf> {
+    let template = std::env::temp_dir().join(format!("{}XXXXXX", get_secret_dir_prefix(job_id)));
+    let mut template = CString::new(template.into_os_string().into_vec())?.into_bytes_with_nul();
+    // SAFETY: the template is a writable NUL-terminated string, which mkdtemp modifies in place
+    let result = unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) };
+    if result.is_null() {
+        Err(std::io::Error::last_os_error()).context("Failed creating credentials directory")?;
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
 }
 
-async fn run_step(
+/// Removes the credentials directories of a job, including those left behind by interrupted pulls
+fn remove_secret_dirs(job_id: &str) {
+    let prefix = get_secret_dir_prefix(job_id);
+    let Ok(entries) = fs::read_dir(std::env::temp_dir()) else {
+        return;
+    };
+    // SAFETY: geteuid has no memory safety requirements
+    let uid = unsafe { libc::geteuid() };
+    for entry in entries.flatten() {
+        let is_own_dir = entry
+            .metadata()
+            .is_ok_and(|metadata| metadata.is_dir() && metadata.uid() == uid);
+        if !is_own_dir || !entry.file_name().as_bytes().starts_with(prefix.as_bytes()) {
+            continue;
+        }
+        if let Err(e) = fs::remove_dir_all(entry.path()) {
+            warn!(
+                "Failed removing credentials directory {:?}: {:?}",
+                entry.path(),
+                e
+            );
+        }
+    }
+}
+
+/// Passes the credentials of the registry the image is pulled from to the pull command.
+/// docker:// and oras:// images use an auth file in a private directory, which is returned so it
+/// can be removed after the pull, while library:// images are pulled from the library endpoint
+/// after logging in to it.
+fn apply_registry_credentials(
     context: &JobContext,
-    script_path: &PathBuf,
-    step_name: &str,
+    pull_command: &mut async_process::Command,
+    pull_url: &str,
+) -> anyhow::Result<Option<PathBuf>> {
+    let config = &context.config;
+    let Some((name, registry)) = credentials::find_registry(&config.registries, pull_url) else {
+        return Ok(None);
+    };
+    let password = credentials::read_password(name, registry, |v| std::env::var(v).ok())?;
+    let secret_dir = create_secret_dir(&context.env.job_id)?;
+    if let Some(library_url) = &registry.library_url {
+        let token_file = secret_dir.join("token");
+        let result = write_secret_file(&token_file, &password).and_then(|_| {
+            credentials::login_library(&config.apptainer_executable, name, library_url, &token_file)
+        });
+        let _ = fs::remove_dir_all(&secret_dir);
+        result?;
+        pull_command.arg("--library").arg(library_url);
+        return Ok(None);
+    }
+    let auth_file = secret_dir.join("auth.json");
+    let result = registry
+        .username
+        .as_ref()
+        .ok_or(anyhow!("Registry {} has no username", name))
+        .and_then(|username| {
+            write_secret_file(
+                &auth_file,
+                &credentials::auth_file_content(name, username, &password),
+            )
+        });
+    if let Err(e) = result {
+        let _ = fs::remove_dir_all(&secret_dir);
+        Err(e)?;
+    }
+    info!("Using credentials of registry {}", name);
+    pull_command.arg("--authfile").arg(&auth_file);
+    Ok(Some(secret_dir))
+}
+
+/// Temporary directory of the image pulls of a job, so concurrent pulls don't share their temporary files
+fn get_job_tmp_dir(config: &GitLabCustomExecutorConfig, job_id: &str) -> Option<PathBuf> {
+    config.image_tmp_dir.as_ref().map(|dir| dir.join(job_id))
+}
+
+/// Removes the temporary directory of the image pulls of a job, including files of interrupted pulls
+fn remove_job_tmp_dir(config: &GitLabCustomExecutorConfig, job_id: &str) {
+    let Some(dir) = get_job_tmp_dir(config, job_id).filter(|dir| dir.exists()) else {
+        return;
+    };
+    debug!("Deleting temporary pull directory {:?}", dir);
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!(
+            "Failed deleting temporary pull directory {:?}: {:?}",
+            dir, e
+        );
+    }
+}
+
+/// Checks whether a local image file was modified after the image was built from it
+fn is_local_image_updated(local_path: &Path, image_path: &Path) -> anyhow::Result<bool> {
+    let source_modified = fs::metadata(local_path)
+        .and_then(|m| m.modified())
+        .context(format!("Local image {:?} doesn't exist", local_path))?;
+    // the image file is a symlink into the store, which is replaced whenever the image is built
+    let image_modified = fs::symlink_metadata(image_path)
+        .and_then(|m| m.modified())
+        .context(format!("Failed reading metadata of {:?}", image_path))?;
+    let updated = source_modified > image_modified;
+    if updated {
+        info!("Local image {:?} was updated", local_path);
+    }
+    Ok(updated)
+}
+
+/// Checks whether the image file may be executed if verify_signatures is enabled
+fn verify_image(config: &GitLabCustomExecutorConfig, image_path: &Path) -> anyhow::Result<()> {
+    if !config.verify_signatures {
+        return Ok(());
+    }
+    if !config.trusted_digests.is_empty() {
+        // the trusted digests refer to the image files, not to the registry digest the store is keyed by
+        let digest =
+            hash_file(image_path).context(format!("Failed hashing image file {:?}", image_path))?;
+        if verify::is_trusted_digest(&config.trusted_digests, &digest) {
+            info!("Image {:?} has trusted digest {}", image_path, digest);
+            return Ok(());
+        }
+    }
+    verify::verify_signature(
+        &config.apptainer_executable,
+        image_path,
+        &config.trusted_fingerprints,
+    )
+    .context("Refusing to use unverified image")
+}
+
+async fn pull_docker_image(context: &JobContext, image: &str) -> anyhow::Result<()> {
+    let executable = &context.config.docker_executable;
+    let image_exists = docker::image_exists(executable, image);
+    let pull_needed = is_pull_needed(context.config.pull_policy, image_exists)?;
+    // the docker engine records the registry digests of pulled images itself
+    let local_digest = docker::image_digest(executable, image);
+    let (digest_changed, _) = check_digest(&context.config, image, local_digest).await;
+    info!("Using image {}", docker::image_name(image));
+    if !(pull_needed || digest_changed) {
+        info!("No pull necessary");
+        return Ok(());
+    }
+    let mut pull_command = docker::pull_command(executable, image);
+    run_pull_command(context, &mut pull_command, None).await
+}
+
+/// Imports the image into image_dir if necessary, returning the path of the squashfs file
+async fn import_enroot_image(context: &JobContext, image: &str) -> anyhow::Result<PathBuf> {
+    let config = &context.config;
+    let filename = enroot::image_filename(image);
+    let filepath = config.image_dir.join(&filename);
+    let (_lock_file, pulled_concurrently) = lock_image(&filepath)?;
+    let image_exists =
+        fs::exists(&filepath).context("Failed checking for existence of image file")?;
+    let pull_needed = is_pull_needed(config.pull_policy, image_exists)?;
+    let local_digest = fs::read_to_string(get_digest_filepath(config, &filename)).ok();
+    let (digest_changed, digest) = check_digest(config, image, local_digest).await;
+    info!("Using image {}", image);
+    if !(pull_needed || digest_changed) || (pulled_concurrently && image_exists) {
+        info!("No pull necessary");
+        return Ok(filepath);
+    }
+    // the temporary file is meant to prevent race conditions in image replacement
+    let tmp_filepath = filepath.with_extension(format!("{}.tmp", context.env.job_id));
+    let mut import_command =
+        enroot::import_command(&config.enroot_executable, image, &tmp_filepath);
+    run_pull_command(context, &mut import_command, Some(&tmp_filepath)).await?;
+    fs::rename(&tmp_filepath, &filepath)
+        .context(format!("Renaming {:?} to {:?}", tmp_filepath, filepath))?;
+    record_digest(config, &filename, digest);
+    Ok(filepath)
+}
+
+/// Parses and resolves the additional mounts, failing for missing sources
+fn get_mounts(config: &GitLabCustomExecutorConfig) -> anyhow::Result<Vec<MountSpec>> {
+    let mut mounts = config
+        .mount
+        .iter()
+        .map(|spec| MountSpec::parse(spec)?.resolve(config.create_mount_sources))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .context("Invalid mount")?;
+    // caches are mounted at their configured path, which their environment variable points to
+    for (name, cache) in &config.caches {
+        let mount = MountSpec::parse(&cache.dir)
+            .and_then(|mount| mount.resolve(true))
+            .context(format!("Invalid cache {}", name))?;
+        mounts.push(MountSpec {
+            destination: cache.dir.clone().into(),
+            ..mount
+        });
+    }
+    Ok(mounts)
+}
+
+/// Environment variables pointing the job to the configured caches
+fn get_cache_env(config: &GitLabCustomExecutorConfig) -> Vec<EnvChange> {
+    let mut changes: Vec<_> = config
+        .caches
+        .iter()
+        .map(|(name, cache)| EnvChange {
+            name: caches::variable_name(name, cache),
+            value: cache.dir.clone(),
+            previous: None,
+        })
+        .collect();
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    changes
+}
+
+/// Directories searched for the Level Zero libraries of the host
+const HOST_LIBRARY_DIRS: [&str; 4] = [
+    "/usr/lib64",
+    "/usr/lib/x86_64-linux-gnu",
+    "/usr/lib",
+    "/usr/local/lib",
+];
+/// Prefixes of the libraries required for using Intel GPUs via Level Zero
+const LEVEL_ZERO_LIBRARIES: [&str; 5] = [
+    "libze_loader.so",
+    "libze_intel_gpu.so",
+    "libigc.so",
+    "libigdfcl.so",
+    "libigdgmm.so",
+];
+/// Directory inside apptainer containers that is part of LD_LIBRARY_PATH, also used for the --nv libraries
+const APPTAINER_LIBRARY_DIR: &str = "/.singularity.d/libs";
+
+/// Finds the Level Zero libraries of the host, preferring the first directory containing a library
+fn find_level_zero_libraries() -> Vec<PathBuf> {
+    let mut libraries: Vec<PathBuf> = Vec::new();
+    for dir in HOST_LIBRARY_DIRS {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name();
+            let is_library = name
+                .to_str()
+                .is_some_and(|name| LEVEL_ZERO_LIBRARIES.iter().any(|l| name.starts_with(l)));
+            let is_duplicate = libraries.iter().any(|l| l.file_name() == Some(&name));
+            if is_library && !is_duplicate {
+                libraries.push(entry.path());
+            }
+        }
+    }
+    if libraries.is_empty() {
+        warn!("No Level Zero libraries found on the host");
+    }
+    libraries
+}
+
+/// GPUs granted to the job by the batch system, None if all GPUs of the node may be used
+#[derive(Debug, PartialEq)]
+struct AllocatedGpus {
+    amd: Option<String>,
+    nvidia: Option<String>,
+}
+
+/// Determines the allocated GPUs from the visibility variables set by the batch system,
+/// falling back to SLURM_JOB_GPUS
+fn get_allocated_gpus(variable: impl Fn(&str) -> Option<String>) -> AllocatedGpus {
+    let slurm_gpus = variable("SLURM_JOB_GPUS");
+    let allocated = |name| {
+        variable(name)
+            .or(slurm_gpus.clone())
+            .filter(|gpus| !gpus.is_empty())
+    };
+    AllocatedGpus {
+        amd: allocated("ROCR_VISIBLE_DEVICES"),
+        nvidia: allocated("CUDA_VISIBLE_DEVICES"),
+    }
+}
+
+/// Creates the container all job steps are executed in, with the same mounts and devices as apptainer containers
+fn create_docker_container(context: &JobContext, image: &str) -> anyhow::Result<()> {
+    let env = &context.env;
+    let config = &context.config;
+    let mut args = Vec::new();
+    for dir in [&env.builds_dir, &config.cache_dir] {
+        let dir = dir.to_string_lossy();
+        args.extend(["--volume".to_owned(), format!("{}:{}", dir, dir)]);
+    }
+    for mount in get_mounts(config)? {
+        args.extend(["--volume".to_owned(), mount.to_bind()]);
+    }
+    if let Some(network) = &config.network {
+        args.extend(["--network".to_owned(), network.clone()]);
+    }
+    let gpus = get_allocated_gpus(|name| std::env::var(name).ok());
+    if config.gpu_amd {
+        args.extend(["--device", "/dev/kfd", "--device", "/dev/dri"].map(str::to_owned));
+        if let Some(gpus) = &gpus.amd {
+            args.extend(["--env".to_owned(), format!("ROCR_VISIBLE_DEVICES={}", gpus)]);
+        }
+    }
+    if config.gpu_intel {
+        args.extend(["--device", "/dev/dri"].map(str::to_owned));
+    }
+    if config.gpu_nvidia {
+        // only the selected devices are exposed, so CUDA_VISIBLE_DEVICES isn't needed
+        let devices = gpus
+            .nvidia
+            .map_or("all".to_owned(), |gpus| format!("\"device={}\"", gpus));
+        args.extend(["--gpus".to_owned(), devices]);
+    }
+    if let Some(limits) = &config.limits {
+        args.extend(limits::docker_args(limits));
+    }
+    let container = docker::container_name(&env.job_id);
+    // a container left over from an earlier attempt would prevent creating a fresh one
+    docker::remove_container(&config.docker_executable, &container)?;
+    debug!("Creating container {} with arguments {:?}", container, args);
+    docker::create_container(&config.docker_executable, &container, image, &args)
+}
+
+/// Prints the start of a collapsible section in the GitLab job log
+fn section_start(name: &str, header: &str, collapsed: bool) {
+    println!(
+        "\x1b[0Ksection_start:{}:{}[collapsed={}]\r\x1b[0K{}",
+        unix_timestamp(),
+        name,
+        collapsed,
+        header
+    );
+}
+
+/// Prints the end of a collapsible section in the GitLab job log
+fn section_end(name: &str) {
+    println!("\x1b[0Ksection_end:{}:{}\r\x1b[0K", unix_timestamp(), name);
+}
+
+fn get_hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_owned())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or("unknown".into())
+}
+
+/// Extracts the registry digest from an image path that points into the content-addressed store
+fn get_image_digest(image_path: &Path) -> Option<String> {
+    let resolved = fs::canonicalize(image_path).ok()?;
+    if resolved.parent()?.file_name()? != IMAGE_STORE_DIR {
+        return None;
+    }
+    let (hex, _arch) = resolved.file_stem()?.to_str()?.rsplit_once('_')?;
+    Some(format!("sha256:{}", hex))
+}
+
+/// Prints a collapsed section to the job log describing how the container will be launched
+fn print_job_diagnostics(context: &JobContext) {
+    let env = &context.env;
+    let config = &context.config;
+    section_start("meta_runner_diagnostics", "Executor diagnostics", true);
+    println!("Runner instance: {}", context.runner_name);
+    println!("Host: {}", get_hostname());
+    match get_container_image(context) {
+        Some(ContainerImage::Pulled(image)) if config.backend == GitLabExecutorBackend::Docker => {
+            println!("Image: {}", docker::image_name(image));
+            println!(
+                "Image digest: {}",
+                docker::image_digest(&config.docker_executable, image).unwrap_or("unknown".into())
+            );
+        }
+        Some(ContainerImage::Pulled(image)) if config.backend == GitLabExecutorBackend::Enroot => {
+            let image_path = config.image_dir.join(enroot::image_filename(image));
+            println!("Image: {} ({:?})", image, image_path);
+        }
+        Some(ContainerImage::Pulled(image)) => {
+            let image_path = get_image_path(config, image);
+            println!("Image: {} ({:?})", image, image_path);
+            println!(
+                "Image registry digest: {}",
+                get_image_digest(&image_path).unwrap_or("unknown".into())
+            );
+        }
+        Some(ContainerImage::Built(definition)) => println!("Image: built from {}", definition),
+        None if is_container_backend(config.backend) => {
+            println!("Image: none (running on host)")
+        }
+        None => println!(
+            "Environment: {:?} {}",
+            config.backend,
+            env.environment.as_deref().unwrap_or("(project default)")
+        ),
+    }
+    println!("Builds directory: {:?}", env.builds_dir);
+    println!("Cache directory: {:?}", config.cache_dir);
+    println!("Additional mounts: {:?}", config.mount);
+    println!("Network: {}", config.network.as_deref().unwrap_or("host"));
+    if !context.extra_flags.is_empty() {
+        println!("Additional flags: {:?}", context.extra_flags);
+    }
+    if let Some(overlay) = get_overlay_path(context) {
+        println!("Overlay: {:?}", overlay);
+    }
+    if !context.services.is_empty() {
+        let services: Vec<_> = context.services.iter().map(|s| &s.name).collect();
+        println!("Services: {:?}", services);
+    }
+    if let Ok(shell_command) = get_shell_command(config) {
+        println!("Shell: {}", shell_command.join(" "));
+    }
+    if !context.entrypoint.is_empty() {
+        println!("Entrypoint: {:?}", context.entrypoint);
+    }
+    println!("Modules: {:?}", get_modules(config));
+    println!("AMD GPUs: {}", config.gpu_amd);
+    println!("NVIDIA GPUs: {}", config.gpu_nvidia);
+    println!("Intel GPUs: {}", config.gpu_intel);
+    let gpus = get_allocated_gpus(|name| std::env::var(name).ok());
+    if config.gpu_amd || config.gpu_nvidia {
+        println!(
+            "Allocated GPUs: AMD {}, NVIDIA {}",
+            gpus.amd.as_deref().unwrap_or("all"),
+            gpus.nvidia.as_deref().unwrap_or("all")
+        );
+    }
+    section_end("meta_runner_diagnostics");
+}
+
+/// Builds the command line for executing a job script generated by gitlab-runner for the given shell
+fn get_default_shell_command(shell: Option<&str>) -> anyhow::Result<Vec<&'static str>> {
+    match shell.unwrap_or("bash") {
+        "bash" => Ok(vec!["bash", "-l"]),
+        "sh" => Ok(vec!["sh"]),
+        "pwsh" => Ok(vec![
+            "pwsh",
+            "-NoProfile",
+            "-NonInteractive",
+            "-ExecutionPolicy",
+            "Bypass",
+            "-File",
+        ]),
+        "powershell" => Ok(vec![
+            "powershell",
+            "-NoProfile",
+            "-NonInteractive",
+            "-ExecutionPolicy",
+            "Bypass",
+            "-File",
+        ]),
+        shell => Err(anyhow!("Unsupported shell {}", shell)),
+    }
+}
+
+/// Fails for PowerShell, whose job scripts can't be executed in the Linux containers of the container backends.
+/// The shell is the one gitlab-runner generates the job scripts for, i.e. runner.shell.
+pub fn check_shell_support(
+    shell: Option<&str>,
+    backend: GitLabExecutorBackend,
 ) -> anyhow::Result<()> {
-    debug!(
-        "Executing run step {} for job {} with runner {}",
-        step_name, context.env.job_id, context.runner_name
+    match shell {
+        Some(shell @ ("pwsh" | "powershell")) if is_container_backend(backend) => Err(anyhow!(
+            "runner.shell {} is not supported by the {:?} backend, which runs jobs in Linux containers",
+            shell,
+            backend
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Builds the command line for executing a job script inside the job container. User steps are executed
+/// via the entrypoint from the job definition, which receives the shell command line as arguments.
+fn get_container_shell_command(
+    context: &JobContext,
+    step_name: &str,
+) -> anyhow::Result<Vec<String>> {
+    let shell_command = get_shell_command(&context.config)?;
+    if is_user_step(step_name) {
+        Ok(context
+            .entrypoint
+            .iter()
+            .cloned()
+            .chain(shell_command)
+            .collect())
+    } else {
+        Ok(shell_command)
+    }
+}
+
+/// Builds the command line for executing a job script, applying executor.shell and executor.shell_args
+/// to the default command line for runner.shell
+pub fn get_shell_command(config: &GitLabCustomExecutorConfig) -> anyhow::Result<Vec<String>> {
+    let default = get_default_shell_command(config.shell.as_deref())?;
+    let program = match config.shell_executable.as_deref() {
+        Some(program) if program.is_empty() || program.contains(char::is_whitespace) => {
+            Err(anyhow!(
+                "executor.shell {:?} must be a single executable, pass arguments via executor.shell_args",
+                program
+            ))?
+        }
+        Some(program) => program,
+        None => default[0],
+    };
+    let args = match &config.shell_args {
+        Some(args) => args.clone(),
+        None => default[1..].iter().map(|&arg| arg.to_owned()).collect(),
+    };
+    Ok([program.to_owned()].into_iter().chain(args).collect())
+}
+
+/// Environment variable added or modified by the environment setup
+#[derive(Debug, PartialEq)]
+struct EnvChange {
+    name: String,
+    value: String,
+    previous: Option<String>,
+}
+
+impl EnvChange {
+    /// Builds a line for apptainer's --env-file, modifying path-like variables
+    /// relative to their value inside the container
+    fn to_env_file_line(&self) -> String {
+        let name = &self.name;
+        match &self.previous {
+            Some(previous) if !previous.is_empty() && self.value.ends_with(previous.as_str()) => {
+                let prefix = &self.value[..self.value.len() - previous.len()];
+                format!("export {}={}\"${{{}}}\"", name, shell_quote(prefix), name)
+            }
+            Some(previous) if !previous.is_empty() && self.value.starts_with(previous.as_str()) => {
+                let suffix = &self.value[previous.len()..];
+                format!("export {}=\"${{{}}}\"{}", name, name, shell_quote(suffix))
+            }
+            _ => format!("export {}={}", name, shell_quote(&self.value)),
+        }
+    }
+}
+
+fn parse_env_output(output: &[u8]) -> HashMap<String, String> {
+    output
+        .split(|&c| c == 0)
+        .filter_map(|entry| {
+            let (name, value) = std::str::from_utf8(entry).ok()?.split_once('=')?;
+            Some((name.to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+fn diff_environment(
+    before: &HashMap<String, String>,
+    after: HashMap<String, String>,
+) -> Vec<EnvChange> {
+    let mut changes: Vec<_> = after
+        .into_iter()
+        .filter(|(name, _)| !["_", "SHLVL", "PWD", "OLDPWD"].contains(&name.as_str()))
+        .filter(|(name, value)| before.get(name) != Some(value))
+        .map(|(name, value)| EnvChange {
+            previous: before.get(&name).cloned(),
+            name,
+            value,
+        })
+        .collect();
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    changes
+}
+
+fn run_env_capture(
+    setup_script: &str,
+    switch_user: &[String],
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut command = match switch_user.split_first() {
+        Some((program, args)) => {
+            let mut command = std::process::Command::new(program);
+            command.args(args).arg("bash");
+            command
+        }
+        None => std::process::Command::new("bash"),
+    };
+    // use a login shell, since the module command is usually defined in the system profile
+    let output = command
+        .arg("-l")
+        .arg("-c")
+        // all output of the setup goes to stderr, so stdout only contains the environment
+        .arg(format!("{{\n{}\n}} >&2 && env -0", setup_script))
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed spawning environment setup process")?;
+    if !output.status.success() {
+        Err(anyhow!("Environment setup failed: {:?}", output.status))?;
+    }
+    Ok(parse_env_output(&output.stdout))
+}
+
+/// Runs the given shell snippet on the host and returns all environment variables it modified.
+/// The modules and spack environment are chosen by the job, so the snippet runs as the run_as user.
+fn capture_environment(
+    setup_script: &str,
+    switch_user: &[String],
+) -> anyhow::Result<Vec<EnvChange>> {
+    let before = run_env_capture("true", switch_user)?;
+    let after = run_env_capture(setup_script, switch_user)?;
+    Ok(diff_environment(&before, after))
+}
+
+/// Returns the environment modules to load, which the job may override via CI variable
+fn get_modules(config: &GitLabCustomExecutorConfig) -> Vec<String> {
+    match std::env::var("CUSTOM_ENV_META_RUNNER_MODULES") {
+        Ok(modules) => modules.split_whitespace().map(str::to_owned).collect(),
+        Err(_) => config.modules.clone(),
+    }
+}
+
+/// Resolves a path the job passed relative to the project directory, rejecting paths that lead
+/// outside of it, e.g. via .. or symlinks, since the job could otherwise use any file the executor can read
+fn resolve_project_path(project_dir: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let project_dir = project_dir.canonicalize().context(format!(
+        "Failed resolving project directory {:?}",
+        project_dir
+    ))?;
+    let resolved = project_dir.join(path).canonicalize().context(format!(
+        "Failed resolving {:?} in the project directory",
+        path
+    ))?;
+    if !resolved.starts_with(&project_dir) {
+        Err(anyhow!("{:?} is outside of the project directory", path))?;
+    }
+    Ok(resolved)
+}
+
+/// Returns the spack environment requested by the job, if any
+fn get_spack_environment(context: &JobContext) -> Option<(&GitLabSpackConfig, SpackEnvironment)> {
+    let spack_config = context.config.spack.as_ref()?;
+    SpackEnvironment::from_env(spack_config).map(|environment| (spack_config, environment))
+}
+
+/// Returns the shell snippet activating the spack environment requested by the job, if any.
+/// Environments created from spack.yaml files are installed first. Their package recipes are
+/// controlled by the job, so spack runs like the job step: as the run_as user, inside the step
+/// wrapper and within the step's timeout.
+async fn get_spack_activation(
+    context: &JobContext,
+    step_name: &str,
+) -> anyhow::Result<Option<String>> {
+    let Some((spack_config, environment)) = get_spack_environment(context) else {
+        return Ok(None);
+    };
+    debug!("Activating spack environment {:?}", environment);
+    let file = match environment {
+        SpackEnvironment::Named(name) => {
+            return Ok(Some(spack::activate_named(spack_config, &name)))
+        }
+        SpackEnvironment::File(file) => resolve_project_path(&context.env.project_dir, &file)?,
+    };
+    let cached = spack::cache_environment(spack_config, &file, get_builds_dir_owner(context)?)?;
+    if !cached.is_installed()? {
+        for args in spack::install_commands(&cached.dir) {
+            let mut command = new_step_command(
+                &get_step_wrapper(context, step_name)?,
+                &spack_config.executable,
+            );
+            command
+                .args(&args)
+                .current_dir(&cached.dir)
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .stdin(Stdio::null());
+            debug!("Executing spack command {:?}", command);
+            let status = wait_for_step(
+                command,
+                get_step_timeout(&context.env, step_name, Utc::now()),
+                get_sudo_user(&context.config),
+            )
+            .await?;
+            if !status.success() {
+                Err(anyhow!("Spack command {:?} failed: {:?}", args, status))?;
+            }
+        }
+        cached.mark_installed()?;
+    }
+    spack::activate_dir(spack_config, &cached.dir).map(Some)
+}
+
+/// Builds the shell snippet loading the given modules and activating the spack environment
+fn build_setup_script(modules: &[String], spack_activation: Option<String>) -> String {
+    let mut setup = Vec::new();
+    if !modules.is_empty() {
+        let modules: Vec<_> = modules.iter().map(|m| shell_quote(m)).collect();
+        setup.push(format!("module load {}", modules.join(" ")));
+    }
+    setup.extend(spack_activation);
+    setup.join(" && ")
+}
+
+/// Selects the host environment variables whose names match one of the patterns, which may contain * and ? wildcards
+fn get_passed_env(
+    patterns: &[String],
+    variables: impl Iterator<Item = (String, String)>,
+) -> anyhow::Result<Vec<EnvChange>> {
+    let patterns = patterns
+        .iter()
+        .map(|pattern| {
+            let pattern = regex::escape(pattern)
+                .replace(r"\*", ".*")
+                .replace(r"\?", ".");
+            Regex::new(&format!("^{}$", pattern))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid pass_env pattern")?;
+    let mut passed: Vec<_> = variables
+        .filter(|(name, _)| patterns.iter().any(|pattern| pattern.is_match(name)))
+        .map(|(name, value)| EnvChange {
+            name,
+            value,
+            previous: None,
+        })
+        .collect();
+    passed.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(passed)
+}
+
+/// Captures the environment changes for the given step, printing the setup output in a collapsed section.
+/// The host variables selected by pass_env and the cache variables are forwarded to all steps.
+async fn setup_environment(
+    context: &JobContext,
+    step_name: &str,
+) -> anyhow::Result<Vec<EnvChange>> {
+    let mut changes = get_passed_env(&context.config.pass_env, std::env::vars())?;
+    let cache_changes = get_cache_env(&context.config);
+    changes.retain(|passed| !cache_changes.iter().any(|c| c.name == passed.name));
+    changes.extend(cache_changes);
+    if !is_user_step(step_name) {
+        return Ok(changes);
+    }
+    let modules = get_modules(&context.config);
+    if modules.is_empty() && get_spack_environment(context).is_none() {
+        return Ok(changes);
+    }
+    section_start("meta_runner_environment", "Environment setup", true);
+    let result = match get_spack_activation(context, step_name).await {
+        Ok(spack_activation) => get_switch_user_command(&context.config).and_then(|switch_user| {
+            capture_environment(
+                &build_setup_script(&modules, spack_activation),
+                &switch_user,
+            )
+        }),
+        Err(e) => Err(e),
+    };
+    section_end("meta_runner_environment");
+    let setup_changes = result.context("Failed setting up job environment")?;
+    debug!("Environment changes {:?}", setup_changes);
+    // the environment setup takes precedence over the passed variables
+    changes.retain(|passed| !setup_changes.iter().any(|c| c.name == passed.name));
+    changes.extend(setup_changes);
+    Ok(changes)
+}
+
+/// Returns whether a job step executes user-provided scripts, as opposed to
+/// runner-internal steps like fetching sources or uploading artifacts
+fn is_user_step(step_name: &str) -> bool {
+    step_name == "build_script" || step_name == "after_script" || step_name.starts_with("step_")
+}
+
+/// Describes a step in the job log, so the steps gitlab-runner executes via run can be told apart
+fn get_step_description(step_name: &str) -> String {
+    let description = match step_name {
+        "prepare_script" => "Preparing environment",
+        "get_sources" => "Getting sources",
+        "clear_worktree" => "Clearing worktree",
+        "restore_cache" => "Restoring cache",
+        "download_artifacts" => "Downloading artifacts",
+        "build_script" => "Executing job script",
+        "after_script" => "Running after_script",
+        "archive_cache" | "archive_cache_on_failure" => "Saving cache",
+        "upload_artifacts_on_success" | "upload_artifacts_on_failure" => "Uploading artifacts",
+        "cleanup_file_variables" => "Cleaning up file based variables",
+        _ => {
+            return match step_name.strip_prefix("step_") {
+                Some(name) => format!("Executing step {}", name),
+                None => format!("Executing {}", step_name),
+            }
+        }
+    };
+    description.to_owned()
+}
+
+/// Returns whether the backend runs jobs with an image in containers, as opposed to Nix or Guix environments
+fn is_container_backend(backend: GitLabExecutorBackend) -> bool {
+    match backend {
+        GitLabExecutorBackend::Apptainer
+        | GitLabExecutorBackend::Docker
+        | GitLabExecutorBackend::Enroot => true,
+        GitLabExecutorBackend::Nix | GitLabExecutorBackend::Guix => false,
+    }
+}
+
+/// Builds the command running a job step on the host, inside a Nix or Guix environment
+/// if configured. Runner-internal steps are executed directly on the host, since the
+/// project containing the flake or manifest may not have been fetched yet.
+fn build_environment_command(
+    context: &JobContext,
+    shell_command: &[String],
+    script_path: &PathBuf,
+    step_name: &str,
+) -> anyhow::Result<async_process::Command> {
+    let env = &context.env;
+    let wrapper = get_step_wrapper(context, step_name)?;
+    let mut command = if !is_user_step(step_name) || is_container_backend(context.config.backend) {
+        let mut command = new_step_command(&wrapper, &shell_command[0]);
+        command.args(&shell_command[1..]);
+        command
+    } else if context.config.backend == GitLabExecutorBackend::Nix {
+        let mut command = new_step_command(&wrapper, "nix");
+        command
+            .arg("develop")
+            .arg(env.environment.as_deref().unwrap_or("."))
+            .arg("--command")
+            .args(shell_command);
+        command
+    } else {
+        let mut command = new_step_command(&wrapper, "guix");
+        command
+            .arg("shell")
+            .arg("--manifest")
+            .arg(env.environment.as_deref().unwrap_or("manifest.scm"))
+            .arg("--")
+            .args(shell_command);
+        command
+    };
+    // relative flake or manifest references are resolved inside the project directory
+    let workdir = if is_user_step(step_name) {
+        &env.project_dir
+    } else {
+        &env.builds_dir
+    };
+    command
+        .current_dir(workdir)
+        .arg(script_path)
+        .arg(step_name)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null());
+    Ok(command)
+}
+
+/// Time a job step has to exit after the termination signal was forwarded to it
+const STEP_TERMINATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the command the user steps are wrapped in, runner-internal steps are executed directly
+/// apart from switching to the run_as user. The limits scope is created inside the step wrapper,
+/// so it applies on the node the step runs on.
+fn get_step_wrapper(context: &JobContext, step_name: &str) -> anyhow::Result<Vec<String>> {
+    let config = &context.config;
+    let mut wrapper = Vec::new();
+    if is_user_step(step_name) {
+        wrapper.extend(config.step_wrapper.iter().cloned());
+        if let Some(limits) = &config.limits {
+            wrapper.extend(limits::scope_command(limits, run_as::is_root()));
+        }
+    }
+    wrapper.extend(get_switch_user_command(config)?);
+    Ok(wrapper)
+}
+
+/// Returns the command prefix switching to the run_as user, if there is one
+fn get_switch_user_command(config: &GitLabCustomExecutorConfig) -> anyhow::Result<Vec<String>> {
+    let Some(user) = &config.run_as else {
+        return Ok(Vec::new());
+    };
+    let ids = run_as::lookup_user(user)?;
+    Ok(run_as::switch_user_command(user, ids, run_as::is_root()))
+}
+
+/// Creates the command for a job step in its own process group, so signals can be forwarded
+/// to all processes of the step, prepending the step wrapper if there is one
+fn new_step_command(wrapper: &[String], program: impl AsRef<OsStr>) -> async_process::Command {
+    let mut command = match wrapper.split_first() {
+        Some((wrapper_program, wrapper_args)) => {
+            let mut command = std::process::Command::new(wrapper_program);
+            command.args(wrapper_args).arg(program);
+            command
+        }
+        None => std::process::Command::new(program),
+    };
+    command.process_group(0);
+    command.into()
+}
+
+/// Returns the run_as user of the job steps if the executor switches to it via sudo
+fn get_sudo_user(config: &GitLabCustomExecutorConfig) -> Option<&str> {
+    config.run_as.as_deref().filter(|_| !run_as::is_root())
+}
+
+/// Returns the owner of the builds directory if it was handed over to the run_as user
+fn get_builds_dir_owner(context: &JobContext) -> anyhow::Result<Option<run_as::UserIds>> {
+    context
+        .config
+        .run_as
+        .as_deref()
+        .map(run_as::lookup_user)
+        .transpose()
+}
+
+/// Sends a signal to all processes of a job step, ignoring steps that already exited.
+/// If the step runs as another user via sudo, the executor can only signal sudo itself, which relays
+/// the signal to the step. SIGKILL can't be relayed, so it is sent to the step as the run_as user.
+fn signal_step(process_group: u32, signal: libc::c_int, sudo_user: Option<&str>) {
+    let target = match sudo_user {
+        Some(user) if signal == libc::SIGKILL => {
+            if let Err(e) = run_as::kill_process_group(user, process_group, "KILL") {
+                debug!("Failed signaling job step: {:?}", e);
+            }
+            return;
+        }
+        // sudo is the leader of the step's process group
+        Some(_) => process_group as libc::pid_t,
+        None => -(process_group as libc::pid_t),
+    };
+    // SAFETY: kill has no memory safety requirements, a negative PID selects the step's process group
+    if unsafe { libc::kill(target, signal) } != 0 {
+        debug!(
+            "Failed signaling job step: {:?}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Resolves once gitlab-runner asks the executor to terminate, e.g. because the job was cancelled
+async fn termination_signal() -> anyhow::Result<()> {
+    let mut sigterm =
+        unix_signal(SignalKind::terminate()).context("Failed listening for SIGTERM")?;
+    let mut sigint = unix_signal(SignalKind::interrupt()).context("Failed listening for SIGINT")?;
+    tokio::select! {
+        _ = sigterm.recv() => (),
+        _ = sigint.recv() => (),
+    }
+    Ok(())
+}
+
+/// Time after_script may run, matching gitlab-runner's default after_script timeout
+const AFTER_SCRIPT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time a job step may run: the job script has until the job exceeds its timeout, measured from
+/// the start of the job if known. after_script gets its own grace period, since it also runs after
+/// the job script timed out, and the remaining runner-internal steps (e.g. uploading artifacts)
+/// are left to gitlab-runner.
+fn get_step_timeout(env: &JobEnv, step_name: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if step_name == "after_script" {
+        return Some(AFTER_SCRIPT_TIMEOUT);
+    }
+    if step_name != "build_script" && !step_name.starts_with("step_") {
+        return None;
+    }
+    let timeout = chrono::Duration::seconds(env.timeout? as i64);
+    let elapsed = env
+        .started_at
+        .map_or(chrono::Duration::zero(), |start| now - start);
+    Some((timeout - elapsed).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Runs a job step to completion. If the executor is asked to terminate or the step exceeds
+/// the timeout, the termination is forwarded to the step, which is killed if it doesn't exit
+/// within STEP_TERMINATION_TIMEOUT.
+/// The processes started via docker exec are not part of the process group, they are only stopped
+/// once the cleanup step removes the container.
+async fn wait_for_step(
+    mut command: async_process::Command,
+    timeout: Option<Duration>,
+    sudo_user: Option<&str>,
+) -> anyhow::Result<ExitStatus> {
+    let mut child = command.spawn()?;
+    let process_group = child.id();
+    let termination = termination_signal();
+    // sleep saturates durations that exceed the supported range
+    let deadline = time::sleep(timeout.unwrap_or(Duration::MAX));
+    let status = child.status();
+    tokio::pin!(termination, deadline, status);
+    let timed_out = tokio::select! {
+        status = &mut status => return Ok(status?),
+        result = &mut termination => {
+            result?;
+            info!("Received termination signal, stopping job step");
+            false
+        }
+        _ = &mut deadline => {
+            error!("Job step exceeded its timeout, stopping it");
+            true
+        }
+    };
+    signal_step(process_group, libc::SIGTERM, sudo_user);
+    let status = match time::timeout(STEP_TERMINATION_TIMEOUT, &mut status).await {
+        Ok(status) => status?,
+        Err(_) => {
+            warn!(
+                "Job step didn't stop within {:?}, killing it",
+                STEP_TERMINATION_TIMEOUT
+            );
+            signal_step(process_group, libc::SIGKILL, sudo_user);
+            status.await?
+        }
+    };
+    if timed_out {
+        // a hung script is a failure of the job, not of the executor
+        Err(ScriptFailure(status)).context("Job step exceeded its timeout")
+    } else {
+        Err(anyhow!("Job step was cancelled"))
+    }
+}
+
+/// Failure of the job script itself, as opposed to a failure of the executor or container runtime
+#[derive(Debug)]
+struct ScriptFailure(ExitStatus);
+
+impl std::fmt::Display for ScriptFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Job script failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for ScriptFailure {}
+
+fn check_script_status(status: ExitStatus) -> anyhow::Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(ScriptFailure(status).into())
+    }
+}
+
+/// Adds to the counters in the metrics textfile if configured. Metrics are only informational,
+/// so failing to write them doesn't fail the job
+fn record_metrics(context: &JobContext, increments: &[(&str, String, f64)]) {
+    let Some(metrics) = &context.config.metrics else {
+        return;
+    };
+    if let Err(e) = executor_metrics::record(Path::new(&metrics.textfile), increments) {
+        warn!("Failed recording metrics: {:?}", e);
+    }
+}
+
+/// Result label of a job step in the metrics, distinguishing failures like get_failure_exit_code
+fn get_step_status(result: &anyhow::Result<()>) -> &'static str {
+    match result {
+        Ok(()) => "success",
+        Err(e) if e.downcast_ref::<ScriptFailure>().is_some() => "build_failure",
+        Err(_) => "system_failure",
+    }
+}
+
+/// Exit code to report a failed step with to gitlab-runner: only failures of the job script
+/// are build failures, all other errors (pulling images, starting containers, ...) are system failures
+fn get_failure_exit_code(
+    error: &anyhow::Error,
+    variable: impl Fn(&str) -> Option<String>,
+) -> Option<i32> {
+    let name = if error.downcast_ref::<ScriptFailure>().is_some() {
+        "BUILD_FAILURE_EXIT_CODE"
+    } else {
+        "SYSTEM_FAILURE_EXIT_CODE"
+    };
+    variable(name)?.parse().ok()
+}
+
+/// Reports the failure of a step via the custom executor exit codes,
+/// returning the error unchanged if the executor was not started by gitlab-runner
+fn exit_with_failure(error: anyhow::Error) -> anyhow::Error {
+    let Some(code) = get_failure_exit_code(&error, |name| std::env::var(name).ok()) else {
+        return error;
+    };
+    // gitlab-runner evaluates allow_failure:exit_codes using the exit code of the script
+    if let Some(ScriptFailure(status)) = error.downcast_ref() {
+        if let (Ok(path), Some(script_code)) =
+            (std::env::var("BUILD_EXIT_CODE_FILE"), status.code())
+        {
+            if let Err(e) = fs::write(&path, script_code.to_string()) {
+                warn!("Failed writing build exit code file {:?}: {:?}", path, e);
+            }
+        }
+    }
+    error!("{:?}", error);
+    std::process::exit(code);
+}
+
+/// Runs a job step directly on the host, or inside a Nix or Guix environment
+async fn run_host_step(
+    context: &JobContext,
+    script_path: &PathBuf,
+    step_name: &str,
+    env_changes: &[EnvChange],
+) -> anyhow::Result<()> {
+    let shell_command = get_shell_command(&context.config)?;
+    let mut run_command =
+        build_environment_command(context, &shell_command, script_path, step_name)?;
+    run_command.envs(env_changes.iter().map(|c| (&c.name, &c.value)));
+    debug!("Executing step with command {:?}", run_command);
+    let status = wait_for_step(
+        run_command,
+        get_step_timeout(&context.env, step_name, Utc::now()),
+        get_sudo_user(&context.config),
+    )
+    .await?;
+    check_script_status(status)
+}
+
+/// Builds the job image from its definition file, wrapped in a collapsible section of the job log
+fn build_job_image(context: &JobContext, definition: &str) -> anyhow::Result<PathBuf> {
+    let config = &context.config;
+    // this unwrap can't fail because prepare_step checked that image_build is configured
+    let image_build = config.image_build.as_ref().unwrap();
+    // images built from the same definition in other projects or commits copy different files
+    let source = format!(
+        "{}@{}",
+        std::env::var("CUSTOM_ENV_CI_PROJECT_ID").unwrap_or_default(),
+        std::env::var("CUSTOM_ENV_CI_COMMIT_SHA").unwrap_or_default()
     );
+    section_start("meta_runner_image_build", "Building job image", true);
+    let result = resolve_project_path(&context.env.project_dir, Path::new(definition)).and_then(
+        |definition| {
+            image_build::build_image(
+                image_build,
+                &config.apptainer_executable,
+                &config.image_dir,
+                &definition,
+                &source,
+                &context.env.job_id,
+            )
+        },
+    );
+    section_end("meta_runner_image_build");
+    result.context("Failed building job image")
+}
+
+/// Runs a job step inside the job container created in the prepare step
+async fn run_docker_step(
+    context: &JobContext,
+    script_path: &Path,
+    step_name: &str,
+    env_changes: &[EnvChange],
+) -> anyhow::Result<()> {
     let env = &context.env;
     let config = &context.config;
-    let image = &env.image;
-    let image_path = config.image_dir.join(build_image_filename(image));
+    let shell_command = get_container_shell_command(context, step_name)?;
+    let container = docker::container_name(&env.job_id);
+    // the scripts are located in a temporary directory that is not mounted into the container
+    docker::copy_script(
+        &config.docker_executable,
+        &container,
+        script_path,
+        step_name,
+    )?;
+    let mut run_command = new_step_command(&[], &config.docker_executable);
+    run_command
+        .arg("exec")
+        .arg("--workdir")
+        .arg(&env.builds_dir)
+        .args(&context.extra_flags)
+        .args(
+            env_changes
+                .iter()
+                .flat_map(|c| ["--env".to_owned(), format!("{}={}", c.name, c.value)]),
+        )
+        .arg(&container)
+        .args(shell_command)
+        .arg(docker::script_path(step_name))
+        .arg(step_name)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null());
+    debug!("Executing step with command {:?}", run_command);
+    let status = wait_for_step(
+        run_command,
+        get_step_timeout(&context.env, step_name, Utc::now()),
+        get_sudo_user(&context.config),
+    )
+    .await?;
+    check_script_status(status)
+}
+
+/// Runs a job step inside the enroot container created in the prepare step
+async fn run_enroot_step(
+    context: &JobContext,
+    script_path: &Path,
+    step_name: &str,
+    env_changes: &[EnvChange],
+) -> anyhow::Result<()> {
+    let env = &context.env;
+    let config = &context.config;
+    let shell_command = get_container_shell_command(context, step_name)?;
     // mount script, builds and cache dir
-    let binds: Vec<_> = [script_path, &env.builds_dir, &config.cache_dir]
+    let mounts: Vec<_> = [script_path, &env.builds_dir, &config.cache_dir]
         .iter()
-        .map(|v| v.as_os_str().to_owned())
-        .chain(config.mount.iter().map(|v| v.clone().into()))
+        .map(|v| {
+            let path = v.to_string_lossy();
+            format!("{}:{}", path, path)
+        })
+        .chain(get_mounts(config)?.iter().map(MountSpec::to_enroot))
         .collect();
-    let bind_flags = binds
-        .iter()
-        .map(|mount| [OsStr::new("--bind"), &mount])
-        .flatten();
-    let mut run_command = async_process::Command::new(&config.apptainer_executable);
+    let mut run_command = new_step_command(
+        &get_step_wrapper(context, step_name)?,
+        &config.enroot_executable,
+    );
     run_command
         .current_dir(&env.builds_dir)
-        .arg("exec")
-        .arg("--no-home")
-        .arg("--writable-tmpfs")
-        .arg("--cleanenv")
-        .args(bind_flags)
+        .arg("start")
+        .arg("--rw")
+        .args(&context.extra_flags)
+        .args(
+            mounts
+                .into_iter()
+                .flat_map(|mount| ["--mount".to_owned(), mount]),
+        )
+        .args(
+            env_changes
+                .iter()
+                .flat_map(|c| ["--env".to_owned(), format!("{}={}", c.name, c.value)]),
+        );
+    // enroot's nvidia hook makes only the devices selected by this variable available
+    let gpus = get_allocated_gpus(|name| std::env::var(name).ok());
+    if config.gpu_nvidia {
+        run_command.arg("--env").arg(format!(
+            "NVIDIA_VISIBLE_DEVICES={}",
+            gpus.nvidia.as_deref().unwrap_or("all")
+        ));
+    }
+    if config.gpu_intel {
+        run_command.arg("--mount").arg("/dev/dri:/dev/dri");
+    }
+    if let Some(gpus) = gpus.amd.filter(|_| config.gpu_amd) {
+        run_command
+            .arg("--env")
+            .arg(format!("ROCR_VISIBLE_DEVICES={}", gpus));
+    }
+    run_command
+        .arg(enroot::container_name(&env.job_id))
+        .args(shell_command)
+        .arg(script_path)
+        .arg(step_name)
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .stdin(Stdio::null());
-    // handle additional flags
-    if config.gpu_amd {
-        run_command.arg("--rocm");
-    }
-    if config.gpu_nvidia {
-        run_command.arg("--nv");
+    debug!("Executing step with command {:?}", run_command);
+    let status = wait_for_step(
+        run_command,
+        get_step_timeout(&context.env, step_name, Utc::now()),
+        get_sudo_user(&context.config),
+    )
+    .await?;
+    check_script_status(status)
+}
+
+async fn run_step(context: &JobContext, script_path: &Path, step_name: &str) -> anyhow::Result<()> {
+    debug!(
+        "Executing run step {} for job {} with runner {}",
+        step_name, context.env.job_id, context.runner_name
+    );
+    let recorded = get_builds_dir_owner(context)
+        .and_then(|owner| retention::record_activity(&context.env.builds_dir, owner));
+    if let Err(e) = recorded {
+        warn!("{:?}", e);
     }
+    let env_changes = setup_environment(context, step_name).await?;
+    // the environment file is written next to the script, before it is handed over
+    let original_script_path = script_path;
+    let script_path = &hand_over_file(
+        context,
+        script_path,
+        &format!("script-{}", step_name),
+        0o500,
+    )?;
+    let image_path = match get_container_image(context) {
+        Some(ContainerImage::Pulled(_))
+            if context.config.backend == GitLabExecutorBackend::Docker =>
+        {
+            return run_docker_step(context, script_path, step_name, &env_changes).await
+        }
+        Some(ContainerImage::Pulled(_))
+            if context.config.backend == GitLabExecutorBackend::Enroot =>
+        {
+            return run_enroot_step(context, script_path, step_name, &env_changes).await
+        }
+        Some(ContainerImage::Pulled(image)) => get_image_path(&context.config, image),
+        // the definition is only available once the sources were fetched,
+        // so runner-internal steps run on the host
+        Some(ContainerImage::Built(definition)) if is_user_step(step_name) => {
+            build_job_image(context, definition)?
+        }
+        _ => return run_host_step(context, script_path, step_name, &env_changes).await,
+    };
+    let env = &context.env;
+    let config = &context.config;
+    let shell_command = get_container_shell_command(context, step_name)?;
+    let mut run_command = new_step_command(
+        &get_step_wrapper(context, step_name)?,
+        &config.apptainer_executable,
+    );
+    run_command
+        .current_dir(&env.builds_dir)
+        .arg("exec")
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null());
+    let container_script_path = if uses_job_instance(context) {
+        // the mounts of the instance are fixed, so the script is copied into the builds directory
+        let copied_script = env.builds_dir.join(format!(".meta-runner-{}", step_name));
+        fs::copy(script_path, &copied_script)
+            .context(format!("Failed copying step script to {:?}", copied_script))?;
+        run_command
+            .arg("--cleanenv")
+            .args(get_apptainer_env_args(
+                context,
+                original_script_path,
+                step_name,
+                &env_changes,
+            )?)
+            .arg(format!(
+                "instance://{}",
+                docker::container_name(&env.job_id)
+            ));
+        copied_script
+    } else {
+        run_command
+            .args(get_apptainer_container_args(context, Some(script_path))?)
+            .args(get_apptainer_env_args(
+                context,
+                original_script_path,
+                step_name,
+                &env_changes,
+            )?)
+            .arg(image_path);
+        script_path.clone()
+    };
     // add positional arguments
     run_command
-        .arg(image_path)
-        .arg("bash")
-        .arg("-l")
-        .arg(script_path)
+        .args(shell_command)
+        .arg(container_script_path)
         .arg(step_name);
     debug!("Executing step with command {:?}", run_command);
     // execute process
-    let mut run_process = run_command.spawn()?;
-    let status = run_process.status().await?;
-    if status.success() {
-        Ok(())
-    } else {
-        Err(anyhow!("Subprocess failed: {:?}", status))
+    let status = wait_for_step(
+        run_command,
+        get_step_timeout(&context.env, step_name, Utc::now()),
+        get_sudo_user(&context.config),
+    )
+    .await?;
+    check_script_status(status)
+}
+
+/// Returns whether the job steps are executed in an apptainer instance started by the prepare step
+fn uses_job_instance(context: &JobContext) -> bool {
+    context.config.reuse_instance
+        && context.config.backend == GitLabExecutorBackend::Apptainer
+        && matches!(
+            get_container_image(context),
+            Some(ContainerImage::Pulled(_))
+        )
+}
+
+/// Builds the arguments setting up the job container, shared by apptainer exec and apptainer instance start
+fn get_apptainer_container_args(
+    context: &JobContext,
+    script_path: Option<&Path>,
+) -> anyhow::Result<Vec<OsString>> {
+    let env = &context.env;
+    let config = &context.config;
+    // mount script, builds and cache dir
+    let mut binds: Vec<OsString> = script_path
+        .into_iter()
+        .chain([env.builds_dir.as_path(), config.cache_dir.as_path()])
+        .map(|v| v.as_os_str().to_owned())
+        .chain(get_mounts(config)?.iter().map(|v| v.to_bind().into()))
+        .collect();
+    // apptainer has no equivalent of --nv or --rocm for Intel GPUs
+    if config.gpu_intel {
+        binds.push("/dev/dri".into());
+        binds.extend(find_level_zero_libraries().into_iter().map(|library| {
+            let mut bind = library.clone().into_os_string();
+            bind.push(":");
+            bind.push(Path::new(APPTAINER_LIBRARY_DIR).join(library.file_name().unwrap()));
+            bind
+        }));
+    }
+    // resolve the service aliases inside the container
+    if !context.services.is_empty() {
+        let mut hosts_bind = services::hosts_file(&env.builds_dir).into_os_string();
+        hosts_bind.push(":/etc/hosts");
+        binds.push(hosts_bind);
+    }
+    let mut args: Vec<OsString> = vec!["--cleanenv".into()];
+    match &config.mount_home {
+        GitLabHomeMount::None => args.push("--no-home".into()),
+        GitLabHomeMount::Host => (),
+        GitLabHomeMount::Path(path) => args.extend(["--home".into(), path.into()]),
+    }
+    args.extend(binds.into_iter().flat_map(|mount| ["--bind".into(), mount]));
+    args.extend(config.exec_args.iter().map(OsString::from));
+    args.extend(context.extra_flags.iter().map(OsString::from));
+    match get_overlay_path(context) {
+        Some(overlay) => args.extend(["--overlay".into(), overlay.into_os_string()]),
+        None => args.push("--writable-tmpfs".into()),
+    };
+    if config.gpu_amd {
+        args.push("--rocm".into());
+    }
+    if config.gpu_nvidia {
+        args.push("--nv".into());
+    }
+    if let Some(network) = config.network.as_deref().filter(|&n| n != "host") {
+        args.extend(["--net".into(), "--network".into(), network.into()]);
+        args.extend(
+            config
+                .network_args
+                .iter()
+                .flat_map(|arg| ["--network-args".into(), arg.into()]),
+        );
+    }
+    Ok(args)
+}
+
+/// Makes a file written by the executor readable for the run_as user by installing it into the
+/// builds directory, since the files of gitlab-runner are only readable by the runner's user
+fn hand_over_file(
+    context: &JobContext,
+    path: &Path,
+    name: &str,
+    mode: u32,
+) -> anyhow::Result<PathBuf> {
+    let Some(user) = &context.config.run_as else {
+        return Ok(path.to_path_buf());
+    };
+    let handed_over = context
+        .env
+        .builds_dir
+        .join(format!(".meta-runner-{}", name));
+    run_as::install_file(path, &handed_over, run_as::lookup_user(user)?, mode)
+        .context(format!("Failed handing {:?} over to user {}", path, user))?;
+    Ok(handed_over)
+}
+
+/// Builds the arguments setting the environment of a job step inside an apptainer container
+fn get_apptainer_env_args(
+    context: &JobContext,
+    script_path: &Path,
+    step_name: &str,
+    env_changes: &[EnvChange],
+) -> anyhow::Result<Vec<OsString>> {
+    let config = &context.config;
+    let mut args: Vec<OsString> = Vec::new();
+    // all GPUs of the node are mounted, so the visibility variables restrict them to the allocated ones
+    let gpus = get_allocated_gpus(|name| std::env::var(name).ok());
+    if let Some(gpus) = gpus.amd.filter(|_| config.gpu_amd) {
+        args.extend([
+            "--env".into(),
+            format!("ROCR_VISIBLE_DEVICES={}", gpus).into(),
+        ]);
+    }
+    if let Some(gpus) = gpus.nvidia.filter(|_| config.gpu_nvidia) {
+        args.extend([
+            "--env".into(),
+            format!("CUDA_VISIBLE_DEVICES={}", gpus).into(),
+        ]);
+    }
+    if !env_changes.is_empty() {
+        let env_file = script_path.with_extension("env");
+        let content: Vec<_> = env_changes
+            .iter()
+            .map(EnvChange::to_env_file_line)
+            .collect();
+        fs::write(&env_file, content.join("\n"))
+            .context(format!("Failed writing environment file {:?}", env_file))?;
+        let env_file = hand_over_file(context, &env_file, &format!("env-{}", step_name), 0o400)?;
+        args.extend(["--env-file".into(), env_file.into_os_string()]);
+    }
+    Ok(args)
+}
+
+/// Starts the apptainer instance all steps of the job are executed in
+fn start_job_instance(context: &JobContext, image: &str) -> anyhow::Result<()> {
+    let config = &context.config;
+    let instance = docker::container_name(&context.env.job_id);
+    let mut command = std::process::Command::new(&config.apptainer_executable);
+    command
+        .current_dir(&context.env.builds_dir)
+        .arg("instance")
+        .arg("start")
+        .args(get_apptainer_container_args(context, None)?)
+        .arg(get_image_path(config, image))
+        .arg(&instance)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null());
+    debug!("Starting job instance with command {:?}", command);
+    let status = command
+        .status()
+        .context(format!("Failed spawning {:?}", command))?;
+    if !status.success() {
+        Err(anyhow!("Starting job instance failed: {:?}", status))?;
     }
+    Ok(())
 }
 
 fn cleanup_step(context: &JobContext) -> anyhow::Result<()> {
@@ -274,45 +2685,627 @@ fn cleanup_step(context: &JobContext) -> anyhow::Result<()> {
         "Executing cleanup step for job {} with runner {}",
         context.env.job_id, context.runner_name
     );
-    debug!("Deleting builds_dir {:?}", context.env.builds_dir);
-    std::fs::remove_dir_all(&context.env.builds_dir)?;
+    let mut instances: Vec<_> = (0..context.services.len())
+        .map(|index| services::instance_name(&context.env.job_id, index))
+        .collect();
+    if uses_job_instance(context) {
+        instances.push(docker::container_name(&context.env.job_id));
+    }
+    for instance in instances {
+        if let Err(e) = services::stop(&context.config.apptainer_executable, &instance) {
+            warn!("{:?}", e);
+        }
+    }
+    if context.config.backend == GitLabExecutorBackend::Docker {
+        // the builds directory is retained or deleted regardless of whether this succeeds
+        if let Err(e) = docker::remove_container(
+            &context.config.docker_executable,
+            &docker::container_name(&context.env.job_id),
+        ) {
+            warn!("{:?}", e);
+        }
+    }
+    if context.config.backend == GitLabExecutorBackend::Enroot
+        && get_container_image(context).is_some()
+    {
+        if let Err(e) = enroot::remove_container(
+            &context.config.enroot_executable,
+            &enroot::container_name(&context.env.job_id),
+        ) {
+            warn!("{:?}", e);
+        }
+    }
+    // an interrupted pull leaves its temporary files and credentials behind
+    remove_job_tmp_dir(&context.config, &context.env.job_id);
+    remove_secret_dirs(&context.env.job_id);
+    if context.config.run_as.is_some() && fs::exists(&context.env.builds_dir)? {
+        // the builds directory is retained or deleted by the runner's user
+        let ids = run_as::current_user_ids();
+        if let Err(e) = run_as::chown_recursive(&context.env.builds_dir, ids) {
+            warn!("Failed taking back builds_dir: {:?}", e);
+        }
+    }
+    for (name, cache) in &context.config.caches {
+        if let Some(max_size) = cache.max_size {
+            if let Err(e) = caches::prune(Path::new(&cache.dir), max_size * 1024 * 1024) {
+                warn!("Failed pruning cache {}: {:?}", name, e);
+            }
+        }
+    }
+    let builds_dir = &context.env.builds_dir;
+    // persistent overlays are kept for the following jobs
+    if context.config.overlay.is_none() {
+        if let Some(overlay) = get_overlay_path(context).filter(|path| path.exists()) {
+            debug!("Deleting overlay {:?}", overlay);
+            if let Err(e) = fs::remove_file(&overlay) {
+                warn!("Failed deleting overlay {:?}: {:?}", overlay, e);
+            }
+        }
+    }
+    if !is_per_job_layout(context.config.builds_dir_layout.as_deref()) {
+        debug!("Keeping builds_dir {:?}, which may be shared", builds_dir);
+        return Ok(());
+    }
+    if let Some(failed_builds) = &context.config.failed_builds {
+        if retention::job_failed(builds_dir) {
+            match retention::retain_failed_build(
+                failed_builds,
+                builds_dir,
+                &context.runner_name,
+                &context.env.job_id,
+            ) {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("Failed retaining builds_dir of failed job: {:?}", e),
+            }
+        }
+    }
+    debug!("Deleting builds_dir {:?}", builds_dir);
+    if fs::exists(builds_dir)? {
+        std::fs::remove_dir_all(builds_dir)?;
+    }
     Ok(())
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 1)]
-pub async fn exec(paths: &cli::Paths, options: &cli::ExecutorOptions) -> anyhow::Result<()> {
+async fn exec_step(paths: &cli::Paths, options: &cli::ExecutorOptions) -> anyhow::Result<()> {
     debug!(
         "Starting executor with paths {:?} and options {:?}",
         paths, options
     );
-    let full_config = read_config(&paths.config_file).context(format!(
-        "Failed reading config file {:?}",
-        paths.config_file
-    ))?;
-    debug!("Loaded config {:?}", full_config);
-    let env = get_env().context("Failed parsing environment variables")?;
+    let mut env = match options.command {
+        // pre-warming runs outside of a job, e.g. from the launch command
+        cli::ExecutorCommand::Prewarm => get_prewarm_env(),
+        _ => get_env().context("Failed parsing environment variables")?,
+    };
     debug!("Parsed environment {:?}", env);
     let runner_name = options.runner_name.clone();
-    let instance = full_config
-        .runners
-        .get(&runner_name)
-        .ok_or(anyhow!("Unknown runner instance {}", runner_name))?;
-    debug!("Runner instance {:?}", instance);
-    let config = expand_executor_config_template(&full_config, &runner_name, &instance)
-        .context("Failed expanding executor config template")?;
+    let (mut config, state_db_path) = match &options.embedded_config {
+        Some(embedded) => {
+            let embedded: EmbeddedExecutorConfig = serde_json::from_str(embedded)
+                .context("Failed parsing embedded executor config")?;
+            (embedded.config, embedded.state_db_path)
+        }
+        None => {
+            let full_config = read_config(&paths.config_file).context(format!(
+                "Failed reading config file {:?}",
+                paths.config_file
+            ))?;
+            debug!("Loaded config {:?}", full_config);
+            let instance = full_config
+                .runners
+                .get(&runner_name)
+                .ok_or(anyhow!("Unknown runner instance {}", runner_name))?;
+            debug!("Runner instance {:?}", instance);
+            let config = expand_executor_config_template(&full_config, &runner_name, instance)
+                .context("Failed expanding executor config template")?;
+            let state_db_path = get_state_db_path(&paths.data_dir, &full_config.name);
+            (config, state_db_path)
+        }
+    };
+    let extra_flags = job_overrides::apply(&mut config, |name| {
+        std::env::var(format!("CUSTOM_ENV_{}", name)).ok()
+    })
+    .context("Invalid executor overrides")?;
     debug!("Instance config {:?}", config);
+    env.image = env.image.or(config.default_image.clone());
+    let job_response = services::read_job_response().context("Failed reading job response")?;
     let context = JobContext {
         runner_name,
         env,
+        services: job_response.services,
+        entrypoint: job_response.image.entrypoint(),
+        extra_flags,
         config,
+        state_db_path,
     };
     match &options.command {
         cli::ExecutorCommand::Config => config_step(&context),
-        cli::ExecutorCommand::Prepare => prepare_step(&context).await,
+        cli::ExecutorCommand::Prepare => {
+            // cancelling the prepare step also kills running pulls
+            let result = tokio::select! {
+                result = prepare_step(&context) => result,
+                result = termination_signal() => {
+                    result.and(Err(anyhow!("Prepare step was cancelled")))
+                }
+            };
+            // failed prepare steps are reported to the run loop, which stops using unhealthy instances
+            let error = result.as_ref().err().map(|e| format!("{:#}", e));
+            if let Err(e) = StateDb::open(&context.state_db_path)
+                .and_then(|db| db.record_prepare_result(&context.runner_name, error.as_deref()))
+            {
+                warn!("Failed recording prepare result: {:?}", e);
+            }
+            result
+        }
         cli::ExecutorCommand::Run {
             script_name,
             step_name,
-        } => run_step(&context, script_name, step_name).await,
+        } => {
+            // after_script and the *_on_failure steps run after a failed job script
+            let job_failed = retention::job_failed(&context.env.builds_dir);
+            let description = get_step_description(step_name);
+            if job_failed {
+                info!(
+                    "The job failed in an earlier step, continuing with {}",
+                    step_name
+                );
+            }
+            section_start(
+                &format!("meta_runner_{}", step_name),
+                &format!("{} ({})", description, step_name),
+                false,
+            );
+            let start = Instant::now();
+            let result = run_step(&context, script_name, step_name)
+                .await
+                .context(format!("{} ({}) failed", description, step_name));
+            section_end(&format!("meta_runner_{}", step_name));
+            let runner = ("runner", context.runner_name.as_str());
+            let step = ("step", step_name.as_str());
+            record_metrics(
+                &context,
+                &[
+                    (
+                        "steps_total",
+                        executor_metrics::labels(&[
+                            runner,
+                            step,
+                            ("status", get_step_status(&result)),
+                            ("job_failed", if job_failed { "true" } else { "false" }),
+                        ]),
+                        1.0,
+                    ),
+                    (
+                        "step_seconds_total",
+                        executor_metrics::labels(&[runner, step]),
+                        start.elapsed().as_secs_f64(),
+                    ),
+                ],
+            );
+            if result.is_err() && is_user_step(step_name) {
+                let marked = get_builds_dir_owner(&context)
+                    .and_then(|owner| retention::mark_failed(&context.env.builds_dir, owner));
+                if let Err(e) = marked {
+                    warn!("Failed marking job as failed: {:?}", e);
+                }
+            }
+            result
+        }
         cli::ExecutorCommand::Cleanup => cleanup_step(&context),
+        cli::ExecutorCommand::Prewarm => prewarm_step(&context).await,
+    }
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 1)]
+pub async fn exec(paths: &cli::Paths, options: &cli::ExecutorOptions) -> anyhow::Result<()> {
+    exec_step(paths, options).await.map_err(exit_with_failure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_diff() {
+        let before = parse_env_output(b"PATH=/usr/bin\0HOME=/home/user\0SHLVL=1\0");
+        let after = parse_env_output(
+            b"PATH=/spack/bin:/usr/bin\0HOME=/home/user\0SHLVL=2\0SPACK_ENV=/env\0",
+        );
+        let changes = diff_environment(&before, after);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes[0].to_env_file_line(),
+            "export PATH='/spack/bin:'\"${PATH}\""
+        );
+        assert_eq!(changes[1].to_env_file_line(), "export SPACK_ENV='/env'");
+    }
+
+    #[test]
+    fn setup_script() {
+        assert_eq!(
+            build_setup_script(&["gcc/13".to_owned(), "cuda".to_owned()], None),
+            "module load 'gcc/13' 'cuda'"
+        );
+        assert_eq!(build_setup_script(&[], None), "");
+    }
+
+    #[test]
+    fn allocated_gpus() {
+        let variables = |vars: &'static [(&str, &str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        assert_eq!(
+            get_allocated_gpus(variables(&[])),
+            AllocatedGpus {
+                amd: None,
+                nvidia: None
+            }
+        );
+        assert_eq!(
+            get_allocated_gpus(variables(&[
+                ("SLURM_JOB_GPUS", "2,3"),
+                ("CUDA_VISIBLE_DEVICES", "1")
+            ])),
+            AllocatedGpus {
+                amd: Some("2,3".into()),
+                nvidia: Some("1".into())
+            }
+        );
+    }
+
+    #[test]
+    fn user_steps() {
+        assert!(is_user_step("build_script"));
+        assert!(is_user_step("step_release"));
+        assert!(is_user_step("after_script"));
+        assert!(!is_user_step("get_sources"));
+        assert!(!is_user_step("upload_artifacts_on_success"));
+    }
+
+    #[test]
+    fn image_filenames() {
+        assert_eq!(
+            build_image_filename("docker://ubuntu:24.04", "arm64"),
+            PathBuf::from("ubuntu_24.04_arm64.sif")
+        );
+        assert_eq!(
+            build_image_filename("docker://ghcr.io/org/image", "amd64"),
+            PathBuf::from("image_latest_amd64.sif")
+        );
+        assert_eq!(
+            build_image_filename("https://example.com/image.sif", "amd64"),
+            PathBuf::from("image.sif")
+        );
+    }
+
+    #[test]
+    fn local_images() {
+        assert!(get_local_image("docker://ubuntu:24.04").is_none());
+        assert_eq!(
+            build_image_pull_url("file:///shared/images/base.tar.gz"),
+            "docker-archive:/shared/images/base.tar.gz"
+        );
+        assert_eq!(
+            build_image_pull_url("file:///shared/images/base.sif"),
+            "/shared/images/base.sif"
+        );
+        assert_eq!(
+            build_image_pull_url("docker-archive:/shared/base.tar"),
+            "docker-archive:/shared/base.tar"
+        );
+        let filename = build_image_filename("file:///shared/images/base.tar.gz", "amd64");
+        assert!(filename.to_string_lossy().starts_with("base_"));
+        assert_eq!(filename.extension().unwrap(), "sif");
+        assert_ne!(
+            filename,
+            build_image_filename("file:///other/images/base.tar.gz", "amd64")
+        );
+    }
+
+    #[test]
+    fn shell_command() {
+        assert_eq!(get_default_shell_command(None).unwrap(), vec!["bash", "-l"]);
+        assert_eq!(get_default_shell_command(Some("sh")).unwrap(), vec!["sh"]);
+        assert_eq!(get_default_shell_command(Some("pwsh")).unwrap()[0], "pwsh");
+        assert!(get_default_shell_command(Some("cmd")).is_err());
+        assert!(check_shell_support(Some("pwsh"), GitLabExecutorBackend::Apptainer).is_err());
+        assert!(check_shell_support(Some("powershell"), GitLabExecutorBackend::Docker).is_err());
+        assert!(check_shell_support(Some("pwsh"), GitLabExecutorBackend::Nix).is_ok());
+        assert!(check_shell_support(Some("sh"), GitLabExecutorBackend::Enroot).is_ok());
+        assert!(check_shell_support(None, GitLabExecutorBackend::Apptainer).is_ok());
+    }
+
+    #[tokio::test]
+    async fn pull_timeout() {
+        let mut command = async_process::Command::new("sleep");
+        command.arg("10").kill_on_drop(true);
+        let start = std::time::Instant::now();
+        assert!(run_pull_attempt(&mut command, Some(1)).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let mut command = async_process::Command::new("true");
+        assert!(run_pull_attempt(&mut command, None).await.is_ok());
+    }
+
+    #[test]
+    fn concurrent_pull_lock() {
+        let image_dir = std::env::temp_dir().join(format!("pull-lock-{}", std::process::id()));
+        fs::create_dir_all(&image_dir).unwrap();
+        let filepath = image_dir.join("image.sif");
+        let (lock_file, waited) = lock_image(&filepath).unwrap();
+        assert!(!waited);
+        let waiting = std::thread::spawn(move || lock_image(&filepath).unwrap().1);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(lock_file);
+        assert!(waiting.join().unwrap());
+        fs::remove_dir_all(&image_dir).unwrap();
+    }
+
+    #[test]
+    fn secret_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let job_id = format!("secrets-{}", std::process::id());
+        let secret_dir = create_secret_dir(&job_id).unwrap();
+        let mode = fs::metadata(&secret_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert_ne!(create_secret_dir(&job_id).unwrap(), secret_dir);
+        let auth_file = secret_dir.join("auth.json");
+        write_secret_file(&auth_file, "secret").unwrap();
+        // existing files and symlinks are never written through
+        assert!(write_secret_file(&auth_file, "secret").is_err());
+        let link = secret_dir.join("link");
+        std::os::unix::fs::symlink(secret_dir.join("target"), &link).unwrap();
+        assert!(write_secret_file(&link, "secret").is_err());
+        assert!(!secret_dir.join("target").exists());
+        remove_secret_dirs(&job_id);
+        assert!(!secret_dir.exists());
+    }
+
+    #[test]
+    fn project_paths() {
+        let root = std::env::temp_dir().join(format!("project-paths-{}", std::process::id()));
+        let project_dir = root.join("project");
+        fs::create_dir_all(project_dir.join("ci")).unwrap();
+        fs::write(project_dir.join("ci/spack.yaml"), "").unwrap();
+        fs::write(root.join("secret"), "").unwrap();
+        std::os::unix::fs::symlink(root.join("secret"), project_dir.join("link")).unwrap();
+        assert_eq!(
+            resolve_project_path(&project_dir, Path::new("ci/../ci/spack.yaml")).unwrap(),
+            project_dir.canonicalize().unwrap().join("ci/spack.yaml")
+        );
+        assert!(resolve_project_path(&project_dir, Path::new("../secret")).is_err());
+        assert!(resolve_project_path(&project_dir, &root.join("secret")).is_err());
+        assert!(resolve_project_path(&project_dir, Path::new("link")).is_err());
+        assert!(resolve_project_path(&project_dir, Path::new("missing.yaml")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn store_image_dedup() {
+        let image_dir = std::env::temp_dir().join(format!("store-image-{}", std::process::id()));
+        fs::create_dir_all(&image_dir).unwrap();
+        fs::write(image_dir.join("a.1.tmp"), "content").unwrap();
+        // pulls of the same image are rarely identical, only the registry digest is
+        fs::write(image_dir.join("b.2.tmp"), "other content").unwrap();
+        fs::write(image_dir.join("c.3.tmp"), "unknown digest").unwrap();
+        let stored_filename = get_stored_image_filename("sha256:abcd", "amd64");
+        assert_eq!(stored_filename, PathBuf::from("sha256/abcd_amd64.sif"));
+        let stored = Some(stored_filename.as_path());
+        let stored_a = store_image(
+            &image_dir,
+            Path::new("a.1.tmp"),
+            Path::new("a.sif"),
+            stored,
+            "1",
+        )
+        .unwrap();
+        let stored_b = store_image(
+            &image_dir,
+            Path::new("b.2.tmp"),
+            Path::new("b.sif"),
+            stored,
+            "2",
+        )
+        .unwrap();
+        assert_eq!(stored_a, stored_b);
+        assert_eq!(get_image_digest(&stored_a).as_deref(), Some("sha256:abcd"));
+        let stored_c = store_image(
+            &image_dir,
+            Path::new("c.3.tmp"),
+            Path::new("c.sif"),
+            None,
+            "3",
+        )
+        .unwrap();
+        assert_eq!(stored_c, image_dir.join("c.sif"));
+        assert_eq!(get_image_digest(&stored_c), None);
+        assert_eq!(
+            fs::read_to_string(image_dir.join("a.sif")).unwrap(),
+            "content"
+        );
+        assert_eq!(
+            fs::read_to_string(image_dir.join("b.sif")).unwrap(),
+            "content"
+        );
+        assert!(!fs::exists(image_dir.join("a.1.tmp")).unwrap());
+        assert!(!fs::exists(image_dir.join("b.2.tmp")).unwrap());
+        assert!(!fs::exists(image_dir.join("c.3.tmp")).unwrap());
+        fs::remove_dir_all(&image_dir).unwrap();
+    }
+
+    #[test]
+    fn failure_exit_codes() {
+        use std::os::unix::process::ExitStatusExt;
+        let variable = |name: &str| match name {
+            "BUILD_FAILURE_EXIT_CODE" => Some("1".to_owned()),
+            "SYSTEM_FAILURE_EXIT_CODE" => Some("2".to_owned()),
+            _ => None,
+        };
+        let script_error = check_script_status(ExitStatus::from_raw(3 << 8)).unwrap_err();
+        assert_eq!(get_failure_exit_code(&script_error, variable), Some(1));
+        let context_error = script_error.context("Step failed");
+        assert_eq!(get_failure_exit_code(&context_error, variable), Some(1));
+        let pull_error = anyhow!("Pulling image failed");
+        assert_eq!(get_failure_exit_code(&pull_error, variable), Some(2));
+        assert_eq!(get_failure_exit_code(&pull_error, |_| None), None);
+        assert_eq!(get_step_status(&Err(context_error)), "build_failure");
+        assert_eq!(get_step_status(&Err(pull_error)), "system_failure");
+        assert!(check_script_status(ExitStatus::from_raw(0)).is_ok());
+    }
+
+    #[test]
+    fn step_descriptions() {
+        assert_eq!(get_step_description("after_script"), "Running after_script");
+        assert_eq!(
+            get_step_description("upload_artifacts_on_failure"),
+            "Uploading artifacts"
+        );
+        assert_eq!(
+            get_step_description("step_release"),
+            "Executing step release"
+        );
+    }
+
+    #[tokio::test]
+    async fn step_exit_status() {
+        let mut command = new_step_command(&[], "sh");
+        command.arg("-c").arg("exit 3");
+        let status = wait_for_step(command, None, None).await.unwrap();
+        assert_eq!(status.code(), Some(3));
+        let wrapper = vec!["sh".to_owned(), "-c".to_owned(), "exit 4".to_owned()];
+        let status = wait_for_step(new_step_command(&wrapper, "true"), None, None)
+            .await
+            .unwrap();
+        assert_eq!(status.code(), Some(4));
+        let mut command = new_step_command(&[], "sleep");
+        command.arg("60");
+        let start = std::time::Instant::now();
+        let error = wait_for_step(command, Some(Duration::from_millis(50)), None)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<ScriptFailure>().is_some());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn passed_environment() {
+        let variables = [
+            ("SLURM_JOB_ID", "42"),
+            ("SLURMD_NODENAME", "node1"),
+            ("http_proxy", "http://proxy:3128"),
+            ("HOME", "/home/user"),
+        ]
+        .map(|(name, value)| (name.to_owned(), value.to_owned()));
+        let patterns = vec!["SLURM_*".to_owned(), "http?proxy".to_owned()];
+        let passed = get_passed_env(&patterns, variables.into_iter()).unwrap();
+        let names: Vec<_> = passed.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["SLURM_JOB_ID", "http_proxy"]);
+        assert_eq!(
+            passed[1].to_env_file_line(),
+            "export http_proxy='http://proxy:3128'"
+        );
+    }
+
+    #[test]
+    fn free_space() {
+        assert!(get_free_space(&std::env::temp_dir()).unwrap() > 0);
+        assert!(get_free_space(Path::new("/nonexistent/path")).is_err());
+    }
+
+    #[test]
+    fn builds_root() {
+        let scratch = vec!["SLURM_TMPDIR".to_owned(), "TMPDIR".to_owned()];
+        let tmp_dir = std::env::temp_dir();
+        let variable = |name: &str| match name {
+            "SLURM_TMPDIR" => Some("/nonexistent/scratch".to_owned()),
+            "TMPDIR" => Some(tmp_dir.to_string_lossy().into_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            get_builds_root(Path::new("/builds"), &scratch, variable),
+            tmp_dir.join("gitlab-meta-runner-builds")
+        );
+        assert_eq!(
+            get_builds_root(Path::new("/builds"), &scratch, |_| None),
+            PathBuf::from("/builds")
+        );
+    }
+
+    #[test]
+    fn builds_dir_layout() {
+        let variable = |name: &str| match name {
+            "CUSTOM_ENV_CI_JOB_ID" => Some("42".to_owned()),
+            "CUSTOM_ENV_CI_PROJECT_PATH_SLUG" => Some("group-project".to_owned()),
+            "CUSTOM_ENV_CI_PIPELINE_ID" => Some("..".to_owned()),
+            _ => None,
+        };
+        let root = Path::new("/builds");
+        assert_eq!(
+            get_job_builds_dir(root, None, variable).unwrap(),
+            PathBuf::from("/builds/42")
+        );
+        let layout = Some("${CI_PROJECT_PATH_SLUG}/$CI_JOB_ID");
+        assert_eq!(
+            get_job_builds_dir(root, layout, variable).unwrap(),
+            PathBuf::from("/builds/group-project/42")
+        );
+        assert!(is_per_job_layout(layout));
+        assert!(get_job_builds_dir(root, Some("$CI_PIPELINE_ID"), variable).is_err());
+        assert!(get_job_builds_dir(root, Some("$CI_UNDEFINED"), variable).is_err());
+        assert!(get_job_builds_dir(root, Some("/absolute"), variable).is_err());
+        assert!(!is_per_job_layout(Some("$CI_PROJECT_PATH_SLUG")));
+        assert!(is_per_job_layout(None));
+        assert_eq!(get_job_dir_depth(None), Some(1));
+        assert_eq!(get_job_dir_depth(layout), Some(2));
+        assert_eq!(
+            get_job_dir_depth(Some("$CI_JOB_ID-$CI_PROJECT_PATH_SLUG")),
+            None
+        );
+        assert_eq!(get_job_dir_depth(Some("$CI_PROJECT_PATH_SLUG")), None);
+    }
+
+    #[test]
+    fn step_timeout() {
+        let start = DateTime::parse_from_rfc3339("2024-10-01T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        let mut env = JobEnv {
+            job_id: "1".into(),
+            builds_dir: "/builds".into(),
+            project_dir: "/builds/project".into(),
+            image: None,
+            environment: None,
+            image_definition: None,
+            timeout: Some(3600),
+            started_at: Some(start),
+        };
+        let now = start + chrono::Duration::seconds(600);
+        assert_eq!(
+            get_step_timeout(&env, "build_script", now),
+            Some(Duration::from_secs(3000))
+        );
+        let now = start + chrono::Duration::seconds(7200);
+        assert_eq!(
+            get_step_timeout(&env, "step_release", now),
+            Some(Duration::ZERO)
+        );
+        // the steps following a timed out job script still run
+        assert_eq!(
+            get_step_timeout(&env, "after_script", now),
+            Some(AFTER_SCRIPT_TIMEOUT)
+        );
+        assert_eq!(
+            get_step_timeout(&env, "upload_artifacts_on_failure", now),
+            None
+        );
+        env.started_at = None;
+        assert_eq!(
+            get_step_timeout(&env, "build_script", now),
+            Some(Duration::from_secs(3600))
+        );
+        env.timeout = None;
+        assert_eq!(get_step_timeout(&env, "build_script", now), None);
     }
 }
@@ -0,0 +1,113 @@
+use std::{collections::BTreeMap, fmt::Write as _, fs, path::Path};
+
+use anyhow::Context;
+
+/// Help texts of the counters recorded by the executor
+const HELP: [(&str, &str); 5] = [
+    ("pulls_total", "Number of image pulls"),
+    ("pull_seconds_total", "Time spent pulling images"),
+    ("pull_bytes_total", "Size of the pulled image files"),
+    ("steps_total", "Number of executed job steps by result"),
+    ("step_seconds_total", "Wall time of the executed job steps"),
+];
+
+/// Counter samples of the textfile by metric name and labels
+type Samples = BTreeMap<(String, String), f64>;
+
+/// Formats a label set, escaping the label values
+pub fn labels(labels: &[(&str, &str)]) -> String {
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}=\"{}\"",
+                name,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn parse(content: &str) -> Samples {
+    content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            let value = value.parse().ok()?;
+            let (name, labels) = match series.find('{') {
+                Some(index) => series.split_at(index),
+                None => (series, ""),
+            };
+            Some(((name.to_owned(), labels.to_owned()), value))
+        })
+        .collect()
+}
+
+fn render(samples: &Samples) -> String {
+    let mut out = String::new();
+    let mut previous_name = None;
+    for ((name, labels), value) in samples {
+        if previous_name != Some(name) {
+            let help = HELP
+                .iter()
+                .find(|(suffix, _)| name.ends_with(suffix))
+                .map_or("", |(_, help)| help);
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            previous_name = Some(name);
+        }
+        writeln!(out, "{}{} {}", name, labels, value).unwrap();
+    }
+    out
+}
+
+/// Adds the given values to the counters in a Prometheus textfile, e.g. for the node exporter's
+/// textfile collector. The file is locked, since concurrent jobs on the same node update it,
+/// and replaced atomically, so the collector never reads a partial file.
+pub fn record(path: &Path, increments: &[(&str, String, f64)]) -> anyhow::Result<()> {
+    let lock_path = path.with_extension("lock");
+    let lock_file = fs::File::create(&lock_path)
+        .context(format!("Failed creating lock file {:?}", lock_path))?;
+    lock_file.lock().context("Failed locking metrics file")?;
+    let mut samples = parse(&fs::read_to_string(path).unwrap_or_default());
+    for (name, labels, value) in increments {
+        let name = format!("gitlab_meta_runner_executor_{}", name);
+        *samples.entry((name, labels.clone())).or_default() += value;
+    }
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp_path, render(&samples))
+        .context(format!("Failed writing metrics file {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).context(format!("Failed replacing metrics file {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn textfile_counters() {
+        let path = std::env::temp_dir().join(format!("executor-{}.prom", std::process::id()));
+        let runner = labels(&[("runner", "gpu")]);
+        let step = labels(&[("runner", "gpu"), ("step", "build_script")]);
+        record(&path, &[("pulls_total", runner.clone(), 1.0)]).unwrap();
+        record(
+            &path,
+            &[
+                ("pulls_total", runner.clone(), 1.0),
+                ("step_seconds_total", step.clone(), 2.5),
+            ],
+        )
+        .unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("# TYPE gitlab_meta_runner_executor_pulls_total counter\n"));
+        assert!(content.contains("gitlab_meta_runner_executor_pulls_total{runner=\"gpu\"} 2\n"));
+        assert!(content.contains(
+            "gitlab_meta_runner_executor_step_seconds_total{runner=\"gpu\",step=\"build_script\"} 2.5\n"
+        ));
+        assert_eq!(parse(&content).len(), 2);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+}
@@ -10,6 +10,9 @@ pub struct Runner {
     pub cache_dir: String,
     /// How many kilobytes of output to collect, will NOT be variable-expanded
     pub output_limit: Option<u64>,
+    /// The shell used for generating job scripts (bash, sh, pwsh or powershell), will be variable-expanded
+    /// The executor runs the job scripts with this shell, pwsh and powershell can't be used with container backends
+    pub shell: Option<String>,
     /// The executor to use for this runner
     #[serde(flatten)]
     pub executor: Executor,
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use futures::TryFutureExt;
 use gitlab::{
     api::{ignore, paged, projects, runners, users, ApiError, AsyncQuery, Pagination},
@@ -10,17 +11,59 @@ use crate::gitlab_config::RunnerRegistration;
 
 type ApiResult<T> = Result<T, ApiError<RestError>>;
 
+/// Timeout (in seconds) assumed for jobs that don't report their timeout, matching GitLab's default
+pub const DEFAULT_JOB_TIMEOUT: u64 = 3600;
+
 #[derive(Debug, Deserialize)]
 pub struct Project {
     pub id: u64,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct JobRunner {
+    pub id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobPipeline {
+    /// What triggered the pipeline, e.g. push or schedule
+    pub source: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Job {
     pub id: u64,
     pub name: String,
     #[serde(rename = "tag_list")]
     pub tags: Vec<String>,
+    /// The branch or tag the job runs for
+    #[serde(default, rename = "ref")]
+    pub git_ref: String,
+    #[serde(default)]
+    pub pipeline: Option<JobPipeline>,
+    #[serde(default)]
+    pub status: String,
+    /// The runner that picked up the job, if any
+    #[serde(default)]
+    pub runner: Option<JobRunner>,
+    /// When the job was created
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    /// When the job was started by a runner, if it was started yet
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    /// Whether the pipeline may succeed even if the job fails
+    #[serde(default)]
+    pub allow_failure: bool,
+    /// The job timeout in seconds, if reported by GitLab
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+impl Job {
+    pub fn timeout_or_default(&self) -> u64 {
+        self.timeout.unwrap_or(DEFAULT_JOB_TIMEOUT)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -28,12 +71,34 @@ pub struct RunnerParameters {
     pub description: String,
     #[serde(rename = "tag_list")]
     pub tags: Vec<String>,
+    pub paused: bool,
+    pub run_untagged: bool,
 }
 
 pub async fn init_client(host: &str, token: &str) -> Result<AsyncGitlab, GitlabError> {
     Ok(Gitlab::builder(host, token).build_async().await?)
 }
 
+/// Returns whether the error was caused by GitLab rejecting the API token
+pub fn is_auth_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<ApiError<RestError>>(),
+            Some(
+                ApiError::GitlabWithStatus { status, .. } | ApiError::GitlabService { status, .. }
+            ) if *status == http::StatusCode::UNAUTHORIZED
+        )
+    })
+}
+
+/// Returns whether the error was caused by a failed or timed out request to the GitLab API,
+/// as opposed to e.g. a failed launch
+pub fn is_api_error(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.is::<ApiError<RestError>>() || cause.is::<tokio::time::error::Elapsed>())
+}
+
 pub async fn fetch_project(client: &AsyncGitlab, project: &str) -> ApiResult<Project> {
     let endpoint = projects::Project::builder()
         .project(project)
@@ -74,6 +139,48 @@ pub async fn fetch_pending_project_jobs(
         .await?)
 }
 
+pub async fn fetch_job(client: &AsyncGitlab, project: &Project, job_id: u64) -> ApiResult<Job> {
+    let endpoint = projects::jobs::Job::builder()
+        .project(project.id)
+        .job(job_id)
+        .build()
+        .unwrap();
+    endpoint
+        .query_async(client)
+        .and_then(|v| async move {
+            debug!("Fetched job {}: {:?}", job_id, v);
+            Ok(v)
+        })
+        .or_else(|e| async move {
+            debug!("Failed fetching job {}: {:?}", job_id, e);
+            Err(e)
+        })
+        .await
+}
+
+/// Fetches the most recent job executed by the given runner, if any
+pub async fn fetch_latest_runner_job(
+    client: &AsyncGitlab,
+    runner_id: u64,
+) -> ApiResult<Option<Job>> {
+    // runner jobs are sorted by descending ID by default
+    let endpoint = runners::RunnerJobs::builder()
+        .runner(runner_id)
+        .build()
+        .unwrap();
+    paged(endpoint, Pagination::Limit(1))
+        .query_async(client)
+        .and_then(|v: Vec<Job>| async move {
+            debug!("Fetched latest job of runner {}: {:?}", runner_id, v);
+            Ok(v.into_iter().next())
+        })
+        .or_else(|e| async move {
+            debug!("Failed fetching jobs of runner {}: {:?}", runner_id, e);
+            Err(e)
+        })
+        .await
+}
+
 pub async fn add_project_runner(
     client: &AsyncGitlab,
     project: &Project,
@@ -83,9 +190,9 @@ pub async fn add_project_runner(
         .project(project.id)
         .description(runner.description.clone())
         .tags(runner.tags.iter())
-        .paused(false)
+        .paused(runner.paused)
         .locked(true)
-        .run_untagged(false)
+        .run_untagged(runner.run_untagged)
         .build()
         .unwrap();
     Ok(endpoint
@@ -101,6 +208,34 @@ pub async fn add_project_runner(
         .await?)
 }
 
+/// Registers a runner using the legacy registration token flow, for GitLab < 15.10
+pub async fn register_legacy_runner(
+    client: &AsyncGitlab,
+    registration_token: &str,
+    runner: RunnerParameters,
+) -> ApiResult<RunnerRegistration> {
+    let endpoint = runners::CreateRunner::builder()
+        .token(registration_token)
+        .description(runner.description.clone())
+        .tags(runner.tags.iter())
+        .paused(runner.paused)
+        .locked(true)
+        .run_untagged(runner.run_untagged)
+        .build()
+        .unwrap();
+    endpoint
+        .query_async(client)
+        .and_then(|v| async move {
+            debug!("Registered legacy runner {}: {:?}", runner.description, v);
+            Ok(v)
+        })
+        .or_else(|e| async move {
+            debug!("Failed registering legacy runner: {:?}", e);
+            Err(e)
+        })
+        .await
+}
+
 pub async fn update_runner(
     client: &AsyncGitlab,
     runner_id: u64,
@@ -110,9 +245,9 @@ pub async fn update_runner(
     let error_params = params.clone();
     let endpoint = runners::EditRunner::builder()
         .runner(runner_id)
-        .paused(false)
+        .paused(params.paused)
         .locked(true)
-        .run_untagged(false)
+        .run_untagged(params.run_untagged)
         .description(params.description.clone())
         .tags(params.tags.iter())
         .build()
@@ -133,6 +268,32 @@ pub async fn update_runner(
         .await?)
 }
 
+pub async fn set_runner_paused(
+    client: &AsyncGitlab,
+    runner_id: u64,
+    paused: bool,
+) -> ApiResult<()> {
+    let endpoint = runners::EditRunner::builder()
+        .runner(runner_id)
+        .paused(paused)
+        .build()
+        .unwrap();
+    ignore(endpoint)
+        .query_async(client)
+        .and_then(|()| async move {
+            debug!("Set runner {} paused={}", runner_id, paused);
+            Ok(())
+        })
+        .or_else(|e| async move {
+            debug!(
+                "Failed setting runner {} paused={}: {:?}",
+                runner_id, paused, e
+            );
+            Err(e)
+        })
+        .await
+}
+
 pub async fn delete_runner(client: &AsyncGitlab, runner_id: u64) -> ApiResult<()> {
     let endpoint = runners::DeleteRunner::builder()
         .runner(runner_id)
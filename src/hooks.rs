@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use async_process::{Command, Stdio};
+use futures::{future::join_all, AsyncWriteExt};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use tokio::time;
+use tokio_util::task::TaskTracker;
+
+use crate::{
+    config::{GitLabHookConfig, GitLabRunnersConfig, HookEvent},
+    state::unix_timestamp,
+};
+
+/// Time to wait (in seconds) for a hook without a configured timeout
+const DEFAULT_HOOK_TIMEOUT: u32 = 60;
+
+async fn run_hook(hook: &GitLabHookConfig, payload: &str) -> anyhow::Result<()> {
+    let mut command = Command::new(&hook.executable);
+    command
+        .args(hook.args.iter())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true);
+    debug!("Spawning hook process {:?}", command);
+    let mut child = command
+        .spawn()
+        .context(format!("Failed spawning hook process {:?}", command))?;
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        stdin
+            .write_all(payload.as_bytes())
+            .await
+            .context("Failed writing hook payload")?;
+        // dropping stdin closes it, so the hook sees EOF
+    }
+    let timeout_sec = hook.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT) as u64;
+    let status = time::timeout(Duration::from_secs(timeout_sec), child.status())
+        .await
+        .context(format!("Hook process {} timed out", child.id()))??;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Hook process failed with exit code {}", status))
+    }
+}
+
+/// Runs all hooks subscribed to the given event, passing the payload extended by
+/// the event name, meta-runner name and timestamp as JSON via stdin.
+/// The hooks run on a task of the tracker, so slow hooks never delay polling.
+/// Hook failures are only logged, they never affect the meta-runner itself.
+pub fn run_hooks(
+    config: &GitLabRunnersConfig,
+    tasks: &TaskTracker,
+    event: HookEvent,
+    mut payload: Value,
+) {
+    let hooks: Vec<_> = config
+        .hooks
+        .iter()
+        .filter(|(_, hook)| hook.events.contains(&event))
+        .map(|(name, hook)| (name.clone(), hook.clone()))
+        .collect();
+    if hooks.is_empty() {
+        return;
+    }
+    if let Value::Object(map) = &mut payload {
+        map.insert("event".into(), json!(event));
+        map.insert("meta_runner".into(), json!(config.name));
+        map.insert("timestamp".into(), json!(unix_timestamp()));
+    }
+    let payload = payload.to_string();
+    tasks.spawn(async move {
+        let results = join_all(hooks.iter().map(|(_, hook)| run_hook(hook, &payload))).await;
+        for ((name, _), result) in hooks.iter().zip(results) {
+            if let Err(e) = result {
+                warn!("Hook {} for event {:?} failed: {:?}", name, event, e);
+            }
+        }
+    });
+}
@@ -0,0 +1,136 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+
+use crate::config::GitLabImageBuildConfig;
+
+/// Subdirectory of image_dir containing the built image files, named after the SHA-256 digest of their
+/// definition and source
+const BUILT_IMAGE_DIR: &str = "built";
+
+/// Returns whether the given definition file is a Dockerfile, as opposed to an apptainer definition file
+fn is_dockerfile(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| {
+            name == "Dockerfile" || name.starts_with("Dockerfile.") || name.ends_with(".Dockerfile")
+        })
+}
+
+fn build_command(
+    config: &GitLabImageBuildConfig,
+    apptainer_executable: &Path,
+    definition: &Path,
+    context_dir: &Path,
+    image_file: &Path,
+) -> anyhow::Result<Command> {
+    if is_dockerfile(definition) {
+        let builder = config.dockerfile_builder.as_ref().ok_or(anyhow!(
+            "Building images from Dockerfiles requires executor.image_build.dockerfile_builder"
+        ))?;
+        let mut command = Command::new(builder);
+        command.arg(definition).arg(context_dir).arg(image_file);
+        Ok(command)
+    } else {
+        let mut command = Command::new(apptainer_executable);
+        command
+            .arg("build")
+            .args(&config.apptainer_args)
+            .arg(image_file)
+            .arg(definition);
+        Ok(command)
+    }
+}
+
+/// Returns the name of a built image, which depends on the source (project and commit) of the
+/// definition, since the files it copies from the build context aren't part of the definition
+fn get_image_digest(source: &str, definition: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(source.as_bytes());
+    hasher.update([0]);
+    hasher.update(definition);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Builds an image file from the given apptainer definition file or Dockerfile,
+/// reusing the result for all jobs using an identical file from the same source
+pub fn build_image(
+    config: &GitLabImageBuildConfig,
+    apptainer_executable: &Path,
+    image_dir: &Path,
+    definition: &Path,
+    source: &str,
+    job_id: &str,
+) -> anyhow::Result<PathBuf> {
+    let content = fs::read(definition)
+        .context(format!("Failed reading image definition {:?}", definition))?;
+    let digest = get_image_digest(source, &content);
+    let build_dir = image_dir.join(BUILT_IMAGE_DIR);
+    fs::create_dir_all(&build_dir).context("Failed creating built image directory")?;
+    let image_file = build_dir.join(format!("{}.sif", digest));
+    // concurrent jobs using the same definition wait for the first one to build it
+    let lock_file = fs::File::create(image_file.with_extension("lock"))?;
+    lock_file.lock().context("Failed locking built image")?;
+    if fs::exists(&image_file)? {
+        info!("Reusing image built from {:?}", definition);
+        return Ok(image_file);
+    }
+    // relative paths in the definition are resolved relative to its directory
+    let context_dir = definition
+        .parent()
+        .ok_or(anyhow!("Invalid image definition path {:?}", definition))?;
+    let tmp_file = image_file.with_extension(format!("{}.tmp", job_id));
+    let mut command = build_command(
+        config,
+        apptainer_executable,
+        definition,
+        context_dir,
+        &tmp_file,
+    )?;
+    command
+        .current_dir(context_dir)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null());
+    info!("Building image from {:?}", definition);
+    debug!("Executing image build command {:?}", command);
+    let status = command
+        .status()
+        .context(format!("Failed spawning image build process {:?}", command))?;
+    if !status.success() {
+        // don't leave partial images behind
+        let _ = fs::remove_file(&tmp_file);
+        Err(anyhow!("Image build {:?} failed: {:?}", command, status))?;
+    }
+    fs::rename(&tmp_file, &image_file)
+        .context(format!("Renaming {:?} to {:?}", tmp_file, image_file))?;
+    Ok(image_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dockerfile_detection() {
+        assert!(is_dockerfile(Path::new("Dockerfile")));
+        assert!(is_dockerfile(Path::new("ci/Dockerfile.cuda")));
+        assert!(is_dockerfile(Path::new("ci/cuda.Dockerfile")));
+        assert!(!is_dockerfile(Path::new("ci/image.def")));
+    }
+
+    #[test]
+    fn image_digest() {
+        let digest = get_image_digest("42@abc", b"FROM alpine");
+        assert_eq!(digest, get_image_digest("42@abc", b"FROM alpine"));
+        assert_ne!(digest, get_image_digest("43@abc", b"FROM alpine"));
+        assert_ne!(digest, get_image_digest("42@def", b"FROM alpine"));
+        assert_ne!(digest, get_image_digest("42@abc", b"FROM debian"));
+    }
+}
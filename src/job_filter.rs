@@ -0,0 +1,101 @@
+use anyhow::Context;
+use regex::Regex;
+
+use crate::{config::GitLabRunnerInstance, gitlab_wrap::Job, tag_expression::TagExpression};
+
+/// Compiled job filters of a runner instance, which are evaluated in addition to the tag subset check
+#[derive(Debug)]
+pub struct JobFilter {
+    job_name: Option<Regex>,
+    git_ref: Option<Regex>,
+    pipeline_source: Vec<String>,
+    tag_expression: Option<TagExpression>,
+}
+
+/// Compiles a pattern that has to match the whole string
+fn compile_full_match(pattern: &Option<String>) -> anyhow::Result<Option<Regex>> {
+    pattern
+        .as_ref()
+        .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+        .transpose()
+        .context(format!("Invalid pattern {:?}", pattern))
+}
+
+impl JobFilter {
+    pub fn new(instance: &GitLabRunnerInstance) -> anyhow::Result<JobFilter> {
+        Ok(JobFilter {
+            job_name: compile_full_match(&instance.job_name_pattern).context("job_name_pattern")?,
+            git_ref: compile_full_match(&instance.ref_pattern).context("ref_pattern")?,
+            pipeline_source: instance.pipeline_source.clone(),
+            tag_expression: instance
+                .tag_expression
+                .as_deref()
+                .map(TagExpression::parse)
+                .transpose()
+                .context("tag_expression")?,
+        })
+    }
+
+    pub fn matches(&self, job: &Job) -> bool {
+        let source = job.pipeline.as_ref().and_then(|p| p.source.as_deref());
+        self.job_name.as_ref().is_none_or(|r| r.is_match(&job.name))
+            && self
+                .git_ref
+                .as_ref()
+                .is_none_or(|r| r.is_match(&job.git_ref))
+            && (self.pipeline_source.is_empty()
+                || source.is_some_and(|source| self.pipeline_source.iter().any(|s| s == source)))
+            && self
+                .tag_expression
+                .as_ref()
+                .is_none_or(|e| e.evaluate(&job.tags))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gitlab_wrap::JobPipeline;
+
+    #[test]
+    fn filter() {
+        let filter = JobFilter::new(&GitLabRunnerInstance {
+            tags: Vec::new(),
+            launch_priority: None,
+            job_name_pattern: Some("benchmark-.*".into()),
+            ref_pattern: Some("main|release/.*".into()),
+            pipeline_source: vec!["push".into(), "schedule".into()],
+            tag_expression: Some("!long-running".into()),
+            run_untagged: false,
+            max_launches_per_hour: None,
+            max_launches_per_day: None,
+            schedule: Default::default(),
+            config_variables: Default::default(),
+        })
+        .unwrap();
+        let job = |name: &str, git_ref: &str, source: Option<&str>| Job {
+            id: 1,
+            name: name.into(),
+            tags: vec!["cuda".into()],
+            git_ref: git_ref.into(),
+            pipeline: Some(JobPipeline {
+                source: source.map(Into::into),
+            }),
+            status: "pending".into(),
+            runner: None,
+            created_at: None,
+            started_at: None,
+            allow_failure: false,
+            timeout: None,
+        };
+        assert!(filter.matches(&job("benchmark-cuda", "main", Some("push"))));
+        assert!(filter.matches(&job("benchmark-hip", "release/1.0", Some("schedule"))));
+        assert!(!filter.matches(&job("build-benchmark-cuda", "main", Some("push"))));
+        assert!(!filter.matches(&job("benchmark-cuda", "mainline", Some("push"))));
+        assert!(!filter.matches(&job("benchmark-cuda", "main", Some("web"))));
+        assert!(!filter.matches(&job("benchmark-cuda", "main", None)));
+        let mut long_running = job("benchmark-cuda", "main", Some("push"));
+        long_running.tags.push("long-running".into());
+        assert!(!filter.matches(&long_running));
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fs, path::Component};
+
+use anyhow::{anyhow, Context};
+
+use crate::{config::GitLabCustomExecutorConfig, mounts::MountSpec};
+
+const GPU_VARIABLE: &str = "META_RUNNER_GPU";
+const FLAGS_VARIABLE: &str = "META_RUNNER_EXTRA_FLAGS";
+const MOUNTS_VARIABLE: &str = "META_RUNNER_MOUNTS";
+
+/// Parses the requested GPU devices, returning whether AMD, NVIDIA and Intel GPUs should be mounted
+fn parse_gpus(gpus: &str) -> anyhow::Result<(bool, bool, bool)> {
+    let (mut amd, mut nvidia, mut intel) = (false, false, false);
+    for gpu in gpus.split(',').map(str::trim) {
+        match gpu {
+            "amd" => amd = true,
+            "nvidia" => nvidia = true,
+            "intel" => intel = true,
+            "none" | "" => (),
+            gpu => Err(anyhow!("Unknown GPU {} in {}", gpu, GPU_VARIABLE))?,
+        }
+    }
+    Ok((amd, nvidia, intel))
+}
+
+fn check_flag(allowed: &[String], flag: &str) -> anyhow::Result<()> {
+    let name = flag.split_once('=').map_or(flag, |(name, _)| name);
+    if allowed
+        .iter()
+        .any(|allowed| allowed == flag || allowed == name)
+    {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Flag {} in {} is not allowed",
+            flag,
+            FLAGS_VARIABLE
+        ))
+    }
+}
+
+fn check_mount(prefixes: &[String], mount: &str) -> anyhow::Result<()> {
+    let source = MountSpec::parse(mount)
+        .context(format!("Invalid mount in {}", MOUNTS_VARIABLE))?
+        .source;
+    // symlinks must not lead outside of the allowed directories either
+    let resolved = fs::canonicalize(&source).unwrap_or_else(|_| source.clone());
+    let allowed = !source.components().any(|c| c == Component::ParentDir)
+        && [&source, &resolved]
+            .iter()
+            .all(|path| prefixes.iter().any(|prefix| path.starts_with(prefix)));
+    if allowed {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Mount {} in {} is outside of the allowed directories",
+            mount,
+            MOUNTS_VARIABLE
+        ))
+    }
+}
+
+/// Applies the executor options the job overrides via CI variables to the config,
+/// after validating them against executor.job_overrides.
+/// Returns the additional flags to pass to the container runtime.
+pub fn apply(
+    config: &mut GitLabCustomExecutorConfig,
+    variable: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Vec<String>> {
+    let requested: Vec<_> = [GPU_VARIABLE, FLAGS_VARIABLE, MOUNTS_VARIABLE]
+        .into_iter()
+        .filter(|name| variable(name).is_some())
+        .collect();
+    if requested.is_empty() {
+        return Ok(Vec::new());
+    }
+    let allowed = config.job_overrides.clone().ok_or(anyhow!(
+        "The job sets {}, but executor.job_overrides is not configured",
+        requested.join(", ")
+    ))?;
+    if let Some(gpus) = variable(GPU_VARIABLE) {
+        if !allowed.gpu {
+            Err(anyhow!(
+                "The job sets {}, but executor.job_overrides.gpu is not enabled",
+                GPU_VARIABLE
+            ))?;
+        }
+        (config.gpu_amd, config.gpu_nvidia, config.gpu_intel) = parse_gpus(&gpus)?;
+    }
+    let flags = variable(FLAGS_VARIABLE).unwrap_or_default();
+    let flags = flags
+        .split_whitespace()
+        .map(|flag| check_flag(&allowed.flags, flag).map(|_| flag.to_owned()))
+        .collect::<anyhow::Result<_>>()?;
+    let mounts = variable(MOUNTS_VARIABLE).unwrap_or_default();
+    for mount in mounts.split_whitespace() {
+        check_mount(&allowed.mount_prefixes, mount)?;
+        config.mount.push(mount.to_owned());
+    }
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_overrides() {
+        assert_eq!(parse_gpus("nvidia").unwrap(), (false, true, false));
+        assert_eq!(parse_gpus("amd, nvidia").unwrap(), (true, true, false));
+        assert_eq!(parse_gpus("intel").unwrap(), (false, false, true));
+        assert_eq!(parse_gpus("none").unwrap(), (false, false, false));
+        assert!(parse_gpus("tpu").is_err());
+        let flags = vec!["--containall".to_owned(), "--pwd".to_owned()];
+        assert!(check_flag(&flags, "--containall").is_ok());
+        assert!(check_flag(&flags, "--pwd=/tmp").is_ok());
+        assert!(check_flag(&flags, "--fakeroot").is_err());
+        let prefixes = vec!["/scratch".to_owned()];
+        assert!(check_mount(&prefixes, "/scratch/data:/data:ro").is_ok());
+        assert!(check_mount(&prefixes, "/scratch/../etc:/data").is_err());
+        assert!(check_mount(&prefixes, "/scratchy").is_err());
+        assert!(check_mount(&prefixes, "scratch/data").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use crate::config::{
+    GitLabCancelConfig, GitLabLaunchConfig, GitLabLauncherPreset, GitLabQueueCheckConfig,
+};
+
+/// Batch script starting a runner for the launched jobs, passed to the submit command via stdin
+const RUNNER_SCRIPT: &str = "#!/bin/bash\ngitlab-runner run-single --config $CONFIG --runner $NAME --max-builds $NUM_JOBS --wait-timeout 1\n";
+
+/// Submit, query and cancel commands of a batch system
+struct Launcher {
+    submit: (&'static str, &'static [&'static str]),
+    /// Extracts the allocation ID from the output of the submit command
+    allocation_id_pattern: &'static str,
+    /// Shell command printing the number of queued or running launches of $NAME
+    query: &'static str,
+    cancel: (&'static str, &'static [&'static str]),
+}
+
+fn launcher(preset: GitLabLauncherPreset) -> Launcher {
+    match preset {
+        GitLabLauncherPreset::Slurm => Launcher {
+            submit: (
+                "sbatch",
+                &["--job-name", "$NAME", "--time", "$JOB_TIMEOUT_MINUTES"],
+            ),
+            allocation_id_pattern: r"Submitted batch job (\d+)",
+            query: "squeue --me --name $NAME --noheader | wc -l",
+            cancel: ("scancel", &["$ALLOCATION_ID"]),
+        },
+        GitLabLauncherPreset::Pbs => Launcher {
+            submit: ("qsub", &["-N", "$NAME", "-l", "walltime=$JOB_TIMEOUT"]),
+            allocation_id_pattern: r"^(\S+)",
+            query: "qselect -u $USER -N $NAME -s QR | wc -l",
+            cancel: ("qdel", &["$ALLOCATION_ID"]),
+        },
+        GitLabLauncherPreset::Lsf => Launcher {
+            submit: ("bsub", &["-J", "$NAME", "-W", "$JOB_TIMEOUT_MINUTES"]),
+            allocation_id_pattern: r"Job <(\d+)>",
+            query: "bjobs -J $NAME -noheader 2>/dev/null | wc -l",
+            cancel: ("bkill", &["$ALLOCATION_ID"]),
+        },
+        GitLabLauncherPreset::Flux => Launcher {
+            submit: (
+                "flux",
+                &[
+                    "batch",
+                    "--job-name=$NAME",
+                    "--nslots=1",
+                    "--time-limit=${JOB_TIMEOUT_MINUTES}m",
+                ],
+            ),
+            allocation_id_pattern: r"^(\S+)",
+            query: "flux jobs --name=$NAME --filter=pending,running --no-header | wc -l",
+            cancel: ("flux", &["cancel", "$ALLOCATION_ID"]),
+        },
+    }
+}
+
+fn to_strings(strs: &[&str]) -> Vec<String> {
+    strs.iter().map(|s| s.to_string()).collect()
+}
+
+/// Fills all launch commands that weren't set explicitly from the configured preset
+pub fn apply_preset(launch: &mut GitLabLaunchConfig) {
+    let Some(preset) = launch.preset else {
+        return;
+    };
+    let launcher = launcher(preset);
+    if launch.executable.is_empty() {
+        launch.executable = launcher.submit.0.into();
+        launch.args = to_strings(launcher.submit.1);
+    }
+    launch.stdin.get_or_insert_with(|| RUNNER_SCRIPT.into());
+    launch
+        .allocation_id_pattern
+        .get_or_insert_with(|| launcher.allocation_id_pattern.into());
+    launch.cancel.get_or_insert_with(|| GitLabCancelConfig {
+        executable: launcher.cancel.0.into(),
+        args: to_strings(launcher.cancel.1),
+    });
+    launch
+        .queue_check
+        .get_or_insert_with(|| GitLabQueueCheckConfig {
+            executable: "sh".into(),
+            args: to_strings(&["-c", launcher.query]),
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        let mut launch: GitLabLaunchConfig = toml::from_str(
+            "preset = \"lsf\"\n[cancel]\nexecutable = \"bkill\"\nargs = [\"-s\", \"KILL\", \"$ALLOCATION_ID\"]\n",
+        )
+        .unwrap();
+        apply_preset(&mut launch);
+        assert_eq!(launch.executable, "bsub");
+        assert_eq!(
+            launch.args,
+            vec!["-J", "$NAME", "-W", "$JOB_TIMEOUT_MINUTES"]
+        );
+        assert_eq!(launch.stdin.as_deref(), Some(RUNNER_SCRIPT));
+        // explicitly configured commands are kept
+        assert_eq!(launch.cancel.unwrap().args.len(), 3);
+        let mut launch: GitLabLaunchConfig =
+            toml::from_str("executable = \"./launch.sh\"\nargs = []\n").unwrap();
+        apply_preset(&mut launch);
+        assert!(launch.queue_check.is_none());
+    }
+}
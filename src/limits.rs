@@ -0,0 +1,99 @@
+use crate::config::GitLabLimitsConfig;
+
+/// Builds the systemd-run command placing a job step into a transient scope with the configured
+/// cgroup v2 limits, returning an empty command if no limits are configured.
+/// Scopes of unprivileged runners are created in the user's service manager.
+pub fn scope_command(limits: &GitLabLimitsConfig, is_root: bool) -> Vec<String> {
+    let mut properties = Vec::new();
+    if let Some(cpus) = limits.cpus {
+        properties.push(format!("CPUQuota={}%", (cpus * 100.0).round() as u64));
+    }
+    if let Some(memory) = limits.memory {
+        properties.push(format!("MemoryMax={}M", memory));
+        // without this, the job would start swapping instead of being killed
+        properties.push("MemorySwapMax=0".to_owned());
+    }
+    if let Some(pids) = limits.pids {
+        properties.push(format!("TasksMax={}", pids));
+    }
+    if properties.is_empty() {
+        return Vec::new();
+    }
+    let mut command = vec!["systemd-run".to_owned()];
+    if !is_root {
+        command.push("--user".to_owned());
+    }
+    command.extend(["--scope", "--quiet", "--collect"].map(str::to_owned));
+    command.extend(properties.into_iter().map(|p| format!("--property={}", p)));
+    command.push("--".to_owned());
+    command
+}
+
+/// Arguments applying the limits to a docker container, whose processes are not children of the step
+pub fn docker_args(limits: &GitLabLimitsConfig) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(cpus) = limits.cpus {
+        args.push(format!("--cpus={}", cpus));
+    }
+    if let Some(memory) = limits.memory {
+        args.push(format!("--memory={}m", memory));
+        args.push(format!("--memory-swap={}m", memory));
+    }
+    if let Some(pids) = limits.pids {
+        args.push(format!("--pids-limit={}", pids));
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_arguments() {
+        let limits = GitLabLimitsConfig {
+            cpus: Some(1.5),
+            memory: Some(4096),
+            pids: None,
+        };
+        assert_eq!(
+            scope_command(&limits, false),
+            vec![
+                "systemd-run",
+                "--user",
+                "--scope",
+                "--quiet",
+                "--collect",
+                "--property=CPUQuota=150%",
+                "--property=MemoryMax=4096M",
+                "--property=MemorySwapMax=0",
+                "--"
+            ]
+        );
+        assert_eq!(
+            docker_args(&limits),
+            vec!["--cpus=1.5", "--memory=4096m", "--memory-swap=4096m"]
+        );
+        let pids = GitLabLimitsConfig {
+            cpus: None,
+            memory: None,
+            pids: Some(512),
+        };
+        assert_eq!(
+            scope_command(&pids, true)[1..],
+            [
+                "--scope",
+                "--quiet",
+                "--collect",
+                "--property=TasksMax=512",
+                "--"
+            ]
+        );
+        let none = GitLabLimitsConfig {
+            cpus: None,
+            memory: None,
+            pids: None,
+        };
+        assert!(scope_command(&none, false).is_empty());
+    }
+}
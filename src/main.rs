@@ -1,5 +1,9 @@
 use clap::Parser;
 
+/// Tracking of the last activity of runner instances
+mod activity;
+/// Named build caches shared between jobs
+mod caches;
 /// Tool to check configuration validity
 mod check_config;
 /// All CLI arguments
@@ -8,16 +12,74 @@ mod cli;
 mod config;
 /// Implementation of runner registration and instantiated config file generation
 mod configure;
+/// Control socket for interacting with the running meta-runner
+mod control;
+/// Credentials for pulling images from authenticated registries
+mod credentials;
+/// Machine-readable log of dispatch decisions for pending jobs
+mod decision_log;
+/// Docker engine backend of the custom executor
+mod docker;
+/// enroot backend of the custom executor
+mod enroot;
 /// Implementation of a custom executor
 mod executor;
+/// Metrics of the custom executor written to a Prometheus textfile
+mod executor_metrics;
 /// All config structs that will be used to write gitlab-runner config files
 mod gitlab_config;
 /// All functions related to the GitLab API
 mod gitlab_wrap;
+/// Implementation of event hooks calling external programs
+mod hooks;
+/// Building job images from definition files or Dockerfiles
+mod image_build;
+/// Filtering of pending jobs by name, ref and pipeline source
+mod job_filter;
+/// Validation of executor options overridden by jobs
+mod job_overrides;
+/// Launch command presets for common batch systems
+mod launcher;
+/// Resource limits of job steps via cgroup v2
+mod limits;
+/// Pausing runners during maintenance windows
+mod maintenance;
+/// Prometheus metrics endpoint of the running meta-runner
+mod metrics;
+/// Parsing and validation of bind mount specifications
+mod mounts;
+/// Webhook notifications about persistent failures
+mod notify;
+/// Throttling of the progress output of image pulls
+mod progress;
+/// Reconciliation of launched batch allocations with the jobs they were launched for
+mod reconcile;
+/// Queries of image digests from OCI registries
+mod registry;
+/// Retention of builds directories of failed jobs and removal of stale ones
+mod retention;
 /// Implementation of the meta-runner for dispatching gitlab-runner run-single tasks
 mod run;
+/// Execution of job steps as a dedicated unprivileged user
+mod run_as;
+/// Job response file and the service containers requested by jobs
+mod services;
+/// Provisioning of spack environments for jobs
+mod spack;
+/// Persistent state database shared between all commands
+mod state;
+/// Accounting summary of a `run` session
+mod summary;
+/// Supervision of a persistent gitlab-runner process
+mod supervise;
+/// Service notifications for systemd
+mod systemd;
+/// Boolean expressions over job tags
+mod tag_expression;
 /// All functions related to template instantiation/variable expansion
 mod template;
+/// Verification of image signatures
+mod verify;
 
 fn main() -> anyhow::Result<()> {
     let cli = cli::CliOptions::parse();
@@ -34,5 +96,9 @@ fn main() -> anyhow::Result<()> {
         cli::Command::Executor(options) => executor::exec(&cli.paths, &options),
         cli::Command::RunSingle => run::run_single(&cli.paths),
         cli::Command::Run => run::run(cli.paths),
+        cli::Command::RunMulti => supervise::run_multi(&cli.paths),
+        cli::Command::Status => control::status(&cli.paths),
+        cli::Command::Control(command) => control::control(&cli.paths, &command),
+        cli::Command::Maintenance(command) => maintenance::maintenance(&cli.paths, &command),
     }
 }
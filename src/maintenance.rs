@@ -0,0 +1,107 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use gitlab::AsyncGitlab;
+use log::{error, info, warn};
+
+use crate::{
+    cli,
+    config::{get_state_db_path, read_config, GitLabRunnersConfig},
+    configure::run_bounded,
+    gitlab_wrap::{init_client, set_runner_paused},
+    state::{StateDb, FLAG_MAINTENANCE, FLAG_RUNNERS_PAUSED},
+};
+
+/// Returns the name of the configured maintenance window that is currently active, if any
+pub fn active_window(config: &GitLabRunnersConfig) -> Option<&String> {
+    let now = Utc::now();
+    config
+        .maintenance
+        .iter()
+        .find(|(_, window)| window.contains(now))
+        .map(|(name, _)| name)
+}
+
+/// Pauses or unpauses all registered runners on GitLab
+async fn set_all_runners_paused(
+    config: &GitLabRunnersConfig,
+    client: &AsyncGitlab,
+    db: &Mutex<StateDb>,
+    paused: bool,
+) -> anyhow::Result<()> {
+    let registrations = db.lock().unwrap().read_registrations()?;
+    let futures = registrations
+        .values()
+        .map(|registration| set_runner_paused(client, registration.id, paused));
+    let description = if paused { "Pausing" } else { "Unpausing" };
+    let results = run_bounded(description, futures, config.configure.concurrency).await;
+    let mut failed = 0;
+    for (name, result) in registrations.keys().zip(results) {
+        if let Err(e) = result {
+            error!("{} runner {} failed: {:?}", description, name, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} {} runners failed", description, failed));
+    }
+    db.lock().unwrap().set_flag(FLAG_RUNNERS_PAUSED, paused)?;
+    Ok(())
+}
+
+/// Pauses or unpauses the runners on GitLab when maintenance starts or ends.
+/// Returns whether maintenance is currently active.
+pub async fn sync_maintenance(
+    config: &GitLabRunnersConfig,
+    client: &AsyncGitlab,
+    db: &Mutex<StateDb>,
+) -> anyhow::Result<bool> {
+    let (manual, paused) = {
+        let db = db.lock().unwrap();
+        (
+            db.get_flag(FLAG_MAINTENANCE)?,
+            db.get_flag(FLAG_RUNNERS_PAUSED)?,
+        )
+    };
+    let window = active_window(config);
+    let active = manual || window.is_some();
+    if active != paused {
+        match window {
+            _ if manual => info!("Maintenance mode started, pausing runners"),
+            Some(name) => info!("Maintenance window {} started, pausing runners", name),
+            None => info!("Maintenance ended, unpausing runners"),
+        }
+        set_all_runners_paused(config, client, db, active).await?;
+    }
+    Ok(active)
+}
+
+#[tokio::main]
+pub async fn maintenance(
+    paths: &cli::Paths,
+    command: &cli::MaintenanceCommand,
+) -> anyhow::Result<()> {
+    let config = read_config(&paths.config_file).context(format!(
+        "Failed reading config file {:?}",
+        paths.config_file
+    ))?;
+    std::fs::create_dir_all(&paths.data_dir).context("Creating data dir failed")?;
+    let db = Mutex::new(StateDb::open(&get_state_db_path(
+        &paths.data_dir,
+        &config.name,
+    ))?);
+    let client = init_client(&config.hostname, &config.management_token)
+        .await
+        .context("Failed initializing GitLab client")?;
+    let manual = matches!(command, cli::MaintenanceCommand::Start);
+    db.lock().unwrap().set_flag(FLAG_MAINTENANCE, manual)?;
+    let active = sync_maintenance(&config, &client, &db).await?;
+    if !manual && active {
+        warn!(
+            "Maintenance window {} is still active, runners stay paused until it ends",
+            active_window(&config).unwrap()
+        );
+    }
+    Ok(())
+}
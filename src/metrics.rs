@@ -0,0 +1,375 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use log::{debug, error, info};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    control::{DaemonControl, DaemonStatus},
+    state::{unix_timestamp, InstanceActivity},
+    summary::{InstanceSummary, SessionSummary},
+};
+
+/// Upper bounds (in seconds) of the poll duration histogram buckets
+const POLL_DURATION_BUCKETS: [f64; 9] = [0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+/// Time to wait for a scraper to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct InstanceMetrics {
+    matched_jobs: u64,
+    dispatched_jobs: u64,
+    launches: u64,
+    launch_failures: u64,
+}
+
+#[derive(Debug, Default)]
+struct MetricsData {
+    pending_jobs: usize,
+    polls: u64,
+    poll_failures: u64,
+    api_errors: u64,
+    /// Number of polls per bucket of POLL_DURATION_BUCKETS (not cumulative)
+    poll_duration_buckets: [u64; POLL_DURATION_BUCKETS.len()],
+    poll_duration_sum: f64,
+    longest_poll: f64,
+    /// Number of distinct pending jobs seen since the start
+    seen_jobs: usize,
+    /// IDs of the jobs pending in the last poll, jobs are only counted when they first appear.
+    /// Jobs stop being pending once picked up, so this doesn't grow with the daemon's lifetime.
+    last_pending_jobs: HashSet<u64>,
+    instances: BTreeMap<String, InstanceMetrics>,
+}
+
+/// Counters collected by the poll loop, exposed in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    data: Mutex<MetricsData>,
+}
+
+impl Metrics {
+    pub fn record_poll(&self, duration: Duration, success: bool) {
+        let mut data = self.data.lock().unwrap();
+        let seconds = duration.as_secs_f64();
+        data.polls += 1;
+        if !success {
+            data.poll_failures += 1;
+        }
+        data.poll_duration_sum += seconds;
+        data.longest_poll = data.longest_poll.max(seconds);
+        if let Some(bucket) = POLL_DURATION_BUCKETS.iter().position(|le| seconds <= *le) {
+            data.poll_duration_buckets[bucket] += 1;
+        }
+    }
+
+    pub fn record_api_error(&self) {
+        self.data.lock().unwrap().api_errors += 1;
+    }
+
+    /// Sets the pending jobs seen in the last poll
+    pub fn set_pending_jobs(&self, job_ids: impl ExactSizeIterator<Item = u64>) {
+        let mut data = self.data.lock().unwrap();
+        data.pending_jobs = job_ids.len();
+        let pending_jobs: HashSet<u64> = job_ids.collect();
+        data.seen_jobs += pending_jobs.difference(&data.last_pending_jobs).count();
+        data.last_pending_jobs = pending_jobs;
+    }
+
+    pub fn record_matched(&self, instance: &str, jobs: usize) {
+        let mut data = self.data.lock().unwrap();
+        data.instances
+            .entry(instance.to_owned())
+            .or_default()
+            .matched_jobs += jobs as u64;
+    }
+
+    /// Records the launch commands issued for an instance and the number of jobs they were launched for
+    pub fn record_launches(
+        &self,
+        instance: &str,
+        dispatched_jobs: usize,
+        launches: usize,
+        failures: usize,
+    ) {
+        let mut data = self.data.lock().unwrap();
+        let metrics = data.instances.entry(instance.to_owned()).or_default();
+        metrics.dispatched_jobs += dispatched_jobs as u64;
+        metrics.launches += launches as u64;
+        metrics.launch_failures += failures as u64;
+    }
+
+    /// Summarizes the session since the daemon start
+    pub fn summary(&self, status: &DaemonStatus) -> SessionSummary {
+        let data = self.data.lock().unwrap();
+        SessionSummary {
+            started_at: status.started_at,
+            stopped_at: unix_timestamp(),
+            polls: data.polls,
+            poll_failures: data.poll_failures,
+            longest_poll: data.longest_poll,
+            seen_jobs: data.seen_jobs,
+            dispatched_jobs: status.dispatched_jobs,
+            instances: data
+                .instances
+                .iter()
+                .map(|(name, metrics)| {
+                    let summary = InstanceSummary {
+                        dispatched_jobs: metrics.dispatched_jobs,
+                        launches: metrics.launches,
+                        launch_failures: metrics.launch_failures,
+                    };
+                    (name.clone(), summary)
+                })
+                .collect(),
+        }
+    }
+
+    /// Renders all metrics, including the instance activity from the daemon status
+    pub fn render(&self, status: &DaemonStatus) -> String {
+        let data = self.data.lock().unwrap();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let name = format!("gitlab_meta_runner_{}", name);
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for (labels, value) in samples {
+                writeln!(out, "{}{} {}", name, labels, value).unwrap();
+            }
+        };
+        let single = |value: String| vec![(String::new(), value)];
+        let per_instance = |f: &dyn Fn(&InstanceMetrics) -> u64| {
+            status
+                .instances
+                .iter()
+                .map(|name| {
+                    let value = data.instances.get(name).map_or(0, f);
+                    (format!("{{instance=\"{}\"}}", name), value.to_string())
+                })
+                .collect()
+        };
+        metric(
+            "pending_jobs",
+            "gauge",
+            "Number of pending jobs seen in the last poll",
+            single(data.pending_jobs.to_string()),
+        );
+        metric(
+            "polls_total",
+            "counter",
+            "Number of polls",
+            single(data.polls.to_string()),
+        );
+        metric(
+            "poll_failures_total",
+            "counter",
+            "Number of failed or timed out polls",
+            single(data.poll_failures.to_string()),
+        );
+        metric(
+            "api_errors_total",
+            "counter",
+            "Number of failed requests for pending jobs",
+            single(data.api_errors.to_string()),
+        );
+        let mut cumulative = 0;
+        let mut buckets: Vec<_> = POLL_DURATION_BUCKETS
+            .iter()
+            .zip(data.poll_duration_buckets)
+            .map(|(le, count)| {
+                cumulative += count;
+                (format!("_bucket{{le=\"{}\"}}", le), cumulative.to_string())
+            })
+            .collect();
+        buckets.push(("_bucket{le=\"+Inf\"}".into(), data.polls.to_string()));
+        buckets.push(("_sum".into(), data.poll_duration_sum.to_string()));
+        buckets.push(("_count".into(), data.polls.to_string()));
+        metric(
+            "poll_duration_seconds",
+            "histogram",
+            "Duration of polls",
+            buckets,
+        );
+        metric(
+            "last_poll_timestamp_seconds",
+            "gauge",
+            "Unix timestamp of the last finished poll",
+            single(status.last_poll.unwrap_or(0).to_string()),
+        );
+        metric(
+            "matched_jobs_total",
+            "counter",
+            "Number of pending jobs matched to a runner instance",
+            per_instance(&|m| m.matched_jobs),
+        );
+        metric(
+            "dispatched_jobs_total",
+            "counter",
+            "Number of jobs a runner was launched for",
+            per_instance(&|m| m.dispatched_jobs),
+        );
+        metric(
+            "launches_total",
+            "counter",
+            "Number of launch commands",
+            per_instance(&|m| m.launches),
+        );
+        metric(
+            "launch_failures_total",
+            "counter",
+            "Number of failed launch commands",
+            per_instance(&|m| m.launch_failures),
+        );
+        metric(
+            "instance_paused",
+            "gauge",
+            "Whether the runner instance is paused via the control socket",
+            status
+                .instances
+                .iter()
+                .map(|name| {
+                    let paused = status.paused_instances.contains(name) as u8;
+                    (format!("{{instance=\"{}\"}}", name), paused.to_string())
+                })
+                .collect(),
+        );
+        metric(
+            "draining",
+            "gauge",
+            "Whether the meta-runner is draining via the control socket",
+            single((status.draining as u8).to_string()),
+        );
+        let activity = |f: &dyn Fn(&InstanceActivity) -> Option<i64>| {
+            status
+                .activity
+                .iter()
+                .map(|(name, activity)| {
+                    let value = f(activity).unwrap_or(0);
+                    (format!("{{instance=\"{}\"}}", name), value.to_string())
+                })
+                .collect()
+        };
+        metric(
+            "last_matched_timestamp_seconds",
+            "gauge",
+            "Unix timestamp of the last time a job was matched to the runner instance",
+            activity(&|a| a.last_matched),
+        );
+        metric(
+            "last_launched_timestamp_seconds",
+            "gauge",
+            "Unix timestamp of the last successful launch of the runner instance",
+            activity(&|a| a.last_launched),
+        );
+        metric(
+            "last_executed_timestamp_seconds",
+            "gauge",
+            "Unix timestamp of the last job started by the runner instance",
+            activity(&|a| a.last_executed),
+        );
+        out
+    }
+}
+
+async fn handle_connection(stream: TcpStream, control: &DaemonControl) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut request_line = String::new();
+    time::timeout(
+        REQUEST_TIMEOUT,
+        BufReader::new(reader).read_line(&mut request_line),
+    )
+    .await
+    .context("Timed out reading metrics request")?
+    .context("Failed reading metrics request")?;
+    debug!("Received metrics request {}", request_line.trim());
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let response = if path == "/metrics" {
+        let status = control.status.lock().unwrap().clone();
+        let body = control.metrics.render(&status);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    writer
+        .write_all(response.as_bytes())
+        .await
+        .context("Failed writing metrics response")
+}
+
+/// Serves the metrics on the given address until the cancellation token is triggered
+pub async fn serve(
+    listen: String,
+    control: Arc<DaemonControl>,
+    cancel_token: CancellationToken,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&listen)
+        .await
+        .context(format!("Failed binding metrics endpoint {}", listen))?;
+    info!("Serving metrics on http://{}/metrics", listen);
+    loop {
+        tokio::select! {
+            connection = listener.accept() => match connection {
+                Ok((stream, _)) => {
+                    let control = control.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &control).await {
+                            error!("Failed handling metrics request: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Failed accepting metrics connection: {:?}", e),
+            },
+            _ = cancel_token.cancelled() => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::default();
+        metrics.record_poll(Duration::from_secs(2), true);
+        metrics.record_poll(Duration::from_secs(1000), false);
+        metrics.record_launches("a", 3, 2, 1);
+        metrics.set_pending_jobs([1, 2].into_iter());
+        metrics.set_pending_jobs([2, 3].into_iter());
+        let status = DaemonStatus {
+            instances: ["a".to_owned(), "b".to_owned()].into_iter().collect(),
+            ..Default::default()
+        };
+        let rendered = metrics.render(&status);
+        assert!(rendered.contains("gitlab_meta_runner_polls_total 2\n"));
+        assert!(rendered.contains("gitlab_meta_runner_poll_failures_total 1\n"));
+        assert!(rendered.contains("gitlab_meta_runner_poll_duration_seconds_bucket{le=\"1\"} 0\n"));
+        assert!(
+            rendered.contains("gitlab_meta_runner_poll_duration_seconds_bucket{le=\"2.5\"} 1\n")
+        );
+        assert!(
+            rendered.contains("gitlab_meta_runner_poll_duration_seconds_bucket{le=\"+Inf\"} 2\n")
+        );
+        assert!(rendered.contains("gitlab_meta_runner_dispatched_jobs_total{instance=\"a\"} 3\n"));
+        assert!(rendered.contains("gitlab_meta_runner_launch_failures_total{instance=\"b\"} 0\n"));
+        let summary = metrics.summary(&status);
+        assert_eq!(summary.polls, 2);
+        assert_eq!(summary.longest_poll, 1000.0);
+        assert_eq!(summary.seen_jobs, 3);
+        assert_eq!(summary.instances["a"].launch_failures, 1);
+    }
+}
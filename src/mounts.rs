@@ -0,0 +1,125 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use log::info;
+
+/// Bind mount of a host path into the job container, parsed from a `src[:dst[:opts]]` specification
+#[derive(Debug, PartialEq)]
+pub struct MountSpec {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub read_only: bool,
+}
+
+impl MountSpec {
+    /// Parses a mount specification, the destination defaults to the source and the only
+    /// supported options are "ro" and "rw"
+    pub fn parse(spec: &str) -> anyhow::Result<MountSpec> {
+        let mut parts = spec.split(':');
+        let source = parts.next().unwrap();
+        let destination = parts.next().unwrap_or(source);
+        let options = parts.next().unwrap_or("rw");
+        if parts.next().is_some() {
+            Err(anyhow!("Mount {} has more than three components", spec))?;
+        }
+        let read_only = match options {
+            "ro" => true,
+            "rw" => false,
+            _ => Err(anyhow!(
+                "Mount {} has unsupported options {}, only ro and rw are supported",
+                spec,
+                options
+            ))?,
+        };
+        for path in [source, destination] {
+            if !Path::new(path).is_absolute() {
+                Err(anyhow!("Mount {} contains relative path {:?}", spec, path))?;
+            }
+        }
+        Ok(MountSpec {
+            source: source.into(),
+            destination: destination.into(),
+            read_only,
+        })
+    }
+
+    /// Resolves the source to its real path, so symlinks are followed on the host.
+    /// Missing sources are created as directories if requested, otherwise they are an error,
+    /// since the container runtime would silently create an empty directory in their place.
+    pub fn resolve(self, create_missing: bool) -> anyhow::Result<MountSpec> {
+        if create_missing && !self.source.exists() {
+            info!("Creating missing mount source {:?}", self.source);
+            fs::create_dir_all(&self.source)
+                .context(format!("Failed creating mount source {:?}", self.source))?;
+        }
+        let source = fs::canonicalize(&self.source)
+            .context(format!("Mount source {:?} doesn't exist", self.source))?;
+        Ok(MountSpec { source, ..self })
+    }
+
+    /// Formats the mount for apptainer --bind and docker --volume
+    pub fn to_bind(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.source.to_string_lossy(),
+            self.destination.to_string_lossy(),
+            if self.read_only { "ro" } else { "rw" }
+        )
+    }
+
+    /// Formats the mount for enroot --mount, which only accepts options in the fstab format
+    pub fn to_enroot(&self) -> String {
+        if self.read_only {
+            format!(
+                "{} {} none x-create=auto,bind,ro",
+                self.source.to_string_lossy(),
+                self.destination.to_string_lossy()
+            )
+        } else {
+            format!(
+                "{}:{}",
+                self.source.to_string_lossy(),
+                self.destination.to_string_lossy()
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mounts() {
+        let mount = |source: &str, destination: &str, read_only| MountSpec {
+            source: source.into(),
+            destination: destination.into(),
+            read_only,
+        };
+        assert_eq!(
+            MountSpec::parse("/data").unwrap(),
+            mount("/data", "/data", false)
+        );
+        assert_eq!(
+            MountSpec::parse("/data:/mnt:ro").unwrap(),
+            mount("/data", "/mnt", true)
+        );
+        assert_eq!(
+            MountSpec::parse("/data:/mnt:ro").unwrap().to_bind(),
+            "/data:/mnt:ro"
+        );
+        assert!(MountSpec::parse("data:/mnt").is_err());
+        assert!(MountSpec::parse("/data:/mnt:nosuid").is_err());
+        assert!(MountSpec::parse("/data:/mnt:ro:rw").is_err());
+        let missing = std::env::temp_dir().join(format!("mount-source-{}", std::process::id()));
+        let spec = MountSpec::parse(missing.to_str().unwrap()).unwrap();
+        assert!(spec.resolve(false).is_err());
+        let spec = MountSpec::parse(missing.to_str().unwrap()).unwrap();
+        assert!(spec.resolve(true).is_ok());
+        assert!(missing.is_dir());
+        fs::remove_dir(&missing).unwrap();
+    }
+}
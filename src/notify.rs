@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, warn};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    config::{GitLabNotifyConfig, GitLabNotifyFormat, GitLabRunnersConfig},
+    state::unix_timestamp,
+};
+
+/// Reason for sending a notification
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotifyEvent {
+    /// notify.failure_threshold polls failed in a row
+    PollsFailing,
+    /// Polling succeeded again after failing persistently
+    PollsRecovered,
+    /// A runner instance is not launched for a while after a failed launch
+    InstanceBackoff,
+    /// Launching a runner instance failed notify.failure_threshold times in a row
+    LaunchesFailing,
+    /// Launching a runner instance succeeded again after failing persistently
+    LaunchesRecovered,
+}
+
+#[derive(Debug)]
+pub struct Notification {
+    pub event: NotifyEvent,
+    /// The affected runner instance, if any
+    pub instance: Option<String>,
+    pub message: String,
+}
+
+impl Notification {
+    pub fn new(event: NotifyEvent, instance: Option<&str>, message: String) -> Notification {
+        Notification {
+            event,
+            instance: instance.map(str::to_owned),
+            message,
+        }
+    }
+}
+
+fn request_body(
+    format: GitLabNotifyFormat,
+    meta_runner: &str,
+    notification: &Notification,
+) -> Value {
+    let text = format!(
+        "gitlab-meta-runner {}: {}",
+        meta_runner, notification.message
+    );
+    match format {
+        GitLabNotifyFormat::Json => json!({
+            "event": notification.event,
+            "meta_runner": meta_runner,
+            "instance": notification.instance,
+            "message": notification.message,
+            "timestamp": unix_timestamp(),
+        }),
+        GitLabNotifyFormat::Slack => json!({"text": text}),
+        GitLabNotifyFormat::Matrix => json!({"msgtype": "m.text", "body": text}),
+    }
+}
+
+async fn send(config: &GitLabNotifyConfig, body: &Value) -> anyhow::Result<()> {
+    let mut request = reqwest::Client::new().post(&config.url).json(body);
+    if let Some(timeout) = config.timeout {
+        request = request.timeout(Duration::from_secs(timeout as u64));
+    }
+    request
+        .send()
+        .await
+        .context(format!("Failed sending request to {}", config.url))?
+        .error_for_status()
+        .context("Webhook returned an error")?;
+    Ok(())
+}
+
+/// Sends the notifications to the configured webhook, if any.
+/// Failures are only logged, they never affect the meta-runner itself.
+pub async fn notify(config: &GitLabRunnersConfig, notifications: Vec<Notification>) {
+    let Some(notify_config) = &config.notify else {
+        return;
+    };
+    for notification in notifications {
+        debug!("Sending notification {:?}", notification);
+        let body = request_body(notify_config.format, &config.name, &notification);
+        if let Err(e) = send(notify_config, &body).await {
+            warn!(
+                "Failed sending notification {:?}: {:?}",
+                notification.event, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_bodies() {
+        let notification = Notification::new(
+            NotifyEvent::LaunchesFailing,
+            Some("a"),
+            "Launching runner a failed 3 times in a row".into(),
+        );
+        let body = request_body(GitLabNotifyFormat::Json, "test", &notification);
+        assert_eq!(body["event"], "launches-failing");
+        assert_eq!(body["instance"], "a");
+        let body = request_body(GitLabNotifyFormat::Slack, "test", &notification);
+        assert_eq!(
+            body,
+            json!({"text": "gitlab-meta-runner test: Launching runner a failed 3 times in a row"})
+        );
+        let body = request_body(GitLabNotifyFormat::Matrix, "test", &notification);
+        assert_eq!(body["msgtype"], "m.text");
+    }
+}
@@ -0,0 +1,104 @@
+use std::time::{Duration, Instant};
+
+/// Minimum time between two progress updates forwarded to the job log
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Filters the output of a pull process, which redraws its progress bars via carriage returns.
+/// Regular lines are forwarded, while progress updates are limited to one per interval.
+pub struct ProgressFilter {
+    interval: Duration,
+    buffer: Vec<u8>,
+    last_progress: Option<Instant>,
+    /// Number of image layers the pull reported
+    pub layers: usize,
+}
+
+/// Returns whether an output line reports a transferred layer
+fn is_layer_line(line: &str) -> bool {
+    line.starts_with("Copying blob") || line.ends_with(": Pull complete")
+}
+
+impl ProgressFilter {
+    pub fn new(interval: Duration) -> ProgressFilter {
+        ProgressFilter {
+            interval,
+            buffer: Vec::new(),
+            last_progress: None,
+            layers: 0,
+        }
+    }
+
+    /// Processes a chunk of output, returning the lines to forward
+    pub fn process(&mut self, chunk: &[u8], now: Instant) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&c| c == b'\n' || c == b'\r') {
+            // \r\n terminates a regular line
+            let is_progress = self.buffer[end] == b'\r' && self.buffer.get(end + 1) != Some(&b'\n');
+            let segment: Vec<_> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&segment[..end])
+                .trim_end()
+                .to_owned();
+            if line.is_empty() {
+                continue;
+            }
+            if is_progress {
+                if self
+                    .last_progress
+                    .is_some_and(|last| now.duration_since(last) < self.interval)
+                {
+                    continue;
+                }
+                self.last_progress = Some(now);
+            } else if is_layer_line(&line) {
+                self.layers += 1;
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Returns the remaining output without a final line break
+    pub fn finish(&mut self) -> Option<String> {
+        let line = String::from_utf8_lossy(&self.buffer).trim_end().to_owned();
+        self.buffer.clear();
+        (!line.is_empty()).then_some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_progress() {
+        let start = Instant::now();
+        let mut filter = ProgressFilter::new(Duration::from_secs(5));
+        assert_eq!(
+            filter.process(b"Copying blob 1234\n 10% |==  |\r 20", start),
+            vec!["Copying blob 1234", " 10% |==  |"]
+        );
+        assert_eq!(
+            filter.process(b"% |===  |\r 30% |====|\r", start + Duration::from_secs(1)),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            filter.process(b" 90% |=====|\r", start + Duration::from_secs(6)),
+            vec![" 90% |=====|"]
+        );
+        assert_eq!(
+            filter.process(
+                b"Copying blob 5678\nWriting",
+                start + Duration::from_secs(7)
+            ),
+            vec!["Copying blob 5678"]
+        );
+        assert_eq!(filter.finish(), Some("Writing".to_owned()));
+        assert_eq!(
+            filter.process(b"Done\r\n", start + Duration::from_secs(8)),
+            vec!["Done"]
+        );
+        assert_eq!(filter.finish(), None);
+        assert_eq!(filter.layers, 2);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use anyhow::{anyhow, Context};
+use async_process::{Command, Stdio};
+use futures::future::join_all;
+use gitlab::AsyncGitlab;
+use log::{debug, error, info, warn};
+use regex::Regex;
+
+use crate::{
+    config::GitLabRunnersConfig,
+    gitlab_wrap::{fetch_job, Job, Project},
+    state::{unix_timestamp, Allocation, AllocationState, StateDb},
+    template::expand_cancel_config_template,
+};
+
+/// Extracts the allocation ID (first capture group) from the output of a launch command
+pub fn extract_allocation_id(pattern: &Regex, stdout: &str) -> Option<String> {
+    pattern
+        .captures(stdout)?
+        .get(1)
+        .map(|m| m.as_str().to_owned())
+}
+
+/// An allocation served its purpose once any of its jobs was picked up by the instance's runner
+fn is_picked_up(jobs: &[Job], runner_id: Option<u64>) -> bool {
+    runner_id.is_some()
+        && jobs
+            .iter()
+            .any(|job| job.runner.as_ref().map(|r| r.id) == runner_id)
+}
+
+/// An allocation is orphaned if none of its jobs was picked up by the instance's runner,
+/// while some of them are still pending
+fn is_orphaned(jobs: &[Job], runner_id: Option<u64>) -> bool {
+    !is_picked_up(jobs, runner_id) && jobs.iter().any(|job| job.status == "pending")
+}
+
+/// An allocation is abandoned if none of its jobs is pending anymore and none of them was picked up
+/// by the instance's runner, e.g. because they were cancelled or picked up by another runner
+fn is_abandoned(jobs: &[Job], runner_id: Option<u64>) -> bool {
+    jobs.iter()
+        .all(|job| job.status != "pending" && job.runner.as_ref().map(|r| r.id) != runner_id)
+}
+
+/// Fetches a job from whichever of the polled projects it belongs to
+async fn fetch_allocation_job(
+    client: &AsyncGitlab,
+    projects: &[Project],
+    job_id: u64,
+) -> anyhow::Result<Job> {
+    let mut last_error = None;
+    for project in projects {
+        match fetch_job(client, project, job_id).await {
+            Ok(job) => return Ok(job),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.map_or(anyhow!("No project configured"), |e| {
+        anyhow!(e).context(format!("Failed fetching job {}", job_id))
+    }))
+}
+
+pub async fn cancel_allocation(
+    config: &GitLabRunnersConfig,
+    allocation: &Allocation,
+) -> anyhow::Result<()> {
+    let instance = config
+        .runners
+        .get(&allocation.instance)
+        .ok_or(anyhow!("Unknown runner instance {}", allocation.instance))?;
+    let cancel = expand_cancel_config_template(
+        config,
+        &allocation.instance,
+        instance,
+        &allocation.allocation_id,
+    )
+    .context("Failed expanding launch.cancel")?;
+    let mut command = Command::new(&cancel.executable);
+    command
+        .args(cancel.args.iter())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    debug!("Spawning cancel process {:?}", command);
+    let output = command
+        .output()
+        .await
+        .context(format!("Failed running cancel process {:?}", command))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Cancel command {:?} failed with exit code {}\nstderr:\n{}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+/// Cancels allocations whose runner didn't pick up their jobs within launch.reconcile_timeout,
+/// e.g. because the runner never connected or the jobs were picked up by another runner.
+/// With launch.cancel_orphaned, allocations whose jobs all disappeared are cancelled right away.
+/// Allocations whose runner picked up one of their jobs are completed.
+/// Returns the jobs of cancelled allocations that are still pending, so they can be launched again.
+pub async fn reconcile_allocations(
+    config: &GitLabRunnersConfig,
+    client: &AsyncGitlab,
+    projects: &[Project],
+    db: &Mutex<StateDb>,
+) -> anyhow::Result<Vec<u64>> {
+    let Some(launch) = config.launch.as_ref() else {
+        return Ok(Vec::new());
+    };
+    if launch.reconcile_timeout.is_none() && !launch.cancel_orphaned {
+        return Ok(Vec::new());
+    }
+    let now = unix_timestamp();
+    let (allocations, timed_out, registrations) = {
+        let db = db.lock().unwrap();
+        let timed_out: HashSet<String> = match launch.reconcile_timeout {
+            Some(timeout) => db
+                .read_open_allocations(now - timeout as i64)?
+                .into_iter()
+                .map(|allocation| allocation.allocation_id)
+                .collect(),
+            None => HashSet::new(),
+        };
+        let allocations = if launch.cancel_orphaned {
+            db.read_open_allocations(now + 1)?
+        } else {
+            db.read_open_allocations(now - launch.reconcile_timeout.unwrap() as i64)?
+        };
+        (allocations, timed_out, db.read_registrations()?)
+    };
+    let mut released_jobs = Vec::new();
+    for allocation in allocations {
+        let jobs = join_all(
+            allocation
+                .job_ids
+                .iter()
+                .map(|job_id| fetch_allocation_job(client, projects, *job_id)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>();
+        let jobs = match jobs {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                warn!(
+                    "Failed fetching jobs for allocation {}: {:?}",
+                    allocation.allocation_id, e
+                );
+                continue;
+            }
+        };
+        let runner_id = registrations.get(&allocation.instance).map(|r| r.id);
+        let state = if is_picked_up(&jobs, runner_id) {
+            debug!(
+                "Allocation {} picked up its jobs {:?}",
+                allocation.allocation_id, allocation.job_ids
+            );
+            AllocationState::Completed
+        } else if is_abandoned(&jobs, runner_id)
+            && (launch.cancel_orphaned || timed_out.contains(&allocation.allocation_id))
+        {
+            info!(
+                "Cancelling allocation {} of runner {}, its jobs {:?} are no longer pending",
+                allocation.allocation_id, allocation.instance, allocation.job_ids
+            );
+            if let Err(e) = cancel_allocation(config, &allocation).await {
+                error!(
+                    "Failed cancelling allocation {}: {:?}",
+                    allocation.allocation_id, e
+                );
+            }
+            AllocationState::Cancelled
+        } else if timed_out.contains(&allocation.allocation_id) && is_orphaned(&jobs, runner_id) {
+            info!(
+                "Cancelling allocation {} of runner {}, its runner didn't pick up jobs {:?}",
+                allocation.allocation_id, allocation.instance, allocation.job_ids
+            );
+            // a failed cancellation is not retried, since the allocation has most likely ended already
+            if let Err(e) = cancel_allocation(config, &allocation).await {
+                error!(
+                    "Failed cancelling allocation {}: {:?}",
+                    allocation.allocation_id, e
+                );
+            }
+            // the jobs are launched again, instead of waiting for launch.redispatch_timeout
+            released_jobs.extend(
+                jobs.iter()
+                    .filter(|job| job.status == "pending")
+                    .map(|job| job.id),
+            );
+            AllocationState::Cancelled
+        } else {
+            continue;
+        };
+        db.lock()
+            .unwrap()
+            .set_allocation_state(&allocation.allocation_id, state)?;
+    }
+    Ok(released_jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocation_id() {
+        let pattern = Regex::new("Submitted batch job (\\d+)").unwrap();
+        assert_eq!(
+            extract_allocation_id(&pattern, "Submitted batch job 1234\n"),
+            Some("1234".into())
+        );
+        assert_eq!(extract_allocation_id(&pattern, "error"), None);
+    }
+
+    #[test]
+    fn abandoned_allocation() {
+        let job = |status: &str, runner_id: Option<u64>| {
+            serde_json::from_value::<Job>(serde_json::json!({
+                "id": 1,
+                "name": "job",
+                "status": status,
+                "tag_list": [],
+                "runner": runner_id.map(|id| serde_json::json!({"id": id})),
+            }))
+            .unwrap()
+        };
+        assert!(is_abandoned(&[job("canceled", None)], Some(1)));
+        assert!(is_abandoned(&[job("running", Some(2))], Some(1)));
+        assert!(!is_abandoned(&[job("running", Some(1))], Some(1)));
+        assert!(!is_abandoned(
+            &[job("canceled", None), job("pending", None)],
+            Some(1)
+        ));
+    }
+
+    #[test]
+    fn orphaned_allocation() {
+        let job = |status: &str, runner_id: Option<u64>| {
+            serde_json::from_value::<Job>(serde_json::json!({
+                "id": 1,
+                "name": "job",
+                "status": status,
+                "tag_list": [],
+                "runner": runner_id.map(|id| serde_json::json!({"id": id})),
+            }))
+            .unwrap()
+        };
+        assert!(is_orphaned(&[job("pending", None)], Some(1)));
+        // only pending jobs are waiting for the runner
+        assert!(!is_orphaned(&[job("canceled", None)], Some(1)));
+        assert!(!is_orphaned(&[job("failed", Some(2))], Some(1)));
+        // a runner that picked up one of the jobs keeps the allocation busy
+        assert!(!is_orphaned(
+            &[job("running", Some(1)), job("pending", None)],
+            Some(1)
+        ));
+        assert!(is_picked_up(&[job("success", Some(1))], Some(1)));
+        assert!(!is_picked_up(&[job("pending", None)], None));
+    }
+}
@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use log::debug;
+use reqwest::{header, Client, Response, StatusCode};
+use serde_derive::Deserialize;
+
+use crate::docker;
+
+/// Registry used for image names without a registry hostname
+const DEFAULT_REGISTRY: &str = "registry-1.docker.io";
+/// Time to wait for each request to the registry
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Manifest types the registry may respond with, including multi-platform indices
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.oci.image.manifest.v1+json, application/vnd.docker.distribution.manifest.v2+json";
+
+/// Reference to a tagged image in an OCI registry
+#[derive(Debug, PartialEq)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+}
+
+impl ImageReference {
+    /// Parses a docker image name, returning None for images that are not pulled from
+    /// an OCI registry by tag, e.g. other apptainer URIs or images pinned by digest
+    pub fn parse(image: &str) -> Option<ImageReference> {
+        if image.contains('@') {
+            return None;
+        }
+        let image = docker::image_name(image);
+        if image.contains("://") {
+            return None;
+        }
+        let (registry, path) = match image.split_once('/') {
+            Some((registry, path)) if registry.contains(['.', ':']) || registry == "localhost" => {
+                (registry.to_owned(), path)
+            }
+            _ => (DEFAULT_REGISTRY.to_owned(), image),
+        };
+        let (repository, tag) = match path.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+            _ => (path, "latest"),
+        };
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository.to_owned()
+        };
+        Some(ImageReference {
+            registry,
+            repository,
+            tag: tag.to_owned(),
+        })
+    }
+
+    fn manifest_url(&self) -> String {
+        format!(
+            "https://{}/v2/{}/manifests/{}",
+            self.registry, self.repository, self.tag
+        )
+    }
+}
+
+/// Rewrites a Docker Hub image to be pulled from a mirror, returning None for images from other registries
+pub fn mirror_image(image: &str, mirror: &str) -> Option<String> {
+    let image = docker::image_name(image);
+    if image.contains("://") {
+        return None;
+    }
+    let path = match image.split_once('/') {
+        Some((registry, path)) if registry.contains(['.', ':']) || registry == "localhost" => {
+            if !["docker.io", "index.docker.io", DEFAULT_REGISTRY].contains(&registry) {
+                return None;
+            }
+            path
+        }
+        _ => image,
+    };
+    // official images are located in the library namespace
+    let path = if path.contains('/') {
+        path.to_owned()
+    } else {
+        format!("library/{}", path)
+    };
+    Some(format!(
+        "docker://{}/{}",
+        mirror.trim_end_matches('/'),
+        path
+    ))
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(alias = "access_token")]
+    token: String,
+}
+
+/// Extracts a parameter like realm="..." from a WWW-Authenticate header
+fn auth_parameter<'a>(challenge: &'a str, name: &str) -> Option<&'a str> {
+    let start = challenge.find(&format!("{}=\"", name))? + name.len() + 2;
+    let length = challenge[start..].find('"')?;
+    Some(&challenge[start..start + length])
+}
+
+/// Requests an anonymous pull token for the challenge returned by the registry
+async fn fetch_token(client: &Client, challenge: &str) -> anyhow::Result<String> {
+    let realm = auth_parameter(challenge, "realm").ok_or(anyhow!(
+        "Unsupported authentication challenge {}",
+        challenge
+    ))?;
+    let params: Vec<_> = ["service", "scope"]
+        .into_iter()
+        .filter_map(|name| Some((name, auth_parameter(challenge, name)?)))
+        .collect();
+    let response: TokenResponse = client
+        .get(realm)
+        .query(&params)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(response.token)
+}
+
+async fn head_manifest(
+    client: &Client,
+    reference: &ImageReference,
+    token: Option<&str>,
+) -> reqwest::Result<Response> {
+    let mut request = client
+        .head(reference.manifest_url())
+        .header(header::ACCEPT, MANIFEST_TYPES);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await
+}
+
+/// Queries the digest the tag of the image currently points to in its registry
+pub async fn fetch_digest(reference: &ImageReference) -> anyhow::Result<String> {
+    let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+    let mut response = head_manifest(&client, reference, None).await?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        let challenge = response
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let token = fetch_token(&client, &challenge)
+            .await
+            .context("Failed requesting registry token")?;
+        response = head_manifest(&client, reference, Some(&token)).await?;
+    }
+    let response = response.error_for_status()?;
+    let digest = response
+        .headers()
+        .get("Docker-Content-Digest")
+        .and_then(|v| v.to_str().ok())
+        .ok_or(anyhow!("Registry response contains no digest"))?;
+    debug!("Tag {:?} points to {}", reference, digest);
+    Ok(digest.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_references() {
+        let reference = |registry: &str, repository: &str, tag: &str| {
+            Some(ImageReference {
+                registry: registry.into(),
+                repository: repository.into(),
+                tag: tag.into(),
+            })
+        };
+        assert_eq!(
+            ImageReference::parse("ubuntu"),
+            reference(DEFAULT_REGISTRY, "library/ubuntu", "latest")
+        );
+        assert_eq!(
+            ImageReference::parse("docker://ghcr.io/org/image:v1"),
+            reference("ghcr.io", "org/image", "v1")
+        );
+        assert_eq!(
+            ImageReference::parse("localhost:5000/image"),
+            reference("localhost:5000", "image", "latest")
+        );
+        assert_eq!(ImageReference::parse("ubuntu@sha256:abcd"), None);
+        assert_eq!(ImageReference::parse("library://alpine:latest"), None);
+        assert_eq!(
+            mirror_image("docker://ubuntu:24.04", "mirror.gcr.io").as_deref(),
+            Some("docker://mirror.gcr.io/library/ubuntu:24.04")
+        );
+        assert_eq!(
+            mirror_image("docker.io/org/image@sha256:abcd", "harbor.example.com/hub/").as_deref(),
+            Some("docker://harbor.example.com/hub/org/image@sha256:abcd")
+        );
+        assert_eq!(
+            mirror_image("docker://ghcr.io/org/image", "mirror.gcr.io"),
+            None
+        );
+        assert_eq!(
+            mirror_image("oras://registry.example.com/a", "mirror.gcr.io"),
+            None
+        );
+        assert_eq!(
+            auth_parameter(
+                "Bearer realm=\"https://auth.docker.io/token\",service=\"registry.docker.io\"",
+                "service"
+            ),
+            Some("registry.docker.io")
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use log::{debug, info, warn};
+use serde_json::{json, to_string_pretty};
+
+use crate::{
+    config::GitLabFailedBuildsConfig,
+    run_as::{self, UserIds},
+    state::unix_timestamp,
+};
+
+/// Marker file created in the builds directory when a job step failed
+const FAILED_MARKER: &str = ".meta-runner-failed";
+/// File describing the job a retained builds directory belongs to
+const INFO_FILE: &str = "meta-runner-info.json";
+/// Marker file in the builds directory whose modification time is the start of the last job step
+const ACTIVITY_MARKER: &str = ".meta-runner-activity";
+/// Extension of the per-job overlay images next to the builds directories
+const OVERLAY_EXTENSION: &str = "overlay.img";
+
+/// Creates an empty marker file in the builds directory. If the directory was handed over to the
+/// run_as user, the executor can't write to it, so the file is installed with that owner instead
+fn write_marker(path: &Path, owner: Option<UserIds>) -> anyhow::Result<()> {
+    match owner {
+        Some(ids) => run_as::install_file(Path::new("/dev/null"), path, ids, 0o644),
+        None => Ok(fs::write(path, "")?),
+    }
+}
+
+pub fn mark_failed(builds_dir: &Path, owner: Option<UserIds>) -> anyhow::Result<()> {
+    write_marker(&builds_dir.join(FAILED_MARKER), owner).context("Failed writing failure marker")
+}
+
+/// Removes the failure marker of an earlier job sharing the builds directory
+pub fn clear_failed(builds_dir: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(builds_dir.join(FAILED_MARKER)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).context("Failed removing failure marker")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Determines whether the job failed, either from the failure marker or the job status variable
+pub fn job_failed(builds_dir: &Path) -> bool {
+    builds_dir.join(FAILED_MARKER).exists()
+        || std::env::var("CUSTOM_ENV_CI_JOB_STATUS").is_ok_and(|status| status == "failed")
+}
+
+fn get_size(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| get_size(&entry.path()))
+                    .sum()
+            })
+            .unwrap_or(0),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+fn remove_entry(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Deletes the oldest retained builds directories exceeding the configured limits
+fn prune(config: &GitLabFailedBuildsConfig) -> anyhow::Result<()> {
+    let mut entries: Vec<(PathBuf, SystemTime)> = fs::read_dir(&config.dir)?
+        .filter_map(Result::ok)
+        .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?.modified().ok()?)))
+        .collect();
+    // newest first
+    entries.sort_by_key(|(_, modified)| std::cmp::Reverse(*modified));
+    let max_age = config
+        .max_age
+        .map(|hours| Duration::from_secs(hours as u64 * 3600));
+    let max_size = config.max_size.map(|mb| mb * 1024 * 1024);
+    let mut total_size = 0;
+    for (index, (path, modified)) in entries.iter().enumerate() {
+        total_size += get_size(path);
+        let too_many = config.max_count.is_some_and(|count| index >= count);
+        let too_old = max_age.is_some_and(|age| modified.elapsed().unwrap_or_default() > age);
+        let too_large = max_size.is_some_and(|size| total_size > size);
+        if too_many || too_old || too_large {
+            debug!("Deleting retained builds directory {:?}", path);
+            if let Err(e) = remove_entry(path) {
+                warn!(
+                    "Failed deleting retained builds directory {:?}: {:?}",
+                    path, e
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Records that a step of the job is starting, so its builds directory is not considered stale
+pub fn record_activity(builds_dir: &Path, owner: Option<UserIds>) -> anyhow::Result<()> {
+    write_marker(&builds_dir.join(ACTIVITY_MARKER), owner).context("Failed writing activity marker")
+}
+
+/// Returns the ID of the job a builds directory or overlay image in the builds root belongs to
+fn get_job_id(path: &Path) -> Option<&str> {
+    let name = path.file_name()?.to_str()?;
+    let id = if path.is_dir() {
+        name
+    } else {
+        name.strip_suffix(OVERLAY_EXTENSION)?.strip_suffix('.')?
+    };
+    id.chars().all(|c| c.is_ascii_digit()).then_some(id)
+}
+
+/// Returns the entries the given number of directory levels below the directory
+fn read_dir_at_depth(dir: &Path, depth: usize) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let paths = entries.filter_map(Result::ok).map(|entry| entry.path());
+    if depth <= 1 {
+        paths.collect()
+    } else {
+        paths
+            .filter(|path| path.is_dir())
+            .flat_map(|path| read_dir_at_depth(&path, depth - 1))
+            .collect()
+    }
+}
+
+/// Deletes the builds directories, overlay images or temporary pull directories of jobs whose last step
+/// started more than max_age ago. Since no job step can exceed the job timeout, these jobs were killed
+/// before their cleanup step. The overlay images are located in the builds root, the builds directories
+/// job_dir_depth levels below it, or nowhere if they are shared by several jobs.
+pub fn remove_stale_builds(
+    builds_root: &Path,
+    job_dir_depth: Option<usize>,
+    current_job_id: &str,
+    max_age: Duration,
+) {
+    let mut paths = read_dir_at_depth(builds_root, 1);
+    if job_dir_depth != Some(1) {
+        paths.retain(|path| !path.is_dir());
+        if let Some(depth) = job_dir_depth {
+            paths.extend(read_dir_at_depth(builds_root, depth));
+        }
+    }
+    for path in paths {
+        if get_job_id(&path).is_none_or(|id| id == current_job_id) {
+            continue;
+        }
+        let activity = path.join(ACTIVITY_MARKER);
+        let last_active = fs::metadata(if activity.exists() { &activity } else { &path })
+            .and_then(|metadata| metadata.modified());
+        let Ok(last_active) = last_active else {
+            continue;
+        };
+        if last_active.elapsed().unwrap_or_default() > max_age {
+            info!("Deleting stale job directory {:?}", path);
+            if let Err(e) = remove_entry(&path) {
+                warn!("Failed deleting stale job directory {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
+/// Moves a directory, copying it if the target is on another filesystem,
+/// e.g. if the builds directory is on node-local scratch
+fn move_dir(source: &Path, target: &Path) -> anyhow::Result<()> {
+    match fs::rename(source, target) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => copy_and_remove_dir(source, target),
+        result => Ok(result?),
+    }
+}
+
+/// Copies a directory including its permissions and symlinks, then removes the original
+fn copy_and_remove_dir(source: &Path, target: &Path) -> anyhow::Result<()> {
+    debug!("Copying {:?} to {:?} across filesystems", source, target);
+    let status = Command::new("cp")
+        .arg("-a")
+        .arg(source)
+        .arg(target)
+        .stdin(Stdio::null())
+        .status()
+        .context("Failed spawning cp")?;
+    if !status.success() {
+        // don't leave partial copies behind
+        let _ = fs::remove_dir_all(target);
+        Err(anyhow!("Failed copying {:?}: {:?}", source, status))?;
+    }
+    fs::remove_dir_all(source)?;
+    Ok(())
+}
+
+/// Moves or archives the builds directory of a failed job into the configured directory
+pub fn retain_failed_build(
+    config: &GitLabFailedBuildsConfig,
+    builds_dir: &Path,
+    runner_name: &str,
+    job_id: &str,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(&config.dir).context(format!(
+        "Failed creating failed builds directory {:?}",
+        config.dir
+    ))?;
+    let info = json!({
+        "job_id": job_id,
+        "runner_name": runner_name,
+        "job_url": std::env::var("CUSTOM_ENV_CI_JOB_URL").ok(),
+        "failed_at": unix_timestamp(),
+    });
+    fs::write(builds_dir.join(INFO_FILE), to_string_pretty(&info)?)?;
+    let name = format!("{}-{}", runner_name, job_id);
+    if config.archive {
+        let archive = Path::new(&config.dir).join(format!("{}.tar.gz", name));
+        let status = Command::new("tar")
+            .arg("-czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(builds_dir)
+            .arg(".")
+            .stdin(Stdio::null())
+            .status()
+            .context("Failed spawning tar")?;
+        if !status.success() {
+            Err(anyhow!("Failed archiving builds directory: {:?}", status))?;
+        }
+        fs::remove_dir_all(builds_dir)?;
+        info!("Archived builds directory of failed job to {:?}", archive);
+    } else {
+        let target = Path::new(&config.dir).join(name);
+        move_dir(builds_dir, &target)
+            .context(format!("Failed moving builds directory to {:?}", target))?;
+        info!("Retained builds directory of failed job in {:?}", target);
+    }
+    prune(config).context("Failed pruning retained builds directories")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retain_and_prune() {
+        let base = std::env::temp_dir().join(format!("retention-{}", std::process::id()));
+        let config = GitLabFailedBuildsConfig {
+            dir: base.join("failed").to_str().unwrap().to_owned(),
+            archive: false,
+            max_count: Some(1),
+            max_age: None,
+            max_size: None,
+        };
+        for job_id in ["1", "2"] {
+            let builds_dir = base.join(job_id);
+            fs::create_dir_all(&builds_dir).unwrap();
+            mark_failed(&builds_dir, None).unwrap();
+            assert!(job_failed(&builds_dir));
+            retain_failed_build(&config, &builds_dir, "runner", job_id).unwrap();
+            assert!(!builds_dir.exists());
+            // make sure the modification times differ
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let retained: Vec<_> = fs::read_dir(&config.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(retained, vec!["runner-2"]);
+        assert!(Path::new(&config.dir)
+            .join("runner-2")
+            .join(INFO_FILE)
+            .exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn copy_across_filesystems() {
+        let base = std::env::temp_dir().join(format!("copy-dir-{}", std::process::id()));
+        let source = base.join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file"), "content").unwrap();
+        std::os::unix::fs::symlink("sub/file", source.join("link")).unwrap();
+        let target = base.join("target");
+        copy_and_remove_dir(&source, &target).unwrap();
+        assert!(!source.exists());
+        assert_eq!(
+            fs::read_to_string(target.join("sub/file")).unwrap(),
+            "content"
+        );
+        assert!(fs::symlink_metadata(target.join("link"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(copy_and_remove_dir(&source, &base.join("missing")).is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn stale_builds() {
+        let base = std::env::temp_dir().join(format!("stale-builds-{}", std::process::id()));
+        for job_id in ["1", "2", "3"] {
+            fs::create_dir_all(base.join(job_id)).unwrap();
+        }
+        fs::write(base.join("1.overlay.img"), "").unwrap();
+        fs::create_dir_all(base.join("other")).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        record_activity(&base.join("2"), None).unwrap();
+        remove_stale_builds(&base, Some(1), "3", Duration::from_millis(25));
+        let mut remaining: Vec<_> = fs::read_dir(&base)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["2", "3", "other"]);
+        // builds directories grouped by project
+        fs::create_dir_all(base.join("project").join("4")).unwrap();
+        fs::write(base.join("4.overlay.img"), "").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        remove_stale_builds(&base, Some(2), "3", Duration::from_millis(25));
+        assert!(!base.join("project").join("4").exists());
+        assert!(!base.join("4.overlay.img").exists());
+        // directories at the root are not builds directories in this layout
+        assert!(base.join("2").exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
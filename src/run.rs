@@ -1,27 +1,63 @@
-use async_std::future;
 use itertools::{Either, Itertools};
 use std::{
-    collections::{HashMap, HashSet},
+    cmp::Reverse,
+    collections::{hash_map::RandomState, HashMap, HashSet},
     fmt::Display,
+    hash::{BuildHasher, Hasher},
     ops::Deref,
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
     u32,
 };
-use tokio_util::sync::CancellationToken;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use async_process::{Command, Stdio};
-use futures::{future::join_all, select, AsyncReadExt, AsyncWriteExt, FutureExt};
+use chrono::{DateTime, Local, Utc};
+use futures::{
+    future::{join_all, try_join_all},
+    select, AsyncReadExt, AsyncWriteExt, FutureExt,
+};
 use gitlab::AsyncGitlab;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use regex::Regex;
+use serde_json::json;
 use tokio::{
-    signal,
+    signal::{
+        self,
+        unix::{signal as unix_signal, SignalKind},
+    },
+    sync::Semaphore,
     time::{self, MissedTickBehavior},
 };
 
 use crate::{
-    check_config, cli,
-    config::{read_config, GitLabLaunchConfig, GitLabRunnerInstance, GitLabRunnersConfig},
-    gitlab_wrap::{fetch_pending_project_jobs, fetch_project, init_client, Job, Project},
+    activity, check_config, cli,
+    config::{
+        get_control_socket_path, get_decision_log_path, get_session_log_path, get_state_db_path,
+        in_schedule, read_config, GitLabLaunchConfig, GitLabLaunchHookConfig, GitLabMatchStrategy,
+        GitLabQueueCheckConfig, GitLabRunnerInstance, GitLabRunnersConfig, HookEvent,
+    },
+    configure::update_configuration,
+    control::{self, DaemonControl},
+    decision_log::{self, Decision, DecisionEntry},
+    gitlab_wrap::{
+        fetch_pending_project_jobs, fetch_project, init_client, is_api_error, is_auth_error, Job,
+        Project, DEFAULT_JOB_TIMEOUT,
+    },
+    hooks::run_hooks,
+    job_filter::JobFilter,
+    maintenance::sync_maintenance,
+    metrics,
+    notify::{notify, Notification, NotifyEvent},
+    reconcile::{extract_allocation_id, reconcile_allocations},
+    state::{
+        unix_timestamp, Activity, Allocation, HandledJob, LaunchBackoff, LaunchQuota, LaunchRecord,
+        StateDb,
+    },
+    systemd,
     template::expand_launch_config_template,
 };
 
@@ -30,8 +66,373 @@ use anyhow::{anyhow, Context};
 struct MetaRunnerState {
     config: GitLabRunnersConfig,
     client: AsyncGitlab,
-    project: Project,
-    successful_job_ids: HashSet<u64>,
+    /// All projects whose pending jobs are polled
+    projects: Vec<Project>,
+    /// Dispatch ledger of all jobs that were launched or ignored
+    handled_jobs: HashMap<u64, HandledJob>,
+    db: Mutex<StateDb>,
+    control: Arc<DaemonControl>,
+    allocation_id_pattern: Option<Regex>,
+    /// Compiled job filters for every runner instance
+    job_filters: HashMap<String, JobFilter>,
+    /// Jobs that were already reported as starved
+    starved_job_ids: Mutex<HashSet<u64>>,
+    /// Jobs matched in the previous poll, only newly matched jobs are debounced
+    matched_job_ids: Mutex<HashSet<u64>>,
+    /// Instances whose last launches failed, which are not dispatched to until their backoff expires
+    launch_backoff: Mutex<HashMap<String, LaunchBackoff>>,
+    /// Token buckets of the instances' launch quotas, indexed by instance name and period
+    launch_quota: Mutex<HashMap<(String, i64), LaunchQuota>>,
+    /// Instances whose jobs failed in the executor's prepare step, which are not used until their cooldown ends
+    unhealthy_instances: Mutex<HashSet<String>>,
+    /// Counter for the round-robin match strategy
+    round_robin: AtomicUsize,
+    /// Hooks running in the background, off the poll path
+    hook_tasks: TaskTracker,
+}
+
+const HOUR: i64 = 3600;
+const DAY: i64 = 24 * HOUR;
+
+/// Number of consecutive polls rejected by GitLab after which the token is considered invalid
+const MAX_AUTH_FAILURES: u32 = 5;
+
+/// Time after which handled jobs and launch records are forgotten, longer than jobs can stay pending
+const HANDLED_JOB_RETENTION: i64 = 7 * DAY;
+
+/// Upper limit for the backoff after failed launches
+const MAX_LAUNCH_BACKOFF: u64 = 3600;
+
+/// Returns the configured launch quotas of an instance as (period in seconds, capacity)
+fn get_launch_quotas(instance: &GitLabRunnerInstance) -> Vec<(i64, u32)> {
+    [
+        (HOUR, instance.max_launches_per_hour),
+        (DAY, instance.max_launches_per_day),
+    ]
+    .into_iter()
+    .filter_map(|(period, capacity)| Some((period, capacity?)))
+    .collect()
+}
+
+/// Computes the delay (in seconds) before the next launch attempt after the given number of
+/// consecutive failures, doubling with every failure starting at the poll interval
+fn launch_backoff_delay(interval: u32, failures: u32) -> u64 {
+    let factor = 1u64
+        .checked_shl(failures.saturating_sub(1))
+        .unwrap_or(u64::MAX);
+    (interval as u64)
+        .saturating_mul(factor)
+        .min(MAX_LAUNCH_BACKOFF)
+}
+
+/// Random jitter of up to a quarter of the delay, so instances don't retry in lockstep
+fn jitter(delay: u64) -> u64 {
+    let random = RandomState::new().build_hasher().finish();
+    random % (delay / 4 + 1)
+}
+
+impl MetaRunnerState {
+    /// Remembers jobs that were launched (with their instance) or ignored (without instance),
+    /// so they will not be handled again in later polls. Only launched jobs are remembered across
+    /// restarts. Jobs handled longer than HANDLED_JOB_RETENTION ago are forgotten.
+    fn mark_handled(&mut self, jobs: Vec<(u64, Option<String>)>) {
+        let now = unix_timestamp();
+        let cutoff = now - HANDLED_JOB_RETENTION;
+        self.handled_jobs.retain(|_, job| job.handled_at >= cutoff);
+        if let Err(e) = self.db.get_mut().unwrap().prune_handled_jobs(cutoff) {
+            error!("Failed pruning handled jobs in state database: {:?}", e);
+        }
+        self.handled_jobs
+            .extend(jobs.iter().map(|(job_id, instance)| {
+                let entry = HandledJob {
+                    instance: instance.clone(),
+                    handled_at: now,
+                };
+                (*job_id, entry)
+            }));
+        if let Err(e) = self.db.get_mut().unwrap().add_handled_jobs(jobs) {
+            error!("Failed storing handled jobs in state database: {:?}", e);
+        }
+    }
+
+    /// Forgets jobs whose allocation was cancelled while they were still pending,
+    /// so they are launched again
+    fn release_handled(&mut self, job_ids: Vec<u64>) {
+        if job_ids.is_empty() {
+            return;
+        }
+        info!(
+            "Releasing pending jobs {:?} of cancelled allocations",
+            job_ids
+        );
+        for job_id in &job_ids {
+            self.handled_jobs.remove(job_id);
+        }
+        if let Err(e) = self.db.get_mut().unwrap().remove_handled_jobs(&job_ids) {
+            error!("Failed releasing handled jobs in state database: {:?}", e);
+        }
+    }
+
+    fn in_backoff(&self, instance: &str) -> bool {
+        self.launch_backoff
+            .lock()
+            .unwrap()
+            .get(instance)
+            .is_some_and(|backoff| backoff.until > unix_timestamp())
+    }
+
+    /// Resets the backoff of an instance after a successful launch,
+    /// or extends it exponentially after a failed launch.
+    /// Returns a notification if the instance entered backoff, failed persistently or recovered.
+    fn update_backoff(
+        &self,
+        instance: &str,
+        error: Option<&anyhow::Error>,
+    ) -> Option<Notification> {
+        let threshold = self
+            .config
+            .notify
+            .as_ref()
+            .map_or(u32::MAX, |notify| notify.failure_threshold);
+        let mut launch_backoff = self.launch_backoff.lock().unwrap();
+        let Some(error) = error else {
+            let backoff = launch_backoff.remove(instance)?;
+            self.store_backoff(instance, None);
+            return (backoff.failures >= threshold).then(|| {
+                let message = format!("Launching runner {} succeeded again", instance);
+                Notification::new(NotifyEvent::LaunchesRecovered, Some(instance), message)
+            });
+        };
+        let backoff = launch_backoff
+            .entry(instance.to_owned())
+            .or_insert(LaunchBackoff {
+                failures: 0,
+                until: 0,
+            });
+        backoff.failures += 1;
+        let delay = launch_backoff_delay(self.config.poll.interval, backoff.failures);
+        let delay = delay + jitter(delay);
+        backoff.until = unix_timestamp() + delay as i64;
+        warn!(
+            "Launching runner {} failed {} times in a row, not launching it again for {}s",
+            instance, backoff.failures, delay
+        );
+        self.store_backoff(instance, Some(backoff));
+        let message = format!(
+            "Launching runner {} failed {} times in a row, not launching it again for {}s: {:#}",
+            instance, backoff.failures, delay, error
+        );
+        if backoff.failures == threshold {
+            Some(Notification::new(
+                NotifyEvent::LaunchesFailing,
+                Some(instance),
+                message,
+            ))
+        } else if backoff.failures == 1 {
+            Some(Notification::new(
+                NotifyEvent::InstanceBackoff,
+                Some(instance),
+                message,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Persists the backoff of an instance, so it survives restarts
+    fn store_backoff(&self, instance: &str, backoff: Option<&LaunchBackoff>) {
+        if let Err(e) = self
+            .db
+            .lock()
+            .unwrap()
+            .set_launch_backoff(instance, backoff)
+        {
+            error!(
+                "Failed storing launch backoff of runner {} in state database: {:?}",
+                instance, e
+            );
+        }
+    }
+
+    /// Takes up to the requested number of launches from the launch quotas of an instance,
+    /// returning how many launches may be issued
+    fn reserve_launches(
+        &self,
+        name: &str,
+        instance: &GitLabRunnerInstance,
+        launches: usize,
+    ) -> usize {
+        let quotas = get_launch_quotas(instance);
+        if quotas.is_empty() {
+            return launches;
+        }
+        let now = unix_timestamp();
+        let mut launch_quota = self.launch_quota.lock().unwrap();
+        let mut granted = launches;
+        for (period, capacity) in &quotas {
+            // new instances start with a full quota
+            let quota = launch_quota
+                .entry((name.to_owned(), *period))
+                .or_insert(LaunchQuota {
+                    tokens: *capacity as f64,
+                    updated_at: now,
+                });
+            quota.refill(*capacity, *period, now);
+            granted = granted.min(quota.available());
+        }
+        let db = self.db.lock().unwrap();
+        for (period, _) in &quotas {
+            let quota = launch_quota.get_mut(&(name.to_owned(), *period)).unwrap();
+            quota.tokens -= granted as f64;
+            if let Err(e) = db.set_launch_quota(name, *period, quota) {
+                error!(
+                    "Failed storing launch quota of runner {} in state database: {:?}",
+                    name, e
+                );
+            }
+        }
+        granted
+    }
+
+    /// Returns launches that were reserved but not issued successfully to the launch quotas of an instance
+    fn refund_launches(&self, name: &str, instance: &GitLabRunnerInstance, launches: usize) {
+        let quotas = get_launch_quotas(instance);
+        if quotas.is_empty() || launches == 0 {
+            return;
+        }
+        let mut launch_quota = self.launch_quota.lock().unwrap();
+        let db = self.db.lock().unwrap();
+        for (period, capacity) in &quotas {
+            let Some(quota) = launch_quota.get_mut(&(name.to_owned(), *period)) else {
+                continue;
+            };
+            quota.tokens = (quota.tokens + launches as f64).min(*capacity as f64);
+            if let Err(e) = db.set_launch_quota(name, *period, quota) {
+                error!(
+                    "Failed storing launch quota of runner {} in state database: {:?}",
+                    name, e
+                );
+            }
+        }
+    }
+
+    fn is_unhealthy(&self, instance: &str) -> bool {
+        self.unhealthy_instances.lock().unwrap().contains(instance)
+    }
+
+    /// Updates the unhealthy instances from the prepare results reported by the executor
+    fn refresh_health(&self) {
+        let Some(cooldown) = self
+            .config
+            .launch
+            .as_ref()
+            .and_then(|launch| launch.prepare_failure_cooldown)
+        else {
+            return;
+        };
+        // this unwrap can't fail because the cooldown is part of the launch config
+        let threshold = self
+            .config
+            .launch
+            .as_ref()
+            .unwrap()
+            .prepare_failure_threshold;
+        let health = match self.db.lock().unwrap().read_instance_health() {
+            Ok(health) => health,
+            Err(e) => {
+                error!(
+                    "Failed reading instance health from state database: {:?}",
+                    e
+                );
+                return;
+            }
+        };
+        let now = unix_timestamp();
+        let mut unhealthy_instances = self.unhealthy_instances.lock().unwrap();
+        let previous = std::mem::take(&mut *unhealthy_instances);
+        for (instance, health) in health {
+            let cooling_down = health
+                .last_failure
+                .is_some_and(|failure| failure + cooldown as i64 > now);
+            if health.prepare_failures < threshold || !cooling_down {
+                continue;
+            }
+            if !previous.contains(&instance) {
+                warn!(
+                    "Jobs of runner {} failed to prepare {} times in a row, not using it for {}s, last error: {}",
+                    instance,
+                    health.prepare_failures,
+                    cooldown,
+                    health.message.unwrap_or_default()
+                );
+            }
+            unhealthy_instances.insert(instance);
+        }
+        for instance in previous.difference(&unhealthy_instances) {
+            info!("Runner {} is no longer considered unhealthy", instance);
+        }
+    }
+
+    /// Picks one of the runner instances matching the job according to poll.match_strategy
+    fn find_match(&self, job: &Job) -> Option<(&String, &GitLabRunnerInstance)> {
+        let mut candidates = find_matches(&self.config.runners, &self.job_filters, job);
+        if candidates.is_empty() {
+            debug!("Could not find a suitable runner for pending job {:?}", job);
+            return None;
+        }
+        // prefer healthy instances, jobs only matching unhealthy instances wait for their cooldown
+        if candidates.iter().any(|(name, _)| !self.is_unhealthy(name)) {
+            candidates.retain(|(name, _)| !self.is_unhealthy(name));
+        }
+        match self.config.poll.match_strategy {
+            GitLabMatchStrategy::FewestTags => {
+                candidates.into_iter().min_by_key(|i| i.1.tags.len())
+            }
+            GitLabMatchStrategy::RoundRobin => {
+                let index = self.round_robin.fetch_add(1, Ordering::Relaxed);
+                Some(candidates[index % candidates.len()])
+            }
+            GitLabMatchStrategy::Random => {
+                let random = RandomState::new().build_hasher().finish() as usize;
+                Some(candidates[random % candidates.len()])
+            }
+            GitLabMatchStrategy::LeastRecentlyLaunched => {
+                let status = self.control.status.lock().unwrap();
+                // instances that never launched a runner come first
+                candidates.into_iter().min_by_key(|i| {
+                    status
+                        .activity
+                        .get(i.0)
+                        .and_then(|activity| activity.last_launched)
+                })
+            }
+        }
+    }
+
+    /// Returns whether a pending job needs to be dispatched. Launched jobs are dispatched again
+    /// if they are still pending launch.redispatch_timeout seconds after their last launch.
+    fn needs_dispatch(&self, job_id: u64) -> bool {
+        let Some(handled) = self.handled_jobs.get(&job_id) else {
+            return true;
+        };
+        let timeout = self
+            .config
+            .launch
+            .as_ref()
+            .and_then(|launch| launch.redispatch_timeout);
+        match (&handled.instance, timeout) {
+            (Some(instance), Some(timeout))
+                if handled.handled_at + (timeout as i64) < unix_timestamp() =>
+            {
+                debug!(
+                    "Job {} is still pending {}s after launching runner {}, dispatching it again",
+                    job_id, timeout, instance
+                );
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 async fn initialize(paths: &cli::Paths) -> anyhow::Result<MetaRunnerState> {
@@ -42,20 +443,126 @@ async fn initialize(paths: &cli::Paths) -> anyhow::Result<MetaRunnerState> {
     let client = init_client(&config.hostname, &config.management_token)
         .await
         .context("Failed configuring GitLab API client")?;
-    let project = fetch_project(&client, &config.project).await?;
+    let projects = try_join_all(
+        config
+            .project
+            .as_slice()
+            .iter()
+            .map(|project| fetch_project(&client, project)),
+    )
+    .await?;
+    std::fs::create_dir_all(&paths.data_dir).context("Creating data dir failed")?;
+    let db = StateDb::open(&get_state_db_path(&paths.data_dir, &config.name))?;
+    let handled_jobs = db
+        .read_handled_jobs()
+        .context("Failed reading handled jobs from state database")?;
+    let launch_backoff = db
+        .read_launch_backoff()
+        .context("Failed reading launch backoff from state database")?;
+    let launch_quota = db
+        .read_launch_quota()
+        .context("Failed reading launch quota from state database")?;
+    let control = Arc::new(DaemonControl::new(config.runners.keys().cloned()));
+    control.set_activity(
+        db.read_activity()
+            .context("Failed reading instance activity from state database")?,
+    );
+    let allocation_id_pattern = config
+        .launch
+        .as_ref()
+        .and_then(|launch| launch.allocation_id_pattern.as_ref())
+        .map(|pattern| Regex::new(pattern))
+        .transpose()
+        .context("Failed parsing launch.allocation_id_pattern")?;
+    let job_filters = config
+        .runners
+        .iter()
+        .map(|(name, instance)| Ok((name.clone(), JobFilter::new(instance)?)))
+        .collect::<anyhow::Result<_>>()
+        .context("Failed compiling job filters")?;
     Ok(MetaRunnerState {
+        job_filters,
         config,
         client,
-        project,
-        successful_job_ids: HashSet::new(),
+        projects,
+        handled_jobs,
+        db: Mutex::new(db),
+        control,
+        allocation_id_pattern,
+        starved_job_ids: Mutex::new(HashSet::new()),
+        matched_job_ids: Mutex::new(HashSet::new()),
+        launch_backoff: Mutex::new(launch_backoff),
+        launch_quota: Mutex::new(launch_quota),
+        unhealthy_instances: Mutex::new(HashSet::new()),
+        round_robin: AtomicUsize::new(0),
+        hook_tasks: TaskTracker::new(),
     })
 }
 
-/// find the runner instance that has the correct tags with the smallest number of non-matching tags
-fn find_match<'a>(
+/// Creates the timer for updating the runner registrations if configure.auto_interval is set
+fn auto_configure_timer(config: &GitLabRunnersConfig) -> Option<time::Interval> {
+    config.configure.auto_interval.map(|interval| {
+        let mut timer = time::interval(Duration::from_secs(interval as u64));
+        timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        timer
+    })
+}
+
+/// Waits for the next tick of the timer, or forever if there is none
+async fn tick(timer: &mut Option<time::Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Re-reads the management token from the configuration file and rebuilds the GitLab client,
+/// e.g. after the token was rotated
+async fn reinit_client(paths: &cli::Paths, state: &mut MetaRunnerState) -> anyhow::Result<()> {
+    let config = read_config(&paths.config_file).context(format!(
+        "Failed reading configuration {:?}",
+        paths.config_file
+    ))?;
+    state.client = init_client(&state.config.hostname, &config.management_token)
+        .await
+        .context("Failed configuring GitLab API client")?;
+    state.config.management_token = config.management_token;
+    Ok(())
+}
+
+/// Reloads the configuration file, keeping the control socket and reported starved jobs.
+/// Only launched jobs are read back from the state database, so ignored jobs are matched against
+/// the reloaded instances and tags again.
+async fn reload(paths: &cli::Paths, state: &MetaRunnerState) -> anyhow::Result<MetaRunnerState> {
+    check_config::check(paths)?;
+    let mut new_state = initialize(paths).await?;
+    if new_state.config.name != state.config.name {
+        return Err(anyhow!(
+            "Changing the meta-runner name from {} to {} requires a restart",
+            state.config.name,
+            new_state.config.name
+        ));
+    }
+    let control = state.control.clone();
+    control.set_instances(new_state.config.runners.keys().cloned());
+    control.set_activity(new_state.db.get_mut().unwrap().read_activity()?);
+    new_state.control = control;
+    new_state.hook_tasks = state.hook_tasks.clone();
+    new_state.starved_job_ids = Mutex::new(state.starved_job_ids.lock().unwrap().clone());
+    new_state.matched_job_ids = Mutex::new(state.matched_job_ids.lock().unwrap().clone());
+    Ok(new_state)
+}
+
+/// find the runner instance that has the correct tags with the smallest number of non-matching tags,
+/// among the instances whose job filters match the job
+/// Returns all runner instances that can run the job, sorted by name
+fn find_matches<'a>(
     instances: &'a HashMap<String, GitLabRunnerInstance>,
+    job_filters: &HashMap<String, JobFilter>,
     job: &Job,
-) -> Option<(&'a String, &'a GitLabRunnerInstance)> {
+) -> Vec<(&'a String, &'a GitLabRunnerInstance)> {
     let requested_tags: HashSet<_> = job.tags.iter().collect();
     instances
         .iter()
@@ -63,27 +570,128 @@ fn find_match<'a>(
             let available_tags: HashSet<_> = i.1.tags.iter().collect();
             requested_tags.intersection(&available_tags).count() == requested_tags.len()
         })
-        .min_by_key(|i| i.1.tags.len())
-        .or_else(|| {
-            debug!("Could not find a suitable runner for pending job {:?}", job);
-            None
+        // GitLab only assigns untagged jobs to runners registered with run_untagged
+        .filter(|i| !requested_tags.is_empty() || i.1.run_untagged)
+        .filter(|i| {
+            job_filters
+                .get(i.0)
+                .is_none_or(|filter| filter.matches(job))
         })
+        .sorted_by_key(|i| i.0)
+        .collect()
+}
+
+/// Reports pending jobs that a runner was launched for more than launch.starvation_timeout seconds ago.
+/// Every job is only reported once.
+async fn report_starved_jobs(state: &MetaRunnerState, jobs: &[Job]) -> anyhow::Result<()> {
+    let Some(timeout) = state
+        .config
+        .launch
+        .as_ref()
+        .and_then(|l| l.starvation_timeout)
+    else {
+        return Ok(());
+    };
+    let launched_jobs = state
+        .db
+        .lock()
+        .unwrap()
+        .read_launched_jobs(unix_timestamp() - timeout as i64)?;
+    let starved: Vec<_> = {
+        let mut starved_job_ids = state.starved_job_ids.lock().unwrap();
+        jobs.iter()
+            .filter_map(|job| Some((job, launched_jobs.get(&job.id)?)))
+            .filter(|(job, _)| starved_job_ids.insert(job.id))
+            .collect()
+    };
+    for (job, name) in starved {
+        warn!(
+            "Job {} ({}) is still pending {}s after launching runner {} for it, \
+             the runner may not be able to pick it up",
+            job.id, job.name, timeout, name
+        );
+        let payload = json!({"instance": name, "job_id": job.id, "job_name": job.name});
+        run_hooks(
+            &state.config,
+            &state.hook_tasks,
+            HookEvent::JobStarved,
+            payload,
+        );
+    }
+    Ok(())
+}
+
+/// Pending jobs matched to runner instances, the ignored jobs and the number of jobs per instance
+/// that a runner was already launched for, but which are still pending
+type MatchedJobs<'a> = (
+    Vec<(&'a String, &'a GitLabRunnerInstance, Job)>,
+    Vec<Job>,
+    HashMap<String, usize>,
+);
+
+/// Fetches the pending jobs of all projects
+async fn fetch_pending_jobs(state: &MetaRunnerState) -> anyhow::Result<Vec<Job>> {
+    let api_timeout = state.config.poll.api_timeout;
+    let jobs = time::timeout(
+        Duration::from_secs(api_timeout as u64),
+        try_join_all(
+            state
+                .projects
+                .iter()
+                .map(|project| fetch_pending_project_jobs(&state.client, project)),
+        ),
+    )
+    .await
+    .context(format!(
+        "Timed out after {}s fetching pending jobs",
+        api_timeout
+    ))
+    .and_then(|jobs| Ok(jobs?))
+    .inspect_err(|_| state.control.metrics.record_api_error())?
+    .into_iter()
+    .flatten()
+    // the same job is returned twice if a project is configured twice, e.g. by path and ID
+    .unique_by(|job| job.id)
+    .collect();
+    Ok(jobs)
 }
 
-async fn check_jobs<'a>(
-    state: &'a MetaRunnerState,
-) -> anyhow::Result<(Vec<(&'a String, &'a GitLabRunnerInstance, Job)>, Vec<Job>)> {
-    let jobs = fetch_pending_project_jobs(&state.client, &state.project).await?;
-    Ok(jobs
+/// Fetches the pending jobs, reports starved jobs and matches the jobs that need to be dispatched
+async fn check_jobs(state: &MetaRunnerState) -> anyhow::Result<MatchedJobs<'_>> {
+    let jobs = fetch_pending_jobs(state).await?;
+    state
+        .control
+        .metrics
+        .set_pending_jobs(jobs.iter().map(|job| job.id));
+    report_starved_jobs(state, &jobs).await?;
+    state.refresh_health();
+    Ok(match_jobs(state, jobs))
+}
+
+/// Matches the pending jobs that need to be dispatched to runner instances
+fn match_jobs(state: &MetaRunnerState, jobs: Vec<Job>) -> MatchedJobs<'_> {
+    let mut dispatched_jobs = HashMap::new();
+    let (matched_jobs, ignored_jobs) = jobs
         .into_iter()
-        .filter(|job| !state.successful_job_ids.contains(&job.id))
-        .partition_map(|job| match find_match(&state.config.runners, &job) {
+        .filter(|job| {
+            let needs_dispatch = state.needs_dispatch(job.id);
+            if !needs_dispatch {
+                let handled = state.handled_jobs.get(&job.id);
+                if let Some(instance) = handled.and_then(|handled| handled.instance.clone()) {
+                    *dispatched_jobs.entry(instance).or_default() += 1;
+                }
+            }
+            needs_dispatch
+        })
+        .partition_map(|job| match state.find_match(&job) {
             None => Either::Right(job),
             Some((name, instance)) => Either::Left((name, instance, job)),
-        }))
+        });
+    (matched_jobs, ignored_jobs, dispatched_jobs)
 }
 
-async fn launch_runner(config: &GitLabLaunchConfig) -> anyhow::Result<()> {
+/// Runs the launch command, returning its stdout
+async fn launch_runner(config: &GitLabLaunchConfig) -> anyhow::Result<String> {
     let mut command: Command = Command::new(&config.executable);
     if let Some(workdir) = &config.workdir {
         command.current_dir(workdir);
@@ -139,7 +747,7 @@ async fn launch_runner(config: &GitLabLaunchConfig) -> anyhow::Result<()> {
         config, stdout, stderr
     );
     if exit_status.success() {
-        Ok(())
+        Ok(stdout.into_owned())
     } else {
         Err(anyhow!(
             "Runner launch with configuration {:?} failed with exit code {}\nstdout:\n{}\nstderr:\n{}",
@@ -150,6 +758,110 @@ async fn launch_runner(config: &GitLabLaunchConfig) -> anyhow::Result<()> {
     }
 }
 
+/// Runs a pre- or post-launch hook with the given environment variables
+async fn run_launch_hook(
+    hook: &GitLabLaunchHookConfig,
+    timeout: Option<u32>,
+    env: &[(&str, &str)],
+) -> anyhow::Result<()> {
+    let mut command = Command::new(&hook.executable);
+    command
+        .args(hook.args.iter())
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    debug!("Spawning launch hook process {:?}", command);
+    let timeout_sec = timeout.unwrap_or(u32::MAX) as u64;
+    let output = time::timeout(time::Duration::from_secs(timeout_sec), command.output())
+        .await
+        .context(format!("Launch hook {:?} timed out", command))?
+        .context(format!("Failed running launch hook {:?}", command))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Launch hook {:?} failed with exit code {}\nstderr:\n{}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+/// Runs the queue check command, returning the number of queued or running launches it reports
+async fn queued_launches(
+    queue_check: &GitLabQueueCheckConfig,
+    timeout: Option<u32>,
+) -> anyhow::Result<usize> {
+    let mut command = Command::new(&queue_check.executable);
+    command
+        .args(queue_check.args.iter())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    debug!("Spawning queue check process {:?}", command);
+    let timeout_sec = timeout.unwrap_or(u32::MAX) as u64;
+    let output = time::timeout(time::Duration::from_secs(timeout_sec), command.output())
+        .await
+        .context(format!("Queue check {:?} timed out", command))?
+        .context(format!("Failed running queue check {:?}", command))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Queue check {:?} failed with exit code {}\nstderr:\n{}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.trim().parse().context(format!(
+        "Queue check {:?} produced invalid output {:?}",
+        command, stdout
+    ))
+}
+
+/// Launches a runner for a group of jobs, surrounded by the configured pre- and post-launch hooks
+async fn launch_runner_with_hooks(
+    config: &GitLabLaunchConfig,
+    name: &str,
+    jobs: &[&Job],
+) -> anyhow::Result<String> {
+    let job_ids = jobs.iter().map(|job| job.id).join(" ");
+    let env = [
+        ("META_RUNNER_INSTANCE", name),
+        ("META_RUNNER_JOB_IDS", &job_ids),
+    ];
+    if let Some(pre_hook) = &config.pre_hook {
+        run_launch_hook(pre_hook, config.timeout, &env)
+            .await
+            .context("Pre-launch hook failed")?;
+    }
+    let mut result = launch_runner(config).await;
+    for attempt in 1..=config.retries {
+        let Err(e) = &result else {
+            break;
+        };
+        warn!(
+            "Launching runner {} failed, retrying in {}s ({}/{}): {:?}",
+            name, config.retry_delay, attempt, config.retries, e
+        );
+        time::sleep(Duration::from_secs(config.retry_delay as u64)).await;
+        result = launch_runner(config).await;
+    }
+    if let Some(post_hook) = &config.post_hook {
+        let status = if result.is_ok() { "success" } else { "failed" };
+        let env = [env[0], env[1], ("META_RUNNER_LAUNCH_STATUS", status)];
+        // the launch already happened, so a failing post-launch hook doesn't change its outcome
+        if let Err(e) = run_launch_hook(post_hook, config.timeout, &env).await {
+            warn!("Post-launch hook for runner {} failed: {:?}", name, e);
+        }
+    }
+    result
+}
+
 struct PrintableJobVec<'a> {
     jobs: &'a Vec<&'a Job>,
 }
@@ -167,8 +879,191 @@ impl Display for PrintableJobVec<'_> {
     }
 }
 
-async fn run_impl(paths: &cli::Paths, state: &MetaRunnerState) -> anyhow::Result<Vec<u64>> {
-    let (matched_jobs, ignored_jobs) = check_jobs(state).await?;
+/// Sort key ordering jobs from oldest to newest, with jobs of unknown age last
+fn job_age_key(job: &Job) -> (bool, Option<DateTime<Utc>>) {
+    (job.created_at.is_none(), job.created_at)
+}
+
+/// Returns the number of newly matched jobs already covered by queued launches of an instance.
+/// Queued launches pick up the oldest pending jobs, starting with the dispatched jobs they were
+/// launched for, so only their surplus capacity covers newly matched jobs.
+fn count_queued_jobs(queued: usize, group_size: usize, dispatched: usize, matched: usize) -> usize {
+    (queued * group_size)
+        .saturating_sub(dispatched)
+        .min(matched)
+}
+
+/// Drops the jobs exceeding the given number of launch commands, starting with the lowest priority.
+/// The dropped jobs stay pending and will be dispatched in the following polls.
+fn limit_launches<'a, N: Clone, I>(
+    grouped_jobs: &mut Vec<(N, (I, Vec<&'a Job>))>,
+    group_size: usize,
+    max_launches: usize,
+) -> Vec<(N, &'a Job)> {
+    let mut remaining = max_launches;
+    let mut deferred = Vec::new();
+    for (name, (_, jobs)) in grouped_jobs.iter_mut() {
+        let max_jobs = remaining * group_size;
+        if jobs.len() > max_jobs {
+            deferred.extend(
+                jobs.split_off(max_jobs)
+                    .into_iter()
+                    .map(|job| (name.clone(), job)),
+            );
+        }
+        remaining -= jobs.len().div_ceil(group_size);
+    }
+    grouped_jobs.retain(|(_, (_, jobs))| !jobs.is_empty());
+    if !deferred.is_empty() {
+        info!(
+            "Reached launch.max_per_poll ({}), deferring {} jobs to the next poll",
+            max_launches,
+            deferred.len()
+        );
+    }
+    deferred
+}
+
+async fn run_impl(
+    paths: &cli::Paths,
+    state: &MetaRunnerState,
+) -> anyhow::Result<Vec<(u64, Option<String>)>> {
+    let maintenance = time::timeout(
+        Duration::from_secs(state.config.poll.api_timeout as u64),
+        sync_maintenance(&state.config, &state.client, &state.db),
+    )
+    .await
+    .context(format!(
+        "Timed out after {}s synchronizing maintenance mode",
+        state.config.poll.api_timeout
+    ))??;
+    if maintenance {
+        info!("Maintenance is active, not launching any runners");
+        return Ok(Vec::new());
+    }
+    let (mut matched_jobs, mut ignored_jobs, mut dispatched_jobs) = check_jobs(state).await?;
+    if let Some(debounce) = state.config.poll.debounce {
+        // jobs that stay pending, e.g. because of the launch quota, don't delay every poll
+        let new_jobs = {
+            let matched_job_ids = state.matched_job_ids.lock().unwrap();
+            matched_jobs
+                .iter()
+                .filter(|(_, _, job)| !matched_job_ids.contains(&job.id))
+                .count()
+        };
+        if new_jobs > 0 {
+            // give bursts of newly created jobs time to show up, so they can be grouped
+            debug!(
+                "Found {} new matching jobs, waiting {}s for further jobs",
+                new_jobs, debounce
+            );
+            time::sleep(Duration::from_secs(debounce as u64)).await;
+            // starved jobs were already reported for the first fetch
+            (matched_jobs, ignored_jobs, dispatched_jobs) =
+                match_jobs(state, fetch_pending_jobs(state).await?);
+        }
+        *state.matched_job_ids.lock().unwrap() =
+            matched_jobs.iter().map(|(_, _, job)| job.id).collect();
+    }
+    let mut decisions = Vec::new();
+    {
+        let now = unix_timestamp();
+        let db = state.db.lock().unwrap();
+        for (name, count) in matched_jobs.iter().map(|(name, _, _)| name).counts() {
+            state.control.metrics.record_matched(name, count);
+            if let Err(e) = db.record_activity(name, Activity::Matched, now) {
+                warn!("Failed storing activity of runner {}: {:?}", name, e);
+            }
+        }
+    }
+    // while draining, all matched jobs stay pending until drain mode is ended
+    if state.control.is_draining() && !matched_jobs.is_empty() {
+        info!(
+            "Draining, not launching runners for {} matched jobs",
+            matched_jobs.len()
+        );
+        decisions.extend(
+            matched_jobs
+                .drain(..)
+                .map(|(name, _, job)| DecisionEntry::new(&job, Some(name), Decision::Draining)),
+        );
+    }
+    // jobs for paused instances stay pending until the instance is resumed
+    matched_jobs.retain(|(name, _, job)| {
+        let paused = state.control.is_paused(name);
+        if paused {
+            debug!("Not dispatching job {} for paused runner {}", job.id, name);
+            decisions.push(DecisionEntry::new(
+                job,
+                Some(name),
+                Decision::InstancePaused,
+            ));
+        }
+        !paused
+    });
+    // the same holds for instances whose launches failed recently
+    matched_jobs.retain(|(name, _, job)| {
+        let backoff = state.in_backoff(name);
+        if backoff {
+            debug!(
+                "Not dispatching job {} for runner {} after failed launches",
+                job.id, name
+            );
+            decisions.push(DecisionEntry::new(
+                job,
+                Some(name),
+                Decision::InstanceBackoff,
+            ));
+        }
+        !backoff
+    });
+    // and unhealthy instances whose jobs failed to prepare
+    matched_jobs.retain(|(name, _, job)| {
+        let unhealthy = state.is_unhealthy(name);
+        if unhealthy {
+            debug!(
+                "Not dispatching job {} for unhealthy runner {}",
+                job.id, name
+            );
+            decisions.push(DecisionEntry::new(
+                job,
+                Some(name),
+                Decision::InstanceUnhealthy,
+            ));
+        }
+        !unhealthy
+    });
+    // and instances outside their dispatch schedule
+    let now = Local::now().naive_local();
+    let globally_scheduled = in_schedule(&state.config.schedule, now);
+    let mut unscheduled = Vec::new();
+    matched_jobs.retain(|(name, instance, job)| {
+        let scheduled = globally_scheduled && in_schedule(&instance.schedule, now);
+        if !scheduled {
+            unscheduled.push(name.as_str());
+            decisions.push(DecisionEntry::new(
+                job,
+                Some(name),
+                Decision::OutsideSchedule,
+            ));
+        }
+        scheduled
+    });
+    for (name, count) in unscheduled.into_iter().counts() {
+        info!(
+            "Runner {} is outside its dispatch schedule, not launching it for {} matched jobs",
+            name, count
+        );
+    }
+    for (name, _, job) in &matched_jobs {
+        let payload = json!({"instance": name, "job_id": job.id, "job_name": job.name});
+        run_hooks(
+            &state.config,
+            &state.hook_tasks,
+            HookEvent::JobMatched,
+            payload,
+        );
+    }
     // Group jobs by runner instance
     let mut grouped_matched_jobs = HashMap::new();
     for (name, instance, job) in matched_jobs.iter() {
@@ -177,14 +1072,104 @@ async fn run_impl(paths: &cli::Paths, state: &MetaRunnerState) -> anyhow::Result
         }
         grouped_matched_jobs.get_mut(name).unwrap().1.push(job);
     }
-    // sort descending by priority
+    // sort descending by priority, then by the age of the oldest job,
+    // so long-queued jobs are launched first.
+    // Within an instance, jobs that are allowed to fail are launched after all others.
     let mut grouped_matched_jobs: Vec<_> = grouped_matched_jobs.into_iter().collect();
-    grouped_matched_jobs.sort_by_key(|(_, (instance, _))| instance.launch_priority);
-    grouped_matched_jobs.reverse();
+    for (_, (_, jobs)) in grouped_matched_jobs.iter_mut() {
+        jobs.sort_by_key(|job| (job.allow_failure, job_age_key(job)));
+    }
+    grouped_matched_jobs.sort_by_key(|(_, (instance, jobs))| {
+        (
+            Reverse(instance.launch_priority),
+            jobs.first().map(|job| job_age_key(job)),
+        )
+    });
+    // this unwrap can't fail because we ran check_config::check
+    let launch_config = state.config.launch.as_ref().unwrap();
+    let group_size = launch_config.group_size;
+    if launch_config.queue_check.is_some() {
+        let queued = join_all(grouped_matched_jobs.iter().map(|(name, (instance, _))| {
+            let instantiated_config = expand_launch_config_template(
+                paths,
+                &state.config,
+                name,
+                instance,
+                group_size,
+                group_size as u64 * DEFAULT_JOB_TIMEOUT,
+            )
+            .unwrap(); // this can't fail because we ran check_config::check
+            async move {
+                let queue_check = instantiated_config.queue_check.as_ref().unwrap();
+                queued_launches(queue_check, instantiated_config.timeout).await
+            }
+        }))
+        .await;
+        // launches that are already queued will pick up the oldest matched jobs
+        for ((name, (_, jobs)), queued) in grouped_matched_jobs.iter_mut().zip(queued) {
+            match queued {
+                Ok(queued) => {
+                    let dispatched = dispatched_jobs.get(name.as_str()).copied().unwrap_or(0);
+                    let skipped = count_queued_jobs(queued, group_size, dispatched, jobs.len());
+                    if skipped > 0 {
+                        debug!(
+                            "Runner {} has {} queued launches, not launching it for {} jobs",
+                            name, queued, skipped
+                        );
+                    }
+                    decisions.extend(
+                        jobs.drain(..skipped).map(|job| {
+                            DecisionEntry::new(job, Some(name), Decision::AlreadyQueued)
+                        }),
+                    );
+                }
+                Err(e) => warn!(
+                    "Queue check for runner {} failed, launching it for all matched jobs: {:?}",
+                    name, e
+                ),
+            }
+        }
+        grouped_matched_jobs.retain(|(_, (_, jobs))| !jobs.is_empty());
+    }
+    if let Some(max_per_poll) = launch_config.max_per_poll {
+        let deferred = limit_launches(&mut grouped_matched_jobs, group_size, max_per_poll);
+        decisions.extend(
+            deferred
+                .into_iter()
+                .map(|(name, job)| DecisionEntry::new(job, Some(name), Decision::LaunchLimit)),
+        );
+    }
+    // launches exceeding the hourly or daily quota of an instance are deferred until it refills
+    for (name, (instance, jobs)) in grouped_matched_jobs.iter_mut() {
+        let launches = jobs.len().div_ceil(group_size);
+        let granted = state.reserve_launches(name, instance, launches);
+        if granted < launches {
+            let deferred = jobs.split_off(granted * group_size);
+            info!(
+                "Runner {} reached its launch quota, not launching it for {} jobs",
+                name,
+                deferred.len()
+            );
+            decisions.extend(
+                deferred
+                    .into_iter()
+                    .map(|job| DecisionEntry::new(job, Some(name), Decision::LaunchQuota)),
+            );
+        }
+    }
+    grouped_matched_jobs.retain(|(_, (_, jobs))| !jobs.is_empty());
     // Dispatch jobs
+    let semaphore = Semaphore::new(
+        launch_config
+            .parallelism
+            .unwrap_or(Semaphore::MAX_PERMITS)
+            .min(Semaphore::MAX_PERMITS),
+    );
+    let semaphore = &semaphore;
+    let budget_deadline = launch_config
+        .budget
+        .map(|budget| Instant::now() + Duration::from_secs(budget as u64));
     let mut queue = Vec::new();
-    // this unwrap can't fail because we ran check_config::check
-    let group_size = state.config.launch.as_ref().unwrap().group_size;
     for (name, (instance, jobs)) in &grouped_matched_jobs {
         debug!(
             "Using runner {} {:?} to dispatch jobs {}",
@@ -193,65 +1178,179 @@ async fn run_impl(paths: &cli::Paths, state: &MetaRunnerState) -> anyhow::Result
             PrintableJobVec { jobs }
         );
         queue.push(async move {
-            join_all(
-                (0..jobs.len())
-                    .into_iter()
-                    .chunks(group_size as usize)
-                    .into_iter()
-                    .map(|chunk| {
-                        let count = chunk.count();
-                        async move {
-                            let instantiated_config = expand_launch_config_template(
-                                paths,
-                                &state.config,
-                                name,
-                                instance,
-                                count,
-                            )
-                            .unwrap(); // this can't fail because we ran check_config::check
-                            launch_runner(&instantiated_config).await
-                        }
-                    }),
-            )
+            // NUM_JOBS and JOB_TIMEOUT depend on the jobs in each chunk, so they are expanded for every chunk separately
+            join_all(jobs.chunks(group_size).map(|chunk| async move {
+                let num_jobs = chunk.len();
+                // the runner executes the jobs one after another
+                let job_timeout = chunk.iter().map(|job| job.timeout_or_default()).sum();
+                debug!("Launching runner {} for {} jobs", name, num_jobs);
+                let instantiated_config = expand_launch_config_template(
+                    paths,
+                    &state.config,
+                    name,
+                    instance,
+                    num_jobs,
+                    job_timeout,
+                )
+                .unwrap(); // this can't fail because we ran check_config::check
+                let _permit = semaphore.acquire().await.unwrap(); // the semaphore is never closed
+
+                // launches that would start after the launch budget is used up are postponed
+                if budget_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return None;
+                }
+                Some(launch_runner_with_hooks(&instantiated_config, name, chunk).await)
+            }))
             .await
-            .into_iter()
-            .collect()
         });
     }
     // Collect results from dispatch
-    let launch_results: Vec<Vec<anyhow::Result<_>>> = join_all(queue.into_iter()).await;
+    let launch_results: Vec<Vec<Option<anyhow::Result<_>>>> = join_all(queue.into_iter()).await;
     let mut successful = Vec::new();
-    for ((name, (_, jobs)), result) in grouped_matched_jobs.iter().zip(launch_results.iter()) {
-        let job_chunks = jobs.into_iter().chunks(group_size as usize);
-        let (success, failure): (Vec<_>, Vec<_>) = job_chunks
-            .into_iter()
-            .zip(result.into_iter())
-            .partition_map(|(job_chunk, result)| match result {
-                Ok(_) => Either::Left(job_chunk),
-                Err(e) => Either::Right((job_chunk, e)),
+    let mut hook_events = Vec::new();
+    let mut notifications = Vec::new();
+    for ((name, (instance, jobs)), result) in grouped_matched_jobs.iter().zip(&launch_results) {
+        let mut postponed = Vec::new();
+        let (success, failure): (Vec<_>, Vec<_>) = jobs
+            .chunks(group_size)
+            .zip(result.iter())
+            .filter_map(|(job_chunk, result)| match result {
+                Some(result) => Some((job_chunk, result)),
+                None => {
+                    postponed.extend(job_chunk.iter().map(Deref::deref));
+                    None
+                }
+            })
+            .partition_map(|(job_chunk, result)| {
+                let job_chunk: Vec<&Job> = job_chunk.iter().map(Deref::deref).collect();
+                match result {
+                    Ok(stdout) => Either::Left((job_chunk, stdout)),
+                    Err(e) => Either::Right((job_chunk, e)),
+                }
             });
+        if !postponed.is_empty() {
+            info!(
+                "Launch budget used up, postponing launching runner {} for {} jobs to the next poll",
+                name,
+                postponed.len()
+            );
+            decisions.extend(
+                postponed
+                    .into_iter()
+                    .map(|job| DecisionEntry::new(job, Some(name), Decision::LaunchBudget)),
+            );
+        }
+        // only successful launches count towards the launch quota
+        let unlaunched = result.iter().filter(|result| result.is_none()).count() + failure.len();
+        state.refund_launches(name, instance, unlaunched);
+        // if all launches were postponed, nothing tells whether the instance is healthy again
+        if !success.is_empty() || !failure.is_empty() {
+            notifications.extend(state.update_backoff(name, failure.first().map(|(_, e)| *e)));
+        }
+        state.control.record_launches(
+            name,
+            success.iter().map(|(job_chunk, _)| job_chunk.len()).sum(),
+            success.len() + failure.len(),
+            failure.len(),
+        );
+        let db = state.db.lock().unwrap();
+        let mut record_launch = |jobs: &Vec<&Job>, message: Option<String>| {
+            let event = match message {
+                None => HookEvent::LaunchSucceeded,
+                Some(_) => HookEvent::LaunchFailed,
+            };
+            hook_events.push((
+                event,
+                json!({
+                    "instance": name,
+                    "job_ids": jobs.iter().map(|job| job.id).collect::<Vec<_>>(),
+                    "error": message,
+                }),
+            ));
+            decisions.extend(jobs.iter().map(|job| {
+                match &message {
+                    None => DecisionEntry::new(job, Some(name), Decision::Launched),
+                    Some(message) => DecisionEntry::new(job, Some(name), Decision::LaunchFailed)
+                        .with_message(message.clone()),
+                }
+            }));
+            let record = LaunchRecord {
+                instance: name,
+                job_ids: jobs.iter().map(|job| job.id).collect(),
+                success: message.is_none(),
+                message,
+            };
+            if let Err(e) = db.add_launch_record(&record) {
+                warn!("Failed storing launch record {:?}: {:?}", record, e);
+            }
+        };
+        for (job_chunk, stdout) in &success {
+            record_launch(job_chunk, None);
+            if let Some(pattern) = &state.allocation_id_pattern {
+                match extract_allocation_id(pattern, stdout) {
+                    Some(allocation_id) => {
+                        let allocation = Allocation {
+                            allocation_id,
+                            instance: name.to_string(),
+                            job_ids: job_chunk.iter().map(|job| job.id).collect(),
+                        };
+                        if let Err(e) = db.add_allocation(&allocation) {
+                            warn!("Failed storing allocation {:?}: {:?}", allocation, e);
+                        }
+                    }
+                    None => warn!(
+                        "Could not find allocation ID in launch output for runner {}:\n{}",
+                        name, stdout
+                    ),
+                }
+            }
+        }
         if success.len() > 0 {
-            let success_vec = success.into_iter().flatten().map(Deref::deref).collect();
+            if let Err(e) = db.record_activity(name, Activity::Launched, unix_timestamp()) {
+                warn!("Failed storing activity of runner {}: {:?}", name, e);
+            }
+            let success_vec = success
+                .into_iter()
+                .flat_map(|(job_chunk, _)| job_chunk)
+                .collect();
             info!(
                 "Launched runner {} for jobs {} successfully",
                 name,
                 PrintableJobVec { jobs: &success_vec }
             );
-            successful.extend(success_vec.into_iter().map(|job| job.id));
+            successful.extend(
+                success_vec
+                    .into_iter()
+                    .map(|job| (job.id, Some(name.to_string()))),
+            );
         }
-        for f in failure {
+        for (jobs, e) in failure {
             error!(
                 "Failed launching runner {} for jobs {}, error message: {:?}",
                 name,
-                PrintableJobVec {
-                    jobs: &f.0.into_iter().map(Deref::deref).collect()
-                },
-                f.1
-            )
+                PrintableJobVec { jobs: &jobs },
+                e
+            );
+            record_launch(&jobs, Some(format!("{:?}", e)));
+        }
+    }
+    for (event, payload) in hook_events {
+        run_hooks(&state.config, &state.hook_tasks, event, payload);
+    }
+    notify(&state.config, notifications).await;
+    decisions.extend(
+        ignored_jobs
+            .iter()
+            .map(|job| DecisionEntry::new(job, None, Decision::Unmatched)),
+    );
+    if state.config.poll.decision_log {
+        let path = get_decision_log_path(&paths.data_dir, &state.config.name);
+        if let Err(e) = decision_log::append(&path, &decisions) {
+            warn!("{:?}", e);
         }
     }
     // ignore any jobs that we couldn't find a runner for
-    successful.extend(ignored_jobs.into_iter().map(|job| job.id));
+    successful.extend(ignored_jobs.into_iter().map(|job| (job.id, None)));
     Ok(successful)
 }
 
@@ -261,50 +1360,364 @@ pub async fn run(paths: cli::Paths) -> anyhow::Result<()> {
     let mut state = initialize(&paths).await?;
     let cancel_token = CancellationToken::new();
     let job_cancel_token = cancel_token.clone();
+    let control = state.control.clone();
+    let socket_path = get_control_socket_path(&paths.data_dir, &state.config.name);
+    let session_log_path = get_session_log_path(&paths.data_dir, &state.config.name);
+    let control_task = tokio::spawn(control::serve(
+        socket_path,
+        control.clone(),
+        cancel_token.clone(),
+    ));
+    let metrics_task = state.config.metrics.as_ref().map(|metrics| {
+        tokio::spawn(metrics::serve(
+            metrics.listen.clone(),
+            control.clone(),
+            cancel_token.clone(),
+        ))
+    });
 
+    let mut sighup = unix_signal(SignalKind::hangup()).context("Failed listening for SIGHUP")?;
+    // systemd stops the service with SIGTERM
+    let mut sigterm =
+        unix_signal(SignalKind::terminate()).context("Failed listening for SIGTERM")?;
+    if let Some(watchdog) = systemd::watchdog_interval() {
+        if watchdog.as_secs() <= state.config.poll.interval as u64 {
+            warn!(
+                "The systemd watchdog interval ({}s) should be larger than poll.interval ({}s)",
+                watchdog.as_secs(),
+                state.config.poll.interval
+            );
+        }
+    }
+    systemd::notify("READY=1");
+    let session_control = control.clone();
     let task = tokio::spawn(async move {
-        let poll_duration = Duration::from_secs(state.config.poll.interval as u64);
+        let mut poll_duration = Duration::from_secs(state.config.poll.interval as u64);
         let mut timer = time::interval(poll_duration);
         timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut configure_timer = auto_configure_timer(&state.config);
+        let mut auth_failures = 0;
+        let mut poll_failures = 0;
         loop {
             // Handle cancellation
             select! {
                 _ = timer.tick().fuse() => (),
+                _ = control.poll_trigger.notified().fuse() => (),
+                _ = sighup.recv().fuse() => {
+                    info!("Received SIGHUP, reloading configuration {:?}", paths.config_file);
+                    match reload(&paths, &state).await {
+                        Ok(new_state) => {
+                            state = new_state;
+                            poll_duration = Duration::from_secs(state.config.poll.interval as u64);
+                            timer = time::interval(poll_duration);
+                            timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                            configure_timer = auto_configure_timer(&state.config);
+                            info!("Reloaded configuration");
+                        }
+                        Err(e) => error!("Failed reloading configuration, keeping the previous one: {:?}", e),
+                    }
+                    continue
+                }
+                _ = tick(&mut configure_timer).fuse() => {
+                    info!("Updating runner registrations and gitlab-runner configuration");
+                    match update_configuration(&paths, &state.config, &state.hook_tasks).await {
+                        Ok(path) => info!("Wrote gitlab-runner configuration file {:?}", path),
+                        Err(e) => error!("Failed updating runner registrations: {:?}", e),
+                    }
+                    continue
+                }
                 _ =  job_cancel_token.cancelled().fuse() => {
                     info!("Poll task shutting down");
-                    break
+                    return Ok(())
                 }
             };
             // Actual poll loop
             info!("Polling for jobs...");
-            let result = future::timeout(poll_duration, run_impl(&paths, &state)).await;
-            match result {
-                Ok(Ok(new_successful_jobs)) => state
-                    .successful_job_ids
-                    .extend(new_successful_jobs.into_iter()),
-                Ok(Err(e)) => error!("Failed poll: {:?}", e),
-                Err(_) => error!("Poll timed out"),
+            let poll_start = Instant::now();
+            // the API requests and launches of a poll are limited by poll.api_timeout and launch.budget
+            let error = match run_impl(&paths, &state).await {
+                Ok(new_successful_jobs) => {
+                    state.mark_handled(new_successful_jobs);
+                    systemd::notify("WATCHDOG=1");
+                    auth_failures = 0;
+                    None
+                }
+                Err(e) if is_auth_error(&e) => {
+                    auth_failures += 1;
+                    if auth_failures >= MAX_AUTH_FAILURES {
+                        return Err(anyhow!(
+                            "GitLab rejected the management token in {} consecutive polls, please check management_token in {:?}",
+                            auth_failures,
+                            paths.config_file
+                        ));
+                    }
+                    error!("Failed poll: {:?}", e);
+                    let payload = json!({"error": format!("{:?}", e)});
+                    run_hooks(
+                        &state.config,
+                        &state.hook_tasks,
+                        HookEvent::ApiOutage,
+                        payload,
+                    );
+                    warn!("GitLab rejected the management token, reinitializing the API client");
+                    if let Err(e) = reinit_client(&paths, &mut state).await {
+                        error!("Failed reinitializing the GitLab API client: {:?}", e);
+                    }
+                    Some(format!("{:?}", e))
+                }
+                Err(e) => {
+                    error!("Failed poll: {:?}", e);
+                    if is_api_error(&e) {
+                        let payload = json!({"error": format!("{:?}", e)});
+                        run_hooks(
+                            &state.config,
+                            &state.hook_tasks,
+                            HookEvent::ApiOutage,
+                            payload,
+                        );
+                    }
+                    Some(format!("{:?}", e))
+                }
+            };
+            let threshold = state
+                .config
+                .notify
+                .as_ref()
+                .map_or(u32::MAX, |notify| notify.failure_threshold);
+            let notification = match &error {
+                Some(error) => {
+                    poll_failures += 1;
+                    (poll_failures == threshold).then(|| {
+                        let message =
+                            format!("Polling failed {} times in a row: {}", poll_failures, error);
+                        Notification::new(NotifyEvent::PollsFailing, None, message)
+                    })
+                }
+                None => {
+                    let recovered = poll_failures >= threshold;
+                    poll_failures = 0;
+                    recovered.then(|| {
+                        Notification::new(
+                            NotifyEvent::PollsRecovered,
+                            None,
+                            "Polling succeeded again".into(),
+                        )
+                    })
+                }
             };
+            notify(&state.config, notification.into_iter().collect()).await;
+            control
+                .metrics
+                .record_poll(poll_start.elapsed(), error.is_none());
+            control.finish_poll(error, state.handled_jobs.len());
+            match reconcile_allocations(&state.config, &state.client, &state.projects, &state.db)
+                .await
+            {
+                Ok(released_jobs) => state.release_handled(released_jobs),
+                Err(e) => error!("Failed reconciling allocations: {:?}", e),
+            }
+            if state.allocation_id_pattern.is_some() {
+                let allocations = state
+                    .db
+                    .lock()
+                    .unwrap()
+                    .read_open_allocations(unix_timestamp() + 1);
+                match allocations {
+                    Ok(allocations) => {
+                        let counts = allocations
+                            .into_iter()
+                            .map(|allocation| allocation.instance)
+                            .counts();
+                        let outstanding = state
+                            .config
+                            .runners
+                            .keys()
+                            .map(|name| (name.clone(), counts.get(name).copied().unwrap_or(0)))
+                            .collect();
+                        control.set_outstanding_launches(outstanding);
+                    }
+                    Err(e) => error!("Failed reading open allocations: {:?}", e),
+                }
+            }
+            if let Err(e) = activity::refresh_executed(&state.client, &state.db).await {
+                error!("Failed refreshing runner activity: {:?}", e);
+            }
+            match state.db.lock().unwrap().read_activity() {
+                Ok(activity) => control.set_activity(activity),
+                Err(e) => error!("Failed reading runner activity: {:?}", e),
+            }
         }
     });
 
-    match signal::ctrl_c().await {
-        Ok(()) => info!("Received shutdown signal (Ctrl+C), cancelling poll task"),
-        Err(_) => error!("Failed to listen for shutdown signal, shutting down anyways."),
-    }
+    // the poll task only stops by itself after a fatal error
+    let mut task = task;
+    let stopped_task = tokio::select! {
+        shutdown = signal::ctrl_c() => {
+            match shutdown {
+                Ok(()) => info!("Received shutdown signal (Ctrl+C), cancelling poll task"),
+                Err(_) => error!("Failed to listen for shutdown signal, shutting down anyways."),
+            }
+            None
+        }
+        _ = sigterm.recv() => {
+            info!("Received shutdown signal (SIGTERM), cancelling poll task");
+            None
+        }
+        result = &mut task => Some(result),
+    };
 
+    systemd::notify("STOPPING=1");
     // the result of the shutdown signal send doesn't matter, since if it fails, the task already hung up
     cancel_token.cancel();
-    task.await
-        .context("Failed waiting for poll task to finish")?;
+    let result = match stopped_task {
+        Some(result) => result,
+        None => task.await,
+    }
+    .context("Failed waiting for poll task to finish")?;
+    if let Err(e) = control_task
+        .await
+        .context("Failed waiting for control socket task to finish")?
+    {
+        error!("Control socket failed: {:?}", e);
+    }
+    if let Some(metrics_task) = metrics_task {
+        if let Err(e) = metrics_task
+            .await
+            .context("Failed waiting for metrics task to finish")?
+        {
+            error!("Metrics endpoint failed: {:?}", e);
+        }
+    }
+    let summary = {
+        let status = session_control.status.lock().unwrap();
+        session_control.metrics.summary(&status)
+    };
+    summary.print();
+    if let Err(e) = summary.append(&session_log_path) {
+        error!("{:?}", e);
+    }
 
-    Ok(())
+    result
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 8)]
 pub async fn run_single(paths: &cli::Paths) -> anyhow::Result<()> {
     check_config::check(paths)?;
-    let state = initialize(paths).await?;
-    run_impl(paths, &state).await?;
+    let mut state = initialize(paths).await?;
+    let handled_jobs = run_impl(paths, &state).await?;
+    state.mark_handled(handled_jobs);
+    let released_jobs =
+        reconcile_allocations(&state.config, &state.client, &state.projects, &state.db)
+            .await
+            .context("Failed reconciling allocations")?;
+    state.release_handled(released_jobs);
+    activity::refresh_executed(&state.client, &state.db)
+        .await
+        .context("Failed refreshing runner activity")?;
+    state.hook_tasks.close();
+    state.hook_tasks.wait().await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay() {
+        assert_eq!(launch_backoff_delay(30, 1), 30);
+        assert_eq!(launch_backoff_delay(30, 2), 60);
+        assert_eq!(launch_backoff_delay(30, 4), 240);
+        assert_eq!(launch_backoff_delay(30, 10), MAX_LAUNCH_BACKOFF);
+        assert_eq!(launch_backoff_delay(30, 100), MAX_LAUNCH_BACKOFF);
+        assert!(jitter(100) <= 25);
+    }
+
+    fn dummy_job(id: u64) -> Job {
+        Job {
+            id,
+            name: format!("job-{}", id),
+            tags: Vec::new(),
+            git_ref: "main".into(),
+            pipeline: None,
+            status: "pending".into(),
+            runner: None,
+            created_at: DateTime::from_timestamp(id as i64, 0),
+            started_at: None,
+            allow_failure: false,
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn job_age_order() {
+        let mut jobs = [dummy_job(3), dummy_job(1), dummy_job(2)];
+        jobs[0].created_at = None;
+        jobs.sort_by_key(job_age_key);
+        let ids: Vec<_> = jobs.iter().map(|job| job.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn untagged_match() {
+        let instance = |tags: &[&str], run_untagged: bool| GitLabRunnerInstance {
+            tags: tags.iter().map(|s| s.to_string()).collect(),
+            launch_priority: None,
+            job_name_pattern: None,
+            ref_pattern: None,
+            pipeline_source: Vec::new(),
+            tag_expression: None,
+            run_untagged,
+            max_launches_per_hour: None,
+            max_launches_per_day: None,
+            schedule: HashMap::new(),
+            config_variables: HashMap::new(),
+        };
+        let mut instances = HashMap::new();
+        instances.insert("tagged".to_owned(), instance(&["cuda"], false));
+        fn names(instances: &HashMap<String, GitLabRunnerInstance>, job: &Job) -> Vec<String> {
+            find_matches(instances, &HashMap::new(), job)
+                .into_iter()
+                .map(|(name, _)| name.clone())
+                .collect()
+        }
+        let mut job = dummy_job(1);
+        assert!(names(&instances, &job).is_empty());
+        instances.insert("untagged".to_owned(), instance(&["cpu", "cuda"], true));
+        assert_eq!(names(&instances, &job), vec!["untagged"]);
+        job.tags.push("cuda".into());
+        assert_eq!(names(&instances, &job), vec!["tagged", "untagged"]);
+    }
+
+    #[test]
+    fn launch_limit() {
+        let jobs: Vec<_> = (0..5).map(dummy_job).collect();
+        let mut grouped = vec![
+            ("high", ((), jobs[0..3].iter().collect::<Vec<_>>())),
+            ("low", ((), jobs[3..5].iter().collect())),
+        ];
+        assert!(limit_launches(&mut grouped, 2, 3).is_empty());
+        let ids: Vec<Vec<u64>> = grouped
+            .iter()
+            .map(|(_, (_, jobs))| jobs.iter().map(|job| job.id).collect())
+            .collect();
+        assert_eq!(ids, vec![vec![0, 1, 2], vec![3, 4]]);
+        let deferred = limit_launches(&mut grouped, 2, 2);
+        assert_eq!(deferred.iter().map(|(name, _)| *name).unique().count(), 1);
+        assert_eq!(deferred.len(), 2);
+        assert_eq!(grouped.len(), 1);
+        assert_eq!(grouped[0].1 .1.len(), 3);
+        limit_launches(&mut grouped, 1, 2);
+        assert_eq!(grouped[0].1 .1.len(), 2);
+    }
+
+    #[test]
+    fn queued_jobs() {
+        assert_eq!(count_queued_jobs(2, 1, 0, 5), 2);
+        assert_eq!(count_queued_jobs(2, 4, 0, 5), 5);
+        // the queued launch is still waiting for the job it was launched for
+        assert_eq!(count_queued_jobs(1, 1, 1, 1), 0);
+        assert_eq!(count_queued_jobs(2, 2, 3, 4), 1);
+        assert_eq!(count_queued_jobs(0, 2, 0, 4), 0);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    ffi::{CString, OsStr},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use log::debug;
+
+/// User and group ID of a user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UserIds {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Returns whether the executor runs as root, which is required for switching users without sudo
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no memory safety requirements
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Returns the user and group ID the executor runs as
+pub fn current_user_ids() -> UserIds {
+    // SAFETY: geteuid and getegid have no memory safety requirements
+    unsafe {
+        UserIds {
+            uid: libc::geteuid(),
+            gid: libc::getegid(),
+        }
+    }
+}
+
+/// Looks up the user and primary group ID of a user in the passwd database
+pub fn lookup_user(name: &str) -> anyhow::Result<UserIds> {
+    let c_name = CString::new(name).context(format!("Invalid user name {:?}", name))?;
+    // SAFETY: passwd is a plain C struct, which getpwnam_r fills in
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: all pointers are valid for the duration of the call, the buffer length is passed along
+    let error = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if error != 0 {
+        Err(std::io::Error::from_raw_os_error(error))
+            .context(format!("Failed looking up user {}", name))?;
+    }
+    if result.is_null() {
+        Err(anyhow!("Unknown user {}", name))?;
+    }
+    Ok(UserIds {
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+    })
+}
+
+/// Builds the command prefix executing a command as the given user, keeping the environment,
+/// which contains the CI variables of the job. Root uses setpriv, everyone else sudo.
+pub fn switch_user_command(name: &str, ids: UserIds, is_root: bool) -> Vec<String> {
+    if is_root {
+        vec![
+            "setpriv".to_owned(),
+            format!("--reuid={}", ids.uid),
+            format!("--regid={}", ids.gid),
+            "--init-groups".to_owned(),
+            "--".to_owned(),
+        ]
+    } else {
+        vec![
+            "sudo".to_owned(),
+            "--non-interactive".to_owned(),
+            "--preserve-env".to_owned(),
+            format!("--user={}", name),
+            "--".to_owned(),
+        ]
+    }
+}
+
+/// Runs a command with root privileges, via sudo unless the executor already runs as root
+fn run_privileged(program: &str, args: &[&OsStr]) -> anyhow::Result<()> {
+    let mut command = if is_root() {
+        Command::new(program)
+    } else {
+        let mut command = Command::new("sudo");
+        command.arg("--non-interactive").arg(program);
+        command
+    };
+    command.args(args);
+    run_checked(command)
+}
+
+/// Runs a command to completion, returning its error output if it fails
+fn run_checked(mut command: Command) -> anyhow::Result<()> {
+    command.stdin(Stdio::null()).stdout(Stdio::null());
+    debug!("Executing {:?}", command);
+    let output = command
+        .output()
+        .context(format!("Failed spawning {:?}", command))?;
+    if !output.status.success() {
+        Err(anyhow!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ))?;
+    }
+    Ok(())
+}
+
+/// Recursively changes the owner of a directory
+pub fn chown_recursive(path: &Path, ids: UserIds) -> anyhow::Result<()> {
+    let owner = format!("{}:{}", ids.uid, ids.gid);
+    run_privileged("chown", &["-R".as_ref(), owner.as_ref(), path.as_os_str()])
+}
+
+/// Copies a file, making it owned by the given user with the given permissions
+pub fn install_file(
+    source: &Path,
+    destination: &Path,
+    ids: UserIds,
+    mode: u32,
+) -> anyhow::Result<()> {
+    let (uid, gid, mode) = (
+        ids.uid.to_string(),
+        ids.gid.to_string(),
+        format!("{:o}", mode),
+    );
+    run_privileged(
+        "install",
+        &[
+            "-o".as_ref(),
+            uid.as_ref(),
+            "-g".as_ref(),
+            gid.as_ref(),
+            "-m".as_ref(),
+            mode.as_ref(),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ],
+    )
+}
+
+/// Builds the command sending a signal to a process group as the given user via sudo
+fn kill_command(name: &str, process_group: u32, signal: &str) -> Vec<String> {
+    vec![
+        "sudo".to_owned(),
+        "--non-interactive".to_owned(),
+        format!("--user={}", name),
+        "kill".to_owned(),
+        "-s".to_owned(),
+        signal.to_owned(),
+        "--".to_owned(),
+        format!("-{}", process_group),
+    ]
+}
+
+/// Sends a signal to the processes of a process group owned by the given user,
+/// which an unprivileged executor can't signal itself
+pub fn kill_process_group(name: &str, process_group: u32, signal: &str) -> anyhow::Result<()> {
+    let args = kill_command(name, process_group, signal);
+    let mut command = Command::new(&args[0]);
+    command.args(&args[1..]);
+    run_checked(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switch_user() {
+        let root = lookup_user("root").unwrap();
+        assert_eq!(root, UserIds { uid: 0, gid: 0 });
+        assert!(lookup_user("no-such-user-exists").is_err());
+        let ids = UserIds {
+            uid: 1234,
+            gid: 100,
+        };
+        assert_eq!(
+            switch_user_command("ci", ids, true),
+            vec![
+                "setpriv",
+                "--reuid=1234",
+                "--regid=100",
+                "--init-groups",
+                "--"
+            ]
+        );
+        assert_eq!(
+            switch_user_command("ci", ids, false),
+            vec![
+                "sudo",
+                "--non-interactive",
+                "--preserve-env",
+                "--user=ci",
+                "--"
+            ]
+        );
+        assert_eq!(
+            kill_command("ci", 4321, "KILL"),
+            vec![
+                "sudo",
+                "--non-interactive",
+                "--user=ci",
+                "kill",
+                "-s",
+                "KILL",
+                "--",
+                "-4321"
+            ]
+        );
+    }
+}
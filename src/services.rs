@@ -0,0 +1,210 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use log::{debug, warn};
+use serde_derive::Deserialize;
+
+/// File in the builds directory replacing /etc/hosts in the job container, resolving the service aliases
+const HOSTS_FILE: &str = ".meta-runner-hosts";
+
+#[derive(Debug, Deserialize)]
+pub struct ServiceVariable {
+    pub key: String,
+    pub value: String,
+}
+
+/// Service container requested via `services:` in the job definition
+#[derive(Debug, Deserialize)]
+pub struct Service {
+    pub name: String,
+    #[serde(default)]
+    pub alias: String,
+    #[serde(default)]
+    pub entrypoint: Vec<String>,
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub variables: Vec<ServiceVariable>,
+}
+
+/// Image of the job as configured via `image:` in the job definition
+#[derive(Debug, Default, Deserialize)]
+pub struct JobImage {
+    #[serde(default)]
+    pub entrypoint: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct JobResponse {
+    #[serde(default)]
+    pub image: JobImage,
+    #[serde(default)]
+    pub services: Vec<Service>,
+}
+
+/// Reads the job response file gitlab-runner provides to custom executors
+pub fn read_job_response() -> anyhow::Result<JobResponse> {
+    let Ok(path) = std::env::var("JOB_RESPONSE_FILE") else {
+        return Ok(JobResponse::default());
+    };
+    let content =
+        fs::read_to_string(&path).context(format!("Failed reading job response {:?}", path))?;
+    serde_json::from_str(&content).context(format!("Failed parsing job response {:?}", path))
+}
+
+impl JobImage {
+    /// Entrypoint the job script is executed with, like for the docker executor,
+    /// where an entrypoint of [""] disables the entrypoint of the image
+    pub fn entrypoint(&self) -> Vec<String> {
+        if self.entrypoint.iter().all(String::is_empty) {
+            Vec::new()
+        } else {
+            self.entrypoint.clone()
+        }
+    }
+}
+
+impl Service {
+    /// Hostnames the service is reachable under, matching the aliases of the docker executor:
+    /// the image name without tag with / replaced by __ and -, plus the explicitly configured aliases
+    pub fn aliases(&self) -> Vec<String> {
+        let image = self.name.strip_prefix("docker://").unwrap_or(&self.name);
+        let image = image.split('@').next().unwrap();
+        let image = match image.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => name,
+            _ => image,
+        };
+        let mut aliases = vec![image.replace('/', "__"), image.replace('/', "-")];
+        aliases.extend(
+            self.alias
+                .split([',', ' '])
+                .filter(|alias| !alias.is_empty())
+                .map(str::to_owned),
+        );
+        aliases.dedup();
+        aliases
+    }
+}
+
+/// Name of the apptainer instance running a service of a job
+pub fn instance_name(job_id: &str, index: usize) -> String {
+    format!(
+        "{}-job-{}-service-{}",
+        env!("CARGO_PKG_NAME"),
+        job_id,
+        index
+    )
+}
+
+/// Path of the hosts file for the job container
+pub fn hosts_file(builds_dir: &Path) -> PathBuf {
+    builds_dir.join(HOSTS_FILE)
+}
+
+/// Writes a hosts file resolving the aliases of all services to the local host,
+/// since apptainer instances share the network namespace of the host
+pub fn write_hosts_file(builds_dir: &Path, services: &[Service]) -> anyhow::Result<()> {
+    let mut content = fs::read_to_string("/etc/hosts").unwrap_or_default();
+    let aliases: Vec<_> = services.iter().flat_map(Service::aliases).collect();
+    content.push_str(&format!("127.0.0.1 {}\n", aliases.join(" ")));
+    let path = hosts_file(builds_dir);
+    fs::write(&path, content).context(format!("Failed writing hosts file {:?}", path))
+}
+
+/// Starts a service from its pulled image as an apptainer instance running the image's runscript
+pub fn start(
+    apptainer_executable: &Path,
+    image_path: &Path,
+    instance: &str,
+    service: &Service,
+) -> anyhow::Result<()> {
+    if !service.entrypoint.is_empty() {
+        warn!(
+            "Ignoring the entrypoint of service {}, which is not supported",
+            service.name
+        );
+    }
+    let mut command = Command::new(apptainer_executable);
+    command
+        .arg("instance")
+        .arg("run")
+        .arg("--writable-tmpfs")
+        .args(
+            service
+                .variables
+                .iter()
+                .flat_map(|v| ["--env".to_owned(), format!("{}={}", v.key, v.value)]),
+        )
+        .arg(image_path)
+        .arg(instance)
+        .args(&service.command)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null());
+    debug!("Starting service with command {:?}", command);
+    let status = command
+        .status()
+        .context(format!("Failed spawning {:?}", command))?;
+    if !status.success() {
+        Err(anyhow!(
+            "Starting service {} failed: {:?}",
+            service.name,
+            status
+        ))?;
+    }
+    Ok(())
+}
+
+/// Stops an apptainer instance, also used for the instance of the job container
+pub fn stop(apptainer_executable: &Path, instance: &str) -> anyhow::Result<()> {
+    let status = Command::new(apptainer_executable)
+        .arg("instance")
+        .arg("stop")
+        .arg(instance)
+        .stdin(Stdio::null())
+        .status()
+        .context("Failed spawning apptainer instance stop")?;
+    if !status.success() {
+        Err(anyhow!(
+            "Stopping instance {} failed: {:?}",
+            instance,
+            status
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_aliases() {
+        let services: JobResponse = serde_json::from_str(
+            r#"{"id": 1, "image": {"name": "alpine", "entrypoint": [""]}, "services": [
+                {"name": "registry.example.com/db/postgres:15", "alias": "db,database"},
+                {"name": "redis"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            services.services[0].aliases(),
+            vec![
+                "registry.example.com__db__postgres",
+                "registry.example.com-db-postgres",
+                "db",
+                "database"
+            ]
+        );
+        assert_eq!(services.services[1].aliases(), vec!["redis"]);
+        assert!(services.image.entrypoint().is_empty());
+        let image = JobImage {
+            entrypoint: vec!["/entrypoint.sh".to_owned()],
+        };
+        assert_eq!(image.entrypoint(), vec!["/entrypoint.sh"]);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::GitLabSpackConfig,
+    run_as::{self, UserIds},
+};
+
+/// Suffix of the marker file indicating that all packages of a cached environment were installed
+const INSTALLED_SUFFIX: &str = ".installed";
+
+/// Spack environment requested by a job
+#[derive(Debug, PartialEq)]
+pub enum SpackEnvironment {
+    /// A named environment managed by spack
+    Named(String),
+    /// An environment created from a spack.yaml file, relative to the project directory
+    File(PathBuf),
+}
+
+impl SpackEnvironment {
+    /// Determines the environment requested via CI variables, falling back to the configured default
+    pub fn from_env(config: &GitLabSpackConfig) -> Option<SpackEnvironment> {
+        if let Ok(file) = std::env::var("CUSTOM_ENV_META_RUNNER_SPACK_YAML") {
+            return Some(SpackEnvironment::File(file.into()));
+        }
+        std::env::var("CUSTOM_ENV_META_RUNNER_SPACK_ENV")
+            .ok()
+            .or(config.environment.clone())
+            .map(SpackEnvironment::Named)
+    }
+}
+
+/// Quotes a string for use in a POSIX shell
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Returns whether an entry of an environment directory is copied into the cache,
+/// skipping the repository metadata and spack's generated files
+fn is_environment_file(name: &OsStr) -> bool {
+    name != ".git" && name != ".spack-env"
+}
+
+/// Returns the files of an environment directory relative to it, in a stable order.
+/// Symlinks are skipped, so the copy can't contain files from outside the directory.
+fn list_environment_files(dir: &Path, relative: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir.join(relative))
+        .context(format!(
+            "Failed reading spack environment directory {:?}",
+            dir
+        ))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut files = Vec::new();
+    for entry in entries {
+        if !is_environment_file(&entry.file_name()) {
+            continue;
+        }
+        let path = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files.extend(list_environment_files(dir, &path)?);
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Hashes the name of the spack.yaml file and the names and contents of the files of its
+/// environment directory, since the file is copied to spack.yaml in the cache
+fn hash_environment(dir: &Path, files: &[PathBuf], file_name: &OsStr) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(file_name.as_bytes());
+    hasher.update([0]);
+    for file in files {
+        let content = fs::read(dir.join(file)).context(format!("Failed reading {:?}", file))?;
+        hasher.update(file.as_os_str().as_bytes());
+        hasher.update([0]);
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(&content);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Copy of an environment directory in the cache, locked while the job uses it
+pub struct CachedEnvironment {
+    /// The directory spack installs the environment from
+    pub dir: PathBuf,
+    /// Marker file indicating that all packages of the environment were installed
+    marker: PathBuf,
+    _lock: fs::File,
+}
+
+impl CachedEnvironment {
+    pub fn is_installed(&self) -> anyhow::Result<bool> {
+        Ok(fs::exists(&self.marker)?)
+    }
+
+    pub fn mark_installed(&self) -> anyhow::Result<()> {
+        fs::write(&self.marker, "").context("Failed marking spack environment as installed")
+    }
+}
+
+/// Copies the directory containing a spack.yaml file into the cache, so relative includes
+/// and repos keep working. Jobs using identical environment directories share the copy,
+/// which is handed over to the given owner, since spack writes into it during the installation.
+pub fn cache_environment(
+    config: &GitLabSpackConfig,
+    file: &Path,
+    owner: Option<UserIds>,
+) -> anyhow::Result<CachedEnvironment> {
+    let source_dir = file.parent().unwrap_or(Path::new("/"));
+    let files = list_environment_files(source_dir, Path::new(""))?;
+    let file_name = file.file_name().unwrap_or_default();
+    let digest = hash_environment(source_dir, &files, file_name)?;
+    let cache_dir = Path::new(&config.cache_dir);
+    fs::create_dir_all(cache_dir).context(format!(
+        "Failed creating spack cache directory {:?}",
+        cache_dir
+    ))?;
+    // concurrent jobs using the same environment wait for the first one to install it
+    let lock = fs::File::create(cache_dir.join(format!("{}.lock", digest)))?;
+    lock.lock().context("Failed locking spack environment")?;
+    let cached = CachedEnvironment {
+        dir: cache_dir.join(&digest),
+        marker: cache_dir.join(format!("{}{}", digest, INSTALLED_SUFFIX)),
+        _lock: lock,
+    };
+    if cached.is_installed()? {
+        info!("Reusing cached spack environment {:?}", cached.dir);
+        return Ok(cached);
+    }
+    if fs::exists(&cached.dir)? {
+        debug!("Removing incomplete spack environment {:?}", cached.dir);
+        if owner.is_some() {
+            run_as::chown_recursive(&cached.dir, run_as::current_user_ids())?;
+        }
+        fs::remove_dir_all(&cached.dir)?;
+    }
+    for file in &files {
+        let destination = cached.dir.join(file);
+        fs::create_dir_all(destination.parent().unwrap())?;
+        fs::copy(source_dir.join(file), &destination)
+            .context(format!("Failed copying {:?} into spack environment", file))?;
+    }
+    if file_name != "spack.yaml" {
+        fs::copy(file, cached.dir.join("spack.yaml"))?;
+    }
+    if let Some(owner) = owner {
+        run_as::chown_recursive(&cached.dir, owner)?;
+    }
+    Ok(cached)
+}
+
+/// Arguments of the spack commands concretizing and installing an environment directory
+pub fn install_commands(env_dir: &Path) -> [Vec<&OsStr>; 2] {
+    ["concretize", "install"]
+        .map(|command| vec![OsStr::new("-e"), env_dir.as_os_str(), OsStr::new(command)])
+}
+
+/// Returns a shell snippet activating a named spack environment
+pub fn activate_named(config: &GitLabSpackConfig, name: &str) -> String {
+    format!(
+        "eval \"$({} env activate --sh {})\"",
+        shell_quote(&config.executable),
+        shell_quote(name)
+    )
+}
+
+/// Returns a shell snippet activating the spack environment in the given directory
+pub fn activate_dir(config: &GitLabSpackConfig, env_dir: &Path) -> anyhow::Result<String> {
+    let env_dir = env_dir
+        .to_str()
+        .ok_or(anyhow!("Path {:?} can't be converted to string", env_dir))?;
+    Ok(format!(
+        "eval \"$({} env activate --sh -d {})\"",
+        shell_quote(&config.executable),
+        shell_quote(env_dir)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote() {
+        assert_eq!(shell_quote("abc"), "'abc'");
+        assert_eq!(shell_quote("a'b"), "'a'\\''b'");
+    }
+
+    #[test]
+    fn cached_environment() {
+        let root = std::env::temp_dir().join(format!("spack-cache-{}", std::process::id()));
+        let source = root.join("project/ci");
+        fs::create_dir_all(source.join("repo")).unwrap();
+        fs::create_dir_all(source.join(".spack-env")).unwrap();
+        fs::write(source.join("spack.yaml"), "spack: {}").unwrap();
+        fs::write(source.join("repo/package.py"), "pass").unwrap();
+        fs::write(source.join(".spack-env/view"), "").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", source.join("passwd")).unwrap();
+        let config = GitLabSpackConfig {
+            executable: "spack".into(),
+            cache_dir: root.join("cache").to_str().unwrap().into(),
+            environment: None,
+        };
+        let cached = cache_environment(&config, &source.join("spack.yaml"), None).unwrap();
+        assert!(!cached.is_installed().unwrap());
+        assert!(cached.dir.join("repo/package.py").exists());
+        assert!(!cached.dir.join(".spack-env").exists());
+        assert!(!cached.dir.join("passwd").exists());
+        cached.mark_installed().unwrap();
+        let dir = cached.dir.clone();
+        drop(cached);
+        assert!(cache_environment(&config, &source.join("spack.yaml"), None)
+            .unwrap()
+            .is_installed()
+            .unwrap());
+        // package recipes are part of the environment
+        fs::write(source.join("repo/package.py"), "import os").unwrap();
+        let changed = cache_environment(&config, &source.join("spack.yaml"), None).unwrap();
+        assert_ne!(changed.dir, dir);
+        assert!(!changed.is_installed().unwrap());
+        assert_eq!(
+            install_commands(Path::new("/env")),
+            [["-e", "/env", "concretize"], ["-e", "/env", "install"]]
+                .map(|args| args.map(OsStr::new).to_vec())
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn named_activation() {
+        let config = GitLabSpackConfig {
+            executable: "spack".into(),
+            cache_dir: "".into(),
+            environment: None,
+        };
+        assert_eq!(
+            activate_named(&config, "dev"),
+            "eval \"$('spack' env activate --sh 'dev')\""
+        );
+    }
+}
@@ -0,0 +1,760 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::gitlab_config::RunnerRegistration;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS registrations (
+    name TEXT PRIMARY KEY NOT NULL,
+    id INTEGER NOT NULL,
+    token TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS handled_jobs (
+    job_id INTEGER PRIMARY KEY NOT NULL,
+    instance TEXT,
+    handled_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS launches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    instance TEXT NOT NULL,
+    job_ids TEXT NOT NULL,
+    success INTEGER NOT NULL,
+    message TEXT,
+    launched_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS allocations (
+    allocation_id TEXT PRIMARY KEY NOT NULL,
+    instance TEXT NOT NULL,
+    job_ids TEXT NOT NULL,
+    launched_at INTEGER NOT NULL,
+    state TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS flags (
+    name TEXT PRIMARY KEY NOT NULL,
+    value INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS activity (
+    instance TEXT PRIMARY KEY NOT NULL,
+    last_matched INTEGER,
+    last_launched INTEGER,
+    last_executed INTEGER
+);
+CREATE TABLE IF NOT EXISTS launch_backoff (
+    instance TEXT PRIMARY KEY NOT NULL,
+    failures INTEGER NOT NULL,
+    until INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS launch_quota (
+    instance TEXT NOT NULL,
+    period INTEGER NOT NULL,
+    tokens REAL NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (instance, period)
+);
+CREATE TABLE IF NOT EXISTS instance_health (
+    instance TEXT PRIMARY KEY NOT NULL,
+    prepare_failures INTEGER NOT NULL,
+    last_failure INTEGER,
+    message TEXT
+);
+CREATE TABLE IF NOT EXISTS images (
+    filename TEXT PRIMARY KEY NOT NULL,
+    digest TEXT NOT NULL,
+    pulled_at INTEGER NOT NULL
+);
+";
+
+/// How long to wait for other processes (e.g. concurrent executors) holding a lock on the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn unix_timestamp() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Maintenance mode was started manually via `gitlab-meta-runner maintenance start`
+pub const FLAG_MAINTENANCE: &str = "maintenance";
+/// All registered runners are currently paused on GitLab
+pub const FLAG_RUNNERS_PAUSED: &str = "runners_paused";
+
+/// Record of a single launch command execution
+#[derive(Debug)]
+pub struct LaunchRecord<'a> {
+    /// The runner instance name
+    pub instance: &'a str,
+    /// The GitLab job IDs this launch was issued for
+    pub job_ids: Vec<u64>,
+    /// Whether the launch command succeeded
+    pub success: bool,
+    /// The error message if the launch failed
+    pub message: Option<String>,
+}
+
+/// Entry of the dispatch ledger, recording that a job was launched or ignored
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandledJob {
+    /// The runner instance a runner was launched for, None if the job was ignored
+    pub instance: Option<String>,
+    /// Unix timestamp of the launch
+    pub handled_at: i64,
+}
+
+/// Backoff of a runner instance after consecutive failed launches
+#[derive(Debug, Clone, PartialEq)]
+pub struct LaunchBackoff {
+    /// Number of consecutive failed launches
+    pub failures: u32,
+    /// Unix timestamp until which no runners will be launched
+    pub until: i64,
+}
+
+/// Token bucket limiting the number of launches of a runner instance within a period
+#[derive(Debug, Clone, PartialEq)]
+pub struct LaunchQuota {
+    /// Number of launches that may currently be issued, may be fractional while refilling
+    pub tokens: f64,
+    /// Unix timestamp of the last refill
+    pub updated_at: i64,
+}
+
+impl LaunchQuota {
+    /// Refills the bucket at a rate of capacity tokens per period (in seconds), up to the capacity
+    pub fn refill(&mut self, capacity: u32, period: i64, now: i64) {
+        let elapsed = (now - self.updated_at).max(0) as f64;
+        self.tokens =
+            (self.tokens + elapsed * capacity as f64 / period as f64).min(capacity as f64);
+        self.updated_at = now;
+    }
+
+    /// Number of whole launches left in the bucket
+    pub fn available(&self) -> usize {
+        self.tokens.max(0.0) as usize
+    }
+}
+
+/// Results of the executor's prepare step for the jobs of a runner instance
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceHealth {
+    /// Number of consecutive failed prepare steps
+    pub prepare_failures: u32,
+    /// Unix timestamp of the last failed prepare step
+    pub last_failure: Option<i64>,
+    /// Error message of the last failed prepare step
+    pub message: Option<String>,
+}
+
+/// Batch allocation created by a launch command
+#[derive(Debug, PartialEq)]
+pub struct Allocation {
+    /// The allocation ID extracted from the launch command output
+    pub allocation_id: String,
+    /// The runner instance name
+    pub instance: String,
+    /// The GitLab job IDs this allocation was launched for
+    pub job_ids: Vec<u64>,
+}
+
+/// Allocation state stored in the database
+#[derive(Debug, Clone, Copy)]
+pub enum AllocationState {
+    /// The allocation wasn't reconciled yet
+    Open,
+    /// The allocation's runner picked up its jobs
+    Completed,
+    /// The allocation was cancelled
+    Cancelled,
+}
+
+impl AllocationState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AllocationState::Open => "open",
+            AllocationState::Completed => "completed",
+            AllocationState::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Kind of activity tracked per runner instance
+#[derive(Debug, Clone, Copy)]
+pub enum Activity {
+    /// A pending job was matched to the instance
+    Matched,
+    /// A runner was launched for the instance
+    Launched,
+    /// The instance's runner executed a job
+    Executed,
+}
+
+impl Activity {
+    fn column(&self) -> &'static str {
+        match self {
+            Activity::Matched => "last_matched",
+            Activity::Launched => "last_launched",
+            Activity::Executed => "last_executed",
+        }
+    }
+}
+
+/// Unix timestamps of the last activities of a runner instance
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct InstanceActivity {
+    /// Last time a pending job was matched to the instance
+    pub last_matched: Option<i64>,
+    /// Last time a runner was launched for the instance
+    pub last_launched: Option<i64>,
+    /// Last time the instance's runner started executing a job, as reported by GitLab
+    pub last_executed: Option<i64>,
+}
+
+fn join_job_ids(job_ids: &[u64]) -> String {
+    job_ids
+        .iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn split_job_ids(job_ids: &str) -> Vec<u64> {
+    job_ids
+        .split(',')
+        .filter_map(|id| id.parse().ok())
+        .collect()
+}
+
+/// Persistent state shared by `run`, `configure` and the executor, stored in `data_dir`
+pub struct StateDb {
+    connection: Connection,
+}
+
+impl StateDb {
+    pub fn open(path: &Path) -> anyhow::Result<StateDb> {
+        let connection =
+            Connection::open(path).context(format!("Failed opening state database {:?}", path))?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("Failed initializing state database schema")?;
+        Ok(StateDb { connection })
+    }
+
+    pub fn read_registrations(&self) -> anyhow::Result<HashMap<String, RunnerRegistration>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT name, id, token FROM registrations")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                RunnerRegistration {
+                    id: row.get(1)?,
+                    token: row.get(2)?,
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Replaces all stored registrations by the given ones
+    pub fn write_registrations(
+        &mut self,
+        registrations: &HashMap<String, RunnerRegistration>,
+    ) -> anyhow::Result<()> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute("DELETE FROM registrations", [])?;
+        for (name, registration) in registrations {
+            transaction.execute(
+                "INSERT INTO registrations (name, id, token) VALUES (?1, ?2, ?3)",
+                params![name, registration.id, registration.token],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Returns the launched jobs, ignoring ignored jobs stored by earlier versions
+    pub fn read_handled_jobs(&self) -> anyhow::Result<HashMap<u64, HandledJob>> {
+        let mut statement = self.connection.prepare(
+            "SELECT job_id, instance, handled_at FROM handled_jobs WHERE instance IS NOT NULL",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                HandledJob {
+                    instance: row.get(1)?,
+                    handled_at: row.get(2)?,
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Returns the jobs that a runner was launched for before the given time, with their instance
+    pub fn read_launched_jobs(&self, launched_before: i64) -> anyhow::Result<HashMap<u64, String>> {
+        let mut statement = self.connection.prepare(
+            "SELECT job_id, instance FROM handled_jobs WHERE instance IS NOT NULL AND handled_at < ?1",
+        )?;
+        let rows = statement.query_map([launched_before], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Marks jobs as handled by a launch for the given instance. Ignored jobs are not stored,
+    /// so they are checked again after a restart, e.g. once an instance matching them was added.
+    pub fn add_handled_jobs(
+        &mut self,
+        jobs: impl IntoIterator<Item = (u64, Option<String>)>,
+    ) -> anyhow::Result<()> {
+        let now = unix_timestamp();
+        let transaction = self.connection.transaction()?;
+        for (job_id, instance) in jobs.into_iter().filter(|(_, instance)| instance.is_some()) {
+            transaction.execute(
+                "INSERT OR REPLACE INTO handled_jobs (job_id, instance, handled_at) VALUES (?1, ?2, ?3)",
+                params![job_id, instance, now],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Forgets handled jobs, so they are dispatched again if they are still pending
+    pub fn remove_handled_jobs(&mut self, job_ids: &[u64]) -> anyhow::Result<()> {
+        let transaction = self.connection.transaction()?;
+        for job_id in job_ids {
+            transaction.execute("DELETE FROM handled_jobs WHERE job_id = ?1", [job_id])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Deletes the handled jobs and launch records from before the given time,
+    /// along with ignored jobs stored by earlier versions
+    pub fn prune_handled_jobs(&self, before: i64) -> anyhow::Result<()> {
+        self.connection.execute(
+            "DELETE FROM handled_jobs WHERE instance IS NULL OR handled_at < ?1",
+            [before],
+        )?;
+        self.connection
+            .execute("DELETE FROM launches WHERE launched_at < ?1", [before])?;
+        Ok(())
+    }
+
+    pub fn add_launch_record(&self, record: &LaunchRecord) -> anyhow::Result<()> {
+        self.connection.execute(
+            "INSERT INTO launches (instance, job_ids, success, message, launched_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                record.instance,
+                join_job_ids(&record.job_ids),
+                record.success,
+                record.message,
+                unix_timestamp()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Stores a new allocation. Its jobs are removed from all other open allocations,
+    /// so a job redispatched to a new allocation never counts toward two allocations.
+    pub fn add_allocation(&self, allocation: &Allocation) -> anyhow::Result<()> {
+        for previous in self.read_open_allocations(i64::MAX)? {
+            if !previous
+                .job_ids
+                .iter()
+                .any(|job_id| allocation.job_ids.contains(job_id))
+            {
+                continue;
+            }
+            let remaining: Vec<u64> = previous
+                .job_ids
+                .into_iter()
+                .filter(|job_id| !allocation.job_ids.contains(job_id))
+                .collect();
+            self.connection.execute(
+                "UPDATE allocations SET job_ids = ?1 WHERE allocation_id = ?2",
+                params![join_job_ids(&remaining), previous.allocation_id],
+            )?;
+        }
+        self.connection.execute(
+            "INSERT OR REPLACE INTO allocations (allocation_id, instance, job_ids, launched_at, state) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                allocation.allocation_id,
+                allocation.instance,
+                join_job_ids(&allocation.job_ids),
+                unix_timestamp(),
+                AllocationState::Open.as_str()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Returns all allocations that weren't reconciled yet and were launched before the given time
+    pub fn read_open_allocations(&self, launched_before: i64) -> anyhow::Result<Vec<Allocation>> {
+        let mut statement = self.connection.prepare(
+            "SELECT allocation_id, instance, job_ids FROM allocations WHERE state = ?1 AND launched_at < ?2",
+        )?;
+        let rows = statement.query_map(
+            params![AllocationState::Open.as_str(), launched_before],
+            |row| {
+                Ok(Allocation {
+                    allocation_id: row.get(0)?,
+                    instance: row.get(1)?,
+                    job_ids: split_job_ids(&row.get::<_, String>(2)?),
+                })
+            },
+        )?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn set_allocation_state(
+        &self,
+        allocation_id: &str,
+        state: AllocationState,
+    ) -> anyhow::Result<()> {
+        self.connection.execute(
+            "UPDATE allocations SET state = ?1 WHERE allocation_id = ?2",
+            params![state.as_str(), allocation_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn read_launch_backoff(&self) -> anyhow::Result<HashMap<String, LaunchBackoff>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT instance, failures, until FROM launch_backoff")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                LaunchBackoff {
+                    failures: row.get(1)?,
+                    until: row.get(2)?,
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Stores the backoff of an instance, or removes it if None
+    pub fn set_launch_backoff(
+        &self,
+        instance: &str,
+        backoff: Option<&LaunchBackoff>,
+    ) -> anyhow::Result<()> {
+        match backoff {
+            Some(backoff) => self.connection.execute(
+                "INSERT OR REPLACE INTO launch_backoff (instance, failures, until) VALUES (?1, ?2, ?3)",
+                params![instance, backoff.failures, backoff.until],
+            )?,
+            None => self
+                .connection
+                .execute("DELETE FROM launch_backoff WHERE instance = ?1", [instance])?,
+        };
+        Ok(())
+    }
+
+    /// Reads the launch quota buckets, indexed by instance name and period
+    pub fn read_launch_quota(&self) -> anyhow::Result<HashMap<(String, i64), LaunchQuota>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT instance, period, tokens, updated_at FROM launch_quota")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                (row.get(0)?, row.get(1)?),
+                LaunchQuota {
+                    tokens: row.get(2)?,
+                    updated_at: row.get(3)?,
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn set_launch_quota(
+        &self,
+        instance: &str,
+        period: i64,
+        quota: &LaunchQuota,
+    ) -> anyhow::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO launch_quota (instance, period, tokens, updated_at) VALUES (?1, ?2, ?3, ?4)",
+            params![instance, period, quota.tokens, quota.updated_at],
+        )?;
+        Ok(())
+    }
+
+    /// Records the result of the executor's prepare step, resetting the failures after a success
+    pub fn record_prepare_result(&self, instance: &str, error: Option<&str>) -> anyhow::Result<()> {
+        match error {
+            Some(message) => self.connection.execute(
+                "INSERT INTO instance_health (instance, prepare_failures, last_failure, message) VALUES (?1, 1, ?2, ?3)
+                 ON CONFLICT(instance) DO UPDATE SET prepare_failures = prepare_failures + 1, last_failure = ?2, message = ?3",
+                params![instance, unix_timestamp(), message],
+            )?,
+            None => self.connection.execute(
+                "UPDATE instance_health SET prepare_failures = 0 WHERE instance = ?1",
+                [instance],
+            )?,
+        };
+        Ok(())
+    }
+
+    pub fn read_instance_health(&self) -> anyhow::Result<HashMap<String, InstanceHealth>> {
+        let mut statement = self.connection.prepare(
+            "SELECT instance, prepare_failures, last_failure, message FROM instance_health",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                InstanceHealth {
+                    prepare_failures: row.get(1)?,
+                    last_failure: row.get(2)?,
+                    message: row.get(3)?,
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn get_flag(&self, name: &str) -> anyhow::Result<bool> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT value FROM flags WHERE name = ?1")?;
+        let mut rows = statement.query_map([name], |row| row.get(0))?;
+        Ok(rows.next().transpose()?.unwrap_or(false))
+    }
+
+    pub fn set_flag(&self, name: &str, value: bool) -> anyhow::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO flags (name, value) VALUES (?1, ?2)",
+            params![name, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_activity(
+        &self,
+        instance: &str,
+        activity: Activity,
+        timestamp: i64,
+    ) -> anyhow::Result<()> {
+        let column = activity.column();
+        self.connection.execute(
+            &format!(
+                "INSERT INTO activity (instance, {column}) VALUES (?1, ?2) \
+                 ON CONFLICT(instance) DO UPDATE SET {column} = excluded.{column}"
+            ),
+            params![instance, timestamp],
+        )?;
+        Ok(())
+    }
+
+    pub fn read_activity(&self) -> anyhow::Result<HashMap<String, InstanceActivity>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT instance, last_matched, last_launched, last_executed FROM activity")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                InstanceActivity {
+                    last_matched: row.get(1)?,
+                    last_launched: row.get(2)?,
+                    last_executed: row.get(3)?,
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn record_image(&self, filename: &str, digest: &str) -> anyhow::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO images (filename, digest, pulled_at) VALUES (?1, ?2, ?3)",
+            params![filename, digest, unix_timestamp()],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrations_roundtrip() {
+        let mut db = StateDb::open(Path::new(":memory:")).unwrap();
+        let registrations: HashMap<_, _> = [
+            (
+                "a".to_owned(),
+                RunnerRegistration {
+                    id: 1,
+                    token: "t1".into(),
+                },
+            ),
+            (
+                "b".to_owned(),
+                RunnerRegistration {
+                    id: 2,
+                    token: "t2".into(),
+                },
+            ),
+        ]
+        .into_iter()
+        .collect();
+        db.write_registrations(&registrations).unwrap();
+        let read = db.read_registrations().unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read["a"].id, 1);
+        assert_eq!(read["b"].token, "t2");
+        db.write_registrations(&HashMap::new()).unwrap();
+        assert!(db.read_registrations().unwrap().is_empty());
+    }
+
+    #[test]
+    fn allocations() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let allocation = Allocation {
+            allocation_id: "123".into(),
+            instance: "a".into(),
+            job_ids: vec![1, 2],
+        };
+        db.add_allocation(&allocation).unwrap();
+        assert!(db.read_open_allocations(0).unwrap().is_empty());
+        let now = unix_timestamp() + 1;
+        assert_eq!(db.read_open_allocations(now).unwrap(), vec![allocation]);
+        // redispatching job 2 moves it to the new allocation
+        let redispatched = Allocation {
+            allocation_id: "124".into(),
+            instance: "a".into(),
+            job_ids: vec![2],
+        };
+        db.add_allocation(&redispatched).unwrap();
+        let open = db.read_open_allocations(now).unwrap();
+        assert_eq!(open.len(), 2);
+        assert!(open.iter().all(|a| a.job_ids.len() == 1));
+        db.set_allocation_state("123", AllocationState::Cancelled)
+            .unwrap();
+        db.set_allocation_state("124", AllocationState::Cancelled)
+            .unwrap();
+        assert!(db.read_open_allocations(now).unwrap().is_empty());
+    }
+
+    #[test]
+    fn flags() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        assert!(!db.get_flag(FLAG_MAINTENANCE).unwrap());
+        db.set_flag(FLAG_MAINTENANCE, true).unwrap();
+        assert!(db.get_flag(FLAG_MAINTENANCE).unwrap());
+        assert!(!db.get_flag(FLAG_RUNNERS_PAUSED).unwrap());
+        db.set_flag(FLAG_MAINTENANCE, false).unwrap();
+        assert!(!db.get_flag(FLAG_MAINTENANCE).unwrap());
+    }
+
+    #[test]
+    fn activity() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        db.record_activity("a", Activity::Matched, 1).unwrap();
+        db.record_activity("a", Activity::Launched, 2).unwrap();
+        db.record_activity("a", Activity::Matched, 3).unwrap();
+        db.record_activity("b", Activity::Executed, 4).unwrap();
+        let activity = db.read_activity().unwrap();
+        assert_eq!(
+            activity["a"],
+            InstanceActivity {
+                last_matched: Some(3),
+                last_launched: Some(2),
+                last_executed: None,
+            }
+        );
+        assert_eq!(activity["b"].last_executed, Some(4));
+    }
+
+    #[test]
+    fn launch_backoff() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let backoff = LaunchBackoff {
+            failures: 2,
+            until: 100,
+        };
+        db.set_launch_backoff("a", Some(&backoff)).unwrap();
+        assert_eq!(db.read_launch_backoff().unwrap()["a"], backoff);
+        db.set_launch_backoff("a", None).unwrap();
+        assert!(db.read_launch_backoff().unwrap().is_empty());
+    }
+
+    #[test]
+    fn launch_quota() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let mut quota = LaunchQuota {
+            tokens: 0.0,
+            updated_at: 0,
+        };
+        // 4 launches per hour refill one token every 15 minutes
+        quota.refill(4, 3600, 1800);
+        assert_eq!(quota.available(), 2);
+        quota.refill(4, 3600, 100000);
+        assert_eq!(quota.available(), 4);
+        quota.tokens -= 1.5;
+        assert_eq!(quota.available(), 2);
+        db.set_launch_quota("a", 3600, &quota).unwrap();
+        assert_eq!(
+            db.read_launch_quota().unwrap()[&("a".to_owned(), 3600)],
+            quota
+        );
+    }
+
+    #[test]
+    fn instance_health() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        db.record_prepare_result("a", None).unwrap();
+        assert!(db.read_instance_health().unwrap().is_empty());
+        db.record_prepare_result("a", Some("pull failed")).unwrap();
+        db.record_prepare_result("a", Some("not writable")).unwrap();
+        let health = &db.read_instance_health().unwrap()["a"];
+        assert_eq!(health.prepare_failures, 2);
+        assert_eq!(health.message.as_deref(), Some("not writable"));
+        db.record_prepare_result("a", None).unwrap();
+        assert_eq!(db.read_instance_health().unwrap()["a"].prepare_failures, 0);
+    }
+
+    #[test]
+    fn handled_jobs() {
+        let mut db = StateDb::open(Path::new(":memory:")).unwrap();
+        db.add_handled_jobs([(1, Some("a".to_owned())), (2, None)])
+            .unwrap();
+        db.add_handled_jobs([(1, Some("b".to_owned()))]).unwrap();
+        let handled = db.read_handled_jobs().unwrap();
+        // ignored jobs are not persisted
+        assert_eq!(handled.len(), 1);
+        assert_eq!(handled[&1].instance, Some("b".to_owned()));
+        // ignored jobs stored by earlier versions are not read back either
+        db.connection
+            .execute(
+                "INSERT INTO handled_jobs (job_id, instance, handled_at) VALUES (3, NULL, 0)",
+                [],
+            )
+            .unwrap();
+        assert_eq!(db.read_handled_jobs().unwrap().len(), 1);
+        assert!(db.read_launched_jobs(0).unwrap().is_empty());
+        let launched = db.read_launched_jobs(unix_timestamp() + 1).unwrap();
+        assert_eq!(launched, [(1, "b".to_owned())].into_iter().collect());
+        db.add_handled_jobs([(4, Some("a".to_owned()))]).unwrap();
+        db.remove_handled_jobs(&[4]).unwrap();
+        assert_eq!(db.read_handled_jobs().unwrap().len(), 1);
+        db.add_launch_record(&LaunchRecord {
+            instance: "b",
+            job_ids: vec![1],
+            success: true,
+            message: None,
+        })
+        .unwrap();
+        db.prune_handled_jobs(0).unwrap();
+        assert_eq!(db.read_handled_jobs().unwrap().len(), 1);
+        db.prune_handled_jobs(unix_timestamp() + 1).unwrap();
+        assert!(db.read_handled_jobs().unwrap().is_empty());
+        let launches: i64 = db
+            .connection
+            .query_row("SELECT COUNT(*) FROM launches", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(launches, 0);
+    }
+}
@@ -0,0 +1,64 @@
+use std::{collections::BTreeMap, fs::OpenOptions, io::Write, path::Path};
+
+use anyhow::Context;
+use serde_derive::Serialize;
+
+/// Launches of a single runner instance during a session
+#[derive(Debug, Default, Serialize)]
+pub struct InstanceSummary {
+    /// Number of jobs runners were launched for
+    pub dispatched_jobs: u64,
+    /// Number of launch commands
+    pub launches: u64,
+    /// Number of failed launch commands
+    pub launch_failures: u64,
+}
+
+/// Accounting summary of a `gitlab-meta-runner run` session, written on shutdown
+#[derive(Debug, Serialize)]
+pub struct SessionSummary {
+    /// Unix timestamp of the daemon start
+    pub started_at: i64,
+    /// Unix timestamp of the shutdown
+    pub stopped_at: i64,
+    pub polls: u64,
+    pub poll_failures: u64,
+    /// Duration (in seconds) of the longest poll
+    pub longest_poll: f64,
+    /// Number of distinct pending jobs seen
+    pub seen_jobs: usize,
+    /// Number of jobs runners were launched for
+    pub dispatched_jobs: usize,
+    pub instances: BTreeMap<String, InstanceSummary>,
+}
+
+impl SessionSummary {
+    /// Prints a human-readable version of the summary
+    pub fn print(&self) {
+        println!("Session summary ({}s):", self.stopped_at - self.started_at);
+        println!(
+            "  Polls:           {} ({} failed)",
+            self.polls, self.poll_failures
+        );
+        println!("  Longest poll:    {:.1}s", self.longest_poll);
+        println!("  Jobs seen:       {}", self.seen_jobs);
+        println!("  Jobs dispatched: {}", self.dispatched_jobs);
+        for (name, instance) in &self.instances {
+            println!(
+                "  Runner instance {}: {} jobs dispatched in {} launches, {} launch failures",
+                name, instance.dispatched_jobs, instance.launches, instance.launch_failures
+            );
+        }
+    }
+
+    /// Appends the summary to the JSONL session log
+    pub fn append(&self, path: &Path) -> anyhow::Result<()> {
+        let line = serde_json::to_string(self)? + "\n";
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .context(format!("Failed writing session summary {:?}", path))
+    }
+}
@@ -0,0 +1,158 @@
+use std::{path::Path, time::SystemTime};
+
+use anyhow::{anyhow, Context};
+use async_process::{Child, Command, Stdio};
+use log::{debug, error, info, warn};
+use tokio::{
+    signal::{
+        self,
+        unix::{signal as unix_signal, Signal, SignalKind},
+    },
+    time::{self, Duration, MissedTickBehavior},
+};
+
+use crate::{
+    check_config, cli,
+    config::{get_generated_config_file_path, read_config, GitLabSuperviseConfig},
+};
+
+/// Interval for checking the generated config file for changes
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+fn get_modified(path: &Path) -> anyhow::Result<SystemTime> {
+    Ok(std::fs::metadata(path)?.modified()?)
+}
+
+fn send_signal(child: &Child, signal: libc::c_int) -> anyhow::Result<()> {
+    // SAFETY: kill has no memory safety requirements, the PID belongs to our own child
+    if unsafe { libc::kill(child.id() as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error().into())
+    }
+}
+
+fn spawn_runner(config: &GitLabSuperviseConfig, config_file: &Path) -> anyhow::Result<Child> {
+    let mut command = Command::new(&config.executable);
+    command
+        .arg("run")
+        .arg("--config")
+        .arg(config_file)
+        .args(&config.args)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    debug!("Spawning gitlab-runner process {:?}", command);
+    command.spawn().context(format!(
+        "Failed spawning gitlab-runner process {:?}",
+        command
+    ))
+}
+
+/// What ended the supervision of a single gitlab-runner process
+enum Exit {
+    /// The process exited by itself
+    Exited,
+    /// A shutdown was requested
+    Shutdown,
+}
+
+/// Resolves once the supervisor is asked to shut down, either via Ctrl+C or with SIGTERM
+/// by a service manager like systemd, returning the name of the signal
+async fn shutdown_signal(sigterm: &mut Signal) -> std::io::Result<&'static str> {
+    tokio::select! {
+        result = signal::ctrl_c() => result.map(|_| "Ctrl+C"),
+        _ = sigterm.recv() => Ok("SIGTERM"),
+    }
+}
+
+/// Waits for the gitlab-runner process to exit, reloading it whenever the config file changes
+async fn supervise_process(
+    mut child: Child,
+    config_file: &Path,
+    sigterm: &mut Signal,
+) -> anyhow::Result<Exit> {
+    let mut modified = get_modified(config_file)?;
+    let mut timer = time::interval(CONFIG_CHECK_INTERVAL);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            status = child.status() => {
+                let status = status.context("Failed waiting for gitlab-runner process")?;
+                if status.success() {
+                    warn!("gitlab-runner exited");
+                } else {
+                    error!("gitlab-runner exited with {}", status);
+                }
+                return Ok(Exit::Exited);
+            }
+            _ = timer.tick() => {
+                match get_modified(config_file) {
+                    Ok(new_modified) if new_modified != modified => {
+                        info!("Config file {:?} changed, reloading gitlab-runner", config_file);
+                        modified = new_modified;
+                        send_signal(&child, libc::SIGHUP)
+                            .context("Failed sending SIGHUP to gitlab-runner")?;
+                    }
+                    Ok(_) => (),
+                    Err(e) => warn!("Failed checking config file {:?}: {:?}", config_file, e),
+                }
+            }
+            shutdown = shutdown_signal(sigterm) => {
+                match shutdown {
+                    Ok(signal) => info!("Received shutdown signal ({}), stopping gitlab-runner", signal),
+                    Err(_) => error!("Failed to listen for shutdown signal, stopping gitlab-runner anyways."),
+                }
+                // SIGQUIT lets gitlab-runner finish its running jobs before exiting
+                send_signal(&child, libc::SIGQUIT)
+                    .context("Failed sending SIGQUIT to gitlab-runner")?;
+                child
+                    .status()
+                    .await
+                    .context("Failed waiting for gitlab-runner process")?;
+                return Ok(Exit::Shutdown);
+            }
+        }
+    }
+}
+
+/// Runs a persistent gitlab-runner process for all runner instances, restarting it when it exits
+#[tokio::main]
+pub async fn run_multi(paths: &cli::Paths) -> anyhow::Result<()> {
+    check_config::check(paths)?;
+    let config = read_config(&paths.config_file).context(format!(
+        "Failed reading config file {:?}",
+        paths.config_file
+    ))?;
+    let config_file = get_generated_config_file_path(paths, &config.name);
+    if !std::fs::exists(&config_file)? {
+        return Err(anyhow!(
+            "Generated config file {:?} doesn't exist, run `gitlab-meta-runner configure` first",
+            config_file
+        ));
+    }
+    let restart_delay = Duration::from_secs(config.supervise.restart_delay as u64);
+    // created once, so a SIGTERM arriving while gitlab-runner is restarted isn't lost
+    let mut sigterm =
+        unix_signal(SignalKind::terminate()).context("Failed listening for SIGTERM")?;
+    loop {
+        info!("Starting gitlab-runner with config file {:?}", config_file);
+        let child = spawn_runner(&config.supervise, &config_file)?;
+        match supervise_process(child, &config_file, &mut sigterm).await? {
+            Exit::Shutdown => return Ok(()),
+            Exit::Exited => {
+                info!("Restarting gitlab-runner in {}s", restart_delay.as_secs());
+                tokio::select! {
+                    _ = time::sleep(restart_delay) => (),
+                    shutdown = shutdown_signal(&mut sigterm) => {
+                        if let Ok(signal) = shutdown {
+                            info!("Received shutdown signal ({}), not restarting gitlab-runner", signal);
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
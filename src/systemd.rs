@@ -0,0 +1,40 @@
+use std::{
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use log::{debug, warn};
+
+fn send_notification(socket_path: &str, state: &str) -> anyhow::Result<()> {
+    // socket paths starting with @ refer to the abstract namespace
+    let address = match socket_path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket_path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to_addr(state.as_bytes(), &address)
+        .context(format!("Failed sending to {}", socket_path))?;
+    Ok(())
+}
+
+/// Sends a state update to systemd if the process runs as a notify service, otherwise does nothing
+pub fn notify(state: &str) {
+    let Ok(socket_path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    debug!("Sending {} to systemd", state);
+    if let Err(e) = send_notification(&socket_path, state) {
+        warn!("Failed notifying systemd: {:?}", e);
+    }
+}
+
+/// Returns the watchdog interval configured for the service, if any
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
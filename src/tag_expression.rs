@@ -0,0 +1,129 @@
+use std::{iter::Peekable, vec::IntoIter};
+
+use anyhow::anyhow;
+
+/// Boolean expression over job tags, e.g. `cuda & !(long-running | nightly)`
+#[derive(Debug, PartialEq)]
+pub enum TagExpression {
+    Tag(String),
+    Not(Box<TagExpression>),
+    And(Box<TagExpression>, Box<TagExpression>),
+    Or(Box<TagExpression>, Box<TagExpression>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Tag(String),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize(expression: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut tag = String::new();
+    for c in expression.chars() {
+        let token = match c {
+            '!' => Some(Token::Not),
+            '&' => Some(Token::And),
+            '|' => Some(Token::Or),
+            '(' => Some(Token::Open),
+            ')' => Some(Token::Close),
+            c if c.is_whitespace() => None,
+            c => {
+                tag.push(c);
+                continue;
+            }
+        };
+        if !tag.is_empty() {
+            tokens.push(Token::Tag(std::mem::take(&mut tag)));
+        }
+        tokens.extend(token);
+    }
+    if !tag.is_empty() {
+        tokens.push(Token::Tag(tag));
+    }
+    tokens
+}
+
+type Tokens = Peekable<IntoIter<Token>>;
+
+/// or := and ('|' and)*
+fn parse_or(tokens: &mut Tokens) -> anyhow::Result<TagExpression> {
+    let mut expression = parse_and(tokens)?;
+    while tokens.next_if_eq(&Token::Or).is_some() {
+        expression = TagExpression::Or(Box::new(expression), Box::new(parse_and(tokens)?));
+    }
+    Ok(expression)
+}
+
+/// and := unary ('&' unary)*
+fn parse_and(tokens: &mut Tokens) -> anyhow::Result<TagExpression> {
+    let mut expression = parse_unary(tokens)?;
+    while tokens.next_if_eq(&Token::And).is_some() {
+        expression = TagExpression::And(Box::new(expression), Box::new(parse_unary(tokens)?));
+    }
+    Ok(expression)
+}
+
+/// unary := '!' unary | '(' or ')' | tag
+fn parse_unary(tokens: &mut Tokens) -> anyhow::Result<TagExpression> {
+    match tokens.next() {
+        Some(Token::Not) => Ok(TagExpression::Not(Box::new(parse_unary(tokens)?))),
+        Some(Token::Open) => {
+            let expression = parse_or(tokens)?;
+            match tokens.next() {
+                Some(Token::Close) => Ok(expression),
+                _ => Err(anyhow!("Missing closing parenthesis")),
+            }
+        }
+        Some(Token::Tag(tag)) => Ok(TagExpression::Tag(tag)),
+        Some(token) => Err(anyhow!("Unexpected {:?}, expected a tag", token)),
+        None => Err(anyhow!("Unexpected end of expression, expected a tag")),
+    }
+}
+
+impl TagExpression {
+    /// Parses an expression of tags combined with `&` (and), `|` (or), `!` (not) and parentheses
+    pub fn parse(expression: &str) -> anyhow::Result<TagExpression> {
+        let mut tokens = tokenize(expression).into_iter().peekable();
+        let result = parse_or(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(result),
+            Some(token) => Err(anyhow!("Unexpected {:?} after expression", token)),
+        }
+    }
+
+    pub fn evaluate(&self, tags: &[String]) -> bool {
+        match self {
+            TagExpression::Tag(tag) => tags.contains(tag),
+            TagExpression::Not(e) => !e.evaluate(tags),
+            TagExpression::And(a, b) => a.evaluate(tags) && b.evaluate(tags),
+            TagExpression::Or(a, b) => a.evaluate(tags) || b.evaluate(tags),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluate() {
+        let tags = |tags: &[&str]| tags.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let expression = TagExpression::parse("cuda & !long-running").unwrap();
+        assert!(expression.evaluate(&tags(&["cuda"])));
+        assert!(!expression.evaluate(&tags(&["cuda", "long-running"])));
+        assert!(!expression.evaluate(&tags(&["hip"])));
+        // & binds stronger than |
+        let expression = TagExpression::parse("cpu | cuda & !(nightly|long-running)").unwrap();
+        assert!(expression.evaluate(&tags(&["cpu", "nightly"])));
+        assert!(expression.evaluate(&tags(&["cuda"])));
+        assert!(!expression.evaluate(&tags(&["cuda", "nightly"])));
+        assert!(TagExpression::parse("cuda &").is_err());
+        assert!(TagExpression::parse("(cuda").is_err());
+        assert!(TagExpression::parse("cuda hip").is_err());
+    }
+}
@@ -3,10 +3,21 @@ use std::collections::HashMap;
 use crate::cli::Paths;
 use crate::config::get_generated_config_file_path;
 use crate::config::BoolOrString;
+use crate::config::GitLabCacheConfig;
+use crate::config::GitLabCancelConfig;
 use crate::config::GitLabCustomExecutorConfig;
+use crate::config::GitLabExecutorMetricsConfig;
+use crate::config::GitLabFailedBuildsConfig;
+use crate::config::GitLabHomeMount;
+use crate::config::GitLabImageBuildConfig;
+use crate::config::GitLabJobOverridesConfig;
 use crate::config::GitLabLaunchConfig;
+use crate::config::GitLabLaunchHookConfig;
+use crate::config::GitLabQueueCheckConfig;
+use crate::config::GitLabRegistryConfig;
 use crate::config::GitLabRunnerInstance;
 use crate::config::GitLabRunnersConfig;
+use crate::config::GitLabSpackConfig;
 use crate::gitlab_config::CustomExecutor;
 use crate::gitlab_config::Executor;
 use crate::gitlab_config::Runner;
@@ -73,6 +84,12 @@ pub fn expand_runner_config_template(
         builds_dir: string_expand(&config.builds_dir).context("builds_dir")?,
         cache_dir: string_expand(&config.cache_dir).context("cache_dir")?,
         output_limit: config.output_limit.clone(),
+        shell: config
+            .shell
+            .as_ref()
+            .map(|v| string_expand(v))
+            .transpose()
+            .context("shell")?,
         environment: config
             .environment
             .as_ref()
@@ -146,17 +163,96 @@ pub fn expand_executor_config_template(
             .transpose()
             .context("image_tmp_dir")?,
         pull_policy: executor.pull_policy,
+        pull_retries: executor.pull_retries,
+        pull_retry_delay: executor.pull_retry_delay,
+        pull_timeout: executor.pull_timeout,
+        arch: executor.arch.clone(),
+        default_image: executor
+            .default_image
+            .as_ref()
+            .map(|v| string_expand(v))
+            .transpose()
+            .context("default_image")?,
+        allow_host_fallback: executor.allow_host_fallback,
+        prewarm_images: executor
+            .prewarm_images
+            .iter()
+            .map(|v| string_expand(v))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("prewarm_images")?,
+        image_mirrors: executor
+            .image_mirrors
+            .iter()
+            .map(|v| string_expand(v))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("image_mirrors")?,
+        backend: executor.backend,
         apptainer_executable: string_expand(&executor.apptainer_executable)
             .context("apptainer_executable")?
             .into(),
+        docker_executable: string_expand(&executor.docker_executable)
+            .context("docker_executable")?
+            .into(),
+        enroot_executable: string_expand(&executor.enroot_executable)
+            .context("enroot_executable")?
+            .into(),
         gpu_amd: expand_to_bool(&executor.gpu_amd).context("gpu_amd")?,
         gpu_nvidia: expand_to_bool(&executor.gpu_nvidia).context("gpu_nvidia")?,
+        gpu_intel: expand_to_bool(&executor.gpu_intel).context("gpu_intel")?,
+        mount_home: match &executor.mount_home {
+            BoolOrString::Bool(false) => GitLabHomeMount::None,
+            BoolOrString::Bool(true) => GitLabHomeMount::Host,
+            BoolOrString::String(path) => {
+                GitLabHomeMount::Path(string_expand(path).context("mount_home")?.into())
+            }
+        },
         mount: executor
             .mount
             .iter()
             .map(|v| string_expand(v))
             .collect::<anyhow::Result<Vec<_>>>()
             .context("mount")?,
+        create_mount_sources: executor.create_mount_sources,
+        exec_args: executor
+            .exec_args
+            .iter()
+            .map(|v| string_expand(v))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("exec_args")?,
+        pass_env: executor
+            .pass_env
+            .iter()
+            .map(|v| string_expand(v))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("pass_env")?,
+        step_wrapper: executor
+            .step_wrapper
+            .iter()
+            .map(|v| string_expand(v))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("step_wrapper")?,
+        limits: executor.limits.clone(),
+        run_as: executor.run_as.clone(),
+        shell_executable: executor.shell.clone(),
+        shell_args: executor.shell_args.clone(),
+        network: executor.network.clone(),
+        network_args: executor
+            .network_args
+            .iter()
+            .map(|v| string_expand(v))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("network_args")?,
+        overlay_size_mb: executor.overlay_size_mb,
+        reuse_instance: executor.reuse_instance,
+        overlay: executor
+            .overlay
+            .as_ref()
+            .map(|v| string_expand(v).map(|s| s.into()))
+            .transpose()
+            .context("overlay")?,
+        verify_signatures: executor.verify_signatures,
+        trusted_fingerprints: executor.trusted_fingerprints.clone(),
+        trusted_digests: executor.trusted_digests.clone(),
         builds_dir: string_expand(
             executor
                 .builds_dir
@@ -165,9 +261,147 @@ pub fn expand_executor_config_template(
         )
         .context("builds_dir")?
         .into(),
+        builds_dir_scratch: executor.builds_dir_scratch.clone(),
+        builds_dir_layout: executor.builds_dir_layout.clone(),
+        builds_dir_is_shared: executor.builds_dir_is_shared,
         cache_dir: string_expand(&config.runner.cache_dir)
             .context("cache_dir")?
             .into(),
+        shell: config
+            .runner
+            .shell
+            .as_ref()
+            .map(|v| string_expand(v))
+            .transpose()
+            .context("shell")?,
+        modules: executor
+            .modules
+            .iter()
+            .map(|v| string_expand(v))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("modules")?,
+        spack: executor
+            .spack
+            .as_ref()
+            .map(|spack| -> anyhow::Result<_> {
+                Ok(GitLabSpackConfig {
+                    executable: string_expand(&spack.executable).context("executable")?,
+                    cache_dir: string_expand(&spack.cache_dir).context("cache_dir")?,
+                    environment: spack
+                        .environment
+                        .as_ref()
+                        .map(|v| string_expand(v))
+                        .transpose()
+                        .context("environment")?,
+                })
+            })
+            .transpose()
+            .context("spack")?,
+        failed_builds: executor
+            .failed_builds
+            .as_ref()
+            .map(|failed_builds| -> anyhow::Result<_> {
+                Ok(GitLabFailedBuildsConfig {
+                    dir: string_expand(&failed_builds.dir).context("dir")?,
+                    ..failed_builds.clone()
+                })
+            })
+            .transpose()
+            .context("failed_builds")?,
+        stale_builds_max_age: executor.stale_builds_max_age,
+        min_free_space: executor.min_free_space.clone(),
+        caches: executor
+            .caches
+            .iter()
+            .map(|(name, cache)| -> anyhow::Result<_> {
+                Ok((
+                    name.clone(),
+                    GitLabCacheConfig {
+                        dir: string_expand(&cache.dir).context(format!("caches.{}.dir", name))?,
+                        ..cache.clone()
+                    },
+                ))
+            })
+            .collect::<anyhow::Result<_>>()
+            .context("caches")?,
+        registries: executor
+            .registries
+            .iter()
+            .map(|(name, registry)| -> anyhow::Result<_> {
+                Ok((
+                    name.clone(),
+                    GitLabRegistryConfig {
+                        username: registry
+                            .username
+                            .as_ref()
+                            .map(|v| string_expand(v))
+                            .transpose()
+                            .context("username")?,
+                        password_file: registry
+                            .password_file
+                            .as_ref()
+                            .map(|v| string_expand(v))
+                            .transpose()
+                            .context("password_file")?,
+                        password_command: registry
+                            .password_command
+                            .iter()
+                            .map(|v| string_expand(v))
+                            .collect::<anyhow::Result<Vec<_>>>()
+                            .context("password_command")?,
+                        ..registry.clone()
+                    },
+                ))
+            })
+            .collect::<anyhow::Result<_>>()
+            .context("registries")?,
+        metrics: executor
+            .metrics
+            .as_ref()
+            .map(|metrics| -> anyhow::Result<_> {
+                Ok(GitLabExecutorMetricsConfig {
+                    textfile: string_expand(&metrics.textfile).context("textfile")?,
+                })
+            })
+            .transpose()
+            .context("metrics")?,
+        image_build: executor
+            .image_build
+            .as_ref()
+            .map(|image_build| -> anyhow::Result<_> {
+                Ok(GitLabImageBuildConfig {
+                    apptainer_args: image_build
+                        .apptainer_args
+                        .iter()
+                        .map(|v| string_expand(v))
+                        .collect::<anyhow::Result<Vec<_>>>()
+                        .context("apptainer_args")?,
+                    dockerfile_builder: image_build
+                        .dockerfile_builder
+                        .as_ref()
+                        .map(|v| string_expand(v))
+                        .transpose()
+                        .context("dockerfile_builder")?,
+                })
+            })
+            .transpose()
+            .context("image_build")?,
+        job_overrides: executor
+            .job_overrides
+            .as_ref()
+            .map(|job_overrides| -> anyhow::Result<_> {
+                Ok(GitLabJobOverridesConfig {
+                    mount_prefixes: job_overrides
+                        .mount_prefixes
+                        .iter()
+                        .map(|v| string_expand(v))
+                        .collect::<anyhow::Result<Vec<_>>>()
+                        .context("mount_prefixes")?,
+                    ..job_overrides.clone()
+                })
+            })
+            .transpose()
+            .context("job_overrides")?,
         // This one needs to be infallible to handle check-config
         description: executor.description.as_ref().map(|v| {
             string_expand(v)
@@ -183,6 +417,7 @@ pub fn expand_launch_config_template(
     instance_name: &str,
     instance: &GitLabRunnerInstance,
     num_jobs: usize,
+    job_timeout: u64,
 ) -> anyhow::Result<GitLabLaunchConfig> {
     let launch = config
         .launch
@@ -194,10 +429,14 @@ pub fn expand_launch_config_template(
         generated_config_file_path
     ))?;
     let num_jobs_str = format!("{}", num_jobs);
+    let job_timeout_str = format!("{}", job_timeout);
+    let job_timeout_minutes_str = format!("{}", job_timeout.div_ceil(60));
     let string_expand = |s: &str| {
         string_expand_impl(s, instance_name, instance, &|s| match s {
             "CONFIG" => Some(&generated_config_file_path_str),
             "NUM_JOBS" => Some(&num_jobs_str),
+            "JOB_TIMEOUT" => Some(&job_timeout_str),
+            "JOB_TIMEOUT_MINUTES" => Some(&job_timeout_minutes_str),
             _ => None,
         })
     };
@@ -206,7 +445,14 @@ pub fn expand_launch_config_template(
     let string_array_expand = |v: &Vec<String>| -> anyhow::Result<Vec<String>> {
         v.into_iter().map(|s| string_expand(s)).collect()
     };
+    let hook_expand = |hook: &GitLabLaunchHookConfig| -> anyhow::Result<GitLabLaunchHookConfig> {
+        Ok(GitLabLaunchHookConfig {
+            executable: string_expand(&hook.executable).context("executable")?,
+            args: string_array_expand(&hook.args).context("args")?,
+        })
+    };
     Ok(GitLabLaunchConfig {
+        preset: launch.preset,
         executable: string_expand(&launch.executable)
             .context("executable")?
             .into(),
@@ -214,14 +460,82 @@ pub fn expand_launch_config_template(
         workdir: optional_string_expand(&launch.workdir).context("workdir")?,
         stdin: optional_string_expand(&launch.stdin).context("stdin")?,
         timeout: launch.timeout,
+        retries: launch.retries,
+        retry_delay: launch.retry_delay,
         group_size: launch.group_size,
+        max_per_poll: launch.max_per_poll,
+        budget: launch.budget,
+        parallelism: launch.parallelism,
+        allocation_id_pattern: launch.allocation_id_pattern.clone(),
+        reconcile_timeout: launch.reconcile_timeout,
+        cancel_orphaned: launch.cancel_orphaned,
+        // expanded separately for every allocation using expand_cancel_config_template
+        cancel: launch.cancel.clone(),
+        redispatch_timeout: launch.redispatch_timeout,
+        starvation_timeout: launch.starvation_timeout,
+        prepare_failure_cooldown: launch.prepare_failure_cooldown,
+        prepare_failure_threshold: launch.prepare_failure_threshold,
+        pre_hook: launch
+            .pre_hook
+            .as_ref()
+            .map(&hook_expand)
+            .transpose()
+            .context("pre_hook")?,
+        post_hook: launch
+            .post_hook
+            .as_ref()
+            .map(&hook_expand)
+            .transpose()
+            .context("post_hook")?,
+        queue_check: launch
+            .queue_check
+            .as_ref()
+            .map(|queue_check| -> anyhow::Result<_> {
+                Ok(GitLabQueueCheckConfig {
+                    executable: string_expand(&queue_check.executable).context("executable")?,
+                    args: string_array_expand(&queue_check.args).context("args")?,
+                })
+            })
+            .transpose()
+            .context("queue_check")?,
+    })
+}
+
+pub fn expand_cancel_config_template(
+    config: &GitLabRunnersConfig,
+    instance_name: &str,
+    instance: &GitLabRunnerInstance,
+    allocation_id: &str,
+) -> anyhow::Result<GitLabCancelConfig> {
+    let cancel = config
+        .launch
+        .as_ref()
+        .and_then(|launch| launch.cancel.as_ref())
+        .ok_or(anyhow!("Missing launch.cancel configuration"))?;
+    let string_expand = |s: &str| {
+        string_expand_impl(s, instance_name, instance, &|s| match s {
+            "ALLOCATION_ID" => Some(allocation_id),
+            _ => None,
+        })
+    };
+    Ok(GitLabCancelConfig {
+        executable: string_expand(&cancel.executable).context("executable")?,
+        args: cancel
+            .args
+            .iter()
+            .map(|s| string_expand(s))
+            .collect::<anyhow::Result<_>>()
+            .context("args")?,
     })
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        config::{GitLabCustomExecutorConfigTemplate, GitLabExecutorPullPolicy, GitLabPollConfig},
+        config::{
+            GitLabConfigureConfig, GitLabCustomExecutorConfigTemplate, GitLabExecutorBackend,
+            GitLabExecutorPullPolicy, GitLabPollConfig, GitLabSuperviseConfig, StringOrList,
+        },
         gitlab_config,
     };
 
@@ -247,6 +561,14 @@ mod tests {
             &GitLabRunnerInstance {
                 tags: Vec::new(),
                 launch_priority: None,
+                job_name_pattern: None,
+                ref_pattern: None,
+                pipeline_source: Vec::new(),
+                tag_expression: None,
+                run_untagged: false,
+                max_launches_per_hour: None,
+                max_launches_per_day: None,
+                schedule: HashMap::new(),
                 config_variables: [("ME".to_owned(), "me".to_owned())].into_iter().collect(),
             },
             &|v| match v {
@@ -271,6 +593,7 @@ mod tests {
             builds_dir: "~/$FOO/$NAME".into(),
             cache_dir: "$PWD/$BAR".into(),
             output_limit: Some(10),
+            shell: Some("$SHELL_NAME".into()),
             executor: gitlab_config::Executor::Custom {
                 custom: gitlab_config::CustomExecutor {
                     config_exec: "$THIS".into(),
@@ -291,6 +614,14 @@ mod tests {
             &GitLabRunnerInstance {
                 tags: Vec::new(),
                 launch_priority: None,
+                job_name_pattern: None,
+                ref_pattern: None,
+                pipeline_source: Vec::new(),
+                tag_expression: None,
+                run_untagged: false,
+                max_launches_per_hour: None,
+                max_launches_per_day: None,
+                schedule: HashMap::new(),
                 config_variables: [
                     ("FOO", "foo"),
                     ("BAR", "bar"),
@@ -303,6 +634,7 @@ mod tests {
                     ("A6", "a6"),
                     ("A7", "a7"),
                     ("A8", "a8"),
+                    ("SHELL_NAME", "pwsh"),
                 ]
                 .into_iter()
                 .map(|(a, b)| (a.to_owned(), b.to_owned()))
@@ -314,6 +646,7 @@ mod tests {
         assert_eq!(expanded.builds_dir, format!("{}/foo/name", home));
         assert_eq!(expanded.cache_dir, format!("{}/bar", workdir));
         assert_eq!(expanded.output_limit, Some(10));
+        assert_eq!(expanded.shell, Some("pwsh".to_owned()));
         assert_eq!(expanded.environment, Some(vec!["baz".to_owned()]));
         match expanded.executor {
             Executor::Custom { custom } => {
@@ -337,16 +670,30 @@ mod tests {
         GitLabRunnersConfig {
             executor: Some(config),
             name: "".into(),
-            project: "".into(),
+            project: StringOrList::String("".into()),
             hostname: "".into(),
             management_token: "".into(),
             runners: HashMap::new(),
-            poll: GitLabPollConfig { interval: 1 },
+            poll: GitLabPollConfig {
+                interval: 1,
+                api_timeout: 60,
+                debounce: None,
+                match_strategy: Default::default(),
+                decision_log: false,
+            },
+            configure: GitLabConfigureConfig::default(),
+            hooks: HashMap::new(),
+            maintenance: HashMap::new(),
+            schedule: HashMap::new(),
+            supervise: GitLabSuperviseConfig::default(),
+            metrics: None,
+            notify: None,
             launch: None,
             runner: Runner {
                 builds_dir,
                 cache_dir: "".into(),
                 output_limit: None,
+                shell: None,
                 executor: Executor::Custom {
                     custom: CustomExecutor {
                         config_exec: "".into(),
@@ -370,15 +717,57 @@ mod tests {
         let config = build_dummy_config_executor(
             GitLabCustomExecutorConfigTemplate {
                 builds_dir: None,
+                builds_dir_scratch: Vec::new(),
+                builds_dir_layout: None,
+                builds_dir_is_shared: false,
                 image_dir: "$PWD/$FOO".into(),
                 image_cache_dir: None,
                 image_tmp_dir: None,
                 pull_policy: GitLabExecutorPullPolicy::Always,
+                pull_retries: 0,
+                pull_retry_delay: 10,
+                pull_timeout: None,
+                arch: None,
+                default_image: None,
+                allow_host_fallback: false,
+                prewarm_images: Vec::new(),
+                image_mirrors: Vec::new(),
+                backend: GitLabExecutorBackend::Apptainer,
                 apptainer_executable: "~/bin/apptainer".into(),
+                docker_executable: "docker".into(),
+                enroot_executable: "enroot".into(),
                 gpu_amd: BoolOrString::Bool(false),
                 gpu_nvidia: BoolOrString::Bool(true),
+                gpu_intel: BoolOrString::Bool(false),
+                mount_home: BoolOrString::Bool(true),
                 mount: vec!["$BAR".to_owned(), "$THIS".into()],
+                create_mount_sources: false,
+                exec_args: vec!["--env=FOO=$FOO".to_owned()],
+                pass_env: vec!["SLURM_$FOO".to_owned()],
+                step_wrapper: Vec::new(),
+                limits: None,
+                run_as: None,
+                shell: None,
+                shell_args: None,
+                network: None,
+                network_args: Vec::new(),
+                overlay_size_mb: None,
+                reuse_instance: false,
+                overlay: None,
+                verify_signatures: false,
+                trusted_fingerprints: Vec::new(),
+                trusted_digests: Vec::new(),
                 description: None,
+                modules: Vec::new(),
+                spack: None,
+                failed_builds: None,
+                stale_builds_max_age: None,
+                min_free_space: None,
+                caches: HashMap::new(),
+                registries: HashMap::new(),
+                metrics: None,
+                image_build: None,
+                job_overrides: None,
             },
             "$HOME/builds".into(),
         );
@@ -388,6 +777,14 @@ mod tests {
             &GitLabRunnerInstance {
                 tags: Vec::new(),
                 launch_priority: None,
+                job_name_pattern: None,
+                ref_pattern: None,
+                pipeline_source: Vec::new(),
+                tag_expression: None,
+                run_untagged: false,
+                max_launches_per_hour: None,
+                max_launches_per_day: None,
+                schedule: HashMap::new(),
                 config_variables: [("FOO", "foo"), ("BAR", "bar"), ("BAZ", "baz")]
                     .into_iter()
                     .map(|(a, b)| (a.to_owned(), b.to_owned()))
@@ -411,13 +808,19 @@ mod tests {
         assert_eq!(expanded.image_cache_dir, None);
         assert_eq!(expanded.image_tmp_dir, None);
         assert_eq!(expanded.pull_policy, GitLabExecutorPullPolicy::Always);
+        assert_eq!(expanded.default_image, None);
+        assert!(!expanded.allow_host_fallback);
+        assert_eq!(expanded.backend, GitLabExecutorBackend::Apptainer);
         assert_eq!(
             expanded.apptainer_executable.to_str().unwrap(),
             format!("{}/bin/apptainer", home)
         );
         assert_eq!(expanded.gpu_amd, false);
         assert_eq!(expanded.gpu_nvidia, true);
+        assert_eq!(expanded.mount_home, GitLabHomeMount::Host);
         assert_eq!(expanded.mount, vec!["bar".to_owned(), exe]);
+        assert_eq!(expanded.exec_args, vec!["--env=FOO=foo".to_owned()]);
+        assert_eq!(expanded.pass_env, vec!["SLURM_foo".to_owned()]);
         assert_eq!(expanded.description, None);
     }
 
@@ -427,15 +830,85 @@ mod tests {
         let config = build_dummy_config_executor(
             GitLabCustomExecutorConfigTemplate {
                 builds_dir: Some("$HOME/builds2".into()),
+                builds_dir_scratch: vec!["SLURM_TMPDIR".to_owned()],
+                builds_dir_layout: Some("$CI_PIPELINE_ID/$CI_JOB_ID".to_owned()),
+                builds_dir_is_shared: true,
                 image_dir: "$PWD/$FOO".into(),
                 image_cache_dir: Some("$HOME/cache".into()),
                 image_tmp_dir: Some("~/tmp".into()),
                 pull_policy: GitLabExecutorPullPolicy::Never,
+                pull_retries: 0,
+                pull_retry_delay: 10,
+                pull_timeout: None,
+                arch: None,
+                default_image: Some("docker://$FOO".into()),
+                allow_host_fallback: true,
+                prewarm_images: vec!["docker://$BAR".into()],
+                image_mirrors: vec!["$BAR.example.com".into()],
+                backend: GitLabExecutorBackend::Nix,
                 apptainer_executable: "~/bin/apptainer".into(),
+                docker_executable: "docker".into(),
+                enroot_executable: "enroot".into(),
                 gpu_amd: BoolOrString::String("$TRUE".into()),
                 gpu_nvidia: BoolOrString::String("$FALSE".into()),
+                gpu_intel: BoolOrString::String("$TRUE".into()),
+                mount_home: BoolOrString::String("$HOME/job-$FOO".into()),
                 mount: vec!["$BAR".to_owned(), "$THIS".into()],
+                create_mount_sources: false,
+                exec_args: vec!["--env=FOO=$FOO".to_owned()],
+                pass_env: vec!["$THIS".to_owned()],
+                step_wrapper: vec!["srun".to_owned(), "--gpus=$FOO".to_owned()],
+                limits: None,
+                run_as: Some("ci-$FOO".to_owned()),
+                shell: Some("ash".to_owned()),
+                shell_args: Some(vec!["-$FOO".to_owned()]),
+                network: Some("none".into()),
+                network_args: vec!["portmap=$FOO".to_owned()],
+                overlay_size_mb: None,
+                reuse_instance: false,
+                overlay: None,
+                verify_signatures: true,
+                trusted_fingerprints: vec!["8883491F4268F173C6E5DC49EDECE4F3F38D871E".to_owned()],
+                trusted_digests: Vec::new(),
                 description: Some("$BAZ".into()),
+                modules: vec!["gcc/$FOO".to_owned()],
+                spack: Some(GitLabSpackConfig {
+                    executable: "$THIS".into(),
+                    cache_dir: "$HOME/spack".into(),
+                    environment: Some("$FOO".into()),
+                }),
+                failed_builds: Some(GitLabFailedBuildsConfig {
+                    dir: "$HOME/failed".into(),
+                    archive: true,
+                    max_count: Some(1),
+                    max_age: None,
+                    max_size: Some(2),
+                }),
+                stale_builds_max_age: Some(24),
+                min_free_space: None,
+                caches: [(
+                    "ccache".to_owned(),
+                    GitLabCacheConfig {
+                        dir: "/caches/$FOO".into(),
+                        variable: None,
+                        max_size: None,
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                registries: HashMap::new(),
+                metrics: Some(GitLabExecutorMetricsConfig {
+                    textfile: "$HOME/metrics/$FOO.prom".into(),
+                }),
+                image_build: Some(GitLabImageBuildConfig {
+                    apptainer_args: vec!["--bind=$BAR".to_owned()],
+                    dockerfile_builder: Some("$THIS".into()),
+                }),
+                job_overrides: Some(GitLabJobOverridesConfig {
+                    gpu: false,
+                    flags: Vec::new(),
+                    mount_prefixes: vec!["$HOME/$FOO".to_owned()],
+                }),
             },
             "$HOME/builds".into(),
         );
@@ -445,6 +918,14 @@ mod tests {
             &GitLabRunnerInstance {
                 tags: Vec::new(),
                 launch_priority: None,
+                job_name_pattern: None,
+                ref_pattern: None,
+                pipeline_source: Vec::new(),
+                tag_expression: None,
+                run_untagged: false,
+                max_launches_per_hour: None,
+                max_launches_per_day: None,
+                schedule: HashMap::new(),
                 config_variables: [
                     ("FOO", "foo"),
                     ("BAR", "bar"),
@@ -459,6 +940,11 @@ mod tests {
         );
         assert!(expanded.is_ok(), "{:?}", expanded);
         let expanded = expanded.unwrap();
+        // the expanded config can be embedded into the executor arguments
+        let embedded: GitLabCustomExecutorConfig =
+            serde_json::from_str(&serde_json::to_string(&expanded).unwrap()).unwrap();
+        assert_eq!(embedded.caches["ccache"].dir, "/caches/foo");
+        assert_eq!(embedded.mount_home, expanded.mount_home);
         assert_eq!(
             expanded.builds_dir.to_str().unwrap(),
             format!("{}/builds2", home)
@@ -480,12 +966,52 @@ mod tests {
             format!("{}/tmp", home)
         );
         assert_eq!(expanded.pull_policy, GitLabExecutorPullPolicy::Never);
+        assert_eq!(expanded.default_image.as_deref(), Some("docker://foo"));
+        assert!(expanded.allow_host_fallback);
+        assert_eq!(expanded.prewarm_images, vec!["docker://bar".to_owned()]);
+        assert_eq!(expanded.image_mirrors, vec!["bar.example.com"]);
+        assert_eq!(expanded.step_wrapper, vec!["srun", "--gpus=foo"]);
+        assert_eq!(expanded.run_as.as_deref(), Some("ci-$FOO"));
+        assert_eq!(
+            expanded.builds_dir_layout.as_deref(),
+            Some("$CI_PIPELINE_ID/$CI_JOB_ID")
+        );
+        assert!(expanded.builds_dir_is_shared);
+        assert_eq!(expanded.shell_executable, Some("ash".to_owned()));
+        assert_eq!(expanded.caches["ccache"].dir, "/caches/foo");
+        assert_eq!(
+            expanded.metrics.as_ref().unwrap().textfile,
+            format!("{}/metrics/foo.prom", home)
+        );
+        assert_eq!(expanded.shell_args, Some(vec!["-$FOO".to_owned()]));
+        assert_eq!(expanded.network_args, vec!["portmap=foo"]);
+        assert_eq!(expanded.backend, GitLabExecutorBackend::Nix);
         assert_eq!(
             expanded.apptainer_executable.to_str().unwrap(),
             format!("{}/bin/apptainer", home)
         );
         assert_eq!(expanded.gpu_amd, true);
         assert_eq!(expanded.gpu_nvidia, false);
+        assert!(expanded.gpu_intel);
+        assert_eq!(
+            expanded.mount_home,
+            GitLabHomeMount::Path(format!("{}/job-foo", home).into())
+        );
+        assert_eq!(expanded.modules, vec!["gcc/foo".to_owned()]);
+        let spack = expanded.spack.unwrap();
+        assert_eq!(spack.executable, exe);
+        assert_eq!(spack.cache_dir, format!("{}/spack", home));
+        assert_eq!(spack.environment, Some("foo".into()));
+        let failed_builds = expanded.failed_builds.unwrap();
+        assert_eq!(failed_builds.dir, format!("{}/failed", home));
+        assert_eq!(failed_builds.max_size, Some(2));
+        let image_build = expanded.image_build.unwrap();
+        assert_eq!(image_build.apptainer_args, vec!["--bind=bar".to_owned()]);
+        assert_eq!(image_build.dockerfile_builder, Some(exe.clone()));
+        assert_eq!(
+            expanded.job_overrides.unwrap().mount_prefixes,
+            vec![format!("{}/foo", home)]
+        );
         assert_eq!(expanded.mount, vec!["bar".to_owned(), exe]);
         assert_eq!(expanded.description, Some("baz".into()));
     }
@@ -494,16 +1020,30 @@ mod tests {
         GitLabRunnersConfig {
             executor: None,
             name: "".into(),
-            project: "".into(),
+            project: StringOrList::String("".into()),
             hostname: "".into(),
             management_token: "".into(),
             runners: HashMap::new(),
-            poll: GitLabPollConfig { interval: 1 },
+            poll: GitLabPollConfig {
+                interval: 1,
+                api_timeout: 60,
+                debounce: None,
+                match_strategy: Default::default(),
+                decision_log: false,
+            },
+            configure: GitLabConfigureConfig::default(),
+            hooks: HashMap::new(),
+            maintenance: HashMap::new(),
+            schedule: HashMap::new(),
+            supervise: GitLabSuperviseConfig::default(),
+            metrics: None,
+            notify: None,
             launch: Some(config),
             runner: Runner {
                 builds_dir: "".into(),
                 cache_dir: "".into(),
                 output_limit: None,
+                shell: None,
                 executor: Executor::Custom {
                     custom: CustomExecutor {
                         config_exec: "".into(),
@@ -530,17 +1070,35 @@ mod tests {
             generated_config_file: Some("generated-config-path".into()),
         };
         let config = build_dummy_config_launch(GitLabLaunchConfig {
+            preset: None,
             executable: "~/bin/$FOO".into(),
             args: vec![
                 "$PWD/$BAR".to_owned(),
                 "~/".into(),
                 "$THIS".into(),
                 "$CONFIG-$NUM_JOBS".into(),
+                "$JOB_TIMEOUT/$JOB_TIMEOUT_MINUTES".into(),
             ],
             workdir: None,
             stdin: None,
             timeout: None,
+            retries: 0,
+            retry_delay: 0,
             group_size: 43,
+            max_per_poll: None,
+            budget: None,
+            parallelism: None,
+            allocation_id_pattern: None,
+            reconcile_timeout: None,
+            cancel_orphaned: false,
+            cancel: None,
+            redispatch_timeout: None,
+            starvation_timeout: None,
+            prepare_failure_cooldown: None,
+            prepare_failure_threshold: 3,
+            pre_hook: None,
+            post_hook: None,
+            queue_check: None,
         });
         let expanded = expand_launch_config_template(
             &paths,
@@ -549,12 +1107,21 @@ mod tests {
             &GitLabRunnerInstance {
                 tags: Vec::new(),
                 launch_priority: None,
+                job_name_pattern: None,
+                ref_pattern: None,
+                pipeline_source: Vec::new(),
+                tag_expression: None,
+                run_untagged: false,
+                max_launches_per_hour: None,
+                max_launches_per_day: None,
+                schedule: HashMap::new(),
                 config_variables: [("FOO", "foo"), ("BAR", "bar"), ("BAZ", "baz")]
                     .into_iter()
                     .map(|(a, b)| (a.to_owned(), b.to_owned()))
                     .collect(),
             },
             42,
+            3600,
         );
         assert!(expanded.is_ok(), "{:?}", expanded);
         let expanded = expanded.unwrap();
@@ -565,7 +1132,8 @@ mod tests {
                 format!("{}/bar", workdir),
                 format!("{}/", home),
                 exe,
-                "generated-config-path-42".into()
+                "generated-config-path-42".into(),
+                "3600/60".into()
             ]
         );
         assert_eq!(expanded.workdir, None);
@@ -583,6 +1151,7 @@ mod tests {
             generated_config_file: Some("generated-config-path".into()),
         };
         let config = build_dummy_config_launch(GitLabLaunchConfig {
+            preset: None,
             executable: "~/bin/$FOO".into(),
             args: vec![
                 "$PWD/$BAR".to_owned(),
@@ -593,7 +1162,32 @@ mod tests {
             workdir: Some("$FOO".into()),
             stdin: Some("$FOO $BAR $BAZ".into()),
             timeout: Some(1),
+            retries: 3,
+            retry_delay: 5,
             group_size: 43,
+            max_per_poll: Some(5),
+            budget: None,
+            parallelism: Some(2),
+            allocation_id_pattern: Some("job (\\d+)".into()),
+            reconcile_timeout: Some(10),
+            cancel_orphaned: true,
+            cancel: Some(GitLabCancelConfig {
+                executable: "$FOO".into(),
+                args: vec!["$ALLOCATION_ID".to_owned()],
+            }),
+            redispatch_timeout: Some(30),
+            starvation_timeout: Some(20),
+            prepare_failure_cooldown: None,
+            prepare_failure_threshold: 3,
+            pre_hook: Some(GitLabLaunchHookConfig {
+                executable: "$NAME-hook".into(),
+                args: vec!["$NUM_JOBS".to_owned()],
+            }),
+            post_hook: None,
+            queue_check: Some(GitLabQueueCheckConfig {
+                executable: "squeue".into(),
+                args: vec!["--name".to_owned(), "$NAME".into()],
+            }),
         });
         let expanded = expand_launch_config_template(
             &paths,
@@ -602,12 +1196,21 @@ mod tests {
             &GitLabRunnerInstance {
                 tags: Vec::new(),
                 launch_priority: None,
+                job_name_pattern: None,
+                ref_pattern: None,
+                pipeline_source: Vec::new(),
+                tag_expression: None,
+                run_untagged: false,
+                max_launches_per_hour: None,
+                max_launches_per_day: None,
+                schedule: HashMap::new(),
                 config_variables: [("FOO", "foo"), ("BAR", "bar"), ("BAZ", "baz")]
                     .into_iter()
                     .map(|(a, b)| (a.to_owned(), b.to_owned()))
                     .collect(),
             },
             42,
+            3600,
         );
         assert!(expanded.is_ok(), "{:?}", expanded);
         let expanded = expanded.unwrap();
@@ -625,5 +1228,38 @@ mod tests {
         assert_eq!(expanded.stdin, Some("foo bar baz".into()));
         assert_eq!(expanded.timeout, Some(1));
         assert_eq!(expanded.group_size, 43);
+        assert_eq!(expanded.max_per_poll, Some(5));
+        assert_eq!(expanded.reconcile_timeout, Some(10));
+        assert_eq!(expanded.redispatch_timeout, Some(30));
+        assert_eq!(expanded.starvation_timeout, Some(20));
+        let pre_hook = expanded.pre_hook.unwrap();
+        assert_eq!(pre_hook.executable, "name-hook");
+        assert_eq!(pre_hook.args, vec!["42".to_owned()]);
+        assert!(expanded.post_hook.is_none());
+        assert_eq!(expanded.queue_check.unwrap().args, vec!["--name", "name"]);
+        let cancel = expand_cancel_config_template(
+            &config,
+            "name",
+            &GitLabRunnerInstance {
+                tags: Vec::new(),
+                launch_priority: None,
+                job_name_pattern: None,
+                ref_pattern: None,
+                pipeline_source: Vec::new(),
+                tag_expression: None,
+                run_untagged: false,
+                max_launches_per_hour: None,
+                max_launches_per_day: None,
+                schedule: HashMap::new(),
+                config_variables: [("FOO".to_owned(), "scancel".to_owned())]
+                    .into_iter()
+                    .collect(),
+            },
+            "1234",
+        );
+        assert!(cancel.is_ok(), "{:?}", cancel);
+        let cancel = cancel.unwrap();
+        assert_eq!(cancel.executable, "scancel");
+        assert_eq!(cancel.args, vec!["1234".to_owned()]);
     }
 }
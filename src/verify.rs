@@ -0,0 +1,94 @@
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use log::{debug, info};
+
+/// Extracts the fingerprints of the keys that signed the image from the output of `apptainer verify`
+fn parse_fingerprints(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once("Fingerprint:"))
+        .map(|(_, fingerprint)| fingerprint.trim().to_uppercase())
+        .collect()
+}
+
+/// Normalizes a configured digest, which may be prefixed by sha256:
+fn normalize_digest(digest: &str) -> String {
+    digest
+        .strip_prefix("sha256:")
+        .unwrap_or(digest)
+        .to_lowercase()
+}
+
+/// Returns whether the image file is trusted because its digest is listed
+pub fn is_trusted_digest(trusted_digests: &[String], digest: &str) -> bool {
+    trusted_digests
+        .iter()
+        .any(|trusted| normalize_digest(trusted) == normalize_digest(digest))
+}
+
+/// Verifies the signatures of an image via `apptainer verify`. If trusted fingerprints are given,
+/// at least one of the signatures has to be made by one of these keys.
+pub fn verify_signature(
+    apptainer_executable: &Path,
+    image_path: &Path,
+    trusted_fingerprints: &[String],
+) -> anyhow::Result<()> {
+    let mut command = Command::new(apptainer_executable);
+    command.arg("verify").arg(image_path).stdin(Stdio::null());
+    debug!("Verifying image with command {:?}", command);
+    let output = command
+        .output()
+        .context(format!("Failed spawning {:?}", command))?;
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    if !output.status.success() {
+        Err(anyhow!(
+            "Image {:?} has no valid signature:\n{}",
+            image_path,
+            text
+        ))?;
+    }
+    let fingerprints = parse_fingerprints(&text);
+    if !trusted_fingerprints.is_empty()
+        && !fingerprints.iter().any(|fingerprint| {
+            trusted_fingerprints
+                .iter()
+                .any(|trusted| trusted.to_uppercase() == *fingerprint)
+        })
+    {
+        Err(anyhow!(
+            "Image {:?} is not signed by a trusted key, signed by {:?}",
+            image_path,
+            fingerprints
+        ))?;
+    }
+    info!("Verified signature of image {:?}", image_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_output() {
+        let output = "INFO:    Verifying image with PGP key material\n\
+            [LOCAL]   Signing entity: Jane Doe <jane@example.com>\n\
+            [LOCAL]   Fingerprint: 8883491f4268f173c6e5dc49edece4f3f38d871e\n\
+            Objects verified:\n";
+        assert_eq!(
+            parse_fingerprints(output),
+            vec!["8883491F4268F173C6E5DC49EDECE4F3F38D871E"]
+        );
+        let trusted = vec!["sha256:ABCD".to_owned()];
+        assert!(is_trusted_digest(&trusted, "abcd"));
+        assert!(!is_trusted_digest(&trusted, "abce"));
+    }
+}